@@ -0,0 +1,85 @@
+//! Benchmarks for reading CIF timetables.
+//!
+//! The extract is synthetic: the schedules of `tests/fixtures/sample.MCA`
+//! repeated under fresh UIDs until it reaches the size wanted, so runs are
+//! comparable from machine to machine without a real extract.
+//!
+//! ```sh
+//! cargo bench --bench cif
+//! ```
+
+use criterion::{BenchmarkId, Criterion, Throughput, black_box, criterion_group, criterion_main};
+use nationalrail_gtfs::cif::{CifReader, CifRecord, parse_record};
+
+const SAMPLE: &str = include_str!("../tests/fixtures/sample.MCA");
+
+/// An extract of about `lines` lines: the sample's header, then copies of
+/// its associations and schedules, each copy under new UIDs
+fn synthetic_extract(lines: usize) -> String {
+    let header = SAMPLE.lines().next().expect("the sample has a header");
+    let body: Vec<&str> = SAMPLE
+        .lines()
+        .filter(|line| !line.starts_with("HD") && !line.starts_with("ZZ"))
+        .collect();
+    let mut extract = format!("{}\n", header);
+    let mut copy = 0;
+    let mut written = 1;
+    while written < lines {
+        let uid = |n: usize| {
+            format!(
+                "{}{:05}",
+                char::from(b'A' + (n / 100_000) as u8),
+                n % 100_000
+            )
+        };
+        let (base, assoc) = (uid(copy * 2), uid(copy * 2 + 1));
+        for line in &body {
+            let line = line.replace("C10001", &base).replace("C20001", &assoc);
+            extract.push_str(&line);
+            extract.push('\n');
+        }
+        written += body.len();
+        copy += 1;
+    }
+    extract.push_str("ZZ\n");
+    extract
+}
+
+fn bench_parse_record(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_record");
+    for record_type in ["BS", "BX", "LO", "LI", "LT", "AA"] {
+        let line = SAMPLE
+            .lines()
+            .find(|line| line.starts_with(record_type))
+            .expect("the sample has every record type benchmarked");
+        group.bench_with_input(BenchmarkId::from_parameter(record_type), line, |b, line| {
+            b.iter(|| parse_record(black_box(line)))
+        });
+    }
+    group.finish();
+}
+
+fn bench_read_extract(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_extract");
+    for lines in [10_000, 100_000] {
+        let extract = synthetic_extract(lines);
+        group.throughput(Throughput::Bytes(extract.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(lines),
+            &extract,
+            |b, extract| {
+                b.iter(|| {
+                    let schedules = CifReader::new(extract.as_bytes())
+                        .map(|record| record.expect("the synthetic extract is valid"))
+                        .filter(|record| matches!(record, CifRecord::BasicSchedule(_)))
+                        .count();
+                    black_box(schedules)
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse_record, bench_read_extract);
+criterion_main!(benches);
@@ -0,0 +1,260 @@
+//! The `alerts` subcommand: GTFS-Realtime ServiceAlerts from the
+//! Knowledgebase incidents feed.
+//!
+//! Each incident becomes an alert on the agencies of the operators it
+//! affects. Routes of those agencies whose two end points are both named in
+//! the incident's affected-routes text are listed as well, so consumers can
+//! show the alert on the right lines without a mapping of their own.
+
+use crate::credentials::{Credentials, Service};
+use crate::gtfs_rt::{
+    Alert, EntitySelector, FeedEntity, FeedHeader, FeedMessage, GTFS_REALTIME_VERSION,
+    Incrementality, TimeRange, TranslatedString,
+};
+use crate::knowledgebase::{self, Incident};
+use crate::metrics::{self, Metrics};
+use crate::nrdp::{self, HttpOptions, NrdpClient, RetryPolicy};
+use anyhow::{Context, Result, anyhow};
+use prost::Message;
+use serde::Deserialize;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::runtime::Runtime;
+
+const KB_INCIDENTS_URL: &str = "https://opendata.nationalrail.co.uk/api/staticfeeds/5.0/incidents";
+
+#[derive(clap::Args)]
+pub struct AlertsArgs {
+    /// Directory holding the static feed the alerts refer to
+    #[arg(long, default_value = "./gtfs_output")]
+    gtfs_dir: PathBuf,
+
+    /// Address to serve the ServiceAlerts feed on
+    #[arg(long, default_value = "127.0.0.1:8081")]
+    listen: String,
+
+    /// Seconds between downloads of the incidents feed
+    #[arg(long, default_value_t = 300)]
+    interval: u64,
+
+    /// Address to serve Prometheus metrics on (e.g. :9090)
+    #[arg(long, value_name = "ADDR")]
+    serve_metrics: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RouteRow {
+    route_id: String,
+    agency_id: String,
+    route_long_name: String,
+}
+
+fn load_routes(dir: &Path) -> Result<Vec<RouteRow>> {
+    let path = dir.join("routes.txt");
+    let mut reader = csv::Reader::from_path(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    reader
+        .deserialize()
+        .collect::<Result<_, _>>()
+        .map_err(Into::into)
+}
+
+/// Whether both end points of an `A - B` route name appear in the text
+fn names_route(text: &str, route: &RouteRow) -> bool {
+    let text = text.to_lowercase();
+    let name = route.route_long_name.to_lowercase();
+    match name.split_once(" - ") {
+        Some((a, b)) => text.contains(a.trim()) && text.contains(b.trim()),
+        None => false,
+    }
+}
+
+fn alert(incident: &Incident, routes: &[RouteRow]) -> Alert {
+    let mut informed_entity: Vec<EntitySelector> = incident
+        .operators
+        .iter()
+        .map(|operator| EntitySelector {
+            agency_id: Some(operator.clone()),
+            route_id: None,
+        })
+        .collect();
+    informed_entity.extend(
+        routes
+            .iter()
+            .filter(|route| incident.operators.contains(&route.agency_id))
+            .filter(|route| names_route(&incident.routes_affected, route))
+            .map(|route| EntitySelector {
+                agency_id: None,
+                route_id: Some(route.route_id.clone()),
+            }),
+    );
+    let time = |t: Option<i64>| t.and_then(|t| u64::try_from(t).ok());
+    Alert {
+        active_period: if incident.start.is_some() || incident.end.is_some() {
+            vec![TimeRange {
+                start: time(incident.start),
+                end: time(incident.end),
+            }]
+        } else {
+            Vec::new()
+        },
+        informed_entity,
+        url: incident.url.clone().map(TranslatedString::english),
+        header_text: Some(TranslatedString::english(&incident.summary)),
+        description_text: (!incident.description.is_empty())
+            .then(|| TranslatedString::english(&incident.description)),
+    }
+}
+
+/// Alerts for every incident that names the operators it affects
+fn alert_feed(incidents: &[Incident], routes: &[RouteRow], now: u64) -> FeedMessage {
+    FeedMessage {
+        header: FeedHeader {
+            gtfs_realtime_version: GTFS_REALTIME_VERSION.to_string(),
+            incrementality: Some(Incrementality::FullDataset as i32),
+            timestamp: Some(now),
+        },
+        entity: incidents
+            .iter()
+            .filter(|incident| !incident.operators.is_empty())
+            .map(|incident| FeedEntity {
+                id: incident.id.clone(),
+                trip_update: None,
+                alert: Some(alert(incident, routes)),
+            })
+            .collect(),
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn poll(
+    runtime: &Runtime,
+    nrdp: &NrdpClient,
+    routes: &[RouteRow],
+    metrics: &Metrics,
+) -> Result<Vec<u8>> {
+    let started = Instant::now();
+    let file = runtime
+        .block_on(nrdp.download_feed(KB_INCIDENTS_URL))
+        .context("Failed to download Knowledgebase incidents feed")?;
+    let incidents = knowledgebase::parse_incidents(BufReader::new(file))?;
+    let feed = alert_feed(&incidents, routes, unix_now());
+    println!(
+        "Loaded {} incidents, {} affecting operators.",
+        incidents.len(),
+        feed.entity.len()
+    );
+    metrics.record_update(started.elapsed(), incidents.len(), feed.entity.len());
+    Ok(feed.encode_to_vec())
+}
+
+pub fn run(
+    args: AlertsArgs,
+    credentials: &Credentials,
+    http_options: &HttpOptions,
+    retry_policy: RetryPolicy,
+    token_cache: Option<PathBuf>,
+) -> Result<()> {
+    let (username, password) = credentials.get(Service::Nrdp)?;
+    let runtime = nrdp::runtime()?;
+    let nrdp = runtime.block_on(NrdpClient::connect(
+        http_options.client()?,
+        retry_policy,
+        username,
+        password,
+        token_cache,
+    ))?;
+
+    let routes = load_routes(&args.gtfs_dir)?;
+    println!(
+        "Loaded {} routes from {}.",
+        routes.len(),
+        args.gtfs_dir.display()
+    );
+    let metrics = Arc::new(Metrics::default());
+    if let Some(listen) = &args.serve_metrics {
+        metrics::serve(Arc::clone(&metrics), listen)?;
+    }
+    let latest = Arc::new(Mutex::new(poll(&runtime, &nrdp, &routes, &metrics)?));
+
+    let server = tiny_http::Server::http(&args.listen)
+        .map_err(|e| anyhow!("Failed to listen on {}: {}", args.listen, e))?;
+
+    let poller_latest = Arc::clone(&latest);
+    let interval = Duration::from_secs(args.interval);
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(interval);
+            // Keep serving the last good feed if a download fails
+            match poll(&runtime, &nrdp, &routes, &metrics) {
+                Ok(feed) => *poller_latest.lock().unwrap() = feed,
+                Err(e) => {
+                    metrics.record_error();
+                    println!("Warning: {:#}", e);
+                }
+            }
+        }
+    });
+
+    println!("Serving ServiceAlerts on http://{}/", args.listen);
+    let content_type = tiny_http::Header::from_bytes("Content-Type", "application/x-protobuf")
+        .expect("static header is valid");
+    for request in server.incoming_requests() {
+        let body = latest.lock().unwrap().clone();
+        let response = tiny_http::Response::from_data(body).with_header(content_type.clone());
+        if let Err(e) = request.respond(response) {
+            println!("Warning: failed to answer a request: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alert_names_agency_and_matching_routes() {
+        let route = |route_id: &str, agency_id: &str, name: &str| RouteRow {
+            route_id: route_id.to_string(),
+            agency_id: agency_id.to_string(),
+            route_long_name: name.to_string(),
+        };
+        let routes = [
+            route("GR_KNGX_YORK", "GR", "London Kings Cross - York"),
+            route("GR_KNGX_EDINBUR", "GR", "London Kings Cross - Edinburgh"),
+            route("XC_YORK_BHAMNWS", "XC", "York - Peterborough"),
+        ];
+        let incident = Incident {
+            id: "ABC123".to_string(),
+            summary: "Disruption between London Kings Cross and York".to_string(),
+            operators: vec!["GR".to_string()],
+            routes_affected: "between London Kings Cross and York".to_string(),
+            start: Some(1_704_088_800),
+            ..Default::default()
+        };
+        let unaffected = Incident {
+            id: "DEF456".to_string(),
+            ..Default::default()
+        };
+
+        let feed = alert_feed(&[incident, unaffected], &routes, 1_704_090_000);
+        assert_eq!(feed.entity.len(), 1);
+        let alert = feed.entity[0].alert.as_ref().unwrap();
+        let informed: Vec<(Option<&str>, Option<&str>)> = alert
+            .informed_entity
+            .iter()
+            .map(|e| (e.agency_id.as_deref(), e.route_id.as_deref()))
+            .collect();
+        assert_eq!(informed, [(Some("GR"), None), (None, Some("GR_KNGX_YORK"))]);
+        assert_eq!(alert.active_period[0].start, Some(1_704_088_800));
+        assert_eq!(alert.description_text, None);
+    }
+}
@@ -0,0 +1,72 @@
+//! Other names stations go by, from the MSN's `L` records, written as
+//! `stop_aliases.txt` so apps searching the feed can match them.
+//!
+//! An alias record gives a station's name as its `A` record has it and one
+//! alternative, so "KINGS CROSS" finds "LONDON KINGS CROSS". GTFS has no
+//! place for them (translations.txt is for languages), hence the extra file.
+
+use crate::writer::create_csv;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+/// Aliases by the station name they're for, in file order
+pub fn parse_msn_aliases<R: Read>(reader: R) -> Result<HashMap<String, Vec<String>>> {
+    let mut aliases: HashMap<String, Vec<String>> = HashMap::new();
+    for line in BufReader::new(reader).lines() {
+        let line = line?;
+        if !line.starts_with('L') {
+            continue;
+        }
+        // RSPS5046: station name 6-35, alias 37 onwards
+        let name = line.get(5..35).unwrap_or("").trim();
+        let alias = line.get(36..).unwrap_or("").trim();
+        if !name.is_empty() && !alias.is_empty() && alias != name {
+            aliases
+                .entry(name.to_string())
+                .or_default()
+                .push(alias.to_string());
+        }
+    }
+    Ok(aliases)
+}
+
+#[derive(Serialize)]
+struct Alias<'a> {
+    stop_id: &'a str,
+    alias: &'a str,
+}
+
+/// Write stop_aliases.txt for the given stop ids and aliases
+pub fn write_aliases(
+    output_dir: &Path,
+    buffer_size: usize,
+    aliases: &[(String, String)],
+) -> Result<()> {
+    let mut writer = create_csv(output_dir, "stop_aliases.txt", buffer_size)?;
+    for (stop_id, alias) in aliases {
+        writer.serialize(Alias { stop_id, alias })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_msn_aliases() {
+        let msn = "\
+A    LONDON KINGS CROSS            2KNGX   KGX   KGX15303 6183215
+L    LONDON KINGS CROSS             KINGS CROSS
+L    LONDON KINGS CROSS             KINGS X
+L    YORK                           YORK
+";
+        let aliases = parse_msn_aliases(msn.as_bytes()).unwrap();
+        assert_eq!(aliases["LONDON KINGS CROSS"], ["KINGS CROSS", "KINGS X"]);
+        assert_eq!(aliases.len(), 1);
+    }
+}
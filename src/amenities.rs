@@ -0,0 +1,69 @@
+//! On-board facilities from the BS record, as words for `trips.txt`.
+//!
+//! The CIF codes are terse and partly overlapping, so each column is
+//! decoded to a fixed vocabulary rather than passed through.
+
+/// 1 if first class seats are sold, 0 for standard class only. A blank
+/// seating class means both.
+pub fn first_class(seating_class: Option<char>) -> u8 {
+    match seating_class {
+        Some('S') => 0,
+        _ => 1,
+    }
+}
+
+/// Sleeping berths by class
+pub fn sleepers(code: Option<char>) -> Option<&'static str> {
+    match code? {
+        'B' => Some("first_and_standard"),
+        'F' => Some("first"),
+        'S' => Some("standard"),
+        _ => None,
+    }
+}
+
+/// Seat reservation policy
+pub fn reservations(code: Option<char>) -> Option<&'static str> {
+    match code? {
+        'A' => Some("compulsory"),
+        'E' => Some("bicycles_essential"),
+        'R' => Some("recommended"),
+        'S' => Some("possible"),
+        _ => None,
+    }
+}
+
+/// Catering, one word per code, separated by `;`
+pub fn catering(codes: Option<&str>) -> Option<String> {
+    let words: Vec<&str> = codes?
+        .chars()
+        .filter_map(|code| match code {
+            'C' => Some("buffet"),
+            'F' => Some("first_class_restaurant"),
+            'H' => Some("hot_food"),
+            'M' => Some("first_class_meal"),
+            'R' => Some("restaurant"),
+            'T' => Some("trolley"),
+            _ => None,
+        })
+        .collect();
+    (!words.is_empty()).then(|| words.join(";"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_amenities() {
+        assert_eq!(first_class(Some('B')), 1);
+        assert_eq!(first_class(None), 1);
+        assert_eq!(first_class(Some('S')), 0);
+        assert_eq!(sleepers(Some('S')), Some("standard"));
+        assert_eq!(sleepers(None), None);
+        assert_eq!(reservations(Some('A')), Some("compulsory"));
+        assert_eq!(catering(Some("CT")).as_deref(), Some("buffet;trolley"));
+        // P is wheelchair-only reservations, not catering
+        assert_eq!(catering(Some("P")), None);
+    }
+}
@@ -0,0 +1,92 @@
+//! `attributions.txt`, crediting the organisations whose data the feed is
+//! built from. OpenStreetMap's ODbL and the Open Government Licence both
+//! require downstream publications to carry the credit.
+
+use crate::writer::create_csv;
+use anyhow::Result;
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Debug, Serialize)]
+struct Attribution {
+    attribution_id: &'static str,
+    organization_name: &'static str,
+    is_producer: u8,
+    attribution_url: &'static str,
+}
+
+/// Which optional sources went into the feed
+pub struct DataSources {
+    /// CORPUS or BPLAN
+    pub network_rail: bool,
+    pub naptan: bool,
+}
+
+fn attributions(sources: &DataSources) -> Vec<Attribution> {
+    let credit = |attribution_id, organization_name, attribution_url| Attribution {
+        attribution_id,
+        organization_name,
+        is_producer: 1,
+        attribution_url,
+    };
+    let mut rows = vec![
+        credit(
+            "rdg",
+            "Rail Delivery Group (National Rail Data Portal)",
+            "https://opendata.nationalrail.co.uk",
+        ),
+        credit(
+            "osm",
+            "OpenStreetMap contributors",
+            "https://www.openstreetmap.org/copyright",
+        ),
+    ];
+    if sources.network_rail {
+        rows.push(credit(
+            "network-rail",
+            "Network Rail",
+            "https://www.networkrail.co.uk",
+        ));
+    }
+    if sources.naptan {
+        rows.push(credit(
+            "naptan",
+            "Department for Transport (NaPTAN), Open Government Licence",
+            "https://naptan.api.dft.gov.uk",
+        ));
+    }
+    rows
+}
+
+pub fn write_attributions(
+    output_dir: &Path,
+    buffer_size: usize,
+    sources: &DataSources,
+) -> Result<()> {
+    let mut writer = create_csv(output_dir, "attributions.txt", buffer_size)?;
+    for attribution in attributions(sources) {
+        writer.serialize(attribution)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_optional_sources_are_credited_when_used() {
+        let ids = |network_rail, naptan| -> Vec<&str> {
+            attributions(&DataSources {
+                network_rail,
+                naptan,
+            })
+            .iter()
+            .map(|a| a.attribution_id)
+            .collect()
+        };
+        assert_eq!(ids(false, false), ["rdg", "osm"]);
+        assert_eq!(ids(true, true), ["rdg", "osm", "network-rail", "naptan"]);
+    }
+}
@@ -0,0 +1,103 @@
+//! Bank holidays in England and Wales.
+//!
+//! Schedules flagged `X` in the BS record's bank holiday running column
+//! don't run on bank holiday Mondays. The dates come from gov.uk, with a
+//! built-in list for when it isn't fetched.
+
+use anyhow::Result;
+use chrono::{Datelike, NaiveDate, Weekday};
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::io::Read;
+
+pub const BANK_HOLIDAYS_URL: &str = "https://www.gov.uk/bank-holidays.json";
+
+/// The gov.uk division the National Rail timetable's exclusions follow
+const DIVISION: &str = "england-and-wales";
+
+#[rustfmt::skip]
+const BUILTIN: &[&str] = &[
+    "2024-01-01", "2024-03-29", "2024-04-01", "2024-05-06", "2024-05-27", "2024-08-26", "2024-12-25", "2024-12-26",
+    "2025-01-01", "2025-04-18", "2025-04-21", "2025-05-05", "2025-05-26", "2025-08-25", "2025-12-25", "2025-12-26",
+    "2026-01-01", "2026-04-03", "2026-04-06", "2026-05-04", "2026-05-25", "2026-08-31", "2026-12-25", "2026-12-28",
+    "2027-01-01", "2027-03-26", "2027-03-29", "2027-05-03", "2027-05-31", "2027-08-30", "2027-12-27", "2027-12-28",
+];
+
+#[derive(Deserialize)]
+struct Division {
+    events: Vec<Event>,
+}
+
+#[derive(Deserialize)]
+struct Event {
+    date: NaiveDate,
+}
+
+/// The bank holiday Mondays `X` schedules are excluded from
+#[derive(Debug, Clone, PartialEq)]
+pub struct BankHolidays(BTreeSet<NaiveDate>);
+
+impl BankHolidays {
+    pub fn builtin() -> Self {
+        BankHolidays::mondays(
+            BUILTIN
+                .iter()
+                .map(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").expect("valid built-in date")),
+        )
+    }
+
+    /// Read gov.uk's `bank-holidays.json`
+    pub fn parse_gov_uk<R: Read>(reader: R) -> Result<Self> {
+        let mut divisions: HashMap<String, Division> = serde_json::from_reader(reader)?;
+        let division = divisions
+            .remove(DIVISION)
+            .ok_or_else(|| anyhow::anyhow!("No {} division in bank holidays", DIVISION))?;
+        Ok(BankHolidays::mondays(
+            division.events.into_iter().map(|e| e.date),
+        ))
+    }
+
+    fn mondays(dates: impl Iterator<Item = NaiveDate>) -> Self {
+        BankHolidays(dates.filter(|d| d.weekday() == Weekday::Mon).collect())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Bank holiday Mondays from `start` to `end` inclusive
+    pub fn between(&self, start: NaiveDate, end: NaiveDate) -> impl Iterator<Item = NaiveDate> {
+        self.0.range(start..=end).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_mondays_are_kept() {
+        let json = r#"{
+            "england-and-wales": {"division": "england-and-wales", "events": [
+                {"title": "Good Friday", "date": "2025-04-18", "notes": "", "bunting": false},
+                {"title": "Easter Monday", "date": "2025-04-21", "notes": "", "bunting": true}
+            ]},
+            "scotland": {"division": "scotland", "events": [
+                {"title": "2nd January", "date": "2025-01-02", "notes": "", "bunting": true}
+            ]}
+        }"#;
+        let holidays = BankHolidays::parse_gov_uk(json.as_bytes()).unwrap();
+        let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+        let dates: Vec<NaiveDate> = holidays
+            .between(date("2025-01-01"), date("2025-12-31"))
+            .collect();
+        assert_eq!(dates, [date("2025-04-21")]);
+
+        let builtin = BankHolidays::builtin();
+        assert!(
+            builtin
+                .between(date("2026-12-25"), date("2026-12-28"))
+                .eq([date("2026-12-28")])
+        );
+    }
+}
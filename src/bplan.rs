@@ -0,0 +1,73 @@
+//! Network Rail BPLAN geography.
+//!
+//! BPLAN is a tab-separated extract of the timetable planning data. Its
+//! `LOC` records give every TIPLOC a name and an OSGB36 grid reference in
+//! metres, covering junctions and sidings as well as stations.
+
+use crate::osgb36_to_lat_lon;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::io::BufRead;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BplanLocation {
+    pub name: String,
+    pub lat: f64,
+    pub lon: f64,
+}
+
+/// Current `LOC` records with a grid reference, by TIPLOC
+pub fn parse_bplan<R: BufRead>(reader: R) -> Result<HashMap<String, BplanLocation>> {
+    let mut map = HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+        // LOC, action, TIPLOC, name, start date, end date, easting, northing, ...
+        let ["LOC", _, tiploc, name, _, end_date, easting, northing, ..] = fields[..] else {
+            continue;
+        };
+        if !end_date.is_empty() {
+            continue;
+        }
+        let (Ok(easting), Ok(northing)) = (easting.parse::<f64>(), northing.parse::<f64>()) else {
+            continue;
+        };
+        // Locations without a surveyed position have a zero grid reference
+        if easting <= 0.0 || northing <= 0.0 {
+            continue;
+        }
+        if let Some((lat, lon)) = osgb36_to_lat_lon(easting, northing) {
+            map.insert(
+                tiploc.to_string(),
+                BplanLocation {
+                    name: name.to_string(),
+                    lat,
+                    lon,
+                },
+            );
+        }
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bplan_locations() {
+        let bplan = [
+            "PIF\t5.0.0\tPlanning data",
+            "LOC\tA\tKNGX\tLondon Kings Cross\t09-12-2023 00:00:00\t\t530370\t183220\tT\t1\t72410\t\tN",
+            "LOC\tA\tOLDLOC\tOld Sidings\t01-01-2000 00:00:00\t01-01-2010 00:00:00\t530000\t183000\tT\t1\t\t\tN",
+            "LOC\tA\tNOWHERE\tUnsurveyed\t01-01-2000 00:00:00\t\t0\t0\tT\t1\t\t\tN",
+        ]
+        .join("\n");
+        let map = parse_bplan(bplan.as_bytes()).unwrap();
+        assert_eq!(map.len(), 1);
+        let kgx = &map["KNGX"];
+        assert_eq!(kgx.name, "London Kings Cross");
+        assert!((kgx.lat - 51.531).abs() < 0.005, "lat {}", kgx.lat);
+        assert!((kgx.lon - -0.123).abs() < 0.005, "lon {}", kgx.lon);
+    }
+}
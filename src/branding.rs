@@ -0,0 +1,169 @@
+//! Operator branding for agencies and routes.
+//!
+//! Neither the timetable nor the fares feed carries colours or contact
+//! details, so a built-in table keyed by ATOC code supplies them. A TOML
+//! file can override or extend it, one table per operator:
+//!
+//! ```toml
+//! [GW]
+//! color = "0A493E"
+//! text_color = "FFFFFF"
+//! url = "https://www.gwr.com"
+//! phone = "03457 000 125"
+//! fare_url = "https://www.gwr.com/tickets"
+//! lang = "en"
+//! ```
+//!
+//! Any retailer sells tickets for any operator at the same price, so the
+//! built-in fare URL is National Rail's journey planner for everyone.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Fallback for operators without an entry of their own
+pub const DEFAULT_AGENCY_URL: &str = "https://www.nationalrail.co.uk";
+/// Where to buy tickets, for operators without a fare URL of their own
+pub const DEFAULT_FARE_URL: &str = "https://www.nationalrail.co.uk/journey-planner/";
+/// Language of names and headsigns, for operators that don't set one
+pub const DEFAULT_LANG: &str = "en";
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Branding {
+    /// Route colour, six hex digits without the `#`
+    pub color: Option<String>,
+    pub text_color: Option<String>,
+    pub url: Option<String>,
+    pub phone: Option<String>,
+    pub fare_url: Option<String>,
+    /// IETF language tag for `agency_lang`
+    pub lang: Option<String>,
+}
+
+impl Branding {
+    /// Take every field `other` sets, keeping ours where it doesn't
+    fn merge(&mut self, other: Branding) {
+        self.color = other.color.or(self.color.take());
+        self.text_color = other.text_color.or(self.text_color.take());
+        self.url = other.url.or(self.url.take());
+        self.phone = other.phone.or(self.phone.take());
+        self.fare_url = other.fare_url.or(self.fare_url.take());
+        self.lang = other.lang.or(self.lang.take());
+    }
+}
+
+/// ATOC code, colour, text colour, website, enquiries phone number
+#[rustfmt::skip]
+const BUILTIN: &[(&str, &str, &str, &str, &str)] = &[
+    ("AW", "E0252F", "FFFFFF", "https://tfw.wales", "03333 211 202"),
+    ("CC", "B7007C", "FFFFFF", "https://www.c2c-online.co.uk", "0345 744 4422"),
+    ("CH", "00A0E2", "FFFFFF", "https://www.chilternrailways.co.uk", "03456 005 165"),
+    ("CS", "1D2E35", "FFFFFF", "https://www.sleeper.scot", "0330 060 0500"),
+    ("EM", "4C2F48", "FFFFFF", "https://www.eastmidlandsrailway.co.uk", "03457 125 678"),
+    ("GC", "1D1D1B", "FFFFFF", "https://www.grandcentralrail.com", "0345 603 4852"),
+    ("GN", "6B2C91", "FFFFFF", "https://www.greatnorthernrail.com", "0345 026 4700"),
+    ("GR", "CE0E2D", "FFFFFF", "https://www.lner.co.uk", "03457 225 333"),
+    ("GW", "0A493E", "FFFFFF", "https://www.gwr.com", "03457 000 125"),
+    ("GX", "DC0A1E", "000000", "https://www.gatwickexpress.com", "0345 850 1530"),
+    ("HT", "DE005B", "FFFFFF", "https://www.hulltrains.co.uk", "0345 071 0222"),
+    ("HX", "532E63", "FFFFFF", "https://www.heathrowexpress.com", "0345 600 1515"),
+    ("LD", "2B6DF8", "FFFFFF", "https://www.lumo.co.uk", "0345 608 6686"),
+    ("LE", "D70428", "FFFFFF", "https://www.greateranglia.co.uk", "0345 600 7245"),
+    ("LM", "FF8200", "000000", "https://www.westmidlandsrailway.co.uk", "0333 311 0039"),
+    ("LO", "EE7C0E", "FFFFFF", "https://tfl.gov.uk/modes/london-overground/", "0343 222 1234"),
+    ("ME", "FFF200", "000000", "https://www.merseyrail.org", "0151 555 1111"),
+    ("NT", "262262", "FFFFFF", "https://www.northernrailway.co.uk", "0800 200 6060"),
+    ("SE", "00AFE8", "000000", "https://www.southeasternrailway.co.uk", "0345 322 7021"),
+    ("SN", "8CC63E", "000000", "https://www.southernrailway.com", "0345 127 2920"),
+    ("SR", "1E467D", "FFFFFF", "https://www.scotrail.co.uk", "0344 811 0141"),
+    ("SW", "24398C", "FFFFFF", "https://www.southwesternrailway.com", "0345 6000 650"),
+    ("TL", "E9438D", "FFFFFF", "https://www.thameslinkrailway.com", "0345 026 4700"),
+    ("TP", "09A4EC", "FFFFFF", "https://www.tpexpress.co.uk", "0345 600 1671"),
+    ("VT", "004354", "FFFFFF", "https://www.avantiwestcoast.co.uk", "0344 556 5650"),
+    ("XC", "660F21", "FFFFFF", "https://www.crosscountrytrains.co.uk", "0344 811 0124"),
+    ("XR", "6950A1", "FFFFFF", "https://tfl.gov.uk/modes/elizabeth-line/", "0343 222 1234"),
+];
+
+pub struct BrandingTable(HashMap<String, Branding>);
+
+impl BrandingTable {
+    pub fn builtin() -> Self {
+        BrandingTable(
+            BUILTIN
+                .iter()
+                .map(|&(atoc, color, text_color, url, phone)| {
+                    let branding = Branding {
+                        color: Some(color.to_string()),
+                        text_color: Some(text_color.to_string()),
+                        url: Some(url.to_string()),
+                        phone: Some(phone.to_string()),
+                        fare_url: None,
+                        lang: None,
+                    };
+                    (atoc.to_string(), branding)
+                })
+                .collect(),
+        )
+    }
+
+    /// Apply a TOML override file on top of the table
+    pub fn load_overrides(&mut self, path: &Path) -> Result<()> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        self.apply_overrides(&text)
+            .with_context(|| format!("Invalid branding file {}", path.display()))
+    }
+
+    fn apply_overrides(&mut self, text: &str) -> Result<()> {
+        let overrides: HashMap<String, Branding> = toml::from_str(text)?;
+        for (atoc, branding) in overrides {
+            self.0.entry(atoc).or_default().merge(branding);
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, atoc_code: &str) -> Option<&Branding> {
+        self.0.get(atoc_code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_branding_overrides() {
+        let mut table = BrandingTable::builtin();
+        table
+            .apply_overrides(
+                r#"
+                [GW]
+                color = "123456"
+                fare_url = "https://example.com/gwr-tickets"
+
+                [ZZ]
+                url = "https://example.com"
+                "#,
+            )
+            .unwrap();
+
+        let gw = table.get("GW").unwrap();
+        assert_eq!(gw.color.as_deref(), Some("123456"));
+        assert_eq!(gw.url.as_deref(), Some("https://www.gwr.com"));
+        assert_eq!(
+            gw.fare_url.as_deref(),
+            Some("https://example.com/gwr-tickets")
+        );
+        assert_eq!(
+            table.get("ZZ"),
+            Some(&Branding {
+                url: Some("https://example.com".to_string()),
+                ..Default::default()
+            })
+        );
+        assert!(table.apply_overrides("[GW]\ncolour = \"123456\"").is_err());
+    }
+}
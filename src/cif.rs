@@ -0,0 +1,873 @@
+//! Typed parsing of UK rail CIF timetable files.
+//!
+//! Each line of a CIF file is a fixed-width record identified by its first
+//! two characters. [`parse_record`] turns a line into a [`CifRecord`], failing
+//! with a [`CifParseError`] when a required field is missing or malformed
+//! instead of quietly reading it as blank. [`CifReader`] does the same for
+//! every line of a file.
+//!
+//! Column positions follow the Network Rail CIF End User Specification.
+
+use chrono::NaiveDate;
+use std::io::BufRead;
+use std::ops::Range;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum CifParseError {
+    #[error("{record} record truncated before its {field} (columns {}-{})", .columns.start + 1, .columns.end)]
+    Truncated {
+        record: &'static str,
+        field: &'static str,
+        columns: Range<usize>,
+    },
+    #[error("{record} record has an invalid {field}: '{value}'")]
+    InvalidField {
+        record: &'static str,
+        field: &'static str,
+        value: String,
+    },
+}
+
+#[derive(Debug, Error)]
+pub enum CifError {
+    #[error("line {line}: {source}")]
+    Parse {
+        line: usize,
+        /// The record's two-character type, e.g. `BS`
+        record_type: String,
+        /// The line as read, without its line ending
+        text: String,
+        #[source]
+        source: CifParseError,
+    },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transaction {
+    New,
+    Revise,
+    Delete,
+}
+
+/// A working timetable time: `HHMM`, with an `H` suffix for the half minute
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CifTime {
+    pub hour: u8,
+    pub minute: u8,
+    pub half_minute: bool,
+}
+
+impl CifTime {
+    /// As a GTFS `HH:MM:SS` time, dropping the half minute
+    pub fn to_gtfs(self) -> String {
+        format!("{:02}:{:02}:00", self.hour, self.minute)
+    }
+}
+
+/// `HD`: the file header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub mainframe_identity: String,
+    pub extract_date: NaiveDate,
+    /// `HHMM`
+    pub extract_time: String,
+    pub current_file_reference: String,
+    pub last_file_reference: Option<String>,
+    /// `F` for a full extract, `U` for an update
+    pub update_indicator: char,
+    pub version: Option<String>,
+    pub user_start_date: NaiveDate,
+    pub user_end_date: NaiveDate,
+}
+
+impl Header {
+    pub fn is_full_extract(&self) -> bool {
+        self.update_indicator == 'F'
+    }
+}
+
+/// `TI`: a new TIPLOC
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TiplocInsert {
+    pub tiploc: String,
+    pub nalco: Option<String>,
+    pub tps_description: Option<String>,
+    pub stanox: Option<String>,
+    pub crs: Option<String>,
+    pub description: Option<String>,
+}
+
+/// `TA`: changes to a TIPLOC, possibly renaming it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TiplocAmend {
+    pub location: TiplocInsert,
+    pub new_tiploc: Option<String>,
+}
+
+/// `TD`: a TIPLOC that no longer exists
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TiplocDelete {
+    pub tiploc: String,
+}
+
+/// `AA`: a join, divide or next-working link between two schedules
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Association {
+    pub transaction: Transaction,
+    pub base_uid: String,
+    pub assoc_uid: String,
+    pub start_date: NaiveDate,
+    /// Blank on deletions
+    pub end_date: Option<NaiveDate>,
+    /// Monday to Sunday, `1` for each day the association applies
+    pub days_run: String,
+    /// `JJ` join, `VV` divide or `NP` next working; blank on deletions
+    pub category: String,
+    /// `S` same day, `N` over next midnight, `P` over previous midnight
+    pub date_indicator: Option<char>,
+    pub location: String,
+    pub base_location_suffix: Option<char>,
+    pub assoc_location_suffix: Option<char>,
+    /// `P` passenger or `O` operating use only
+    pub assoc_type: char,
+    pub stp_indicator: char,
+}
+
+/// `BS`: the start of a schedule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicSchedule {
+    pub transaction: Transaction,
+    pub uid: String,
+    pub start_date: NaiveDate,
+    /// Blank on deletions
+    pub end_date: Option<NaiveDate>,
+    /// Monday to Sunday, `1` for each day the schedule runs
+    pub days_run: String,
+    /// `X` doesn't run on bank holiday Mondays
+    pub bank_holiday_running: Option<char>,
+    /// `P` passenger, `F` freight, `T` trip, `B` bus, `S` ship, or `1` to
+    /// `5` for their short-term-planned equivalents
+    pub train_status: Option<char>,
+    /// e.g. `OO` ordinary passenger, `XX` express, `EE` empty coaching stock
+    pub train_category: Option<String>,
+    pub train_identity: String,
+    pub headcode: Option<String>,
+    pub train_service_code: Option<String>,
+    pub portion_id: Option<char>,
+    pub power_type: Option<String>,
+    pub timing_load: Option<String>,
+    pub speed: Option<String>,
+    pub operating_characteristics: Option<String>,
+    pub seating_class: Option<char>,
+    pub sleepers: Option<char>,
+    pub reservations: Option<char>,
+    pub catering_code: Option<String>,
+    pub service_branding: Option<String>,
+    pub stp_indicator: char,
+}
+
+/// `BX`: schedule details that didn't fit on the `BS` record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicScheduleExtra {
+    pub uic_code: Option<String>,
+    pub atoc_code: String,
+    pub applicable_timetable: Option<char>,
+    pub retail_service_id: Option<String>,
+}
+
+/// `LO`: where a schedule starts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OriginLocation {
+    pub tiploc: String,
+    pub tiploc_suffix: Option<char>,
+    pub scheduled_departure: CifTime,
+    pub public_departure: Option<CifTime>,
+    pub platform: Option<String>,
+    pub line: Option<String>,
+    pub activity: String,
+    /// Allowances in minutes, with `H` for a half
+    pub engineering_allowance: Option<String>,
+    pub pathing_allowance: Option<String>,
+    pub performance_allowance: Option<String>,
+}
+
+/// `LI`: a location a schedule calls at or passes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IntermediateLocation {
+    pub tiploc: String,
+    pub tiploc_suffix: Option<char>,
+    pub scheduled_arrival: Option<CifTime>,
+    pub scheduled_departure: Option<CifTime>,
+    /// Set instead of arrival and departure where the train doesn't stop
+    pub scheduled_pass: Option<CifTime>,
+    pub public_arrival: Option<CifTime>,
+    pub public_departure: Option<CifTime>,
+    pub platform: Option<String>,
+    pub line: Option<String>,
+    pub path: Option<String>,
+    pub activity: String,
+    /// Allowances in minutes, with `H` for a half
+    pub engineering_allowance: Option<String>,
+    pub pathing_allowance: Option<String>,
+    pub performance_allowance: Option<String>,
+}
+
+/// Categories of train that carry the public
+const PASSENGER_CATEGORIES: &[&str] = &[
+    "OL", "OO", "OW", "XC", "XD", "XI", "XR", "XX", "XZ", "BR", "BS", "SS",
+];
+
+impl BasicSchedule {
+    /// Whether the schedule is a passenger service by both its status and
+    /// its category, as opposed to freight, empty stock or a light engine
+    pub fn is_passenger(&self) -> bool {
+        let status = matches!(
+            self.train_status,
+            None | Some('P' | 'B' | 'S' | '1' | '4' | '5')
+        );
+        let category = self
+            .train_category
+            .as_deref()
+            .is_none_or(|c| PASSENGER_CATEGORIES.contains(&c));
+        status && category
+    }
+
+    /// `bus`, `ship` or `train`, from the train status
+    pub fn vehicle_type(&self) -> &'static str {
+        match self.train_status {
+            Some('B' | '5') => "bus",
+            Some('S' | '4') => "ship",
+            _ => "train",
+        }
+    }
+}
+
+impl IntermediateLocation {
+    /// Whether passengers can use the call, i.e. it has a public time
+    pub fn is_public(&self) -> bool {
+        self.public_arrival.is_some() || self.public_departure.is_some()
+    }
+}
+
+/// `CR`: a change to the train's characteristics part way along
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEnRoute {
+    pub tiploc: String,
+    pub tiploc_suffix: Option<char>,
+    pub train_category: Option<String>,
+    pub train_identity: Option<String>,
+    pub headcode: Option<String>,
+    pub train_service_code: Option<String>,
+    pub portion_id: Option<char>,
+    pub power_type: Option<String>,
+    pub timing_load: Option<String>,
+    pub speed: Option<String>,
+    pub operating_characteristics: Option<String>,
+    pub seating_class: Option<char>,
+    pub sleepers: Option<char>,
+    pub reservations: Option<char>,
+    pub catering_code: Option<String>,
+    pub service_branding: Option<String>,
+    pub uic_code: Option<String>,
+    pub retail_service_id: Option<String>,
+}
+
+/// `LT`: where a schedule ends
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TerminatingLocation {
+    pub tiploc: String,
+    pub tiploc_suffix: Option<char>,
+    pub scheduled_arrival: CifTime,
+    pub public_arrival: Option<CifTime>,
+    pub platform: Option<String>,
+    pub path: Option<String>,
+    pub activity: String,
+}
+
+/// The two-character activity codes of a location record, e.g. `T` (stops
+/// to take up and set down passengers) or `R` (stops when required)
+pub fn activities(activity: &str) -> impl Iterator<Item = &str> {
+    activity
+        .as_bytes()
+        .chunks(2)
+        .filter_map(|code| std::str::from_utf8(code).ok())
+        .map(str::trim)
+        .filter(|code| !code.is_empty())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CifRecord {
+    Header(Header),
+    TiplocInsert(TiplocInsert),
+    TiplocAmend(TiplocAmend),
+    TiplocDelete(TiplocDelete),
+    Association(Association),
+    BasicSchedule(BasicSchedule),
+    BasicScheduleExtra(BasicScheduleExtra),
+    OriginLocation(OriginLocation),
+    IntermediateLocation(IntermediateLocation),
+    ChangeEnRoute(ChangeEnRoute),
+    TerminatingLocation(TerminatingLocation),
+    /// `ZZ`: the end of the file
+    Trailer,
+    /// A record type this parser doesn't model
+    Other,
+}
+
+/// Parse one line of a CIF file
+pub fn parse_record(line: &str) -> Result<CifRecord, CifParseError> {
+    let Some(record_type) = line.get(0..2) else {
+        return Ok(CifRecord::Other);
+    };
+    Ok(match record_type {
+        "HD" => {
+            let f = Fields::new(line, "HD");
+            CifRecord::Header(Header {
+                mainframe_identity: f.text(2..22, "mainframe identity")?,
+                extract_date: f.ddmmyy(22..28, "date of extract")?,
+                extract_time: f.text(28..32, "time of extract")?,
+                current_file_reference: f.text(32..39, "current file reference")?,
+                last_file_reference: f.optional(39..46),
+                update_indicator: f.char(46..47, "update indicator")?,
+                version: f.optional(47..48),
+                user_start_date: f.ddmmyy(48..54, "user start date")?,
+                user_end_date: f.ddmmyy(54..60, "user end date")?,
+            })
+        }
+        "TI" => CifRecord::TiplocInsert(Fields::new(line, "TI").tiploc_details()?),
+        "TA" => {
+            let f = Fields::new(line, "TA");
+            CifRecord::TiplocAmend(TiplocAmend {
+                location: f.tiploc_details()?,
+                new_tiploc: f.optional(72..79),
+            })
+        }
+        "TD" => CifRecord::TiplocDelete(TiplocDelete {
+            tiploc: Fields::new(line, "TD").tiploc()?,
+        }),
+        "AA" => {
+            let f = Fields::new(line, "AA");
+            let transaction = f.transaction(2..3)?;
+            let deleting = transaction == Transaction::Delete;
+            CifRecord::Association(Association {
+                transaction,
+                base_uid: f.uid(3..9, "base UID")?,
+                assoc_uid: f.uid(9..15, "associated UID")?,
+                start_date: f.date(15..21, "start date")?,
+                end_date: f.optional_date(21..27, "end date", deleting)?,
+                days_run: f.optional(27..34).unwrap_or_default(),
+                category: f.optional(34..36).unwrap_or_default(),
+                date_indicator: f.optional_char(36..37),
+                location: f.text(37..44, "location")?,
+                base_location_suffix: f.optional_char(44..45),
+                assoc_location_suffix: f.optional_char(45..46),
+                assoc_type: f.optional_char(47..48).unwrap_or('P'),
+                stp_indicator: f.stp_indicator(79..80)?,
+            })
+        }
+        "BS" => {
+            let f = Fields::new(line, "BS");
+            let transaction = f.transaction(2..3)?;
+            let deleting = transaction == Transaction::Delete;
+            CifRecord::BasicSchedule(BasicSchedule {
+                transaction,
+                uid: f.uid(3..9, "train UID")?,
+                start_date: f.date(9..15, "start date")?,
+                end_date: f.optional_date(15..21, "end date", deleting)?,
+                days_run: if deleting {
+                    f.optional(21..28).unwrap_or_default()
+                } else {
+                    f.days_run(21..28)?
+                },
+                bank_holiday_running: f.optional_char(28..29),
+                train_status: f.optional_char(29..30),
+                train_category: f.optional(30..32),
+                train_identity: f.optional(32..36).unwrap_or_default(),
+                headcode: f.optional(36..40),
+                train_service_code: f.optional(41..49),
+                portion_id: f.optional_char(49..50),
+                power_type: f.optional(50..53),
+                timing_load: f.optional(53..57),
+                speed: f.optional(57..60),
+                operating_characteristics: f.optional(60..66),
+                seating_class: f.optional_char(66..67),
+                sleepers: f.optional_char(67..68),
+                reservations: f.optional_char(68..69),
+                catering_code: f.optional(70..74),
+                service_branding: f.optional(74..78),
+                stp_indicator: f.stp_indicator(79..80)?,
+            })
+        }
+        "BX" => {
+            let f = Fields::new(line, "BX");
+            CifRecord::BasicScheduleExtra(BasicScheduleExtra {
+                uic_code: f.optional(6..11),
+                atoc_code: f.text(11..13, "ATOC code")?,
+                applicable_timetable: f.optional_char(13..14),
+                retail_service_id: f.optional(14..22),
+            })
+        }
+        "LO" => {
+            let f = Fields::new(line, "LO");
+            CifRecord::OriginLocation(OriginLocation {
+                tiploc: f.tiploc()?,
+                tiploc_suffix: f.optional_char(9..10),
+                scheduled_departure: f.time(10..15, "scheduled departure")?,
+                public_departure: f.public_time(15..19, "public departure")?,
+                platform: f.optional(19..22),
+                line: f.optional(22..25),
+                activity: f.activity(29..41),
+                engineering_allowance: f.optional(25..27),
+                pathing_allowance: f.optional(27..29),
+                performance_allowance: f.optional(41..43),
+            })
+        }
+        "LI" => {
+            let f = Fields::new(line, "LI");
+            CifRecord::IntermediateLocation(IntermediateLocation {
+                tiploc: f.tiploc()?,
+                tiploc_suffix: f.optional_char(9..10),
+                scheduled_arrival: f.optional_time(10..15, "scheduled arrival")?,
+                scheduled_departure: f.optional_time(15..20, "scheduled departure")?,
+                scheduled_pass: f.optional_time(20..25, "scheduled pass")?,
+                public_arrival: f.public_time(25..29, "public arrival")?,
+                public_departure: f.public_time(29..33, "public departure")?,
+                platform: f.optional(33..36),
+                line: f.optional(36..39),
+                path: f.optional(39..42),
+                activity: f.activity(42..54),
+                engineering_allowance: f.optional(54..56),
+                pathing_allowance: f.optional(56..58),
+                performance_allowance: f.optional(58..60),
+            })
+        }
+        "CR" => {
+            let f = Fields::new(line, "CR");
+            CifRecord::ChangeEnRoute(ChangeEnRoute {
+                tiploc: f.tiploc()?,
+                tiploc_suffix: f.optional_char(9..10),
+                train_category: f.optional(10..12),
+                train_identity: f.optional(12..16),
+                headcode: f.optional(16..20),
+                train_service_code: f.optional(21..29),
+                portion_id: f.optional_char(29..30),
+                power_type: f.optional(30..33),
+                timing_load: f.optional(33..37),
+                speed: f.optional(37..40),
+                operating_characteristics: f.optional(40..46),
+                seating_class: f.optional_char(46..47),
+                sleepers: f.optional_char(47..48),
+                reservations: f.optional_char(48..49),
+                catering_code: f.optional(50..54),
+                service_branding: f.optional(54..58),
+                uic_code: f.optional(62..67),
+                retail_service_id: f.optional(67..75),
+            })
+        }
+        "LT" => {
+            let f = Fields::new(line, "LT");
+            CifRecord::TerminatingLocation(TerminatingLocation {
+                tiploc: f.tiploc()?,
+                tiploc_suffix: f.optional_char(9..10),
+                scheduled_arrival: f.time(10..15, "scheduled arrival")?,
+                public_arrival: f.public_time(15..19, "public arrival")?,
+                platform: f.optional(19..22),
+                path: f.optional(22..25),
+                activity: f.activity(25..37),
+            })
+        }
+        "ZZ" => CifRecord::Trailer,
+        _ => CifRecord::Other,
+    })
+}
+
+/// Reads the records of a CIF file one line at a time.
+///
+/// Lines that aren't valid UTF-8 are decoded lossily, so a corrupt record
+/// surfaces as a [`CifError::Parse`] for that line rather than ending the
+/// file early.
+pub struct CifReader<R> {
+    reader: R,
+    line: usize,
+    buf: Vec<u8>,
+}
+
+impl<R: BufRead> CifReader<R> {
+    pub fn new(reader: R) -> Self {
+        CifReader {
+            reader,
+            line: 0,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for CifReader<R> {
+    type Item = Result<CifRecord, CifError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.buf.clear();
+        match self.reader.read_until(b'\n', &mut self.buf) {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(e) => return Some(Err(e.into())),
+        }
+        self.line += 1;
+        let text = String::from_utf8_lossy(&self.buf);
+        let text = text.trim_end_matches(['\n', '\r']);
+        Some(parse_record(text).map_err(|source| CifError::Parse {
+            line: self.line,
+            record_type: text.chars().take(2).collect(),
+            text: text.to_string(),
+            source,
+        }))
+    }
+}
+
+/// Column access for one record, reporting failures against its type
+struct Fields<'a> {
+    line: &'a str,
+    record: &'static str,
+}
+
+impl<'a> Fields<'a> {
+    fn new(line: &'a str, record: &'static str) -> Self {
+        Fields { line, record }
+    }
+
+    fn raw(&self, columns: Range<usize>, field: &'static str) -> Result<&'a str, CifParseError> {
+        if self.line.len() < columns.end {
+            return Err(CifParseError::Truncated {
+                record: self.record,
+                field,
+                columns,
+            });
+        }
+        self.line
+            .get(columns)
+            .ok_or_else(|| self.invalid(field, self.line))
+    }
+
+    fn invalid(&self, field: &'static str, value: &str) -> CifParseError {
+        CifParseError::InvalidField {
+            record: self.record,
+            field,
+            value: value.to_string(),
+        }
+    }
+
+    /// A trimmed field that may be blank
+    fn text(&self, columns: Range<usize>, field: &'static str) -> Result<String, CifParseError> {
+        Ok(self.raw(columns, field)?.trim().to_string())
+    }
+
+    /// A trimmed field that may be blank or absent from a short line
+    fn optional(&self, columns: Range<usize>) -> Option<String> {
+        let value = self.line.get(columns)?.trim();
+        (!value.is_empty()).then(|| value.to_string())
+    }
+
+    fn tiploc_details(&self) -> Result<TiplocInsert, CifParseError> {
+        Ok(TiplocInsert {
+            tiploc: self.tiploc()?,
+            nalco: self.optional(11..17),
+            tps_description: self.optional(18..44),
+            stanox: self.optional(44..49),
+            crs: self.optional(53..56),
+            description: self.optional(56..72),
+        })
+    }
+
+    /// The activity field, left untrimmed so its two-character codes line up
+    fn activity(&self, columns: Range<usize>) -> String {
+        let end = columns.end.min(self.line.len());
+        self.line
+            .get(columns.start.min(end)..end)
+            .unwrap_or("")
+            .to_string()
+    }
+
+    fn optional_char(&self, columns: Range<usize>) -> Option<char> {
+        self.optional(columns)?.chars().next()
+    }
+
+    fn char(&self, columns: Range<usize>, field: &'static str) -> Result<char, CifParseError> {
+        let raw = self.raw(columns, field)?;
+        raw.chars()
+            .next()
+            .filter(|c| !c.is_whitespace())
+            .ok_or_else(|| self.invalid(field, raw))
+    }
+
+    fn tiploc(&self) -> Result<String, CifParseError> {
+        let tiploc = self.text(2..9, "TIPLOC")?;
+        if tiploc.is_empty() {
+            return Err(self.invalid("TIPLOC", &tiploc));
+        }
+        Ok(tiploc)
+    }
+
+    fn uid(&self, columns: Range<usize>, field: &'static str) -> Result<String, CifParseError> {
+        let uid = self.raw(columns, field)?;
+        if uid.len() != 6 || !uid.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(self.invalid(field, uid));
+        }
+        Ok(uid.to_string())
+    }
+
+    fn transaction(&self, columns: Range<usize>) -> Result<Transaction, CifParseError> {
+        match self.raw(columns, "transaction type")? {
+            "N" => Ok(Transaction::New),
+            "R" => Ok(Transaction::Revise),
+            "D" => Ok(Transaction::Delete),
+            other => Err(self.invalid("transaction type", other)),
+        }
+    }
+
+    fn stp_indicator(&self, columns: Range<usize>) -> Result<char, CifParseError> {
+        match self.raw(columns, "STP indicator")? {
+            "P" => Ok('P'),
+            "O" => Ok('O'),
+            "N" => Ok('N'),
+            "C" => Ok('C'),
+            other => Err(self.invalid("STP indicator", other)),
+        }
+    }
+
+    fn days_run(&self, columns: Range<usize>) -> Result<String, CifParseError> {
+        let days = self.raw(columns, "days run")?;
+        if !days.chars().all(|c| c == '0' || c == '1') {
+            return Err(self.invalid("days run", days));
+        }
+        Ok(days.to_string())
+    }
+
+    /// A yymmdd date
+    fn date(&self, columns: Range<usize>, field: &'static str) -> Result<NaiveDate, CifParseError> {
+        let raw = self.raw(columns, field)?;
+        NaiveDate::parse_from_str(raw, "%y%m%d").map_err(|_| self.invalid(field, raw))
+    }
+
+    /// A ddmmyy date, as used by the header
+    fn ddmmyy(
+        &self,
+        columns: Range<usize>,
+        field: &'static str,
+    ) -> Result<NaiveDate, CifParseError> {
+        let raw = self.raw(columns, field)?;
+        NaiveDate::parse_from_str(raw, "%d%m%y").map_err(|_| self.invalid(field, raw))
+    }
+
+    /// A yymmdd date, which may be left blank when `blank_allowed`
+    fn optional_date(
+        &self,
+        columns: Range<usize>,
+        field: &'static str,
+        blank_allowed: bool,
+    ) -> Result<Option<NaiveDate>, CifParseError> {
+        if blank_allowed && self.optional(columns.clone()).is_none() {
+            return Ok(None);
+        }
+        self.date(columns, field).map(Some)
+    }
+
+    fn time(&self, columns: Range<usize>, field: &'static str) -> Result<CifTime, CifParseError> {
+        self.optional_time(columns.clone(), field)?
+            .ok_or_else(|| self.invalid(field, self.line.get(columns).unwrap_or("")))
+    }
+
+    fn optional_time(
+        &self,
+        columns: Range<usize>,
+        field: &'static str,
+    ) -> Result<Option<CifTime>, CifParseError> {
+        let raw = self.raw(columns, field)?.trim();
+        if raw.is_empty() {
+            return Ok(None);
+        }
+        let (digits, half_minute) = match raw.strip_suffix('H') {
+            Some(digits) => (digits, true),
+            None => (raw, false),
+        };
+        let parsed = (digits.len() == 4 && digits.chars().all(|c| c.is_ascii_digit()))
+            .then(|| (digits[0..2].parse::<u8>(), digits[2..4].parse::<u8>()));
+        match parsed {
+            Some((Ok(hour), Ok(minute))) if hour < 24 && minute < 60 => Ok(Some(CifTime {
+                hour,
+                minute,
+                half_minute,
+            })),
+            _ => Err(self.invalid(field, raw)),
+        }
+    }
+
+    /// A public timetable time, where `0000` means there is none
+    fn public_time(
+        &self,
+        columns: Range<usize>,
+        field: &'static str,
+    ) -> Result<Option<CifTime>, CifParseError> {
+        if self.line.get(columns.clone()) == Some("0000") {
+            return Ok(None);
+        }
+        self.optional_time(columns, field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bs() -> String {
+        format!(
+            "{:<79}P",
+            "BSNC100012401012412141111100 POO1A01    123456789 IEMU   100"
+        )
+    }
+
+    #[test]
+    fn test_parse_schedule_records() {
+        let CifRecord::BasicSchedule(bs) = parse_record(&bs()).unwrap() else {
+            panic!("expected a BS record");
+        };
+        assert_eq!(bs.uid, "C10001");
+        assert_eq!(bs.start_date, NaiveDate::from_ymd_opt(2024, 1, 1).unwrap());
+        assert_eq!(bs.days_run, "1111100");
+        assert_eq!(bs.train_identity, "1A01");
+        assert_eq!(bs.stp_indicator, 'P');
+        assert!(bs.is_passenger());
+        assert_eq!(bs.vehicle_type(), "train");
+
+        let ecs = BasicSchedule {
+            train_category: Some("EE".to_string()),
+            ..bs.clone()
+        };
+        assert!(!ecs.is_passenger());
+        let freight = BasicSchedule {
+            train_status: Some('F'),
+            train_category: None,
+            ..bs.clone()
+        };
+        assert!(!freight.is_passenger());
+        let bus = BasicSchedule {
+            train_status: Some('5'),
+            train_category: Some("BR".to_string()),
+            ..bs.clone()
+        };
+        assert!(bus.is_passenger());
+        assert_eq!(bus.vehicle_type(), "bus");
+
+        let li = "LIPBRO    1012H1013      10121013 2  FL     T";
+        let CifRecord::IntermediateLocation(li) = parse_record(li).unwrap() else {
+            panic!("expected an LI record");
+        };
+        assert_eq!(li.tiploc, "PBRO");
+        assert_eq!(
+            li.scheduled_arrival,
+            Some(CifTime {
+                hour: 10,
+                minute: 12,
+                half_minute: true
+            })
+        );
+        assert_eq!(li.platform.as_deref(), Some("2"));
+        assert!(li.is_public());
+        assert_eq!(li.engineering_allowance, None);
+
+        let li = format!(
+            "{:<54}1H2 1 ",
+            "LIPBRO    1012H1013      10121013 2  FL     T"
+        );
+        let CifRecord::IntermediateLocation(li) = parse_record(&li).unwrap() else {
+            panic!("expected an LI record");
+        };
+        assert_eq!(li.engineering_allowance.as_deref(), Some("1H"));
+        assert_eq!(li.pathing_allowance.as_deref(), Some("2"));
+        assert_eq!(li.performance_allowance.as_deref(), Some("1"));
+
+        let lo = format!(
+            "{:<22}{:<3}{:<2}{:<2}{:<12}{:<2}",
+            "LOKNGX    0900 09004", "FL", "1", "H", "TB", "2"
+        );
+        let CifRecord::OriginLocation(lo) = parse_record(&lo).unwrap() else {
+            panic!("expected an LO record");
+        };
+        assert_eq!(lo.line.as_deref(), Some("FL"));
+        assert_eq!(lo.engineering_allowance.as_deref(), Some("1"));
+        assert_eq!(lo.pathing_allowance.as_deref(), Some("H"));
+        assert_eq!(lo.performance_allowance.as_deref(), Some("2"));
+        assert_eq!(lo.activity.trim(), "TB");
+    }
+
+    #[test]
+    fn test_malformed_records_are_rejected() {
+        assert!(matches!(
+            parse_record(&bs()[..40]),
+            Err(CifParseError::Truncated {
+                field: "STP indicator",
+                ..
+            })
+        ));
+        let bad_date = bs().replace("240101", "241301");
+        assert!(matches!(
+            parse_record(&bad_date),
+            Err(CifParseError::InvalidField {
+                field: "start date",
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse_record("LOKNGX    09x0 09004"),
+            Err(CifParseError::InvalidField {
+                field: "scheduled departure",
+                ..
+            })
+        ));
+        assert_eq!(parse_record("ZZ").unwrap(), CifRecord::Trailer);
+    }
+
+    #[test]
+    fn test_reader_reports_line_numbers() {
+        let file = [
+            "HDTPS.UDFROC1.PD2401010101240000DFROC1ADFROC1AUA010124311224".to_string(),
+            "TIKNGX   00612100ALONDON KINGS CROSS        87701    KGXLONDON KINGS X   ".to_string(),
+            bs(),
+            "LOKNGX    09x0 0900".to_string(),
+            "ZZ".to_string(),
+        ]
+        .join("\r\n");
+        let records: Vec<_> = CifReader::new(file.as_bytes()).collect();
+        assert_eq!(records.len(), 5);
+
+        let Ok(CifRecord::Header(header)) = &records[0] else {
+            panic!("expected a header");
+        };
+        assert!(!header.is_full_extract());
+        assert_eq!(
+            header.user_end_date,
+            NaiveDate::from_ymd_opt(2024, 12, 31).unwrap()
+        );
+
+        let Ok(CifRecord::TiplocInsert(ti)) = &records[1] else {
+            panic!("expected a TI record");
+        };
+        assert_eq!(ti.tiploc, "KNGX");
+        assert_eq!(ti.crs.as_deref(), Some("KGX"));
+        assert_eq!(ti.description.as_deref(), Some("LONDON KINGS X"));
+
+        assert!(matches!(
+            &records[3],
+            Err(CifError::Parse { line: 4, record_type, .. }) if record_type == "LO"
+        ));
+        assert!(matches!(records[4], Ok(CifRecord::Trailer)));
+    }
+
+    #[test]
+    fn test_activities() {
+        let codes: Vec<&str> = activities("T RMD         ").collect();
+        assert_eq!(codes, ["T", "RM", "D"]);
+    }
+}
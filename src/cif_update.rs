@@ -0,0 +1,175 @@
+//! Merging of CIF update files onto a full extract.
+//!
+//! A full extract and the daily updates share the same record layout; each
+//! record carries a transaction type (`N`ew, `R`evise, `D`elete) that says how
+//! it changes the previously known state. [`CifStore`] keeps the latest version
+//! of every TIPLOC, association and schedule and can write the merged result
+//! back out as a single full CIF for `parse_mca`.
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+
+/// Schedules are identified by UID, start date and STP indicator
+type ScheduleKey = (String, String, String);
+/// Associations are identified by both UIDs, start date, location and STP indicator
+type AssociationKey = (String, String, String, String, String);
+
+#[derive(Default)]
+pub struct CifStore {
+    header: Option<String>,
+    tiplocs: BTreeMap<String, String>,
+    associations: BTreeMap<AssociationKey, String>,
+    /// BS record followed by its BX/LO/LI/CR/LT records
+    schedules: BTreeMap<ScheduleKey, Vec<String>>,
+}
+
+impl CifStore {
+    /// Apply a full extract or an update file on top of the current state.
+    /// A header flagged as a full extract (`F`) replaces everything held so far.
+    pub fn apply<R: Read>(&mut self, reader: R) -> Result<()> {
+        let mut current: Option<ScheduleKey> = None;
+
+        for line in BufReader::new(reader).lines().map_while(Result::ok) {
+            if line.len() < 2 {
+                continue;
+            }
+            let transaction = line.get(2..3).unwrap_or("N");
+
+            match &line[0..2] {
+                "HD" => {
+                    if line.get(46..47) == Some("F") {
+                        *self = CifStore::default();
+                    }
+                    self.header = Some(line);
+                }
+                "TI" | "TA" => {
+                    let tiploc = field(&line, 2..9);
+                    let new_tiploc = field(&line, 72..79);
+                    if line.starts_with("TA") && !new_tiploc.is_empty() {
+                        self.tiplocs.remove(&tiploc);
+                        self.tiplocs.insert(new_tiploc, line);
+                    } else {
+                        self.tiplocs.insert(tiploc, line);
+                    }
+                }
+                "TD" => {
+                    self.tiplocs.remove(&field(&line, 2..9));
+                }
+                "AA" => {
+                    let key = (
+                        field(&line, 3..9),
+                        field(&line, 9..15),
+                        field(&line, 15..21),
+                        field(&line, 37..44),
+                        field(&line, 79..80),
+                    );
+                    if transaction == "D" {
+                        self.associations.remove(&key);
+                    } else {
+                        self.associations.insert(key, line);
+                    }
+                }
+                "BS" => {
+                    let key = (
+                        field(&line, 3..9),
+                        field(&line, 9..15),
+                        field(&line, 79..80),
+                    );
+                    if transaction == "D" {
+                        self.schedules.remove(&key);
+                        current = None;
+                    } else {
+                        // A revision replaces the whole schedule, locations included
+                        self.schedules.insert(key.clone(), vec![line]);
+                        current = Some(key);
+                    }
+                }
+                "BX" | "LO" | "LI" | "CR" | "LT" => {
+                    if let Some(records) = current.as_ref().and_then(|k| self.schedules.get_mut(k))
+                    {
+                        records.push(line);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the merged state as a full CIF extract
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<()> {
+        if let Some(header) = &self.header {
+            writeln!(writer, "{}", header)?;
+        }
+        // parse_mca relies on associations preceding the schedules they link
+        let records = self
+            .tiplocs
+            .values()
+            .chain(self.associations.values())
+            .chain(self.schedules.values().flatten());
+        for record in records {
+            writeln!(writer, "{}", record)?;
+        }
+        writeln!(writer, "ZZ")?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn schedule_count(&self) -> usize {
+        self.schedules.len()
+    }
+}
+
+fn field(line: &str, range: std::ops::Range<usize>) -> String {
+    line.get(range).unwrap_or("").trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bs(transaction: char, uid: &str, start: &str, headcode: &str) -> String {
+        format!(
+            "BS{}{}{}2412141111100 POO{}{:>43}P",
+            transaction, uid, start, headcode, ""
+        )
+    }
+
+    #[test]
+    fn test_update_revises_and_deletes_schedules() {
+        let full = [
+            format!("HD{:<44}F", "TPS.UDFROC1.PD240101"),
+            bs('N', "C10001", "240101", "1A01"),
+            "LOKNGX    0900 09004".to_string(),
+            "LTEDINBUR 1320 13205".to_string(),
+            bs('N', "C10002", "240101", "1A02"),
+            "LOKNGX    1000 10004".to_string(),
+            "LTYORK    1200 12005".to_string(),
+        ]
+        .join("\n");
+        let update = [
+            format!("HD{:<44}U", "TPS.UDFROC1.PD240102"),
+            bs('R', "C10001", "240101", "1A09"),
+            "LOKNGX    0930 09304".to_string(),
+            "LTEDINBUR 1350 13505".to_string(),
+            bs('D', "C10002", "240101", "1A02"),
+        ]
+        .join("\n");
+
+        let mut store = CifStore::default();
+        store.apply(full.as_bytes()).unwrap();
+        assert_eq!(store.schedule_count(), 2);
+        store.apply(update.as_bytes()).unwrap();
+        assert_eq!(store.schedule_count(), 1);
+
+        let mut merged = Vec::new();
+        store.write_to(&mut merged).unwrap();
+        let merged = String::from_utf8(merged).unwrap();
+        assert!(merged.contains("1A09"));
+        assert!(merged.contains("LOKNGX    0930"));
+        assert!(!merged.contains("LOKNGX    0900"));
+        assert!(!merged.contains("C10002"));
+        assert!(merged.ends_with("ZZ\n"));
+    }
+}
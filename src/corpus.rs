@@ -0,0 +1,106 @@
+//! Network Rail CORPUS reference data.
+//!
+//! CORPUS lists every TIPLOC with its STANOX, NLC and, for stations, its
+//! CRS code. It has no coordinates of its own, but the CRS lets locations
+//! the MSN doesn't cover be placed from the sources that do know stations
+//! by CRS.
+
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{BufReader, Read};
+
+pub const CORPUS_URL: &str =
+    "https://publicdatafeeds.networkrail.co.uk/ntrod/SupportingFileAuthenticate?type=CORPUS";
+
+#[derive(Deserialize)]
+struct CorpusFile {
+    #[serde(rename = "TIPLOCDATA")]
+    tiploc_data: Vec<RawEntry>,
+}
+
+#[derive(Deserialize)]
+struct RawEntry {
+    #[serde(rename = "TIPLOC")]
+    tiploc: String,
+    #[serde(rename = "3ALPHA")]
+    crs: String,
+    #[serde(rename = "NLCDESC")]
+    description: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorpusEntry {
+    pub crs: Option<String>,
+    pub description: Option<String>,
+}
+
+/// TIPLOC -> CORPUS entry. The feed is distributed gzipped, but plain JSON
+/// is accepted too.
+pub fn parse_corpus<R: Read>(reader: R) -> Result<HashMap<String, CorpusEntry>> {
+    let mut reader = BufReader::new(reader);
+    let mut magic = [0u8; 2];
+    reader
+        .read_exact(&mut magic)
+        .context("CORPUS file is empty")?;
+    let reader = magic.as_slice().chain(reader);
+    let file: CorpusFile = if magic == [0x1f, 0x8b] {
+        serde_json::from_reader(GzDecoder::new(reader))
+    } else {
+        serde_json::from_reader(reader)
+    }
+    .context("Failed to parse CORPUS JSON")?;
+
+    // Unused fields are a single space rather than empty
+    let present = |s: String| {
+        let s = s.trim().to_string();
+        (!s.is_empty()).then_some(s)
+    };
+    Ok(file
+        .tiploc_data
+        .into_iter()
+        .filter(|e| !e.tiploc.trim().is_empty())
+        .map(|e| {
+            (
+                e.tiploc.trim().to_string(),
+                CorpusEntry {
+                    crs: present(e.crs),
+                    description: present(e.description),
+                },
+            )
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    const CORPUS: &str = r#"{"TIPLOCDATA":[
+        {"NLC":612100,"STANOX":"72410","TIPLOC":"KNGX","3ALPHA":"KGX","UIC":"61210","NLCDESC":"LONDON KINGS CROSS","NLCDESC16":"LONDON KINGS X"},
+        {"NLC":612101,"STANOX":"72411","TIPLOC":"KNGXBEL","3ALPHA":" ","UIC":" ","NLCDESC":"KINGS CROSS BELLE ISLE","NLCDESC16":" "},
+        {"NLC":999999,"STANOX":" ","TIPLOC":" ","3ALPHA":" ","UIC":" ","NLCDESC":"NO TIPLOC","NLCDESC16":" "}
+    ]}"#;
+
+    #[test]
+    fn test_parse_corpus() {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(CORPUS.as_bytes()).unwrap();
+        let gzipped = gz.finish().unwrap();
+
+        for input in [CORPUS.as_bytes(), gzipped.as_slice()] {
+            let corpus = parse_corpus(input).unwrap();
+            assert_eq!(corpus.len(), 2);
+            assert_eq!(corpus["KNGX"].crs.as_deref(), Some("KGX"));
+            assert_eq!(corpus["KNGXBEL"].crs, None);
+            assert_eq!(
+                corpus["KNGXBEL"].description.as_deref(),
+                Some("KINGS CROSS BELLE ISLE")
+            );
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Usernames and passwords for the feeds that need them.
+//!
+//! Each is looked for in turn in the environment (`NR_USERNAME` and so on),
+//! then the config file, by default
+//! `~/.config/nationalrail-gtfs/config.toml`:
+//!
+//! ```toml
+//! [nrdp]
+//! username = "me@example.com"
+//! password = "..."
+//!
+//! [datafeeds]
+//! username = "me@example.com"
+//! ```
+//!
+//! Built with the `keyring` feature, a password missing from both is read
+//! from the OS keyring, stored under `nationalrail-gtfs-<service>` and the
+//! username.
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+    /// The National Rail Data Portal
+    Nrdp,
+    /// Network Rail's open data feeds
+    Datafeeds,
+    /// The Darwin Push Port
+    Darwin,
+}
+
+impl Service {
+    fn name(self) -> &'static str {
+        match self {
+            Service::Nrdp => "nrdp",
+            Service::Datafeeds => "datafeeds",
+            Service::Darwin => "darwin",
+        }
+    }
+
+    /// Environment variables holding the username and password
+    fn env_vars(self) -> (&'static str, &'static str) {
+        match self {
+            Service::Nrdp => ("NR_USERNAME", "NR_PASSWORD"),
+            Service::Datafeeds => ("NR_DATAFEEDS_USERNAME", "NR_DATAFEEDS_PASSWORD"),
+            Service::Darwin => ("DARWIN_USERNAME", "DARWIN_PASSWORD"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Account {
+    username: Option<String>,
+    password: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    nrdp: Account,
+    #[serde(default)]
+    datafeeds: Account,
+    #[serde(default)]
+    darwin: Account,
+}
+
+impl ConfigFile {
+    fn account(&self, service: Service) -> &Account {
+        match service {
+            Service::Nrdp => &self.nrdp,
+            Service::Datafeeds => &self.datafeeds,
+            Service::Darwin => &self.darwin,
+        }
+    }
+}
+
+pub fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("nationalrail-gtfs").join("config.toml"))
+}
+
+pub struct Credentials {
+    config: ConfigFile,
+}
+
+impl Credentials {
+    /// Read the config file at `path`, or the default one if it exists
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_config_path().filter(|path| path.exists()) {
+                Some(path) => path,
+                None => {
+                    return Ok(Credentials {
+                        config: ConfigFile::default(),
+                    });
+                }
+            },
+        };
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if fs::metadata(&path)?.permissions().mode() & 0o077 != 0 {
+                println!(
+                    "Warning: {} can be read by other users; chmod 600 it",
+                    path.display()
+                );
+            }
+        }
+        Ok(Credentials {
+            config: toml::from_str(&text)
+                .with_context(|| format!("Invalid config file {}", path.display()))?,
+        })
+    }
+
+    /// The username and password for a service
+    pub fn get(&self, service: Service) -> Result<(String, String)> {
+        self.resolve(service, |var| std::env::var(var).ok(), keyring_password)
+    }
+
+    fn resolve(
+        &self,
+        service: Service,
+        env: impl Fn(&str) -> Option<String>,
+        keyring: impl Fn(Service, &str) -> Option<String>,
+    ) -> Result<(String, String)> {
+        let (username_var, password_var) = service.env_vars();
+        let account = self.config.account(service);
+        let Some(username) = env(username_var).or_else(|| account.username.clone()) else {
+            bail!(
+                "No {} username: set {} or add it to the [{}] section of the config file",
+                service.name(),
+                username_var,
+                service.name()
+            );
+        };
+        let password = env(password_var)
+            .or_else(|| account.password.clone())
+            .or_else(|| keyring(service, &username));
+        match password {
+            Some(password) => Ok((username, password)),
+            None => bail!(
+                "No {} password for {}: set {}, add it to the config file or store it in the keyring",
+                service.name(),
+                username,
+                password_var
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_password(service: Service, username: &str) -> Option<String> {
+    let entry = keyring::Entry::new(&format!("nationalrail-gtfs-{}", service.name()), username);
+    match entry.and_then(|entry| entry.get_password()) {
+        Ok(password) => Some(password),
+        Err(keyring::Error::NoEntry) => None,
+        Err(e) => {
+            println!("Warning: could not read the keyring: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "keyring"))]
+fn keyring_password(_service: Service, _username: &str) -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_environment_then_config_then_keyring() {
+        let credentials = Credentials {
+            config: toml::from_str(
+                r#"
+[nrdp]
+username = "config@example.com"
+password = "from-config"
+
+[darwin]
+username = "darwin@example.com"
+"#,
+            )
+            .unwrap(),
+        };
+        let no_env = |_: &str| None;
+        let keyring = |service: Service, username: &str| {
+            (service == Service::Darwin && username == "darwin@example.com")
+                .then(|| "from-keyring".to_string())
+        };
+
+        let (username, password) = credentials.resolve(Service::Nrdp, no_env, keyring).unwrap();
+        assert_eq!(
+            (username.as_str(), password.as_str()),
+            ("config@example.com", "from-config")
+        );
+
+        let env = |var: &str| (var == "NR_PASSWORD").then(|| "from-env".to_string());
+        let (_, password) = credentials.resolve(Service::Nrdp, env, keyring).unwrap();
+        assert_eq!(password, "from-env");
+
+        let (_, password) = credentials
+            .resolve(Service::Darwin, no_env, keyring)
+            .unwrap();
+        assert_eq!(password, "from-keyring");
+
+        assert!(
+            credentials
+                .resolve(Service::Datafeeds, no_env, keyring)
+                .is_err()
+        );
+    }
+}
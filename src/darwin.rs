@@ -0,0 +1,409 @@
+//! Darwin timetable files, as an alternative to the CIF.
+//!
+//! Darwin publishes a daily timetable (`PportTimetable`, schema v8) with one
+//! `<Journey>` per train per day, and a reference file (`PportTimetableRef`)
+//! naming every TIPLOC and operator. Journeys and associations are turned
+//! into the CIF records they correspond to, so the rest of the conversion
+//! is shared with the CIF input.
+
+use anyhow::{Context, Result};
+use chrono::{Datelike, NaiveDate};
+use flate2::read::GzDecoder;
+use nationalrail_gtfs::cif::{
+    Association, BasicSchedule, BasicScheduleExtra, CifRecord, CifTime, IntermediateLocation,
+    OriginLocation, TerminatingLocation, TiplocInsert, Transaction,
+};
+use quick_xml::Reader;
+use quick_xml::events::{BytesStart, Event};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// Open a Darwin file, decompressing it if it's gzipped as published
+pub fn open(path: &Path) -> Result<Box<dyn BufRead>> {
+    let file = File::open(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(if path.extension().is_some_and(|ext| ext == "gz") {
+        Box::new(BufReader::new(GzDecoder::new(file)))
+    } else {
+        Box::new(BufReader::new(file))
+    })
+}
+
+/// Locations and operator names from the reference file
+#[derive(Debug, Default)]
+pub struct DarwinReference {
+    pub locations: Vec<TiplocInsert>,
+    pub tocs: HashMap<String, String>,
+}
+
+fn attributes(e: &BytesStart) -> Result<HashMap<String, String>> {
+    let mut map = HashMap::new();
+    for attr in e.attributes() {
+        let attr = attr?;
+        let key = String::from_utf8_lossy(attr.key.local_name().as_ref()).into_owned();
+        map.insert(key, attr.unescape_value()?.into_owned());
+    }
+    Ok(map)
+}
+
+pub fn parse_reference<R: BufRead>(reader: R) -> Result<DarwinReference> {
+    let mut xml = Reader::from_reader(reader);
+    let mut reference = DarwinReference::default();
+    let mut buf = Vec::new();
+    loop {
+        match xml.read_event_into(&mut buf)? {
+            Event::Start(e) | Event::Empty(e) => match e.local_name().as_ref() {
+                b"LocationRef" => {
+                    let mut attrs = attributes(&e)?;
+                    let Some(tiploc) = attrs.remove("tpl") else {
+                        continue;
+                    };
+                    reference.locations.push(TiplocInsert {
+                        tiploc,
+                        nalco: None,
+                        tps_description: None,
+                        stanox: None,
+                        crs: attrs.remove("crs"),
+                        // Darwin names locations without one by their TIPLOC
+                        description: attrs.remove("locname"),
+                    });
+                }
+                b"TocRef" => {
+                    let mut attrs = attributes(&e)?;
+                    if let (Some(toc), Some(name)) = (attrs.remove("toc"), attrs.remove("tocname"))
+                    {
+                        reference.tocs.insert(toc, name);
+                    }
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(reference)
+}
+
+/// A `HH:MM` or `HH:MM:SS` Darwin time. Working times can fall on the half
+/// minute.
+pub fn time(value: Option<&String>) -> Option<CifTime> {
+    let mut parts = value?.split(':').map(|p| p.parse::<u8>().ok());
+    let hour = parts.next()??;
+    let minute = parts.next()??;
+    let second = parts.next().flatten().unwrap_or(0);
+    Some(CifTime {
+        hour,
+        minute,
+        half_minute: second >= 30,
+    })
+}
+
+/// The CIF days-run mask of a single day
+fn days_run(date: NaiveDate) -> String {
+    let day = date.weekday().num_days_from_monday() as usize;
+    (0..7).map(|d| if d == day { '1' } else { '0' }).collect()
+}
+
+struct Journey {
+    uid: String,
+    ssd: NaiveDate,
+    records: Vec<CifRecord>,
+}
+
+struct DarwinAssociation {
+    attrs: HashMap<String, String>,
+    main_rid: Option<String>,
+    assoc_rid: Option<String>,
+}
+
+fn journey_schedule(attrs: &mut HashMap<String, String>) -> Result<Journey> {
+    let uid = attrs.remove("uid").context("Journey without a uid")?;
+    let ssd = attrs.get("ssd").context("Journey without an ssd")?;
+    let ssd = NaiveDate::parse_from_str(ssd, "%Y-%m-%d")
+        .with_context(|| format!("Invalid ssd on journey {}", uid))?;
+    let cancelled = attrs.get("can").is_some_and(|c| c == "true");
+    let bs = BasicSchedule {
+        transaction: Transaction::New,
+        uid: uid.clone(),
+        start_date: ssd,
+        end_date: Some(ssd),
+        days_run: days_run(ssd),
+        bank_holiday_running: None,
+        // The schema's defaults when the attributes are left out
+        train_status: Some(
+            attrs
+                .get("status")
+                .and_then(|s| s.chars().next())
+                .unwrap_or('P'),
+        ),
+        train_category: Some(attrs.remove("trainCat").unwrap_or_else(|| "OO".to_string())),
+        train_identity: attrs.remove("trainId").unwrap_or_default(),
+        headcode: None,
+        train_service_code: None,
+        portion_id: None,
+        power_type: None,
+        timing_load: None,
+        speed: None,
+        operating_characteristics: None,
+        seating_class: None,
+        sleepers: None,
+        reservations: None,
+        catering_code: None,
+        service_branding: None,
+        // A journey is already the schedule that applies on its day
+        stp_indicator: if cancelled { 'C' } else { 'P' },
+    };
+    let bx = BasicScheduleExtra {
+        uic_code: None,
+        atoc_code: attrs.remove("toc").unwrap_or_default(),
+        applicable_timetable: None,
+        retail_service_id: None,
+    };
+    Ok(Journey {
+        uid,
+        ssd,
+        records: vec![
+            CifRecord::BasicSchedule(bs),
+            CifRecord::BasicScheduleExtra(bx),
+        ],
+    })
+}
+
+/// The CIF location record for a calling point, if it's one a trip can use
+fn calling_point(kind: &[u8], mut attrs: HashMap<String, String>) -> Option<CifRecord> {
+    if attrs.get("can").is_some_and(|c| c == "true") {
+        return None;
+    }
+    let tiploc = attrs.remove("tpl")?;
+    let platform = attrs.remove("plat");
+    let activity = attrs.remove("act").unwrap_or_default();
+    Some(match kind {
+        b"OR" | b"OPOR" => CifRecord::OriginLocation(OriginLocation {
+            tiploc,
+            tiploc_suffix: None,
+            scheduled_departure: time(attrs.get("wtd"))?,
+            public_departure: time(attrs.get("ptd")),
+            platform,
+            line: None,
+            activity,
+            engineering_allowance: None,
+            pathing_allowance: None,
+            performance_allowance: None,
+        }),
+        b"IP" | b"OPIP" | b"PP" => CifRecord::IntermediateLocation(IntermediateLocation {
+            tiploc,
+            tiploc_suffix: None,
+            scheduled_arrival: time(attrs.get("wta")),
+            scheduled_departure: time(attrs.get("wtd")),
+            scheduled_pass: time(attrs.get("wtp")),
+            public_arrival: time(attrs.get("pta")),
+            public_departure: time(attrs.get("ptd")),
+            platform,
+            line: None,
+            path: None,
+            activity,
+            engineering_allowance: None,
+            pathing_allowance: None,
+            performance_allowance: None,
+        }),
+        b"DT" | b"OPDT" => CifRecord::TerminatingLocation(TerminatingLocation {
+            tiploc,
+            tiploc_suffix: None,
+            scheduled_arrival: time(attrs.get("wta"))?,
+            public_arrival: time(attrs.get("pta")),
+            platform,
+            path: None,
+            activity,
+        }),
+        _ => return None,
+    })
+}
+
+/// Read a Darwin timetable into CIF records: associations first, as in a
+/// CIF extract, then each journey's schedule
+pub fn parse_timetable<R: BufRead>(reader: R) -> Result<Vec<CifRecord>> {
+    let mut xml = Reader::from_reader(reader);
+    let mut journeys: Vec<Journey> = Vec::new();
+    // Journey uid and date by RID, for resolving associations
+    let mut rids: HashMap<String, (String, NaiveDate)> = HashMap::new();
+    let mut associations: Vec<DarwinAssociation> = Vec::new();
+    let mut in_journey = false;
+    let mut buf = Vec::new();
+
+    loop {
+        let event = xml.read_event_into(&mut buf)?;
+        let (e, empty) = match &event {
+            Event::Start(e) => (e, false),
+            Event::Empty(e) => (e, true),
+            Event::End(e) => {
+                if e.local_name().as_ref() == b"Journey" {
+                    in_journey = false;
+                }
+                buf.clear();
+                continue;
+            }
+            Event::Eof => break,
+            _ => {
+                buf.clear();
+                continue;
+            }
+        };
+        let name = e.local_name();
+        match name.as_ref() {
+            b"Journey" => {
+                let mut attrs = attributes(e)?;
+                let rid = attrs.remove("rid");
+                let journey = journey_schedule(&mut attrs)?;
+                if let Some(rid) = rid {
+                    rids.insert(rid, (journey.uid.clone(), journey.ssd));
+                }
+                journeys.push(journey);
+                in_journey = !empty;
+            }
+            b"Association" => associations.push(DarwinAssociation {
+                attrs: attributes(e)?,
+                main_rid: None,
+                assoc_rid: None,
+            }),
+            b"main" | b"assoc" => {
+                let rid = attributes(e)?.remove("rid");
+                if let Some(association) = associations.last_mut() {
+                    if name.as_ref() == b"main" {
+                        association.main_rid = rid;
+                    } else {
+                        association.assoc_rid = rid;
+                    }
+                }
+            }
+            kind if in_journey => {
+                if let (Some(record), Some(journey)) =
+                    (calling_point(kind, attributes(e)?), journeys.last_mut())
+                {
+                    journey.records.push(record);
+                }
+            }
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    let mut records = Vec::new();
+    for association in associations {
+        let (Some(main), Some(assoc)) = (
+            association.main_rid.and_then(|rid| rids.get(&rid)),
+            association.assoc_rid.and_then(|rid| rids.get(&rid)),
+        ) else {
+            continue;
+        };
+        let flag = |key: &str| association.attrs.get(key).is_some_and(|v| v == "true");
+        let (base_uid, date) = main.clone();
+        records.push(CifRecord::Association(Association {
+            transaction: if flag("isDeleted") {
+                Transaction::Delete
+            } else {
+                Transaction::New
+            },
+            base_uid,
+            assoc_uid: assoc.0.clone(),
+            start_date: date,
+            end_date: Some(date),
+            days_run: days_run(date),
+            category: association
+                .attrs
+                .get("category")
+                .cloned()
+                .unwrap_or_default(),
+            date_indicator: None,
+            location: association.attrs.get("tiploc").cloned().unwrap_or_default(),
+            base_location_suffix: None,
+            assoc_location_suffix: None,
+            assoc_type: 'P',
+            stp_indicator: if flag("isCancelled") { 'C' } else { 'P' },
+        }));
+    }
+    records.extend(journeys.into_iter().flat_map(|j| j.records));
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timetable() {
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<PportTimetable xmlns="http://www.thalesgroup.com/rtti/XmlTimetable/v8" timetableID="20240101020000">
+  <Journey rid="202401017100001" uid="C10001" trainId="1A01" ssd="2024-01-01" toc="GR" trainCat="XX">
+    <OR tpl="KNGX" act="TB" plat="8" ptd="09:00" wtd="09:00" />
+    <PP tpl="HUNTNGN" wtp="09:40:30" />
+    <IP tpl="PBRO" act="T " plat="2" pta="10:12" ptd="10:13" wta="10:12" wtd="10:13:30" />
+    <DT tpl="YORK" act="TF" plat="3" pta="10:50" wta="10:50" />
+  </Journey>
+  <Journey rid="202401017100002" uid="C20001" trainId="5S99" ssd="2024-01-01" toc="XC" trainCat="EE" can="true">
+    <OPOR tpl="YORK" wtd="11:30" />
+    <OPDT tpl="HOLGATE" wta="11:40" />
+  </Journey>
+  <Association tiploc="YORK" category="NP">
+    <main rid="202401017100001" wta="10:50" />
+    <assoc rid="202401017100002" wtd="11:30" />
+  </Association>
+</PportTimetable>"#;
+        let records = parse_timetable(xml.as_bytes()).unwrap();
+        let kinds: Vec<&str> = records
+            .iter()
+            .map(|r| match r {
+                CifRecord::Association(_) => "AA",
+                CifRecord::BasicSchedule(_) => "BS",
+                CifRecord::BasicScheduleExtra(_) => "BX",
+                CifRecord::OriginLocation(_) => "LO",
+                CifRecord::IntermediateLocation(_) => "LI",
+                CifRecord::TerminatingLocation(_) => "LT",
+                _ => "other",
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                "AA", "BS", "BX", "LO", "LI", "LI", "LT", "BS", "BX", "LO", "LT"
+            ]
+        );
+
+        let CifRecord::Association(aa) = &records[0] else {
+            unreachable!()
+        };
+        assert_eq!(
+            (aa.base_uid.as_str(), aa.assoc_uid.as_str()),
+            ("C10001", "C20001")
+        );
+        let CifRecord::BasicSchedule(bs) = &records[1] else {
+            unreachable!()
+        };
+        assert_eq!(bs.days_run, "1000000");
+        assert_eq!(bs.train_identity, "1A01");
+        let CifRecord::IntermediateLocation(pbro) = &records[5] else {
+            unreachable!()
+        };
+        assert!(pbro.is_public());
+        assert_eq!(pbro.scheduled_departure.map(|t| t.half_minute), Some(true));
+        let CifRecord::BasicSchedule(cancelled) = &records[7] else {
+            unreachable!()
+        };
+        assert_eq!(cancelled.stp_indicator, 'C');
+    }
+
+    #[test]
+    fn test_parse_reference() {
+        let xml = r#"<PportTimetableRef xmlns="http://www.thalesgroup.com/rtti/XmlRefData/v3">
+  <LocationRef tpl="KNGX" crs="KGX" toc="NR" locname="London Kings Cross" />
+  <LocationRef tpl="HUNTNGN" locname="HUNTNGN" />
+  <TocRef toc="GR" tocname="London North Eastern Railway" url="https://www.lner.co.uk" />
+</PportTimetableRef>"#;
+        let reference = parse_reference(xml.as_bytes()).unwrap();
+        assert_eq!(reference.locations.len(), 2);
+        assert_eq!(reference.locations[0].crs.as_deref(), Some("KGX"));
+        assert_eq!(reference.locations[1].crs, None);
+        assert_eq!(reference.tocs["GR"], "London North Eastern Railway");
+    }
+}
@@ -0,0 +1,423 @@
+//! Comparison of two generated feeds.
+//!
+//! Daily builds are checked before publishing by diffing them against the
+//! last one. Routes, stops, trips and calendars are matched by id and
+//! reported as added, removed or changed, with the fields that changed. A
+//! trip's stop times count as one field, summarised, so a retimed train
+//! shows up as a changed trip.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use zip::ZipArchive;
+
+#[derive(clap::Args)]
+pub struct DiffArgs {
+    /// The earlier feed, as a directory or ZIP
+    old: PathBuf,
+
+    /// The later feed, as a directory or ZIP
+    new: PathBuf,
+
+    /// Write the report to this file rather than standard output
+    #[arg(long, value_name = "PATH")]
+    output: Option<PathBuf>,
+}
+
+/// A feed's files, wherever they're kept
+pub enum Feed {
+    Directory(PathBuf),
+    Zip(ZipArchive<File>),
+}
+
+impl Feed {
+    pub fn open(path: &Path) -> Result<Self> {
+        if path.is_dir() {
+            return Ok(Feed::Directory(path.to_path_buf()));
+        }
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let archive = ZipArchive::new(file)
+            .with_context(|| format!("{} is neither a directory nor a ZIP", path.display()))?;
+        Ok(Feed::Zip(archive))
+    }
+
+    /// Read one file of the feed, if it has it. A ZIP may keep its files
+    /// in a folder.
+    pub fn read<T>(
+        &mut self,
+        name: &str,
+        f: impl FnOnce(&mut dyn Read) -> Result<T>,
+    ) -> Result<Option<T>> {
+        match self {
+            Feed::Directory(dir) => {
+                let path = dir.join(name);
+                if !path.exists() {
+                    return Ok(None);
+                }
+                let mut file = File::open(&path)
+                    .with_context(|| format!("Failed to open {}", path.display()))?;
+                f(&mut file).map(Some)
+            }
+            Feed::Zip(archive) => {
+                let Some(entry) = archive
+                    .file_names()
+                    .find(|entry| entry.rsplit('/').next() == Some(name))
+                    .map(str::to_string)
+                else {
+                    return Ok(None);
+                };
+                let mut file = archive.by_name(&entry)?;
+                f(&mut file).map(Some)
+            }
+        }
+    }
+
+    /// Names of the feed's `.txt` files
+    pub fn file_names(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = match self {
+            Feed::Directory(dir) => fs::read_dir(dir)?
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                .collect::<Result<_>>()?,
+            Feed::Zip(archive) => archive
+                .file_names()
+                .filter_map(|entry| entry.rsplit('/').next())
+                .map(str::to_string)
+                .collect(),
+        };
+        names.retain(|name| name.ends_with(".txt"));
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+}
+
+/// Fields of each row by id, without the id itself
+type Rows = BTreeMap<String, BTreeMap<String, String>>;
+
+fn read_rows(reader: &mut dyn Read, id_column: &str) -> Result<Rows> {
+    let mut csv = csv::Reader::from_reader(reader);
+    let headers = csv.headers()?.clone();
+    let id = headers
+        .iter()
+        .position(|header| header == id_column)
+        .with_context(|| format!("No {} column", id_column))?;
+    let mut rows = Rows::new();
+    for record in csv.records() {
+        let record = record?;
+        let fields = headers
+            .iter()
+            .zip(record.iter())
+            .enumerate()
+            .filter(|&(i, _)| i != id)
+            .map(|(_, (header, value))| (header.to_string(), value.to_string()))
+            .collect();
+        rows.insert(record[id].to_string(), fields);
+    }
+    Ok(rows)
+}
+
+/// A trip's stop times, hashed to compare and summarised to report
+#[derive(Default)]
+struct StopTimes {
+    hasher: DefaultHasher,
+    calls: usize,
+    first: String,
+    last: String,
+}
+
+impl StopTimes {
+    fn summary(&self) -> String {
+        format!(
+            "{} calls, {} to {} [{:016x}]",
+            self.calls,
+            self.first,
+            self.last,
+            self.hasher.finish()
+        )
+    }
+}
+
+/// Stop times of each trip as a `stop_times` field of its row
+fn add_stop_times(trips: &mut Rows, reader: &mut dyn Read) -> Result<()> {
+    let mut csv = csv::Reader::from_reader(reader);
+    let headers = csv.headers()?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|header| header == name)
+            .with_context(|| format!("No {} column in stop_times.txt", name))
+    };
+    let (trip_id, stop_id, arrival, departure) = (
+        column("trip_id")?,
+        column("stop_id")?,
+        column("arrival_time")?,
+        column("departure_time")?,
+    );
+    let mut stop_times: HashMap<String, StopTimes> = HashMap::new();
+    for record in csv.records() {
+        let record = record?;
+        let trip = stop_times.entry(record[trip_id].to_string()).or_default();
+        for (i, value) in record.iter().enumerate() {
+            if i != trip_id {
+                value.hash(&mut trip.hasher);
+            }
+        }
+        if trip.calls == 0 {
+            trip.first = format!("{} {}", &record[stop_id], &record[departure]);
+        }
+        trip.last = format!("{} {}", &record[stop_id], &record[arrival]);
+        trip.calls += 1;
+    }
+    for (trip_id, stop_times) in stop_times {
+        trips
+            .entry(trip_id)
+            .or_default()
+            .insert("stop_times".to_string(), stop_times.summary());
+    }
+    Ok(())
+}
+
+/// The parts of a feed compared
+struct Tables {
+    routes: Rows,
+    stops: Rows,
+    trips: Rows,
+    /// calendar.txt rows, with each service's calendar_dates.txt rows as
+    /// a `calendar_dates` field
+    calendars: Rows,
+}
+
+impl Tables {
+    fn load(path: &Path) -> Result<Self> {
+        let mut feed = Feed::open(path)?;
+        let mut required = |name: &str, id_column: &str| -> Result<Rows> {
+            feed.read(name, |reader| read_rows(reader, id_column))?
+                .with_context(|| format!("{} has no {}", path.display(), name))
+        };
+        let routes = required("routes.txt", "route_id")?;
+        let stops = required("stops.txt", "stop_id")?;
+        let mut trips = required("trips.txt", "trip_id")?;
+        feed.read("stop_times.txt", |reader| {
+            add_stop_times(&mut trips, reader)
+        })?;
+
+        let mut calendars = feed
+            .read("calendar.txt", |reader| read_rows(reader, "service_id"))?
+            .unwrap_or_default();
+        feed.read("calendar_dates.txt", |reader| {
+            for row in csv::Reader::from_reader(reader).deserialize() {
+                let (service_id, date, exception_type): (String, String, String) = row?;
+                let dates = calendars
+                    .entry(service_id)
+                    .or_default()
+                    .entry("calendar_dates".to_string())
+                    .or_default();
+                if !dates.is_empty() {
+                    dates.push(' ');
+                }
+                dates.push_str(&format!("{}:{}", date, exception_type));
+            }
+            Ok(())
+        })?;
+        Ok(Tables {
+            routes,
+            stops,
+            trips,
+            calendars,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct FieldChange {
+    pub old: String,
+    pub new: String,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct Change {
+    pub id: String,
+    pub fields: BTreeMap<String, FieldChange>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct TableDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<Change>,
+}
+
+impl TableDiff {
+    fn between(old: &Rows, new: &Rows) -> Self {
+        let mut diff = TableDiff::default();
+        for (id, old_fields) in old {
+            let Some(new_fields) = new.get(id) else {
+                diff.removed.push(id.clone());
+                continue;
+            };
+            let fields: BTreeMap<String, FieldChange> = old_fields
+                .keys()
+                .chain(new_fields.keys())
+                .filter_map(|field| {
+                    let value = |fields: &BTreeMap<String, String>| {
+                        fields.get(field).cloned().unwrap_or_default()
+                    };
+                    let (old, new) = (value(old_fields), value(new_fields));
+                    (old != new).then(|| (field.clone(), FieldChange { old, new }))
+                })
+                .collect();
+            if !fields.is_empty() {
+                diff.changed.push(Change {
+                    id: id.clone(),
+                    fields,
+                });
+            }
+        }
+        diff.added = new
+            .keys()
+            .filter(|id| !old.contains_key(*id))
+            .cloned()
+            .collect();
+        diff
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "{} added, {} removed, {} changed",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len()
+        )
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct FeedDiff {
+    pub routes: TableDiff,
+    pub stops: TableDiff,
+    pub trips: TableDiff,
+    pub calendars: TableDiff,
+}
+
+pub fn diff_feeds(old: &Path, new: &Path) -> Result<FeedDiff> {
+    let (old, new) = (Tables::load(old)?, Tables::load(new)?);
+    Ok(FeedDiff {
+        routes: TableDiff::between(&old.routes, &new.routes),
+        stops: TableDiff::between(&old.stops, &new.stops),
+        trips: TableDiff::between(&old.trips, &new.trips),
+        calendars: TableDiff::between(&old.calendars, &new.calendars),
+    })
+}
+
+pub fn run(args: DiffArgs) -> Result<()> {
+    let diff = diff_feeds(&args.old, &args.new)?;
+    match &args.output {
+        Some(path) => {
+            let file = File::create(path)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            serde_json::to_writer_pretty(file, &diff)?;
+            for (table, changes) in [
+                ("Routes", &diff.routes),
+                ("Stops", &diff.stops),
+                ("Trips", &diff.trips),
+                ("Calendars", &diff.calendars),
+            ] {
+                println!("{}: {}", table, changes.summary());
+            }
+        }
+        None => println!("{}", serde_json::to_string_pretty(&diff)?),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_feed(dir: &Path, files: &[(&str, &str)]) {
+        for (name, text) in files {
+            fs::write(dir.join(name), text).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_diff_feeds() {
+        let old = tempfile::tempdir().unwrap();
+        let new = tempfile::tempdir().unwrap();
+        let routes = "route_id,route_long_name\nGR,LNER\n";
+        let stops = "stop_id,stop_name\nKNGX,London Kings Cross\nYORK,York\n";
+        write_feed(
+            old.path(),
+            &[
+                ("routes.txt", routes),
+                ("stops.txt", stops),
+                (
+                    "trips.txt",
+                    "route_id,service_id,trip_id\nGR,S1,T1\nGR,S1,T2\n",
+                ),
+                (
+                    "stop_times.txt",
+                    "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+                     T1,09:00:00,09:00:00,KNGX,1\nT1,10:50:00,10:50:00,YORK,2\n\
+                     T2,11:00:00,11:00:00,KNGX,1\nT2,12:50:00,12:50:00,YORK,2\n",
+                ),
+                ("calendar.txt", "service_id,monday\nS1,1\n"),
+            ],
+        );
+        write_feed(
+            new.path(),
+            &[
+                ("routes.txt", routes),
+                ("stops.txt", "stop_id,stop_name\nKNGX,London King's Cross\n"),
+                (
+                    "trips.txt",
+                    "route_id,service_id,trip_id\nGR,S1,T1\nGR,S1,T3\n",
+                ),
+                (
+                    "stop_times.txt",
+                    "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+                     T1,09:00:00,09:00:00,KNGX,1\nT1,10:55:00,10:55:00,YORK,2\n\
+                     T3,13:00:00,13:00:00,KNGX,1\nT3,14:50:00,14:50:00,YORK,2\n",
+                ),
+                ("calendar.txt", "service_id,monday\nS1,1\n"),
+                (
+                    "calendar_dates.txt",
+                    "service_id,date,exception_type\nS1,20240301,2\n",
+                ),
+            ],
+        );
+
+        let diff = diff_feeds(old.path(), new.path()).unwrap();
+        assert_eq!(diff.routes.summary(), "0 added, 0 removed, 0 changed");
+        assert_eq!(diff.stops.removed, ["YORK"]);
+        assert_eq!(
+            diff.stops.changed[0].fields["stop_name"],
+            FieldChange {
+                old: "London Kings Cross".to_string(),
+                new: "London King's Cross".to_string(),
+            }
+        );
+        assert_eq!(
+            (diff.trips.added, diff.trips.removed),
+            (vec!["T3".to_string()], vec!["T2".to_string()])
+        );
+        // T1 now arrives at York five minutes later
+        let retimed = &diff.trips.changed[0];
+        assert_eq!(retimed.id, "T1");
+        assert!(
+            retimed.fields["stop_times"]
+                .new
+                .starts_with("2 calls, KNGX 09:00:00 to YORK 10:55:00")
+        );
+        assert_eq!(
+            diff.calendars.changed[0].fields["calendar_dates"].new,
+            "20240301:2"
+        );
+    }
+}
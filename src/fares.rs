@@ -0,0 +1,1296 @@
+//! GTFS-Fares v2 output from the RSP fares feed.
+//!
+//! Flows (`.FFL`) price journeys between NLC locations, ticket types (`.TTY`)
+//! describe what is bought, and locations (`.LOC`) tie NLCs to CRS codes. Each
+//! NLC that resolves to stations becomes a fare area, each priced ticket a fare
+//! product, and each flow a set of fare leg rules between two areas. Station
+//! groups such as London Terminals are NLCs too, whose area is the stops of
+//! every member station.
+//!
+//! A ticket is priced for adults and, where the ticket type allows children,
+//! for children too, as rows of the same fare product. Each railcard
+//! (`.RLC`) is a rider category as well, priced by the status discount
+//! (`.DIS`) its adult holders get on the ticket's discount category. How
+//! long a ticket stays valid comes from its validity code (`.TVL`).
+//!
+//! With route networks asked for, each fare route code (`.RTE`) becomes a
+//! network of the GTFS routes that call at its included stations and none
+//! of its excluded ones, and leg rules are limited to it. "Any Permitted"
+//! covers every route. Routes are judged by where any of their trips call,
+//! so this approximates the Routeing Guide rather than applying it.
+//!
+//! Non-derivable fares (`.NDF`) and their overrides (`.NFO`) price a ticket
+//! between two NLCs directly. As in retail systems they win over the flow
+//! fare for the same direction, route and ticket, and may suppress it.
+
+use crate::ParsedStation;
+use crate::writer::create_csv;
+use anyhow::Result;
+use chrono::NaiveDate;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read, Seek};
+use std::path::Path;
+use zip::ZipArchive;
+
+const FARE_MEDIA_ID: &str = "paper";
+const ADULT: &str = "adult";
+const CHILD: &str = "child";
+/// Anytime Single, then Anytime Day Single, in order of preference for Fares v1
+const ANYTIME_SINGLE_CODES: [&str; 2] = ["SOS", "SDS"];
+
+#[derive(Debug, Serialize)]
+struct FareMedia {
+    fare_media_id: String,
+    fare_media_name: String,
+    fare_media_type: u8,
+}
+
+#[derive(Debug, Serialize)]
+struct RiderCategory {
+    rider_category_id: String,
+    rider_category_name: String,
+    is_default_fare_category: u8,
+}
+
+#[derive(Debug, Serialize)]
+struct FareProduct {
+    fare_product_id: String,
+    fare_product_name: String,
+    rider_category_id: String,
+    fare_media_id: String,
+    amount: String,
+    currency: String,
+    /// How long the ticket can be used for, as in the Fares v2 duration
+    /// proposal; most consumers ignore these
+    duration_amount: Option<u8>,
+    duration_unit: Option<u8>,
+}
+
+#[derive(Debug, Serialize)]
+struct Area {
+    area_id: String,
+    area_name: String,
+}
+
+#[derive(Debug, Serialize)]
+struct StopArea {
+    area_id: String,
+    stop_id: String,
+}
+
+#[derive(Debug, Serialize)]
+struct FareAttribute {
+    fare_id: String,
+    price: String,
+    currency_type: String,
+    payment_method: u8,
+    /// Empty means unlimited transfers
+    transfers: Option<u8>,
+}
+
+#[derive(Debug, Serialize)]
+struct FareRule {
+    fare_id: String,
+    origin_id: String,
+    destination_id: String,
+}
+
+#[derive(Debug, Serialize)]
+struct FareLegRule {
+    leg_group_id: String,
+    network_id: Option<String>,
+    from_area_id: String,
+    to_area_id: String,
+    fare_product_id: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Network {
+    network_id: String,
+    network_name: String,
+}
+
+#[derive(Debug, Serialize)]
+struct RouteNetwork {
+    network_id: String,
+    route_id: String,
+}
+
+/// The route code of flows valid by any permitted route
+const ANY_PERMITTED: &str = "00000";
+
+/// A fare route from the `.RTE` file, e.g. "NOT VIA LONDON"
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FareRoute {
+    pub description: String,
+    /// CRS codes the journey must go through
+    pub included: Vec<String>,
+    /// CRS codes it mustn't
+    pub excluded: Vec<String>,
+}
+
+/// An NLC location from the `.LOC` file
+#[derive(Debug, Clone, PartialEq)]
+pub struct FareLocation {
+    pub nlc: String,
+    pub crs: String,
+    pub description: String,
+}
+
+/// A station group from the `G` and `M` records of the `.LOC` file
+#[derive(Debug, Clone, PartialEq)]
+pub struct FareGroup {
+    pub nlc: String,
+    pub description: String,
+    /// CRS codes of the member stations
+    pub members: Vec<String>,
+}
+
+/// A ticket type from the `.TTY` file
+#[derive(Debug, Clone, PartialEq)]
+pub struct TicketType {
+    pub code: String,
+    pub description: String,
+    /// `S`ingle, `R`eturn or seaso`N`
+    pub ticket_type: char,
+    /// Most adults and children one ticket covers, zero if it's not sold
+    /// to them
+    pub max_adults: u16,
+    pub max_children: u16,
+    /// Key into the `.TVL` validities
+    pub validity_code: String,
+    /// Which status discounts apply, with the railcard's status
+    pub discount_category: String,
+}
+
+/// A railcard from the `.RLC` file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Railcard {
+    pub code: String,
+    pub description: String,
+    /// Status of adults travelling on the card, a key into the `.DIS`
+    /// discounts
+    pub adult_status: String,
+}
+
+/// What a status gets off tickets of a discount category, from `.DIS`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusDiscount {
+    Percent(u8),
+    /// The status can't travel on these tickets
+    NotValid,
+}
+
+/// How long tickets of a validity code can be used, from the `.TVL` file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TicketValidity {
+    pub out_days: u8,
+    pub out_months: u8,
+    pub return_days: u8,
+    pub return_months: u8,
+}
+
+/// Fares v2 proposal duration units
+const DURATION_DAYS: u8 = 3;
+const DURATION_MONTHS: u8 = 5;
+
+impl TicketValidity {
+    /// The outward validity for singles, the return for returns, as an
+    /// amount and unit. Mixed days and months have no single unit.
+    fn duration(&self, ticket_type: char) -> Option<(u8, u8)> {
+        let (days, months) = match ticket_type {
+            'R' => (self.return_days, self.return_months),
+            _ => (self.out_days, self.out_months),
+        };
+        match (days, months) {
+            (0, 0) => None,
+            (days, 0) => Some((days, DURATION_DAYS)),
+            (0, months) => Some((months, DURATION_MONTHS)),
+            _ => None,
+        }
+    }
+}
+
+/// The standard child discount: half the adult fare, rounded down to 5p
+fn child_pence(adult: u32) -> u32 {
+    adult / 2 / 5 * 5
+}
+
+/// A percentage discount, rounded down to 5p as railcard fares are
+fn discounted_pence(adult: u32, percent: u8) -> u32 {
+    adult * (100 - u32::from(percent.min(100))) / 100 / 5 * 5
+}
+
+/// A flow from the `.FFL` file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Flow {
+    pub origin: String,
+    pub destination: String,
+    pub route_code: String,
+    pub reversible: bool,
+}
+
+/// A priced ticket on a flow from the `.FFL` file
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlowFare {
+    pub flow_id: String,
+    pub ticket_code: String,
+    pub pence: u32,
+}
+
+/// Origin, destination, route code and ticket code of a non-derivable fare
+type NonDerivableKey = (String, String, String, String);
+
+/// A price from the `.NDF` or `.NFO` file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NonDerivablePrice {
+    /// The ticket isn't sold for this journey at all
+    pub suppressed: bool,
+    pub adult_pence: Option<u32>,
+    pub child_pence: Option<u32>,
+}
+
+/// Non-derivable prices for one direction, route and ticket, by railcard
+/// code (blank for none)
+type NonDerivablePrices = HashMap<String, NonDerivablePrice>;
+
+/// Fares dates are ddmmyyyy
+fn parse_fares_date(raw: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(raw, "%d%m%Y").ok()
+}
+
+fn field(line: &str, range: std::ops::Range<usize>) -> &str {
+    line.get(range).unwrap_or("").trim()
+}
+
+/// Records still valid on `today`; comment lines start with `/`
+fn current_lines<R: Read>(
+    reader: R,
+    end_date: std::ops::Range<usize>,
+    today: NaiveDate,
+) -> impl Iterator<Item = String> {
+    BufReader::new(reader)
+        .lines()
+        .map_while(Result::ok)
+        .filter(|line| !line.starts_with('/'))
+        .filter(move |line| {
+            parse_fares_date(field(line, end_date.clone())).is_none_or(|end| end >= today)
+        })
+}
+
+/// Parse `L` records of the `.LOC` file into NLC locations
+pub fn parse_locations<R: Read>(reader: R, today: NaiveDate) -> Vec<FareLocation> {
+    current_lines(reader, 9..17, today)
+        .filter(|line| line.get(1..2) == Some("L"))
+        .map(|line| FareLocation {
+            nlc: field(&line, 36..40).to_string(),
+            description: field(&line, 40..56).to_string(),
+            crs: field(&line, 56..59).to_string(),
+        })
+        .filter(|loc| !loc.nlc.is_empty())
+        .collect()
+}
+
+/// Parse the station groups of the `.LOC` file. Groups are keyed by UIC
+/// code, whose third to sixth digits are the group's NLC.
+pub fn parse_groups<R: Read>(reader: R, today: NaiveDate) -> Vec<FareGroup> {
+    let mut groups: BTreeMap<String, FareGroup> = BTreeMap::new();
+    let mut members: Vec<(String, String)> = Vec::new();
+    for line in current_lines(reader, 9..17, today) {
+        let uic = field(&line, 2..9).to_string();
+        match line.get(1..2) {
+            Some("G") => {
+                let group = FareGroup {
+                    nlc: field(&line, 4..8).to_string(),
+                    description: field(&line, 33..49).to_string(),
+                    members: Vec::new(),
+                };
+                groups.insert(uic, group);
+            }
+            Some("M") => members.push((uic, field(&line, 24..27).to_string())),
+            _ => {}
+        }
+    }
+    // Members may be listed before their group
+    for (uic, crs) in members {
+        if let Some(group) = groups.get_mut(&uic).filter(|_| !crs.is_empty()) {
+            group.members.push(crs);
+        }
+    }
+    groups.into_values().filter(|g| !g.nlc.is_empty()).collect()
+}
+
+/// Parse the `.RTE` route and route location records, keyed by route code
+pub fn parse_fare_routes<R: Read>(reader: R, today: NaiveDate) -> BTreeMap<String, FareRoute> {
+    let mut routes: BTreeMap<String, FareRoute> = BTreeMap::new();
+    for line in current_lines(reader, 7..15, today) {
+        let route = routes.entry(field(&line, 2..7).to_string()).or_default();
+        match line.get(1..2) {
+            Some("R") => route.description = field(&line, 31..47).to_string(),
+            Some("L") => {
+                let crs = field(&line, 22..25).to_string();
+                match field(&line, 25..26) {
+                    "I" => route.included.push(crs),
+                    "E" => route.excluded.push(crs),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    routes.retain(|code, route| !code.is_empty() && !route.description.is_empty());
+    routes
+}
+
+/// GTFS routes each fare route allows, given the stations (CRS) each
+/// route calls at
+pub fn fare_route_networks(
+    fare_routes: &BTreeMap<String, FareRoute>,
+    route_stations: &BTreeMap<String, BTreeSet<String>>,
+) -> BTreeMap<String, Vec<String>> {
+    fare_routes
+        .iter()
+        .map(|(code, fare_route)| {
+            let routes = route_stations
+                .iter()
+                .filter(|(_, stations)| {
+                    code == ANY_PERMITTED
+                        || (fare_route.included.iter().all(|crs| stations.contains(crs))
+                            && !fare_route.excluded.iter().any(|crs| stations.contains(crs)))
+                })
+                .map(|(route_id, _)| route_id.clone())
+                .collect();
+            (code.clone(), routes)
+        })
+        .collect()
+}
+
+fn network_id(route_code: &str) -> String {
+    format!("fare_route_{}", route_code)
+}
+
+/// Parse the `.TTY` ticket types, keyed by ticket code
+pub fn parse_ticket_types<R: Read>(reader: R, today: NaiveDate) -> HashMap<String, TicketType> {
+    current_lines(reader, 4..12, today)
+        .map(|line| TicketType {
+            code: field(&line, 1..4).to_string(),
+            description: field(&line, 28..43).to_string(),
+            ticket_type: line.chars().nth(44).unwrap_or('S'),
+            max_adults: field(&line, 60..63).parse().unwrap_or(1),
+            max_children: field(&line, 66..69).parse().unwrap_or(1),
+            validity_code: field(&line, 75..77).to_string(),
+            discount_category: field(&line, 111..113).to_string(),
+        })
+        .filter(|tt| !tt.code.is_empty())
+        .map(|tt| (tt.code.clone(), tt))
+        .collect()
+}
+
+/// Parse the `.TVL` ticket validities, keyed by validity code
+pub fn parse_validities<R: Read>(reader: R, today: NaiveDate) -> HashMap<String, TicketValidity> {
+    let number = |line: &str, range| field(line, range).parse().unwrap_or(0);
+    current_lines(reader, 3..11, today)
+        .filter(|line| line.starts_with('R'))
+        .map(|line| {
+            let validity = TicketValidity {
+                out_days: number(&line, 39..41),
+                out_months: number(&line, 41..43),
+                return_days: number(&line, 43..45),
+                return_months: number(&line, 45..47),
+            };
+            (field(&line, 1..3).to_string(), validity)
+        })
+        .collect()
+}
+
+/// Parse the `.NDF` or `.NFO` fares, keyed by direction, route and ticket,
+/// then railcard
+pub fn parse_non_derivable<R: Read>(
+    reader: R,
+    today: NaiveDate,
+) -> Vec<(NonDerivableKey, String, NonDerivablePrice)> {
+    current_lines(reader, 21..29, today)
+        .filter(|line| line.starts_with('R'))
+        .map(|line| {
+            let key = (
+                field(&line, 1..5).to_string(),
+                field(&line, 5..9).to_string(),
+                field(&line, 9..14).to_string(),
+                field(&line, 17..20).to_string(),
+            );
+            let price = NonDerivablePrice {
+                suppressed: field(&line, 45..46) == "Y",
+                adult_pence: field(&line, 46..54).parse().ok(),
+                child_pence: field(&line, 54..62).parse().ok(),
+            };
+            (key, field(&line, 14..17).to_string(), price)
+        })
+        .collect()
+}
+
+/// Parse the `.RLC` railcards that are shown to buyers
+pub fn parse_railcards<R: Read>(reader: R, today: NaiveDate) -> Vec<Railcard> {
+    current_lines(reader, 4..12, today)
+        .filter(|line| field(line, 56..57) == "Y")
+        .map(|line| Railcard {
+            code: field(&line, 1..4).to_string(),
+            description: field(&line, 29..49).to_string(),
+            adult_status: field(&line, 113..116).to_string(),
+        })
+        .filter(|railcard| !railcard.code.is_empty() && !railcard.adult_status.is_empty())
+        .collect()
+}
+
+/// Parse the `.DIS` status discounts, keyed by status and discount category
+pub fn parse_status_discounts<R: Read>(
+    reader: R,
+    today: NaiveDate,
+) -> HashMap<(String, String), StatusDiscount> {
+    current_lines(reader, 6..14, today)
+        .filter(|line| line.starts_with('R'))
+        .filter_map(|line| {
+            let discount = match field(&line, 14..15) {
+                "D" => StatusDiscount::Percent(field(&line, 15..18).parse().ok()?),
+                "X" | "N" => StatusDiscount::NotValid,
+                _ => return None,
+            };
+            let key = (
+                field(&line, 1..4).to_string(),
+                field(&line, 4..6).to_string(),
+            );
+            Some((key, discount))
+        })
+        .collect()
+}
+
+/// Parse the `F` flow records of the `.FFL` file, keyed by flow id
+pub fn parse_flows<R: Read>(reader: R, today: NaiveDate) -> HashMap<String, Flow> {
+    current_lines(reader, 20..28, today)
+        .filter(|line| line.get(1..2) == Some("F"))
+        .map(|line| {
+            let flow = Flow {
+                origin: field(&line, 2..6).to_string(),
+                destination: field(&line, 6..10).to_string(),
+                route_code: field(&line, 10..15).to_string(),
+                reversible: field(&line, 19..20) == "R",
+            };
+            (field(&line, 42..49).to_string(), flow)
+        })
+        .collect()
+}
+
+/// Parse the `T` fare records of the `.FFL` file
+pub fn parse_flow_fares<R: Read>(reader: R) -> impl Iterator<Item = FlowFare> {
+    BufReader::new(reader)
+        .lines()
+        .map_while(Result::ok)
+        .filter(|line| line.get(1..2) == Some("T"))
+        .filter_map(|line| {
+            Some(FlowFare {
+                flow_id: field(&line, 2..9).to_string(),
+                ticket_code: field(&line, 9..12).to_string(),
+                pence: field(&line, 12..20).parse().ok()?,
+            })
+        })
+}
+
+fn format_amount(pence: u32) -> String {
+    format!("{}.{:02}", pence / 100, pence % 100)
+}
+
+/// Run `f` over every archive entry whose name ends with `suffix`
+fn for_each_entry<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    suffix: &str,
+    mut f: impl FnMut(&mut dyn Read) -> Result<()>,
+) -> Result<()> {
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if file.name().ends_with(suffix) {
+            println!("Processing Fares File: {}", file.name());
+            f(&mut file)?;
+        }
+    }
+    Ok(())
+}
+
+fn load_locations<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    today: NaiveDate,
+) -> Result<Vec<FareLocation>> {
+    let mut locations = Vec::new();
+    for_each_entry(archive, ".LOC", |r| {
+        locations.extend(parse_locations(r, today));
+        Ok(())
+    })?;
+    Ok(locations)
+}
+
+/// Fare zone (NLC) of each station, used as `zone_id` by Fares v1.
+/// Where several NLCs share a CRS the first listed is the station's own.
+pub fn station_fare_zones<'a, R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    stations: impl IntoIterator<Item = &'a ParsedStation>,
+    today: NaiveDate,
+) -> Result<HashMap<String, String>> {
+    let mut nlc_by_crs: HashMap<String, String> = HashMap::new();
+    for loc in load_locations(archive, today)? {
+        if !loc.crs.is_empty() {
+            nlc_by_crs.entry(loc.crs).or_insert(loc.nlc);
+        }
+    }
+    Ok(stations
+        .into_iter()
+        .filter_map(|st| Some((st.tiploc.clone(), nlc_by_crs.get(&st.crs)?.clone())))
+        .collect())
+}
+
+/// Write `fare_attributes.txt` and `fare_rules.txt` from the anytime single
+/// fare between every pair of fare zones in use
+pub fn write_fares_v1<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    zones: &HashMap<String, String>,
+    output_dir: &str,
+    buffer_size: usize,
+    today: NaiveDate,
+) -> Result<()> {
+    let zones_in_use: BTreeSet<&String> = zones.values().collect();
+    let mut flows = HashMap::new();
+    for_each_entry(archive, ".FFL", |r| {
+        flows.extend(parse_flows(r, today));
+        Ok(())
+    })?;
+
+    // (origin, destination) -> (ticket preference, pence)
+    let mut singles: BTreeMap<(String, String), (usize, u32)> = BTreeMap::new();
+    for_each_entry(archive, ".FFL", |r| {
+        for fare in parse_flow_fares(r) {
+            let Some(rank) = ANYTIME_SINGLE_CODES
+                .iter()
+                .position(|code| *code == fare.ticket_code)
+            else {
+                continue;
+            };
+            let Some(flow) = flows.get(&fare.flow_id) else {
+                continue;
+            };
+            if !zones_in_use.contains(&flow.origin) || !zones_in_use.contains(&flow.destination) {
+                continue;
+            }
+
+            let mut directions = vec![(&flow.origin, &flow.destination)];
+            if flow.reversible {
+                directions.push((&flow.destination, &flow.origin));
+            }
+            for (from, to) in directions {
+                let best = singles
+                    .entry((from.clone(), to.clone()))
+                    .or_insert((rank, fare.pence));
+                if rank < best.0 {
+                    *best = (rank, fare.pence);
+                }
+            }
+        }
+        Ok(())
+    })?;
+
+    let mut attributes_w = create_csv(Path::new(output_dir), "fare_attributes.txt", buffer_size)?;
+    let mut rules_w = create_csv(Path::new(output_dir), "fare_rules.txt", buffer_size)?;
+    for ((origin, destination), (_, pence)) in &singles {
+        let fare_id = format!("{}_{}", origin, destination);
+        attributes_w.serialize(FareAttribute {
+            fare_id: fare_id.clone(),
+            price: format_amount(*pence),
+            currency_type: "GBP".to_string(),
+            payment_method: 0,
+            transfers: None,
+        })?;
+        rules_w.serialize(FareRule {
+            fare_id,
+            origin_id: origin.clone(),
+            destination_id: destination.clone(),
+        })?;
+    }
+
+    println!("Wrote {} Fares v1 origin-destination fares.", singles.len());
+    Ok(())
+}
+
+/// Fare areas: every NLC whose CRS resolves to at least one stop, and
+/// every station group with a member that does. `boarding_stops` lists a
+/// station's platform stops, if it has any, with their `--id-prefix`;
+/// stations without are the stop of that id, which gets `id_prefix` here.
+pub fn build_fare_areas<'a>(
+    locations: &[FareLocation],
+    groups: &[FareGroup],
+    stations: impl IntoIterator<Item = &'a ParsedStation>,
+    boarding_stops: &'a HashMap<String, Vec<String>>,
+    id_prefix: &str,
+) -> BTreeMap<String, (String, BTreeSet<String>)> {
+    let mut stops_by_crs: HashMap<&str, Vec<String>> = HashMap::new();
+    for station in stations {
+        if !station.crs.is_empty() {
+            let stops = stops_by_crs.entry(station.crs.as_str()).or_default();
+            match boarding_stops.get(&station.tiploc) {
+                Some(ids) => stops.extend(ids.iter().cloned()),
+                None => stops.push(format!("{}{}", id_prefix, station.tiploc)),
+            }
+        }
+    }
+
+    let mut areas: BTreeMap<String, (String, BTreeSet<String>)> = BTreeMap::new();
+    for loc in locations {
+        let Some(stops) = stops_by_crs.get(loc.crs.as_str()) else {
+            continue;
+        };
+        let area = areas
+            .entry(loc.nlc.clone())
+            .or_insert_with(|| (loc.description.clone(), BTreeSet::new()));
+        area.1.extend(stops.iter().cloned());
+    }
+    for group in groups {
+        let stops: BTreeSet<String> = group
+            .members
+            .iter()
+            .filter_map(|crs| stops_by_crs.get(crs.as_str()))
+            .flatten()
+            .cloned()
+            .collect();
+        if stops.is_empty() {
+            continue;
+        }
+        areas
+            .entry(group.nlc.clone())
+            .or_insert_with(|| (group.description.clone(), BTreeSet::new()))
+            .1
+            .extend(stops);
+    }
+    areas
+}
+
+/// Products priced by a non-derivable fare are particular to its journey
+fn non_derivable_product_id(key: &NonDerivableKey) -> String {
+    let (origin, destination, route_code, ticket_code) = key;
+    format!("{}_{}_{}_{}", ticket_code, origin, destination, route_code)
+}
+
+/// The price of a ticket to each rider category it's sold to. A
+/// non-derivable fare replaces the flow-derived one, railcard by railcard.
+fn ticket_prices(
+    ticket: &TicketType,
+    derived: Option<u32>,
+    non_derivable: Option<&NonDerivablePrices>,
+    railcards: &[Railcard],
+    status_discounts: &HashMap<(String, String), StatusDiscount>,
+) -> Vec<(String, u32)> {
+    let nd = |railcard: &str| non_derivable.and_then(|prices| prices.get(railcard));
+    let (adult, child) = match nd("") {
+        Some(price) if price.suppressed => return Vec::new(),
+        Some(price) => (
+            price.adult_pence,
+            price.child_pence.or(price.adult_pence.map(child_pence)),
+        ),
+        None => (derived, derived.map(child_pence)),
+    };
+
+    let mut prices = Vec::new();
+    if let Some(adult) = adult.filter(|_| ticket.max_adults > 0) {
+        prices.push((ADULT.to_string(), adult));
+        let derived_railcards = railcard_prices(railcards, status_discounts, ticket, adult);
+        for railcard in railcards {
+            let category = railcard_category(railcard);
+            match nd(&railcard.code) {
+                Some(price) if price.suppressed => {}
+                Some(price) => prices.extend(price.adult_pence.map(|pence| (category, pence))),
+                None => prices.extend(
+                    derived_railcards
+                        .iter()
+                        .find(|(c, _)| *c == category)
+                        .cloned(),
+                ),
+            }
+        }
+    }
+    if let Some(child) = child.filter(|_| ticket.max_children > 0) {
+        prices.push((CHILD.to_string(), child));
+    }
+    prices
+}
+
+/// Record a ticket's rows of fare_products.txt, once per product and
+/// rider category
+fn add_products(
+    products: &mut BTreeMap<(String, String), FareProduct>,
+    product_id: &str,
+    ticket: &TicketType,
+    duration: Option<(u8, u8)>,
+    prices: Vec<(String, u32)>,
+) {
+    for (category, pence) in prices {
+        products
+            .entry((product_id.to_string(), category.clone()))
+            .or_insert_with(|| FareProduct {
+                fare_product_id: product_id.to_string(),
+                fare_product_name: ticket.description.clone(),
+                rider_category_id: category,
+                fare_media_id: FARE_MEDIA_ID.to_string(),
+                amount: format_amount(pence),
+                currency: "GBP".to_string(),
+                duration_amount: duration.map(|(amount, _)| amount),
+                duration_unit: duration.map(|(_, unit)| unit),
+            });
+    }
+}
+
+fn railcard_category(railcard: &Railcard) -> String {
+    format!("railcard_{}", railcard.code)
+}
+
+/// The price of a ticket to adult holders of each railcard that discounts
+/// it. Tickets a railcard isn't valid on, or that it leaves at full price,
+/// get no row of their own.
+fn railcard_prices(
+    railcards: &[Railcard],
+    status_discounts: &HashMap<(String, String), StatusDiscount>,
+    ticket: &TicketType,
+    adult_pence: u32,
+) -> Vec<(String, u32)> {
+    railcards
+        .iter()
+        .filter_map(|railcard| {
+            let key = (
+                railcard.adult_status.clone(),
+                ticket.discount_category.clone(),
+            );
+            match status_discounts.get(&key)? {
+                StatusDiscount::Percent(0) | StatusDiscount::NotValid => None,
+                StatusDiscount::Percent(percent) => Some((
+                    railcard_category(railcard),
+                    discounted_pence(adult_pence, *percent),
+                )),
+            }
+        })
+        .collect()
+}
+
+/// Write `fare_media.txt`, `fare_products.txt`, `areas.txt`, `stop_areas.txt`
+/// and `fare_leg_rules.txt` for every current, non-season flow fare. Given
+/// the stations (CRS) each route calls at, also write `networks.txt` and
+/// `route_networks.txt` and limit leg rules to their fare route's network.
+/// Stop and route ids are written with `id_prefix`, as in the feed.
+#[allow(clippy::too_many_arguments)]
+pub fn write_fares_v2<'a, R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    stations: impl IntoIterator<Item = &'a ParsedStation>,
+    boarding_stops: &'a HashMap<String, Vec<String>>,
+    id_prefix: &str,
+    route_stations: Option<&BTreeMap<String, BTreeSet<String>>>,
+    output_dir: &str,
+    buffer_size: usize,
+    today: NaiveDate,
+) -> Result<()> {
+    let locations = load_locations(archive, today)?;
+    let mut groups = Vec::new();
+    for_each_entry(archive, ".LOC", |r| {
+        groups.extend(parse_groups(r, today));
+        Ok(())
+    })?;
+    let mut ticket_types = HashMap::new();
+    for_each_entry(archive, ".TTY", |r| {
+        ticket_types.extend(parse_ticket_types(r, today));
+        Ok(())
+    })?;
+    let mut validities = HashMap::new();
+    for_each_entry(archive, ".TVL", |r| {
+        validities.extend(parse_validities(r, today));
+        Ok(())
+    })?;
+    let mut railcards = Vec::new();
+    for_each_entry(archive, ".RLC", |r| {
+        railcards.extend(parse_railcards(r, today));
+        Ok(())
+    })?;
+    let mut status_discounts = HashMap::new();
+    for_each_entry(archive, ".DIS", |r| {
+        status_discounts.extend(parse_status_discounts(r, today));
+        Ok(())
+    })?;
+    let mut flows = HashMap::new();
+    for_each_entry(archive, ".FFL", |r| {
+        flows.extend(parse_flows(r, today));
+        Ok(())
+    })?;
+    // Overrides are read last so they replace non-derivable fares
+    let mut non_derivable: BTreeMap<NonDerivableKey, NonDerivablePrices> = BTreeMap::new();
+    for suffix in [".NDF", ".NFO"] {
+        for_each_entry(archive, suffix, |r| {
+            for (key, railcard, price) in parse_non_derivable(r, today) {
+                non_derivable
+                    .entry(key)
+                    .or_default()
+                    .insert(railcard, price);
+            }
+            Ok(())
+        })?;
+    }
+
+    let areas = build_fare_areas(&locations, &groups, stations, boarding_stops, id_prefix);
+
+    let mut networks: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    if let Some(route_stations) = route_stations {
+        let mut fare_routes = BTreeMap::new();
+        for_each_entry(archive, ".RTE", |r| {
+            fare_routes.extend(parse_fare_routes(r, today));
+            Ok(())
+        })?;
+        networks = fare_route_networks(&fare_routes, route_stations);
+        let mut networks_w = create_csv(Path::new(output_dir), "networks.txt", buffer_size)?;
+        let mut route_networks_w =
+            create_csv(Path::new(output_dir), "route_networks.txt", buffer_size)?;
+        for (code, routes) in &networks {
+            networks_w.serialize(Network {
+                network_id: network_id(code),
+                network_name: fare_routes[code].description.clone(),
+            })?;
+            for route_id in routes {
+                route_networks_w.serialize(RouteNetwork {
+                    network_id: network_id(code),
+                    route_id: route_id.clone(),
+                })?;
+            }
+        }
+        println!("Wrote {} fare route networks.", networks.len());
+    }
+    // Routes not in the .RTE file are left unrestricted
+    let leg_network = |route_code: &str| {
+        networks
+            .contains_key(route_code)
+            .then(|| network_id(route_code))
+    };
+
+    let mut areas_w = create_csv(Path::new(output_dir), "areas.txt", buffer_size)?;
+    let mut stop_areas_w = create_csv(Path::new(output_dir), "stop_areas.txt", buffer_size)?;
+    for (area_id, (name, stops)) in &areas {
+        areas_w.serialize(Area {
+            area_id: area_id.clone(),
+            area_name: name.clone(),
+        })?;
+        for stop_id in stops {
+            stop_areas_w.serialize(StopArea {
+                area_id: area_id.clone(),
+                stop_id: stop_id.clone(),
+            })?;
+        }
+    }
+
+    let mut leg_rules_w = create_csv(Path::new(output_dir), "fare_leg_rules.txt", buffer_size)?;
+    let mut products: BTreeMap<(String, String), FareProduct> = BTreeMap::new();
+    let mut rule_count = 0;
+    let mut non_derivable_used: HashSet<NonDerivableKey> = HashSet::new();
+    let priced = |ticket: &TicketType, derived: Option<u32>, nd: Option<&NonDerivablePrices>| {
+        let duration = validities
+            .get(&ticket.validity_code)
+            .and_then(|v| v.duration(ticket.ticket_type));
+        let prices = ticket_prices(ticket, derived, nd, &railcards, &status_discounts);
+        (prices, duration)
+    };
+
+    // Fare records follow the flows, so walk the file a second time for them
+    for_each_entry(archive, ".FFL", |r| {
+        for fare in parse_flow_fares(r) {
+            let Some(flow) = flows.get(&fare.flow_id) else {
+                continue;
+            };
+            let Some(ticket) = ticket_types.get(&fare.ticket_code) else {
+                continue;
+            };
+            // Season tickets aren't priced per leg
+            if ticket.ticket_type == 'N'
+                || !areas.contains_key(&flow.origin)
+                || !areas.contains_key(&flow.destination)
+            {
+                continue;
+            }
+
+            let mut directions = vec![(&flow.origin, &flow.destination)];
+            if flow.reversible {
+                directions.push((&flow.destination, &flow.origin));
+            }
+            for (from, to) in directions {
+                let key = (
+                    from.clone(),
+                    to.clone(),
+                    flow.route_code.clone(),
+                    fare.ticket_code.clone(),
+                );
+                let nd = non_derivable.get(&key);
+                let product_id = match nd {
+                    Some(_) => non_derivable_product_id(&key),
+                    None => format!("{}_{}", fare.ticket_code, fare.pence),
+                };
+                non_derivable_used.insert(key);
+                let (prices, duration) = priced(ticket, Some(fare.pence), nd);
+                if prices.is_empty() {
+                    continue;
+                }
+                add_products(&mut products, &product_id, ticket, duration, prices);
+                leg_rules_w.serialize(FareLegRule {
+                    leg_group_id: format!("flow_{}", fare.flow_id),
+                    network_id: leg_network(&flow.route_code),
+                    from_area_id: from.clone(),
+                    to_area_id: to.clone(),
+                    fare_product_id: product_id,
+                })?;
+                rule_count += 1;
+            }
+        }
+        Ok(())
+    })?;
+
+    // Non-derivable fares with no flow fare to replace stand on their own
+    for (key, nd) in &non_derivable {
+        let (origin, destination, route_code, ticket_code) = key;
+        if non_derivable_used.contains(key)
+            || !areas.contains_key(origin)
+            || !areas.contains_key(destination)
+        {
+            continue;
+        }
+        let Some(ticket) = ticket_types
+            .get(ticket_code)
+            .filter(|t| t.ticket_type != 'N')
+        else {
+            continue;
+        };
+        let (prices, duration) = priced(ticket, None, Some(nd));
+        if prices.is_empty() {
+            continue;
+        }
+        let product_id = non_derivable_product_id(key);
+        add_products(&mut products, &product_id, ticket, duration, prices);
+        leg_rules_w.serialize(FareLegRule {
+            leg_group_id: format!("nd_{}_{}_{}", origin, destination, route_code),
+            network_id: leg_network(route_code),
+            from_area_id: origin.clone(),
+            to_area_id: destination.clone(),
+            fare_product_id: product_id,
+        })?;
+        rule_count += 1;
+    }
+
+    let mut products_w = create_csv(Path::new(output_dir), "fare_products.txt", buffer_size)?;
+    for product in products.values() {
+        products_w.serialize(product)?;
+    }
+    let mut categories_w = create_csv(Path::new(output_dir), "rider_categories.txt", buffer_size)?;
+    for (rider_category_id, rider_category_name, is_default) in
+        [(ADULT, "Adult", 1), (CHILD, "Child (5-15)", 0)]
+    {
+        categories_w.serialize(RiderCategory {
+            rider_category_id: rider_category_id.to_string(),
+            rider_category_name: rider_category_name.to_string(),
+            is_default_fare_category: is_default,
+        })?;
+    }
+    for railcard in &railcards {
+        categories_w.serialize(RiderCategory {
+            rider_category_id: railcard_category(railcard),
+            rider_category_name: railcard.description.clone(),
+            is_default_fare_category: 0,
+        })?;
+    }
+    let mut media_w = create_csv(Path::new(output_dir), "fare_media.txt", buffer_size)?;
+    media_w.serialize(FareMedia {
+        fare_media_id: FARE_MEDIA_ID.to_string(),
+        fare_media_name: "Paper ticket".to_string(),
+        fare_media_type: 1,
+    })?;
+
+    println!(
+        "Wrote {} fare areas, {} fare products and {} fare leg rules.",
+        areas.len(),
+        products.len(),
+        rule_count
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn today() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 6, 1).unwrap()
+    }
+
+    #[test]
+    fn test_parse_locations_skips_expired_records() {
+        let loc = [
+            "/!! Start of file",
+            "RL70612103112299901012024010120240006121LONDON KINGS X  KGX",
+            "RL70999903101202001012020010120200009999OLD STATION     OLD",
+        ]
+        .join("\n");
+        let locations = parse_locations(loc.as_bytes(), today());
+        assert_eq!(
+            locations,
+            vec![FareLocation {
+                nlc: "6121".to_string(),
+                crs: "KGX".to_string(),
+                description: "LONDON KINGS X".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_flows_and_fares() {
+        let ffl = [
+            "RF6121923300000000 R3112299901012024GRA0000000001",
+            "RT0000001SOS00012350  ",
+        ]
+        .join("\n");
+        let flows = parse_flows(ffl.as_bytes(), today());
+        let flow = &flows["0000001"];
+        assert_eq!(flow.origin, "6121");
+        assert_eq!(flow.destination, "9233");
+        assert!(flow.reversible);
+
+        let fares: Vec<FlowFare> = parse_flow_fares(ffl.as_bytes()).collect();
+        assert_eq!(fares.len(), 1);
+        assert_eq!(fares[0].ticket_code, "SOS");
+        assert_eq!(format_amount(fares[0].pence), "123.50");
+    }
+
+    #[test]
+    fn test_ticket_riders_and_validity() {
+        let tty = [
+            "RSOR311229990101202401012024ANYTIME RETURN 2RS31122999001001001000001000NNNM1",
+            "RFAM311229990101202401012024FAMILY SINGLE  2SS31122999004002002001000000NNND1",
+        ]
+        .join("\n");
+        let tickets = parse_ticket_types(tty.as_bytes(), today());
+        let anytime = &tickets["SOR"];
+        assert_eq!((anytime.max_adults, anytime.max_children), (1, 1));
+        assert_eq!(anytime.validity_code, "M1");
+        // A ticket for up to two adults, but no children
+        assert_eq!(
+            (tickets["FAM"].max_adults, tickets["FAM"].max_children),
+            (2, 0)
+        );
+
+        let tvl = [
+            "RM13112299901012024ONE MONTH RETURN    00000001",
+            "RD13112299901012024DAY OF TRAVEL       01000000",
+        ]
+        .join("\n");
+        let validities = parse_validities(tvl.as_bytes(), today());
+        assert_eq!(validities["M1"].duration('R'), Some((1, DURATION_MONTHS)));
+        assert_eq!(validities["D1"].duration('S'), Some((1, DURATION_DAYS)));
+
+        assert_eq!(child_pence(12350), 6175);
+        assert_eq!(child_pence(1235), 615);
+    }
+
+    #[test]
+    fn test_railcard_prices() {
+        let rlc = ["RYNG311229990101202401012024A16-25 RAILCARD      NNNNYNGY0010000010010000000010000000000000300001001231122999YYNGYNGYNC   ", "RSTF311229990101202401012024A16-25 RAILCARD      NNNNYNGN0010000010010000000010000000000000300001001231122999YYNGYNGYNC   "].join("\n");
+        let railcards = parse_railcards(rlc.as_bytes(), today());
+        assert_eq!(
+            railcards,
+            vec![Railcard {
+                code: "YNG".to_string(),
+                description: "16-25 RAILCARD".to_string(),
+                adult_status: "YNG".to_string(),
+            }]
+        );
+
+        let dis = ["RYNG0131122999D034", "RYNG0231122999X000"].join("\n");
+        let discounts = parse_status_discounts(dis.as_bytes(), today());
+        assert_eq!(
+            discounts[&("YNG".to_string(), "02".to_string())],
+            StatusDiscount::NotValid
+        );
+
+        let tickets = parse_ticket_types("RSOS311229990101202401012024ANYTIME SINGLE 2SS31122999001001001000001000NNND1                                  01".as_bytes(), today());
+        let mut ticket = tickets["SOS"].clone();
+        assert_eq!(ticket.discount_category, "01");
+        // 34% off, rounded down to 5p
+        assert_eq!(
+            railcard_prices(&railcards, &discounts, &ticket, 12350),
+            vec![("railcard_YNG".to_string(), 8150)]
+        );
+        ticket.discount_category = "02".to_string();
+        assert!(railcard_prices(&railcards, &discounts, &ticket, 12350).is_empty());
+    }
+
+    #[test]
+    fn test_non_derivable_fares_win_over_flows() {
+        let ndf = [
+            "R6121923300000   SOSN311229990101202401012024N0000990000004500  ",
+            "R6121923300000YNGSOSN311229990101202401012024Y0000000000000000  ",
+            "R9233612100000   SOSN311229990101202401012024Y0000000000000000  ",
+        ]
+        .join("\n");
+        let mut non_derivable: HashMap<NonDerivableKey, NonDerivablePrices> = HashMap::new();
+        for (key, railcard, price) in parse_non_derivable(ndf.as_bytes(), today()) {
+            non_derivable
+                .entry(key)
+                .or_default()
+                .insert(railcard, price);
+        }
+        let key = |o: &str, d: &str| {
+            (
+                o.to_string(),
+                d.to_string(),
+                "00000".to_string(),
+                "SOS".to_string(),
+            )
+        };
+        let railcards = vec![Railcard {
+            code: "YNG".to_string(),
+            description: "16-25 RAILCARD".to_string(),
+            adult_status: "YNG".to_string(),
+        }];
+        let discounts = HashMap::from([(
+            ("YNG".to_string(), "01".to_string()),
+            StatusDiscount::Percent(34),
+        )]);
+        let ticket = TicketType {
+            code: "SOS".to_string(),
+            description: "ANYTIME SINGLE".to_string(),
+            ticket_type: 'S',
+            max_adults: 1,
+            max_children: 1,
+            validity_code: "D1".to_string(),
+            discount_category: "01".to_string(),
+        };
+        let prices = |nd| ticket_prices(&ticket, Some(12350), nd, &railcards, &discounts);
+
+        // The flow fare, with the railcard discount derived from it
+        assert_eq!(
+            prices(None),
+            [
+                ("adult".to_string(), 12350),
+                ("railcard_YNG".to_string(), 8150),
+                ("child".to_string(), 6175)
+            ]
+        );
+        // The non-derivable fare replaces it, and suppresses the railcard
+        assert_eq!(
+            prices(non_derivable.get(&key("6121", "9233"))),
+            [("adult".to_string(), 9900), ("child".to_string(), 4500)]
+        );
+        // A suppressed fare isn't sold at all
+        assert!(prices(non_derivable.get(&key("9233", "6121"))).is_empty());
+    }
+
+    #[test]
+    fn test_station_group_area_covers_its_members() {
+        let loc = [
+            "RM7010720311229997061210KGX",
+            "RG7010720311229990101202401012024LONDON TERMINALS",
+            "RM7010720311229997061240EUS",
+        ]
+        .join("\n");
+        let groups = parse_groups(loc.as_bytes(), today());
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].nlc, "1072");
+        assert_eq!(groups[0].members, ["KGX", "EUS"]);
+
+        let station = |tiploc: &str, crs: &str| ParsedStation {
+            tiploc: tiploc.to_string(),
+            name: String::new(),
+            crs: crs.to_string(),
+            lat: 0.0,
+            lon: 0.0,
+        };
+        let stations = [station("KNGX", "KGX"), station("EUSTON", "EUS")];
+        let boarding_stops = HashMap::from([(
+            "KNGX".to_string(),
+            vec!["KNGX_1".to_string(), "KNGX_2".to_string()],
+        )]);
+        let areas = build_fare_areas(&[], &groups, &stations, &boarding_stops, "");
+        let (name, stops) = &areas["1072"];
+        assert_eq!(name, "LONDON TERMINALS");
+        assert_eq!(
+            stops.iter().map(String::as_str).collect::<Vec<_>>(),
+            ["EUSTON", "KNGX_1", "KNGX_2"]
+        );
+    }
+
+    #[test]
+    fn test_fares_v2_stop_areas_take_the_id_prefix() {
+        let loc = [
+            "RL70612103112299901012024010120240006121LONDON KINGS X  KGX",
+            "RL70614403112299901012024010120240006144LONDON EUSTON   EUS",
+            "RM7010720311229997061210KGX",
+            "RG7010720311229990101202401012024LONDON TERMINALS",
+            "RM7010720311229997061240EUS",
+        ]
+        .join("\n");
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        zip.start_file("RJFAF001.LOC", zip::write::FileOptions::default())
+            .unwrap();
+        std::io::Write::write_all(&mut zip, loc.as_bytes()).unwrap();
+        let mut archive = ZipArchive::new(zip.finish().unwrap()).unwrap();
+
+        let station = |tiploc: &str, crs: &str| ParsedStation {
+            tiploc: tiploc.to_string(),
+            name: String::new(),
+            crs: crs.to_string(),
+            lat: 0.0,
+            lon: 0.0,
+        };
+        let stations = [station("KNGX", "KGX"), station("EUSTON", "EUS")];
+        // Platform ids come prefixed, as main writes them
+        let boarding_stops = HashMap::from([("KNGX".to_string(), vec!["gb:KNGX_1".to_string()])]);
+        let dir = tempfile::tempdir().unwrap();
+        write_fares_v2(
+            &mut archive,
+            &stations,
+            &boarding_stops,
+            "gb:",
+            None,
+            dir.path().to_str().unwrap(),
+            16,
+            today(),
+        )
+        .unwrap();
+
+        let mut stop_ids: Vec<String> = csv::Reader::from_path(dir.path().join("stop_areas.txt"))
+            .unwrap()
+            .records()
+            .map(|row| row.unwrap()[1].to_string())
+            .collect();
+        stop_ids.sort();
+        stop_ids.dedup();
+        assert_eq!(stop_ids, ["gb:EUSTON", "gb:KNGX_1"]);
+    }
+
+    #[test]
+    fn test_fare_route_networks() {
+        let rte = [
+            "RR00700311229990101202401012024VIA READING     ",
+            "RL0070031122999   3087RDGI",
+            "RR01000311229990101202401012024NOT VIA LONDON  ",
+            "RL0100031122999   1072ZLTE",
+            "RR00000311229990101202401012024ANY PERMITTED   ",
+        ]
+        .join("\n");
+        let fare_routes = parse_fare_routes(rte.as_bytes(), today());
+        assert_eq!(fare_routes["00700"].description, "VIA READING");
+        assert_eq!(fare_routes["00700"].included, ["RDG"]);
+        assert_eq!(fare_routes["01000"].excluded, ["ZLT"]);
+
+        let calls = |crs: &[&str]| crs.iter().map(|c| c.to_string()).collect::<BTreeSet<_>>();
+        let route_stations = BTreeMap::from([
+            ("GW_PAD_BRI".to_string(), calls(&["PAD", "RDG", "BRI"])),
+            ("GW_PAD_OXF".to_string(), calls(&["PAD", "ZLT", "OXF"])),
+        ]);
+        let networks = fare_route_networks(&fare_routes, &route_stations);
+        assert_eq!(networks["00000"], ["GW_PAD_BRI", "GW_PAD_OXF"]);
+        assert_eq!(networks["00700"], ["GW_PAD_BRI"]);
+        assert_eq!(networks["01000"], ["GW_PAD_BRI"]);
+    }
+}
@@ -0,0 +1,151 @@
+//! `formations.txt`, what each trip is expected to be formed of.
+//!
+//! A schedule gives the train's power type and timing load. For multiple
+//! units the timing load is the class, or for older diesel schedules a
+//! letter standing for a group of classes; for locomotive-hauled trains
+//! it's a weight and tells us nothing of the coaches. Classes only ever
+//! built as units of one length are given that length in cars, but the
+//! timetable doesn't say how many units make up a train, so it's the
+//! length of one unit.
+
+use serde::Serialize;
+
+/// Diesel multiple unit timing loads that stand for a group of classes
+const DMU_TIMING_LOADS: &[(&str, &str)] = &[
+    ("A", "14x"),
+    ("E", "158/168/170/175"),
+    ("N", "165/0"),
+    ("S", "150/153/155/156"),
+    ("T", "165/1/166"),
+    ("V", "220/221"),
+    ("X", "159"),
+];
+
+/// Classes built in a single unit length, and how many cars that is
+const CARS_PER_UNIT: &[(&str, u8)] = &[
+    ("153", 1),
+    ("155", 2),
+    ("156", 2),
+    ("159", 3),
+    ("180", 5),
+    ("185", 3),
+    ("220", 4),
+    ("319", 4),
+    ("321", 4),
+    ("323", 3),
+    ("345", 9),
+    ("357", 4),
+    ("387", 4),
+    ("395", 6),
+    ("397", 5),
+    ("717", 6),
+    ("745", 12),
+];
+
+/// The formation fields of a schedule's `BS` record
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Formation {
+    /// e.g. `EMU`, `DMU`, `HST`, or `D` for a diesel locomotive
+    pub power_type: Option<String>,
+    pub timing_load: Option<String>,
+    pub portion_id: Option<char>,
+}
+
+/// A row of `formations.txt`
+#[derive(Debug, Serialize)]
+pub struct FormationRow<'a> {
+    /// With any `--id-prefix`, as in trips.txt
+    pub trip_id: String,
+    pub power_type: Option<&'a str>,
+    pub timing_load: Option<&'a str>,
+    pub unit_class: Option<String>,
+    pub cars_per_unit: Option<u8>,
+    pub portion_id: Option<char>,
+}
+
+impl Formation {
+    /// The class of unit the train is timed for, if it's a multiple unit
+    pub fn unit_class(&self) -> Option<String> {
+        let power_type = self.power_type.as_deref()?;
+        if power_type == "HST" {
+            return Some("HST".to_string());
+        }
+        if !matches!(power_type, "DMU" | "DEM" | "EMU" | "EML") {
+            return None;
+        }
+        let load = self.timing_load.as_deref()?;
+        if let Some(class) = load
+            .get(..3)
+            .filter(|c| c.bytes().all(|b| b.is_ascii_digit()))
+        {
+            return Some(class.to_string());
+        }
+        DMU_TIMING_LOADS
+            .iter()
+            .find(|(code, _)| *code == load)
+            .map(|(_, classes)| classes.to_string())
+    }
+
+    pub fn row(&self, trip_id: String) -> FormationRow<'_> {
+        let unit_class = self.unit_class();
+        let cars_per_unit = unit_class.as_deref().and_then(|class| {
+            CARS_PER_UNIT
+                .iter()
+                .find(|(known, _)| *known == class)
+                .map(|&(_, cars)| cars)
+        });
+        FormationRow {
+            trip_id,
+            power_type: self.power_type.as_deref(),
+            timing_load: self.timing_load.as_deref(),
+            unit_class,
+            cars_per_unit,
+            portion_id: self.portion_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{Fixture, fixture_text};
+
+    #[test]
+    fn test_unit_class() {
+        let formation = |power_type: &str, timing_load: &str| Formation {
+            power_type: Some(power_type.to_string()),
+            timing_load: Some(timing_load.to_string()),
+            portion_id: None,
+        };
+        let class_345 = formation("EMU", "345");
+        let row = class_345.row("T1".to_string());
+        assert_eq!(row.unit_class.as_deref(), Some("345"));
+        assert_eq!(row.cars_per_unit, Some(9));
+        // Built as both nine and eleven cars
+        assert_eq!(
+            formation("EMU", "390").row(String::new()).cars_per_unit,
+            None
+        );
+        assert_eq!(
+            formation("DMU", "E").unit_class().as_deref(),
+            Some("158/168/170/175")
+        );
+        assert_eq!(formation("HST", "").unit_class().as_deref(), Some("HST"));
+        // A locomotive's timing load is a weight
+        assert_eq!(formation("D", "0450").unit_class(), None);
+    }
+
+    #[test]
+    fn test_formation_from_fixture() {
+        let mca = fixture_text("sample.MCA");
+        // Portion 2 of a class 345
+        let mca = mca.replacen("123456789 IEMU   100", "1234567892EMU345 100", 1);
+        let (trips, _, _) = Fixture::default().convert_text("sample.MSN", "sample.MCA", &mca);
+
+        let row = trips[0].formation.row(trips[0].trip.trip_id.clone());
+        assert_eq!(row.power_type, Some("EMU"));
+        assert_eq!(row.unit_class.as_deref(), Some("345"));
+        assert_eq!(row.cars_per_unit, Some(9));
+        assert_eq!(row.portion_id, Some('2'));
+    }
+}
@@ -0,0 +1,274 @@
+//! Frequency-based trips for clockface services.
+//!
+//! Metro-style operators run the same stopping pattern every few minutes
+//! all day, each departure a schedule of its own. With `--use-frequencies`
+//! trips are held until the timetable has been read, and each run of
+//! departures at a steady interval becomes one trip with a
+//! `frequencies.txt` row, `exact_times=1`. Trips referred to by id from a
+//! block or a transfer are left as they are.
+
+use crate::formations::Formation;
+use crate::intern::Interner;
+use crate::{ConvertedTrip, Transfer, gtfs_seconds, gtfs_time};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+/// Fewest departures worth folding into one trip
+const MIN_FREQUENCY_TRIPS: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Frequency {
+    pub trip_id: String,
+    pub start_time: String,
+    pub end_time: String,
+    pub headway_secs: u32,
+    pub exact_times: u8,
+}
+
+/// A call of a pattern, its times in seconds after the first departure
+#[derive(PartialEq, Eq, Hash)]
+struct Call {
+    stop_id: u32,
+    stop_headsign: Option<u32>,
+    pickup_type: Option<u8>,
+    drop_off_type: Option<u8>,
+    arrival: Option<i64>,
+    departure: Option<i64>,
+}
+
+/// Everything about a trip but its id, headcode and when it runs, with
+/// strings interned
+#[derive(PartialEq, Eq, Hash)]
+struct Pattern {
+    route_id: u32,
+    service_id: u32,
+    trip_headsign: u32,
+    direction_id: Option<u8>,
+    shape_id: Option<u32>,
+    wheelchair_accessible: Option<u8>,
+    trip_category: Option<u32>,
+    train_service_code: Option<u32>,
+    vehicle_type: &'static str,
+    first_class: u8,
+    sleepers: Option<&'static str>,
+    reservations: Option<&'static str>,
+    catering: Option<u32>,
+    formation: Formation,
+    calls: Vec<Call>,
+}
+
+impl Pattern {
+    /// A trip's pattern and the time it leaves its first stop
+    fn of(converted: &ConvertedTrip, strings: &mut Interner) -> Option<(u32, Pattern)> {
+        let start = gtfs_seconds(&converted.stop_times.first()?.departure_time)?;
+        let offset = |time: &str| gtfs_seconds(time).map(|secs| i64::from(secs) - i64::from(start));
+        let calls = converted
+            .stop_times
+            .iter()
+            .map(|stop| Call {
+                stop_id: strings.intern(&stop.stop_id),
+                stop_headsign: stop.stop_headsign.as_deref().map(|s| strings.intern(s)),
+                pickup_type: stop.pickup_type,
+                drop_off_type: stop.drop_off_type,
+                arrival: offset(&stop.arrival_time),
+                departure: offset(&stop.departure_time),
+            })
+            .collect();
+        let trip = &converted.trip;
+        let pattern = Pattern {
+            route_id: strings.intern(&trip.route_id),
+            service_id: strings.intern(&trip.service_id),
+            trip_headsign: strings.intern(&trip.trip_headsign),
+            direction_id: trip.direction_id,
+            shape_id: trip.shape_id.as_deref().map(|s| strings.intern(s)),
+            wheelchair_accessible: trip.wheelchair_accessible,
+            trip_category: trip.trip_category.as_deref().map(|s| strings.intern(s)),
+            train_service_code: trip
+                .train_service_code
+                .as_deref()
+                .map(|s| strings.intern(s)),
+            vehicle_type: trip.vehicle_type,
+            first_class: trip.first_class,
+            sleepers: trip.sleepers,
+            reservations: trip.reservations,
+            catering: trip.catering.as_deref().map(|s| strings.intern(s)),
+            formation: converted.formation.clone(),
+            calls,
+        };
+        Some((start, pattern))
+    }
+}
+
+/// The trips of a timetable, held to be folded into frequencies
+#[derive(Default)]
+pub struct FrequencyTrips {
+    trips: Vec<ConvertedTrip>,
+}
+
+/// Trips once folded
+pub struct Folded {
+    /// Trips to write, in the order they were read, each with the
+    /// frequency it runs at if it stands for others
+    pub trips: Vec<(ConvertedTrip, Option<Frequency>)>,
+    /// Trips folded into another's frequency, with that trip's id
+    pub folded: Vec<(ConvertedTrip, String)>,
+}
+
+impl FrequencyTrips {
+    pub fn push(&mut self, trip: ConvertedTrip) {
+        self.trips.push(trip);
+    }
+
+    /// Fold runs of departures into frequencies. Transfers refer to trips
+    /// by id, so trips they name are kept.
+    pub fn finish(self, transfers: &[Transfer]) -> Folded {
+        let transferring: HashSet<&str> = transfers
+            .iter()
+            .flat_map(|t| [t.from_trip_id.as_deref(), t.to_trip_id.as_deref()])
+            .flatten()
+            .collect();
+        // First departure and index of each trip, by pattern
+        let mut strings = Interner::default();
+        let mut patterns: HashMap<Pattern, Vec<(u32, usize)>> = HashMap::new();
+        for (i, converted) in self.trips.iter().enumerate() {
+            if converted.trip.block_id.is_some()
+                || transferring.contains(converted.trip.trip_id.as_str())
+            {
+                continue;
+            }
+            if let Some((start, pattern)) = Pattern::of(converted, &mut strings) {
+                patterns.entry(pattern).or_default().push((start, i));
+            }
+        }
+
+        let mut frequencies: HashMap<usize, Frequency> = HashMap::new();
+        let mut folded_into: HashMap<usize, usize> = HashMap::new();
+        for mut departures in patterns.into_values() {
+            departures.sort_unstable();
+            let starts: Vec<u32> = departures.iter().map(|&(start, _)| start).collect();
+            for run in steady_runs(&starts) {
+                let (first, head) = departures[run.start];
+                let headway = starts[run.start + 1] - first;
+                frequencies.insert(
+                    head,
+                    Frequency {
+                        trip_id: self.trips[head].trip.trip_id.clone(),
+                        start_time: gtfs_time(first),
+                        end_time: gtfs_time(starts[run.end - 1] + headway),
+                        headway_secs: headway,
+                        exact_times: 1,
+                    },
+                );
+                for &(_, i) in &departures[run.start + 1..run.end] {
+                    folded_into.insert(i, head);
+                }
+            }
+        }
+
+        let head_ids: HashMap<usize, String> = frequencies
+            .iter()
+            .map(|(&i, frequency)| (i, frequency.trip_id.clone()))
+            .collect();
+        let mut folded = Folded {
+            trips: Vec::new(),
+            folded: Vec::new(),
+        };
+        for (i, mut converted) in self.trips.into_iter().enumerate() {
+            if let Some(head) = folded_into.get(&i) {
+                folded.folded.push((converted, head_ids[head].clone()));
+                continue;
+            }
+            let frequency = frequencies.remove(&i);
+            // The headcode is only that of the first departure
+            if frequency.is_some() {
+                converted.trip.trip_short_name.clear();
+            }
+            folded.trips.push((converted, frequency));
+        }
+        folded
+    }
+}
+
+/// Runs of at least [`MIN_FREQUENCY_TRIPS`] sorted departures at a steady
+/// interval, taken greedily from the first
+fn steady_runs(departures: &[u32]) -> Vec<Range<usize>> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    while start + 1 < departures.len() {
+        let headway = departures[start + 1] - departures[start];
+        let mut end = start + 2;
+        while end < departures.len() && departures[end] - departures[end - 1] == headway {
+            end += 1;
+        }
+        if headway > 0 && end - start >= MIN_FREQUENCY_TRIPS {
+            runs.push(start..end);
+            start = end;
+        } else {
+            start += 1;
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::Fixture;
+
+    #[test]
+    fn test_steady_runs() {
+        let mins = |m: &[u32]| -> Vec<u32> { m.iter().map(|m| m * 60).collect() };
+        // Every ten minutes, then a gap, then every fifteen
+        assert_eq!(
+            steady_runs(&mins(&[0, 10, 20, 30, 45, 60, 75])),
+            [0..4, 4..7]
+        );
+        assert_eq!(steady_runs(&mins(&[0, 10, 25, 30])), []);
+        assert_eq!(steady_runs(&mins(&[0, 0, 0])), []);
+        // A stray early departure doesn't hold up the run
+        let runs = steady_runs(&mins(&[0, 7, 9, 11, 13]));
+        assert_eq!((runs.len(), runs.first()), (1, Some(&(1..5))));
+    }
+
+    #[test]
+    fn test_departures_every_half_hour_are_folded() {
+        let (trips, _, _) = Fixture::default().convert("sample.MSN", "sample.MCA");
+        let departure = |trip: &ConvertedTrip, mins: u32| {
+            let mut trip = trip.clone();
+            trip.trip.trip_id = format!("C1000{}", mins);
+            trip.trip.block_id = None;
+            for stop in &mut trip.stop_times {
+                for time in [&mut stop.arrival_time, &mut stop.departure_time] {
+                    *time = gtfs_time(gtfs_seconds(time).unwrap() + mins * 60);
+                }
+            }
+            trip
+        };
+        let mut held = FrequencyTrips::default();
+        for mins in [0, 30, 60] {
+            held.push(departure(&trips[0], mins));
+        }
+        held.push(trips[1].clone());
+
+        let folded = held.finish(&[]);
+        assert_eq!(folded.trips.len(), 2);
+        assert_eq!(
+            folded.trips[0].1,
+            Some(Frequency {
+                trip_id: "C10000".to_string(),
+                start_time: "09:00:00".to_string(),
+                end_time: "10:30:00".to_string(),
+                headway_secs: 1800,
+                exact_times: 1,
+            })
+        );
+        assert_eq!(folded.trips[1].1, None);
+        let ids: Vec<(&str, &str)> = folded
+            .folded
+            .iter()
+            .map(|(trip, head)| (trip.trip.trip_id.as_str(), head.as_str()))
+            .collect();
+        assert_eq!(ids, [("C100030", "C10000"), ("C100060", "C10000")]);
+    }
+}
@@ -0,0 +1,95 @@
+//! Trip headsigns.
+//!
+//! By default a trip is signed with its destination alone. Trains between
+//! the same places can take quite different routes, and overlays often cut
+//! a train short, so either can be spelled out in the headsign:
+//! `Glasgow Central via York`, `Peterborough (short working)`.
+
+/// What goes in a headsign besides the destination
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeadsignPolicy {
+    /// Name the call furthest off the direct line, if it's far enough off
+    /// to tell the route apart
+    pub via: bool,
+    /// Mark STP schedules that stop short of their permanent schedule
+    pub short_workings: bool,
+}
+
+/// How far off the direct line a call must be to be worth naming, as a
+/// share of the distance between the trip's ends
+const VIA_MIN_DEVIATION: f64 = 0.1;
+
+/// The intermediate call that says most about the way a trip goes: the one
+/// furthest from the straight line between its ends, if that's far enough
+/// to matter
+pub fn via_point<'a>(calls: &[(&'a str, (f64, f64))]) -> Option<&'a str> {
+    let (&(_, origin), &(_, dest)) = (calls.first()?, calls.last()?);
+    // Flat enough at these distances, with longitude scaled to match
+    let scale = origin.0.to_radians().cos();
+    let point = |(lat, lon): (f64, f64)| (lon * scale, lat);
+    let (ax, ay) = point(origin);
+    let (bx, by) = point(dest);
+    let length = (bx - ax).hypot(by - ay);
+    if length == 0.0 {
+        return None;
+    }
+    let (name, deviation) = calls[1..calls.len() - 1]
+        .iter()
+        .map(|&(name, coords)| {
+            let (px, py) = point(coords);
+            // Distance from the line through the ends
+            let deviation = ((bx - ax) * (ay - py) - (ax - px) * (by - ay)).abs() / length;
+            (name, deviation)
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))?;
+    (deviation >= VIA_MIN_DEVIATION * length).then_some(name)
+}
+
+pub fn headsign(destination: &str, via: Option<&str>, short_working: bool) -> String {
+    let mut headsign = destination.to_string();
+    if let Some(via) = via.filter(|via| *via != destination) {
+        headsign.push_str(" via ");
+        headsign.push_str(via);
+    }
+    if short_working {
+        headsign.push_str(" (short working)");
+    }
+    headsign
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_via_point_names_the_way_round() {
+        let euston = ("London Euston", (51.5282, -0.1337));
+        let preston = ("Preston", (53.7565, -2.7077));
+        let carlisle = ("Carlisle", (54.8906, -2.9337));
+        let kings_cross = ("London Kings Cross", (51.5320, -0.1233));
+        let york = ("York", (53.9580, -1.0930));
+        let edinburgh = ("Edinburgh", (55.9521, -3.1890));
+        let glasgow = ("Glasgow Central", (55.8597, -4.2578));
+
+        // The West Coast runs close to the direct line; the East Coast doesn't
+        assert_eq!(via_point(&[euston, preston, carlisle, glasgow]), None);
+        assert_eq!(
+            via_point(&[kings_cross, york, edinburgh, glasgow]),
+            Some("York")
+        );
+        assert_eq!(via_point(&[euston, glasgow]), None);
+    }
+
+    #[test]
+    fn test_headsign() {
+        assert_eq!(headsign("Crewe", None, false), "Crewe");
+        assert_eq!(
+            headsign("Glasgow Central", Some("York"), false),
+            "Glasgow Central via York"
+        );
+        assert_eq!(
+            headsign("Peterborough", None, true),
+            "Peterborough (short working)"
+        );
+    }
+}
@@ -0,0 +1,103 @@
+//! Interchange status and minimum change times from the MSN's `A` records,
+//! written as station-level `transfers.txt` rows.
+//!
+//! The MSN grades each station `0` (not an interchange) to `3` (a large
+//! one), with `9` for the subsidiary TIPLOCs of a station. With
+//! `--interchange-transfers`, stations graded `1` to `3` are marked as
+//! recommended transfer points (`transfer_type` 0) from the station to
+//! itself, so journey planners prefer changing there. With
+//! `--min-transfer-times`, every station's minimum change time is written
+//! as a timed transfer (`transfer_type` 2) instead, which planners need to
+//! avoid offering impossible connections at large stations.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+
+/// A station's entry in the MSN interchange grading
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interchange {
+    /// `0` to `3`, or `9` for a subsidiary TIPLOC
+    pub status: u8,
+    /// Minutes needed to change trains at the station
+    pub min_change_mins: Option<u32>,
+}
+
+impl Interchange {
+    /// Whether the station is somewhere passengers are expected to change
+    pub fn is_recognised(&self) -> bool {
+        (1..=3).contains(&self.status)
+    }
+
+    /// The station's `transfer_type` and `min_transfer_time`, if the options
+    /// given call for a row: a timed transfer where its change time is wanted
+    /// and known, otherwise a recommended one at recognised interchanges
+    pub fn transfer(&self, recommend: bool, timed: bool) -> Option<(u8, Option<u32>)> {
+        match self.min_change_mins {
+            Some(mins) if timed => Some((2, Some(mins * 60))),
+            _ if recommend && self.is_recognised() => Some((0, None)),
+            _ => None,
+        }
+    }
+}
+
+/// Interchange status and change time by TIPLOC
+pub fn parse_msn_interchanges<R: Read>(reader: R) -> HashMap<String, Interchange> {
+    let mut interchanges = HashMap::new();
+    for line in BufReader::new(reader).lines().map_while(Result::ok) {
+        // The file header is an A record too
+        if !line.starts_with('A') || line.get(30..40) == Some("FILE-SPEC=") {
+            continue;
+        }
+        // RSPS5046: interchange status 36, TIPLOC 37-43, change time 64-65
+        let status = line.get(35..36).and_then(|digit| digit.parse::<u8>().ok());
+        let tiploc = line.get(36..43).unwrap_or("").trim();
+        let min_change_mins = line
+            .get(63..65)
+            .and_then(|mins| mins.trim().parse::<u32>().ok());
+        if let Some(status) = status
+            && !tiploc.is_empty()
+        {
+            interchanges.insert(
+                tiploc.to_string(),
+                Interchange {
+                    status,
+                    min_change_mins,
+                },
+            );
+        }
+    }
+    interchanges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_msn_interchanges() {
+        let msn = "\
+A                             FILE-SPEC=05 1.00 12/01/24 18.10.25   202
+A    LONDON KINGS CROSS            3KNGX   KGX   KGX15303 6183215
+A    FINSBURY PARK                 2FNPK   FPK   FPK15312 6186915
+A    HORNSEY                       0HRNSY  HRN   HRN15307 6189803
+A    KINGS CROSS THAMESLINK        9KNGXTL ZKX   KGX15304 6183015
+A    UNTIMED HALT                  0UNTIMD ZZZ   ZZZ15000 60000
+";
+        let interchanges = parse_msn_interchanges(msn.as_bytes());
+        let recognised = |tiploc: &str| interchanges[tiploc].is_recognised();
+        assert!(recognised("KNGX") && recognised("FNPK"));
+        assert!(!recognised("HRNSY") && !recognised("KNGXTL"));
+        assert_eq!(interchanges.len(), 5);
+
+        let kngx = interchanges["KNGX"];
+        assert_eq!(kngx.min_change_mins, Some(15));
+        assert_eq!(kngx.transfer(true, false), Some((0, None)));
+        assert_eq!(kngx.transfer(true, true), Some((2, Some(900))));
+        assert_eq!(interchanges["HRNSY"].transfer(true, false), None);
+        assert_eq!(
+            interchanges["HRNSY"].transfer(false, true),
+            Some((2, Some(180)))
+        );
+        assert_eq!(interchanges["UNTIMD"].transfer(false, true), None);
+    }
+}
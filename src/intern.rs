@@ -0,0 +1,39 @@
+//! String interning for keys built per trip.
+//!
+//! Grouping the trips of a national extract hashes the same stop ids and
+//! names over and over. Interned, each distinct string is stored once and
+//! a key holds a `u32` for it, so building and hashing keys doesn't
+//! allocate.
+
+use std::collections::HashMap;
+
+#[derive(Debug, Default)]
+pub struct Interner {
+    ids: HashMap<Box<str>, u32>,
+}
+
+impl Interner {
+    /// The id of a string, the same for equal strings
+    pub fn intern(&mut self, s: &str) -> u32 {
+        if let Some(&id) = self.ids.get(s) {
+            return id;
+        }
+        let id = u32::try_from(self.ids.len()).expect("fewer than 2^32 distinct strings");
+        self.ids.insert(s.into(), id);
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_equal_strings_share_an_id() {
+        let mut interner = Interner::default();
+        let kngx = interner.intern("KNGX");
+        let york = interner.intern("YORK");
+        assert_ne!(kngx, york);
+        assert_eq!(interner.intern(&String::from("KNGX")), kngx);
+    }
+}
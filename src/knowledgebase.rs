@@ -0,0 +1,328 @@
+//! NRE Knowledgebase feeds.
+//!
+//! The stations feed is an XML document with one `<Station>` per CRS code,
+//! carrying accessibility information that the CIF does not have: how much
+//! of the station is step-free, and notes on how, which are read for lifts. The
+//! incidents feed lists current and planned disruption as `<PtIncident>`s.
+
+use anyhow::Result;
+use chrono::DateTime;
+use quick_xml::Reader;
+use quick_xml::events::Event;
+use std::collections::HashMap;
+use std::io::BufRead;
+
+/// Operators whose whole passenger fleet meets the PRM-TSI accessibility
+/// standard that became mandatory for mainline trains in 2020
+const STEP_FREE_FLEET_TOCS: &[&str] = &[
+    "AW", "CC", "CH", "CS", "EM", "ES", "GC", "GN", "GR", "GW", "GX", "HT", "HX", "IL", "LD", "LE",
+    "LM", "LO", "ME", "NT", "SE", "SN", "SR", "SW", "TL", "TP", "VT", "XC", "XR",
+];
+
+/// How platforms are reached from the street, as far as the step-free
+/// access notes say
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessRoute {
+    /// Step-free without lifts: level or ramped
+    Level,
+    Lift,
+    Stairs,
+}
+
+/// A station's accessibility, from its step-free access coverage and notes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StationAccess {
+    /// GTFS `wheelchair_boarding`
+    pub wheelchair_boarding: u8,
+    /// `None` where only part of the station is step-free, or it isn't said
+    pub route: Option<AccessRoute>,
+}
+
+impl StationAccess {
+    fn of(coverage: &str, note: &str) -> Self {
+        let note = note.to_lowercase();
+        let route = match coverage {
+            "wholeStation" if note.contains("lift") => Some(AccessRoute::Lift),
+            "wholeStation" => Some(AccessRoute::Level),
+            "noPartOfStation" => Some(AccessRoute::Stairs),
+            _ => None,
+        };
+        StationAccess {
+            wheelchair_boarding: wheelchair_boarding(coverage),
+            route,
+        }
+    }
+}
+
+/// Accessibility of each station, by CRS, from its step-free access
+/// coverage and notes
+pub fn parse_station_access<R: BufRead>(reader: R) -> Result<HashMap<String, StationAccess>> {
+    let mut xml = Reader::from_reader(reader);
+    xml.config_mut().trim_text(true);
+
+    let mut map = HashMap::new();
+    let mut path: Vec<String> = Vec::new();
+    let mut crs = String::new();
+    let mut coverage = String::new();
+    let mut note = String::new();
+    let mut buf = Vec::new();
+
+    loop {
+        match xml.read_event_into(&mut buf)? {
+            Event::Start(e) => {
+                let name = String::from_utf8_lossy(e.local_name().as_ref()).into_owned();
+                if name == "Station" {
+                    crs.clear();
+                    coverage.clear();
+                    note.clear();
+                }
+                path.push(name);
+            }
+            Event::End(_) => {
+                let closed = path.pop();
+                if closed.as_deref() == Some("Station") && !crs.is_empty() {
+                    map.insert(crs.clone(), StationAccess::of(&coverage, &note));
+                }
+            }
+            Event::Text(t) => {
+                let text = t.unescape()?;
+                if in_element(&path, "Station", "CrsCode") {
+                    crs = text.trim().to_string();
+                } else if in_element(&path, "StepFreeAccess", "Coverage") {
+                    coverage = text.trim().to_string();
+                } else if path.iter().any(|name| name == "StepFreeAccess")
+                    && path.last().is_some_and(|name| name == "Note")
+                {
+                    note.push_str(&strip_html(&text));
+                    note.push('\n');
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(map)
+}
+
+/// A disruption from the incidents feed
+#[derive(Debug, Default, PartialEq)]
+pub struct Incident {
+    pub id: String,
+    pub summary: String,
+    /// Plain text, with the feed's HTML markup removed
+    pub description: String,
+    pub url: Option<String>,
+    /// POSIX times the incident applies between
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+    pub planned: bool,
+    pub cleared: bool,
+    /// ATOC codes of the affected operators
+    pub operators: Vec<String>,
+    /// Free text naming the lines or stations affected
+    pub routes_affected: String,
+}
+
+/// Current incidents, leaving out those marked cleared
+pub fn parse_incidents<R: BufRead>(reader: R) -> Result<Vec<Incident>> {
+    let mut xml = Reader::from_reader(reader);
+    xml.config_mut().trim_text(true);
+
+    let mut incidents = Vec::new();
+    let mut path: Vec<String> = Vec::new();
+    let mut incident = Incident::default();
+    let mut buf = Vec::new();
+
+    loop {
+        let text = match xml.read_event_into(&mut buf)? {
+            Event::Start(e) => {
+                let name = String::from_utf8_lossy(e.local_name().as_ref()).into_owned();
+                if name == "PtIncident" {
+                    incident = Incident::default();
+                }
+                path.push(name);
+                None
+            }
+            Event::End(_) => {
+                if path.pop().as_deref() == Some("PtIncident") && !incident.cleared {
+                    incidents.push(std::mem::take(&mut incident));
+                }
+                None
+            }
+            Event::Text(t) => Some(t.unescape()?.into_owned()),
+            Event::CData(t) => Some(String::from_utf8_lossy(&t).into_owned()),
+            Event::Eof => break,
+            _ => None,
+        };
+        if let Some(text) = text {
+            let text = text.trim();
+            let time = || {
+                DateTime::parse_from_rfc3339(text)
+                    .ok()
+                    .map(|t| t.timestamp())
+            };
+            if in_element(&path, "PtIncident", "IncidentNumber") {
+                incident.id = text.to_string();
+            } else if in_element(&path, "PtIncident", "Summary") {
+                incident.summary = strip_html(text);
+            } else if in_element(&path, "PtIncident", "Description") {
+                incident.description = strip_html(text);
+            } else if in_element(&path, "PtIncident", "Planned") {
+                incident.planned = text == "true";
+            } else if in_element(&path, "PtIncident", "ClearedIncident") {
+                incident.cleared = text == "true";
+            } else if in_element(&path, "ValidityPeriod", "StartTime") {
+                incident.start = incident.start.or_else(time);
+            } else if in_element(&path, "ValidityPeriod", "EndTime") {
+                incident.end = time().max(incident.end);
+            } else if in_element(&path, "InfoLink", "Uri") && incident.url.is_none() {
+                incident.url = Some(text.to_string());
+            } else if in_element(&path, "AffectedOperator", "OperatorRef") {
+                incident.operators.push(text.to_string());
+            } else if in_element(&path, "Affects", "RoutesAffected") {
+                incident.routes_affected = strip_html(text);
+            }
+        }
+        buf.clear();
+    }
+    Ok(incidents)
+}
+
+/// The text of an HTML fragment, one line per paragraph or break
+fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut tag = None::<String>;
+    for c in html.chars() {
+        match (&mut tag, c) {
+            (None, '<') => tag = Some(String::new()),
+            (None, c) => text.push(c),
+            (Some(name), '>') => {
+                let name = name.trim_start_matches('/').to_ascii_lowercase();
+                if name.starts_with("br") || name == "p" {
+                    text.push('\n');
+                }
+                tag = None;
+            }
+            (Some(name), c) => name.push(c),
+        }
+    }
+    let lines: Vec<&str> = text
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .collect();
+    lines
+        .join("\n")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+/// Whether the innermost open element is `child` directly inside `parent`
+fn in_element(path: &[String], parent: &str, child: &str) -> bool {
+    matches!(path, [.., p, c] if p == parent && c == child)
+}
+
+/// Map a step-free access coverage onto GTFS `wheelchair_boarding`
+fn wheelchair_boarding(coverage: &str) -> u8 {
+    match coverage {
+        "wholeStation" | "partialStation" => 1,
+        "noPartOfStation" => 2,
+        _ => 0,
+    }
+}
+
+/// Default GTFS `wheelchair_accessible` for an operator's trips
+pub fn default_wheelchair_accessible(atoc_code: &str) -> u8 {
+    if STEP_FREE_FLEET_TOCS.contains(&atoc_code) {
+        1
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_step_free_coverage() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<StationList xmlns="http://nationalrail.co.uk/xml/station">
+  <Station>
+    <Name>London Kings Cross</Name>
+    <CrsCode>KGX</CrsCode>
+    <Accessibility>
+      <StepFreeAccess>
+        <Coverage>wholeStation</Coverage>
+        <Annotation><Note>&lt;p&gt;Step-free access to all platforms via lifts.&lt;/p&gt;</Note></Annotation>
+      </StepFreeAccess>
+    </Accessibility>
+  </Station>
+  <Station>
+    <CrsCode>YRK</CrsCode>
+    <Accessibility>
+      <StepFreeAccess><Coverage>partialStation</Coverage></StepFreeAccess>
+    </Accessibility>
+  </Station>
+  <Station>
+    <CrsCode>BYN</CrsCode>
+    <Accessibility>
+      <StepFreeAccess><Coverage>noPartOfStation</Coverage></StepFreeAccess>
+    </Accessibility>
+  </Station>
+  <Station>
+    <CrsCode>XYZ</CrsCode>
+  </Station>
+</StationList>"#;
+
+        let map = parse_station_access(xml.as_bytes()).unwrap();
+        let access = |crs: &str| (map[crs].wheelchair_boarding, map[crs].route);
+        assert_eq!(access("KGX"), (1, Some(AccessRoute::Lift)));
+        assert_eq!(access("YRK"), (1, None));
+        assert_eq!(access("BYN"), (2, Some(AccessRoute::Stairs)));
+        assert_eq!(access("XYZ"), (0, None));
+    }
+
+    #[test]
+    fn test_parse_incidents() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Incidents xmlns="http://nationalrail.co.uk/xml/incident" xmlns:com="http://nationalrail.co.uk/xml/common">
+  <PtIncident>
+    <com:CreationTime>2024-01-01T06:00:00.000Z</com:CreationTime>
+    <ValidityPeriod>
+      <com:StartTime>2024-01-01T06:00:00.000Z</com:StartTime>
+      <com:EndTime>2024-01-01T23:59:00.000Z</com:EndTime>
+    </ValidityPeriod>
+    <IncidentNumber>ABC123</IncidentNumber>
+    <Planned>false</Planned>
+    <Summary>Disruption between Peterborough and York</Summary>
+    <Description>&lt;p&gt;A fault with the signalling&lt;br/&gt;means &amp;amp; delays.&lt;/p&gt;</Description>
+    <InfoLinks><InfoLink><Uri>https://www.nationalrail.co.uk/incidents/abc123</Uri></InfoLink></InfoLinks>
+    <Affects>
+      <Operators>
+        <AffectedOperator><OperatorRef>GR</OperatorRef><OperatorName>LNER</OperatorName></AffectedOperator>
+      </Operators>
+      <RoutesAffected>&lt;p&gt;between Peterborough and York&lt;/p&gt;</RoutesAffected>
+    </Affects>
+    <ClearedIncident>false</ClearedIncident>
+  </PtIncident>
+  <PtIncident>
+    <IncidentNumber>DEF456</IncidentNumber>
+    <ClearedIncident>true</ClearedIncident>
+  </PtIncident>
+</Incidents>"#;
+
+        let incidents = parse_incidents(xml.as_bytes()).unwrap();
+        assert_eq!(incidents.len(), 1);
+        let incident = &incidents[0];
+        assert_eq!(incident.id, "ABC123");
+        assert_eq!(
+            incident.description,
+            "A fault with the signalling\nmeans & delays."
+        );
+        assert_eq!(incident.start, Some(1_704_088_800));
+        assert_eq!(incident.operators, ["GR"]);
+        assert_eq!(incident.routes_affected, "between Peterborough and York");
+    }
+}
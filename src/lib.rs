@@ -0,0 +1,6 @@
+//! Conversion of National Rail open data to GTFS.
+//!
+//! The binary does the conversion; the library exposes the CIF timetable
+//! parser it is built on, for use as a general UK rail CIF reader.
+
+pub mod cif;
@@ -0,0 +1,191 @@
+//! User-supplied rules naming the lines trips run on.
+//!
+//! The built-in classification only knows a handful of metro-style
+//! operators. A TOML file can name the lines of any other, each rule
+//! matching on the operator, the train's headcode and where it calls:
+//!
+//! ```toml
+//! [[rule]]
+//! toc = "SR"
+//! train_id_prefix = "2"
+//! calls_at_any = ["GLGQLL", "PTK"]
+//! route_id = "SR-ARGYLE"
+//! name = "Argyle Line"
+//! color = "F0A500"
+//! text_color = "000000"
+//! route_type = 109
+//! ```
+//!
+//! Calling points may be TIPLOCs or CRS codes. Every condition a rule sets
+//! must hold, and the first matching rule wins over the built-in lines.
+
+use crate::operators::{ROUTE_TYPE_RAIL, RouteClass};
+use crate::{ParsedStation, StopTime};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LineRule {
+    /// ATOC code of the operator
+    pub toc: Option<String>,
+    /// Start of the headcode, e.g. `2` or `2A`
+    pub train_id_prefix: Option<String>,
+    /// The trip calls at one of these
+    #[serde(default)]
+    pub calls_at_any: Vec<String>,
+    /// The trip calls at every one of these
+    #[serde(default)]
+    pub calls_at_all: Vec<String>,
+    pub route_id: String,
+    pub name: String,
+    /// Line colour, six hex digits without the `#`
+    pub color: Option<String>,
+    pub text_color: Option<String>,
+    /// GTFS route type, rail (2) unless set
+    pub route_type: Option<u8>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LineRules {
+    #[serde(default, rename = "rule")]
+    rules: Vec<LineRule>,
+}
+
+impl LineRules {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("Invalid line rules file {}", path.display()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// The class of the first rule the trip matches
+    pub fn classify(
+        &self,
+        atoc_code: &str,
+        train_identity: &str,
+        stops: &[StopTime],
+        tiploc_map: &HashMap<String, ParsedStation>,
+    ) -> Option<RouteClass> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        let calls_at = |location: &String| {
+            stops.iter().any(|stop| {
+                stop.tiploc == *location
+                    || tiploc_map
+                        .get(&stop.tiploc)
+                        .is_some_and(|station| station.crs == *location)
+            })
+        };
+        let rule = self.rules.iter().find(|rule| {
+            rule.toc.as_ref().is_none_or(|toc| toc == atoc_code)
+                && rule
+                    .train_id_prefix
+                    .as_ref()
+                    .is_none_or(|prefix| train_identity.starts_with(prefix.as_str()))
+                && (rule.calls_at_any.is_empty() || rule.calls_at_any.iter().any(calls_at))
+                && rule.calls_at_all.iter().all(calls_at)
+        })?;
+        Some(RouteClass {
+            route_id: rule.route_id.clone(),
+            long_name: rule.name.clone(),
+            route_type: rule.route_type.unwrap_or(ROUTE_TYPE_RAIL),
+            color: rule.color.clone(),
+            text_color: rule.text_color.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let rules: LineRules = toml::from_str(
+            r#"
+            [[rule]]
+            toc = "SR"
+            train_id_prefix = "2"
+            calls_at_any = ["GLGQLL", "PTK"]
+            route_id = "SR-ARGYLE"
+            name = "Argyle Line"
+            color = "F0A500"
+            route_type = 109
+
+            [[rule]]
+            toc = "SR"
+            calls_at_all = ["GLGC", "AYR"]
+            route_id = "SR-AYRSHIRE"
+            name = "Ayrshire Coast Line"
+            "#,
+        )
+        .unwrap();
+        let mut tiploc_map = HashMap::new();
+        tiploc_map.insert(
+            "PRTKLOW".to_string(),
+            ParsedStation {
+                tiploc: "PRTKLOW".to_string(),
+                name: "Partick".to_string(),
+                crs: "PTK".to_string(),
+                lat: 0.0,
+                lon: 0.0,
+            },
+        );
+        let stops = |tiplocs: &[&str]| -> Vec<StopTime> {
+            tiplocs
+                .iter()
+                .enumerate()
+                .map(|(i, tiploc)| StopTime {
+                    trip_id: "t1".to_string(),
+                    arrival_time: "00:00".to_string(),
+                    departure_time: "00:00".to_string(),
+                    stop_id: tiploc.to_string(),
+                    stop_sequence: i as u32 + 1,
+                    stop_headsign: None,
+                    pickup_type: None,
+                    drop_off_type: None,
+                    shape_dist_traveled: None,
+                    tiploc: tiploc.to_string(),
+                    platform: None,
+                })
+                .collect()
+        };
+
+        let argyle = rules
+            .classify("SR", "2Y45", &stops(&["DALMUIR", "PRTKLOW"]), &tiploc_map)
+            .unwrap();
+        assert_eq!(argyle.route_id, "SR-ARGYLE");
+        assert_eq!(argyle.route_type, 109);
+        assert_eq!(argyle.color.as_deref(), Some("F0A500"));
+
+        let ayr = rules
+            .classify(
+                "SR",
+                "1A12",
+                &stops(&["GLGC", "PSLYGST", "AYR"]),
+                &tiploc_map,
+            )
+            .unwrap();
+        assert_eq!(ayr.route_id, "SR-AYRSHIRE");
+        assert_eq!(ayr.route_type, ROUTE_TYPE_RAIL);
+
+        assert_eq!(
+            rules.classify("SR", "2Y45", &stops(&["GLGC", "PSLYGST"]), &tiploc_map),
+            None
+        );
+        assert_eq!(
+            rules.classify("NT", "2Y45", &stops(&["PRTKLOW"]), &tiploc_map),
+            None
+        );
+    }
+}
@@ -1,73 +1,810 @@
-use anyhow::{Context, Result};
-use csv::Writer;
+mod alerts;
+mod aliases;
+mod amenities;
+mod attributions;
+mod bank_holidays;
+mod bplan;
+mod branding;
+mod cif_update;
+mod corpus;
+mod credentials;
+mod darwin;
+mod diff;
+mod fares;
+mod formations;
+mod frequencies;
+mod gtfs_rt;
+mod headsign;
+mod interchange;
+mod intern;
+mod knowledgebase;
+mod line_rules;
+mod manifest;
+mod merge;
+mod metrics;
+mod names;
+mod naptan;
+mod netex;
+mod nrdp;
+#[cfg(feature = "object-storage")]
+mod object_store;
+mod operators;
+mod osm;
+mod parquet_feed;
+mod pathways;
+mod reader;
+mod realtime;
+mod routes;
+mod schedule;
+mod service_groups;
+mod shapes;
+mod sqlite;
+mod stats;
+mod status;
+mod stomp;
+mod timezone;
+mod translations;
+mod txc;
+mod validate;
+mod watch;
+mod webhook;
+mod writer;
+
+#[cfg(test)]
+mod test_support;
+
+use anyhow::{Context, Result, bail};
+use bank_holidays::BankHolidays;
+use bplan::BplanLocation;
+use branding::BrandingTable;
+use chrono::{Datelike, NaiveDate};
+use cif_update::CifStore;
+use clap::{Parser, Subcommand, ValueEnum};
+use corpus::CorpusEntry;
+use credentials::Credentials;
+use formations::Formation;
+use frequencies::FrequencyTrips;
+use headsign::HeadsignPolicy;
+use interchange::Interchange;
+use line_rules::LineRules;
 use lonlat_bng::convert_osgb36_to_ll;
-use osmpbfreader::{OsmPbfReader, objects::OsmObj};
+use naptan::RailStop;
+use nationalrail_gtfs::cif::{Association, CifError, CifReader, CifRecord, TiplocInsert};
+use nrdp::{HttpOptions, NrdpClient, RetryPolicy};
+use osm::{Entrance, OsmStations};
+use routes::RouteGrouping;
+use schedule::ScheduleBuilder;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use service_groups::ServiceGroups;
+use sha2::{Digest, Sha256};
+use shapes::ShapePoint;
+use sqlite::{ScheduleRow, SqliteSink};
+use stats::Stats;
+use status::{Stage, StageContext, Status};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::{self, File};
-use std::io::{BufRead, BufReader, Cursor, Read};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Instant;
+use writer::{DirectoryFeed, GtfsWriter};
 use zip::ZipArchive;
+use zip::write::FileOptions;
 
 // --- Configuration ---
-const AUTH_URL: &str = "https://opendata.nationalrail.co.uk/authenticate";
 const TIMETABLE_URL: &str = "https://opendata.nationalrail.co.uk/api/staticfeeds/3.0/timetable";
 const FARES_URL: &str = "https://opendata.nationalrail.co.uk/api/staticfeeds/2.0/fares";
+const KB_STATIONS_URL: &str = "https://opendata.nationalrail.co.uk/api/staticfeeds/4.0/stations";
 const OSM_CRS_URL: &str = "https://github.com/catenarytransit/osm-filter/releases/download/latest/crs-networkrail.osm.pbf";
 
-// --- Data Structures ---
+// --- Command Line ---
+
+#[derive(Parser)]
+#[command(about = "Convert National Rail CIF timetables to GTFS")]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Only convert these operators (comma-separated ATOC codes, e.g. GW,XC,SW)
+    #[arg(long, value_delimiter = ',')]
+    toc: Vec<String>,
+
+    /// Drop service dates before this day (YYYYMMDD or YYYY-MM-DD)
+    #[arg(long, value_parser = parse_date_arg)]
+    from_date: Option<NaiveDate>,
+
+    /// Drop service dates after this day (YYYYMMDD or YYYY-MM-DD)
+    #[arg(long, value_parser = parse_date_arg)]
+    to_date: Option<NaiveDate>,
+
+    /// Only keep trips calling inside this box (minlon,minlat,maxlon,maxlat)
+    #[arg(long, value_parser = parse_bbox_arg, allow_hyphen_values = true)]
+    bbox: Option<BoundingBox>,
+
+    /// Keep freight, empty stock and other non-passenger schedules
+    #[arg(long)]
+    include_non_passenger: bool,
+
+    /// How trips are grouped into routes (operators with named lines keep them)
+    #[arg(long, value_enum, default_value_t = RouteGrouping::Origin)]
+    route_grouping: RouteGrouping,
+
+    /// How service days are written: weekly patterns, explicit dates, or
+    /// whichever takes fewer rows for each service
+    #[arg(long, value_enum, default_value_t = CalendarMode::Calendar)]
+    calendar_mode: CalendarMode,
+
+    /// Put this in front of every stop, trip, route and service id, e.g.
+    /// `gbrail:`, so the feed can be combined with others without clashes
+    #[arg(long, value_name = "PREFIX")]
+    id_prefix: Option<String>,
+
+    /// What the timetable is written as
+    #[arg(long, value_enum, default_value_t = OutputFormat::Gtfs, conflicts_with = "use_frequencies")]
+    format: OutputFormat,
+
+    /// Fold trips running the same pattern at a steady interval into
+    /// frequencies.txt
+    #[arg(long)]
+    use_frequencies: bool,
+
+    /// Add "via" the call that sets a trip's route apart to its headsign
+    #[arg(long)]
+    headsign_via: bool,
+
+    /// Mark STP schedules that stop short of their permanent schedule in the headsign
+    #[arg(long)]
+    mark_short_workings: bool,
+
+    /// Write shapes.txt along the junctions and loops each train passes, with
+    /// shape_dist_traveled on its stop times
+    #[arg(long)]
+    shapes: bool,
+
+    /// How to write stops made on request (CIF activity R)
+    #[arg(long, value_enum, default_value_t = RequestStopPolicy::Coordinate)]
+    request_stops: RequestStopPolicy,
+
+    /// Write formations.txt with the unit class and length each trip is timed for
+    #[arg(long)]
+    formations: bool,
+
+    /// Write every known station to stops.txt, not just those trips call at
+    #[arg(long)]
+    keep_all_stops: bool,
+
+    /// Timetable format to convert: the NRDP CIF extract or Darwin's timetable XML
+    #[arg(long, value_enum, default_value_t = InputSource::Cif)]
+    source: InputSource,
+
+    /// Darwin timetable file (`*_v8.xml`, optionally gzipped), for `--source darwin`
+    #[arg(long, value_name = "PATH", required_if_eq("source", "darwin"))]
+    darwin_timetable: Option<PathBuf>,
+
+    /// Darwin reference file (`*_ref_v3.xml`, optionally gzipped) naming locations and operators
+    #[arg(long, value_name = "PATH", required_if_eq("source", "darwin"))]
+    darwin_reference: Option<PathBuf>,
+
+    /// Read the timetable feed from a local ZIP instead of downloading it
+    #[arg(long)]
+    timetable_zip: Option<PathBuf>,
+
+    /// CIF update file to apply on top of the timetable extract (repeatable, applied in order)
+    #[arg(long = "cif-update")]
+    cif_updates: Vec<PathBuf>,
+
+    /// Save the timetable ZIP with the updates merged in, for the next run's --timetable-zip
+    #[arg(long, requires = "cif_updates")]
+    save_timetable: Option<PathBuf>,
+
+    /// Bytes buffered per output file between writes; raise it for network storage
+    #[arg(long, value_name = "BYTES", default_value_t = writer::DEFAULT_BUFFER_SIZE)]
+    write_buffer_size: usize,
+
+    /// Also write the parsed timetable to a normalised SQLite database
+    #[arg(long)]
+    sqlite: Option<PathBuf>,
+
+    /// Convert the fares feed into GTFS-Fares v2 files
+    #[arg(long)]
+    fares_v2: bool,
+
+    /// Limit Fares v2 leg rules to the routes their fare route code permits, as networks
+    #[arg(long, requires = "fares_v2")]
+    fare_networks: bool,
+
+    /// Also write Fares v1 files (fare_attributes/fare_rules) from anytime single fares
+    #[arg(long)]
+    fares_v1: bool,
+
+    /// Fetch the Knowledgebase stations feed for wheelchair accessibility data
+    #[arg(long)]
+    knowledgebase: bool,
+
+    /// Write pathways.txt and levels.txt linking station entrances to platforms, from the
+    /// Knowledgebase's step-free access notes
+    #[arg(long, requires = "knowledgebase")]
+    pathways: bool,
+
+    /// Download Network Rail's CORPUS to place TIPLOCs the MSN doesn't cover
+    /// (needs NR_DATAFEEDS_USERNAME and NR_DATAFEEDS_PASSWORD)
+    #[arg(long)]
+    corpus: bool,
+
+    /// Read CORPUS from a local JSON or gzipped JSON file instead of downloading it
+    #[arg(long, value_name = "PATH")]
+    corpus_path: Option<PathBuf>,
+
+    /// Filtered OSM extract of stations tagged ref:crs, as a local PBF file or a URL to download
+    #[arg(long, value_name = "PATH_OR_URL", default_value = OSM_CRS_URL)]
+    osm_pbf: String,
+
+    /// Don't use OSM for station coordinates or entrances, e.g. when offline
+    #[arg(long, conflicts_with = "osm_pbf")]
+    no_osm: bool,
+
+    /// Network Rail BPLAN file whose LOC records place locations OSM doesn't
+    /// (coordinates are taken from OSM, then NaPTAN, then BPLAN, then the MSN grid reference)
+    #[arg(long, value_name = "PATH")]
+    bplan_path: Option<PathBuf>,
+
+    /// Which coordinates a station gets where OSM and its MSN grid reference disagree
+    #[arg(long, value_enum, default_value_t = CoordinatePriority::Osm)]
+    coordinate_priority: CoordinatePriority,
+
+    /// Metres apart beyond which OSM and MSN coordinates are listed in coordinate_conflicts.csv
+    #[arg(long, value_name = "METRES", default_value_t = 500.0)]
+    conflict_threshold: f64,
+
+    /// Download NaPTAN for station coordinates and ATCO codes (written as stop_code
+    /// in place of the CRS)
+    #[arg(long)]
+    naptan: bool,
+
+    /// Read NaPTAN from a local CSV instead of downloading it
+    #[arg(long, value_name = "PATH")]
+    naptan_path: Option<PathBuf>,
+
+    /// Download bank holiday dates from gov.uk rather than using the built-in list
+    #[arg(long)]
+    bank_holidays: bool,
+
+    /// Read gov.uk bank holiday JSON from a local file
+    #[arg(long, value_name = "PATH")]
+    bank_holidays_path: Option<PathBuf>,
+
+    /// TOML file overriding the built-in operator colours, websites and phone numbers
+    #[arg(long, value_name = "PATH")]
+    branding: Option<PathBuf>,
+
+    /// TOML file naming service groups by train service code prefix, for route_desc
+    #[arg(long, value_name = "PATH")]
+    service_groups: Option<PathBuf>,
+
+    /// TOML file of rules naming the lines trips run on, by operator, headcode and calling points
+    #[arg(long, value_name = "PATH")]
+    line_rules: Option<PathBuf>,
+
+    /// Title-case station names and spell out their abbreviations, keeping the
+    /// timetable's own in a stop_name_raw column
+    #[arg(long)]
+    pretty_names: bool,
+
+    /// Mark the MSN's recognised interchanges as recommended transfer points in transfers.txt
+    #[arg(long)]
+    interchange_transfers: bool,
+
+    /// Write each station's MSN minimum change time as a timed transfer in transfers.txt
+    #[arg(long)]
+    min_transfer_times: bool,
+
+    /// Write stop_aliases.txt with the other names stations go by, from the MSN's alias records
+    #[arg(long)]
+    station_aliases: bool,
+
+    /// Write translations.txt with Welsh station names (and the feed_info.txt it requires)
+    #[arg(long)]
+    welsh_translations: bool,
+
+    /// Fail if validation of the written feed finds any errors (see report.json)
+    #[arg(long)]
+    strict: bool,
+
+    /// Convert and validate without writing any output, printing the stats and report instead
+    #[arg(long, conflicts_with_all = ["sqlite", "save_timetable"])]
+    dry_run: bool,
+
+    /// Upload the zipped feed and its reports to s3://bucket/prefix/ or gs://bucket/prefix/
+    /// instead of writing gtfs_output
+    #[cfg(feature = "object-storage")]
+    #[arg(long, value_name = "URI", conflicts_with = "dry_run")]
+    output_uri: Option<object_store::ObjectUri>,
+
+    /// What to do with malformed timetable records
+    #[arg(long, value_enum, default_value_t = CifErrorPolicy::Skip)]
+    on_cif_error: CifErrorPolicy,
+
+    /// What to do with trips whose times run backwards other than over midnight
+    #[arg(long, value_enum, default_value_t = BadTimesPolicy::Keep)]
+    on_bad_times: BadTimesPolicy,
+
+    /// Attempts per download before giving up on transient network or server errors
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+    retry_attempts: u32,
+
+    /// Seconds to wait before the first retry, doubling on each further attempt
+    #[arg(long, default_value_t = 10)]
+    retry_backoff: u64,
+
+    /// Send all downloads through this proxy (e.g. http://proxy.example.com:3128)
+    #[arg(long, value_name = "URL")]
+    proxy: Option<String>,
+
+    /// Seconds to wait for a connection before the attempt counts as failed
+    #[arg(long, value_name = "SECS")]
+    connect_timeout: Option<u64>,
+
+    /// Seconds a whole download may take, including reading the response
+    #[arg(long, value_name = "SECS", default_value_t = 300)]
+    timeout: u64,
+
+    /// User-Agent header sent with every request
+    #[arg(long)]
+    user_agent: Option<String>,
+
+    /// Extra root certificate (PEM) to trust, e.g. for a TLS-intercepting proxy (repeatable)
+    #[arg(long = "ca-cert", value_name = "PATH")]
+    ca_certs: Vec<PathBuf>,
+
+    /// Directory for data kept between runs (defaults to the user cache directory)
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// Always authenticate afresh instead of reusing a cached NRDP token
+    #[arg(long)]
+    no_token_cache: bool,
+
+    /// Config file holding credentials (defaults to ~/.config/nationalrail-gtfs/config.toml)
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Write the outcome of the run (status, exit code, error) to this JSON file
+    #[arg(long, value_name = "PATH")]
+    status_file: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve GTFS-Realtime TripUpdates from the Darwin Push Port for a converted feed
+    Realtime(realtime::RealtimeArgs),
+    /// Serve GTFS-Realtime ServiceAlerts from the Knowledgebase incidents feed
+    Alerts(alerts::AlertsArgs),
+    /// Report the routes, stops, trips and calendars that differ between two converted feeds
+    Diff(diff::DiffArgs),
+    /// Combine the converted feed with other GTFS feeds, merging the stations they share
+    Merge(merge::MergeArgs),
+    /// Write a previously generated feed in another output format
+    Export(reader::ExportArgs),
+    /// Rebuild the feed on a schedule or when NRDP has a new extract, publishing it once it validates
+    Watch(Box<watch::WatchArgs>),
+}
+
+impl Args {
+    fn cache_dir(&self) -> Option<PathBuf> {
+        self.cache_dir
+            .clone()
+            .or_else(|| dirs::cache_dir().map(|dir| dir.join("nationalrail-gtfs")))
+    }
+
+    fn http_options(&self) -> HttpOptions {
+        let defaults = HttpOptions::default();
+        HttpOptions {
+            proxy: self.proxy.clone(),
+            connect_timeout: self.connect_timeout.map(std::time::Duration::from_secs),
+            timeout: std::time::Duration::from_secs(self.timeout),
+            user_agent: self.user_agent.clone().unwrap_or(defaults.user_agent),
+            ca_certs: self.ca_certs.clone(),
+        }
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            attempts: self.retry_attempts,
+            initial_backoff: std::time::Duration::from_secs(self.retry_backoff),
+        }
+    }
+
+    /// Where the NRDP token is kept between runs, unless that's turned off
+    fn token_cache(&self) -> Option<PathBuf> {
+        (!self.no_token_cache)
+            .then(|| self.cache_dir())
+            .flatten()
+            .map(|dir| dir.join("nrdp-token.json"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum InputSource {
+    /// The CIF timetable extract from the National Rail Data Portal
+    Cif,
+    /// Darwin's daily timetable and reference XML
+    Darwin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CifErrorPolicy {
+    /// Warn and drop the record (and the schedule it belongs to)
+    Skip,
+    /// Stop the conversion at the first malformed record
+    Abort,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum BadTimesPolicy {
+    /// Warn and write the trip as it is
+    Keep,
+    /// Warn and leave the trip out
+    Drop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CoordinatePriority {
+    /// The OSM station node, the MSN grid reference being the likelier mistake
+    Osm,
+    /// The MSN grid reference, for when OSM is tagged with the wrong CRS
+    Msn,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// GTFS text files
+    #[default]
+    Gtfs,
+    /// A UK profile NeTEx publication delivery in netex.xml, alongside the
+    /// report and side files
+    Netex,
+    /// A TransXChange 2.4 document per operator in txc/
+    Txc,
+    /// GTFS, but with trips, stop times and calendars in typed Parquet
+    /// files rather than CSV
+    Parquet,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum CalendarMode {
+    /// calendar.txt patterns, with exceptions in calendar_dates.txt
+    #[default]
+    Calendar,
+    /// Every date a service runs in calendar_dates.txt, and no calendar.txt rows
+    DatesOnly,
+    /// Per service, whichever of the two takes fewer rows
+    Hybrid,
+}
+
+/// How calls the train only makes on request are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum RequestStopPolicy {
+    /// As stops where passengers arrange with the crew to board or alight
+    #[default]
+    Coordinate,
+    /// Left out, as though the train passed
+    Omit,
+}
+
+/// A step back in time at least this large is taken as the train running
+/// past midnight rather than a data error
+const MIDNIGHT_ROLLOVER_SECS: u32 = 12 * 3600;
+
+/// Malformed records reported individually before going quiet
+const MAX_CIF_WARNINGS: usize = 20;
+
+#[derive(Debug, Clone, Copy)]
+struct BoundingBox {
+    min_lon: f64,
+    min_lat: f64,
+    max_lon: f64,
+    max_lat: f64,
+}
+
+impl BoundingBox {
+    fn contains(&self, lat: f64, lon: f64) -> bool {
+        (self.min_lat..=self.max_lat).contains(&lat) && (self.min_lon..=self.max_lon).contains(&lon)
+    }
+}
+
+fn parse_bbox_arg(raw: &str) -> Result<BoundingBox, String> {
+    let parts: Vec<f64> = raw
+        .split(',')
+        .map(|p| p.trim().parse::<f64>())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("invalid bbox '{}': {}", raw, e))?;
+    let [min_lon, min_lat, max_lon, max_lat] = parts[..] else {
+        return Err(format!(
+            "bbox '{}' must have four comma-separated values",
+            raw
+        ));
+    };
+    if min_lon > max_lon || min_lat > max_lat {
+        return Err(format!("bbox '{}' has its minimum above its maximum", raw));
+    }
+    Ok(BoundingBox {
+        min_lon,
+        min_lat,
+        max_lon,
+        max_lat,
+    })
+}
+
+fn parse_date_arg(raw: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(raw, "%Y%m%d")
+        .or_else(|_| NaiveDate::parse_from_str(raw, "%Y-%m-%d"))
+        .map_err(|_| format!("invalid date '{}', expected YYYYMMDD", raw))
+}
+
+/// Restrictions on which schedules make it into the output feed
+#[derive(Default)]
+struct Filters {
+    tocs: Option<HashSet<String>>,
+    from_date: Option<NaiveDate>,
+    to_date: Option<NaiveDate>,
+    bbox: Option<BoundingBox>,
+    include_non_passenger: bool,
+}
+
+impl Filters {
+    fn from_args(args: &Args) -> Self {
+        let tocs = (!args.toc.is_empty())
+            .then(|| args.toc.iter().map(|t| t.trim().to_uppercase()).collect());
+        Filters {
+            tocs,
+            from_date: args.from_date,
+            to_date: args.to_date,
+            bbox: args.bbox,
+            include_non_passenger: args.include_non_passenger,
+        }
+    }
+
+    /// A trip is kept if it calls at least once inside the bounding box
+    fn touches_bbox(
+        &self,
+        stops: &[StopTime],
+        tiploc_map: &HashMap<String, ParsedStation>,
+    ) -> bool {
+        let Some(bbox) = &self.bbox else {
+            return true;
+        };
+        stops.iter().any(|stop| {
+            tiploc_map
+                .get(&stop.tiploc)
+                .is_some_and(|station| bbox.contains(station.lat, station.lon))
+        })
+    }
+
+    fn allows_toc(&self, atoc_code: &str) -> bool {
+        self.tocs
+            .as_ref()
+            .is_none_or(|tocs| tocs.contains(atoc_code))
+    }
 
-#[derive(Deserialize)]
-struct AuthResponse {
-    token: String,
+    /// Clip a schedule's date range to the requested window, returning `None`
+    /// when no running day of the schedule falls inside it.
+    fn clip_dates(
+        &self,
+        start: NaiveDate,
+        end: NaiveDate,
+        days_run: &str,
+    ) -> Option<(NaiveDate, NaiveDate)> {
+        let start = self.from_date.map_or(start, |from| start.max(from));
+        let end = self.to_date.map_or(end, |to| end.min(to));
+        if start > end {
+            return None;
+        }
+
+        let runs_on = |date: NaiveDate| {
+            let weekday = date.weekday().num_days_from_monday() as usize;
+            days_run.as_bytes().get(weekday) == Some(&b'1')
+        };
+        // A week is enough to see every weekday the schedule runs on
+        let runs_in_window = start
+            .iter_days()
+            .take_while(|d| *d <= end)
+            .take(7)
+            .any(runs_on);
+        runs_in_window.then_some((start, end))
+    }
+}
+
+/// Coordinate sources for timetable locations, in priority order: OSM
+/// station nodes by CRS, then NaPTAN and BPLAN by TIPLOC. Stations in the
+/// MSN fall back to its own grid reference after all of them.
+#[derive(Default)]
+struct LocationIndex {
+    /// OSM station nodes by CRS
+    osm_by_crs: HashMap<String, (f64, f64)>,
+    /// NaPTAN rail access areas by TIPLOC
+    naptan: HashMap<String, RailStop>,
+    /// BPLAN locations by TIPLOC
+    bplan: HashMap<String, BplanLocation>,
+    /// CORPUS entries by TIPLOC, for the CRS of locations defined without one
+    corpus: HashMap<String, CorpusEntry>,
+}
+
+impl LocationIndex {
+    /// A location's CRS, from CORPUS if the timetable doesn't give one
+    fn crs<'a>(&'a self, tiploc: &str, crs: Option<&'a str>) -> Option<&'a str> {
+        crs.or_else(|| self.corpus.get(tiploc)?.crs.as_deref())
+    }
+
+    /// A location's coordinates and the name of the source they came from
+    fn locate(&self, tiploc: &str, crs: Option<&str>) -> Option<((f64, f64), &'static str)> {
+        self.crs(tiploc, crs)
+            .and_then(|crs| self.osm_by_crs.get(crs).map(|&coords| (coords, "osm")))
+            .or_else(|| {
+                let stop = self.naptan.get(tiploc)?;
+                Some(((stop.lat, stop.lon), "naptan"))
+            })
+            .or_else(|| {
+                let loc = self.bplan.get(tiploc)?;
+                Some(((loc.lat, loc.lon), "bplan"))
+            })
+    }
+}
+
+/// Optional extras written alongside the core GTFS fields
+#[derive(Default)]
+struct OutputOptions {
+    wheelchair_accessible: bool,
+    route_grouping: RouteGrouping,
+    calendar_mode: CalendarMode,
+    headsign: HeadsignPolicy,
+    shapes: bool,
+    request_stops: RequestStopPolicy,
 }
 
-#[derive(Debug, Serialize, Clone, PartialEq, Eq, Hash)]
+impl OutputOptions {
+    fn from_args(args: &Args) -> Self {
+        OutputOptions {
+            wheelchair_accessible: args.knowledgebase,
+            route_grouping: args.route_grouping,
+            calendar_mode: args.calendar_mode,
+            headsign: HeadsignPolicy {
+                via: args.headsign_via,
+                short_workings: args.mark_short_workings,
+            },
+            shapes: args.shapes,
+            request_stops: args.request_stops,
+        }
+    }
+}
+
+// --- Data Structures ---
+
+#[derive(Debug, Serialize, Clone, PartialEq, Eq, Hash, Deserialize)]
 struct Agency {
+    #[serde(default)]
     agency_id: String,
     agency_name: String,
     agency_url: String,
     agency_timezone: String,
+    #[serde(default)]
+    agency_lang: String,
+    agency_phone: Option<String>,
+    #[serde(default)]
+    agency_fare_url: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Stop {
     stop_id: String,
+    stop_code: Option<String>,
     stop_name: String,
     stop_lat: f64,
     stop_lon: f64,
+    zone_id: Option<String>,
+    stop_url: Option<String>,
+    location_type: Option<u8>,
+    parent_station: Option<String>,
+    platform_code: Option<String>,
+    wheelchair_boarding: Option<u8>,
+    level_id: Option<String>,
+    /// The name as the timetable gives it, with `--pretty-names`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_name_raw: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+/// Per-station values shared by all of a station's stop rows
+#[derive(Debug, Default)]
+struct StationDetails {
+    /// `--pretty-names` version of the station name
+    pretty_name: Option<String>,
+    stop_code: Option<String>,
+    stop_url: Option<String>,
+    zone_id: Option<String>,
+    wheelchair_boarding: Option<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Route {
     route_id: String,
+    #[serde(default)]
     agency_id: String,
+    #[serde(default)]
     route_short_name: String,
+    #[serde(default)]
     route_long_name: String,
+    /// Service group, from the train service code
+    #[serde(default)]
+    route_desc: String,
     route_type: u8,
+    #[serde(default)]
     route_color: String,
+    #[serde(default)]
     route_text_color: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Trip {
     route_id: String,
     service_id: String,
     trip_id: String,
+    #[serde(default)]
     trip_headsign: String,
-    #[serde(rename = "trip_short_name")]
+    #[serde(rename = "trip_short_name", default)]
     trip_short_name: String,
+    /// 0 towards London, 1 away from it
+    direction_id: Option<u8>,
+    block_id: Option<String>,
+    shape_id: Option<String>,
+    wheelchair_accessible: Option<u8>,
+    /// CIF train category (extension)
+    trip_category: Option<String>,
+    /// CIF train service code, the operator's own grouping of trains into
+    /// a service (extension)
+    #[serde(default)]
+    train_service_code: Option<String>,
+    /// `train`, `bus` or `ship`, from the CIF train status (extension)
+    #[serde(
+        deserialize_with = "reader::vehicle_type",
+        default = "reader::default_vehicle_type"
+    )]
+    vehicle_type: reader::Word,
+    /// 1 if first class is offered (extension)
+    #[serde(default)]
+    first_class: u8,
+    /// Sleeping berths by class (extension)
+    #[serde(deserialize_with = "reader::sleepers", default)]
+    sleepers: Option<reader::Word>,
+    /// Seat reservation policy (extension)
+    #[serde(deserialize_with = "reader::reservations", default)]
+    reservations: Option<reader::Word>,
+    /// Catering on board, `;`-separated (extension)
+    catering: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct StopTime {
     trip_id: String,
     arrival_time: String,
     departure_time: String,
     stop_id: String,
     stop_sequence: u32,
+    /// Where the train is going from this call, when it isn't the trip's
+    /// headsign
+    stop_headsign: Option<String>,
+    /// 3 at stops made on request, where passengers arrange with the crew
+    pickup_type: Option<u8>,
+    drop_off_type: Option<u8>,
+    /// Kilometres along the trip's shape, with `--shapes`
+    shape_dist_traveled: Option<f64>,
+    /// Station called at; `stop_id` is one of its platforms when known
+    #[serde(skip)]
+    tiploc: String,
+    #[serde(skip)]
+    platform: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Calendar {
     service_id: String,
     monday: u8,
@@ -81,208 +818,1236 @@ struct Calendar {
     end_date: String,
 }
 
-#[derive(Debug, Serialize)]
-struct Association {
-    base_uid: String,
-    assoc_uid: String,
-    start_date: String,
-    end_date: String,
-    days_run: String,
-    category: String,
-    location: String,
-    assoc_type: String,
-    stp_indicator: String,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CalendarDate {
+    service_id: String,
+    date: String,
+    /// 1 adds the date to the service, 2 removes it
+    exception_type: u8,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Transfer {
+    from_stop_id: String,
+    to_stop_id: String,
+    /// Empty for station-level transfers
+    from_trip_id: Option<String>,
+    to_trip_id: Option<String>,
+    transfer_type: u8,
+    /// Seconds, for timed transfers
+    min_transfer_time: Option<u32>,
 }
 
+/// Platforms each station is called at, `None` for calls with no platform
+type StationCalls = HashMap<String, BTreeSet<Option<String>>>;
+
 struct ParsedStation {
     tiploc: String,
     name: String,
+    crs: String,
     lat: f64,
     lon: f64,
 }
 
-struct TripState {
+/// Everything `parse_mca` needs besides the timetable itself
+#[derive(Clone, Copy)]
+struct TimetableContext<'a> {
+    locations: &'a LocationIndex,
+    toc_lookup: &'a HashMap<String, String>,
+    branding: &'a BrandingTable,
+    service_groups: &'a ServiceGroups,
+    line_rules: &'a LineRules,
+    filters: &'a Filters,
+    output_options: &'a OutputOptions,
+    bank_holidays: &'a BankHolidays,
+    on_error: CifErrorPolicy,
+    on_bad_times: BadTimesPolicy,
+}
+
+/// A schedule converted to a GTFS trip, with the CIF identity it came from
+#[derive(Debug, Clone)]
+struct ConvertedTrip {
+    trip: Trip,
+    stop_times: Vec<StopTime>,
     uid: String,
     date_start: String,
-    stp_ind: String,
+    date_end: String,
+    stp_indicator: String,
     atoc_code: String,
-    train_identity: String,
-    origin_name: String,
-    dest_name: String,
-    stops: Vec<StopTime>,
+    formation: Formation,
+}
+
+/// A row of `trip_id_map.csv`, tying a trip back to the schedule it came
+/// from for joining to Darwin, TRUST and other feeds keyed by UID
+#[derive(Serialize)]
+struct TripIdMapRow<'a> {
+    /// With any `--id-prefix`, as in trips.txt
+    trip_id: String,
+    uid: &'a str,
+    stp_indicator: &'a str,
+    /// The schedule's own dates (`YYYYMMDD`), before `--from-date` and
+    /// `--to-date` clip the calendar
+    start_date: String,
+    end_date: String,
+    /// For a schedule folded into another trip's frequency, the departure
+    /// it runs as, as `frequencies.txt` times it
+    start_time: Option<String>,
+}
+
+impl ConvertedTrip {
+    fn id_map_row(&self, id_prefix: &str) -> Result<TripIdMapRow<'_>> {
+        let gtfs_date = |cif: &str| -> Result<String> {
+            Ok(NaiveDate::parse_from_str(cif, "%y%m%d")?
+                .format("%Y%m%d")
+                .to_string())
+        };
+        Ok(TripIdMapRow {
+            trip_id: format!("{}{}", id_prefix, self.trip.trip_id),
+            uid: &self.uid,
+            stp_indicator: &self.stp_indicator,
+            start_date: gtfs_date(&self.date_start)?,
+            end_date: gtfs_date(&self.date_end)?,
+            start_time: None,
+        })
+    }
 }
 
-// --- Authentication ---
+/// The timetable to convert, once downloaded or read
+enum TimetableInput {
+    Cif {
+        archive: ZipArchive<File>,
+        /// The extract's MCA with `--cif-update` files applied
+        merged_mca: Option<File>,
+    },
+    Darwin(Vec<CifRecord>),
+}
 
-fn authenticate(username: &str, password: &str) -> Result<String> {
-    println!("Authenticating with NRDP...");
-    let client = reqwest::blocking::Client::new();
-    let params = [("username", username), ("password", password)];
+/// What's only known once the whole timetable has been read
+#[derive(Debug, Default)]
+struct TimetableSummary {
+    agencies: BTreeMap<String, Agency>,
+    routes: BTreeMap<String, Route>,
+    /// Points of each shape, by shape id
+    shapes: BTreeMap<String, Vec<ShapePoint>>,
+    /// Calendars by service id, written once every trip is known
+    calendars: BTreeMap<String, ServiceCalendar>,
+    transfers: Vec<Transfer>,
+    /// Associations as given, for the SQLite copy
+    associations: Vec<Association>,
+    /// Malformed records skipped
+    rejects: Vec<RejectedRecord>,
+    /// Calls left out for want of a station
+    dropped_stops: Vec<DroppedStop>,
+    /// The extracts read, for manifest.json
+    sources: Vec<manifest::Source>,
+}
 
-    let res = client
-        .post(AUTH_URL)
-        .form(&params)
-        .send()
-        .context("Failed to send authentication request")?;
+/// A calendar with the dates it makes exceptions for
+#[derive(Debug, Clone)]
+struct ServiceCalendar {
+    calendar: Calendar,
+    dates: Vec<CalendarDate>,
+}
 
-    if !res.status().is_success() {
-        let status = res.status();
-        let text = res.text().unwrap_or_default();
-        anyhow::bail!("Authentication failed ({}): {}", status, text);
+impl ServiceCalendar {
+    /// Every date the service runs, exceptions applied
+    fn running_dates(&self) -> Vec<NaiveDate> {
+        let calendar = &self.calendar;
+        let days = [
+            calendar.monday,
+            calendar.tuesday,
+            calendar.wednesday,
+            calendar.thursday,
+            calendar.friday,
+            calendar.saturday,
+            calendar.sunday,
+        ];
+        let date = |s: &str| NaiveDate::parse_from_str(s, "%Y%m%d").ok();
+        let (Some(start), Some(end)) = (date(&calendar.start_date), date(&calendar.end_date))
+        else {
+            return Vec::new();
+        };
+        let removed: HashSet<NaiveDate> = self
+            .dates
+            .iter()
+            .filter(|d| d.exception_type == 2)
+            .filter_map(|d| date(&d.date))
+            .collect();
+        start
+            .iter_days()
+            .take_while(|d| *d <= end)
+            .filter(|d| days[d.weekday().num_days_from_monday() as usize] == 1)
+            .filter(|d| !removed.contains(d))
+            .collect()
     }
 
-    let auth_data: AuthResponse = res.json().context("Failed to parse auth JSON")?;
-    println!("Authentication successful.");
-    Ok(auth_data.token)
+    /// The service as added dates alone, if it runs at all
+    fn expanded(&self) -> Option<Vec<CalendarDate>> {
+        let dates = self.running_dates();
+        (!dates.is_empty()).then(|| {
+            dates
+                .into_iter()
+                .map(|date| CalendarDate {
+                    service_id: self.calendar.service_id.clone(),
+                    date: date.format("%Y%m%d").to_string(),
+                    exception_type: 1,
+                })
+                .collect()
+        })
+    }
+}
+
+/// A row of `coordinate_conflicts.csv`: an MSN station whose OSM node is
+/// further from its own grid reference than `--conflict-threshold`
+#[derive(Debug, Serialize)]
+struct CoordinateConflict {
+    tiploc: String,
+    crs: String,
+    name: String,
+    osm_lat: f64,
+    osm_lon: f64,
+    msn_lat: f64,
+    msn_lon: f64,
+    distance_m: f64,
+    /// `osm` or `msn_grid`, as `--coordinate-priority` picked
+    chosen: &'static str,
+}
+
+/// How MSN stations settle disagreements between OSM and their grid reference
+struct CoordinatePolicy {
+    priority: CoordinatePriority,
+    threshold_m: f64,
+}
+
+impl Default for CoordinatePolicy {
+    fn default() -> Self {
+        CoordinatePolicy {
+            priority: CoordinatePriority::Osm,
+            threshold_m: 500.0,
+        }
+    }
+}
+
+/// A row of `dropped_stops.csv`: a public call whose TIPLOC isn't a known
+/// station, so it isn't in the trip's stop times
+#[derive(Debug, Serialize)]
+struct DroppedStop {
+    tiploc: String,
+    uid: String,
+    start_date: String,
+    stp_indicator: String,
+    /// `unknown_origin`, `unknown_intermediate` or `unknown_destination`
+    reason: &'static str,
+    /// Whether the trip was left out too, having fewer than two calls
+    trip_dropped: bool,
+}
+
+/// A row of `rejects.csv`: a timetable record skipped under
+/// `--on-cif-error skip`, with enough to find and fix it at the source
+#[derive(Debug, Serialize)]
+struct RejectedRecord {
+    file: String,
+    line: usize,
+    record_type: String,
+    reason: String,
+    text: String,
+}
+
+impl TimetableSummary {
+    /// Fold in the summary of another timetable file
+    fn merge(&mut self, other: TimetableSummary) {
+        for (id, agency) in other.agencies {
+            self.agencies.entry(id).or_insert(agency);
+        }
+        for (id, route) in other.routes {
+            self.routes.entry(id).or_insert(route);
+        }
+        // Ids are derived from the points, so a clash is the same shape
+        for (id, points) in other.shapes {
+            self.shapes.entry(id).or_insert(points);
+        }
+        // Ids are derived from the calendar, so a clash is the same calendar
+        for (id, service) in other.calendars {
+            self.calendars.entry(id).or_insert(service);
+        }
+        self.transfers.extend(other.transfers);
+        self.associations.extend(other.associations);
+        self.rejects.extend(other.rejects);
+        self.dropped_stops.extend(other.dropped_stops);
+        self.sources.extend(other.sources);
+    }
 }
 
 // --- Main Execution ---
 
-fn main() -> Result<()> {
-    let username = std::env::var("NR_USERNAME").expect("NR_USERNAME must be set");
-    let password = std::env::var("NR_PASSWORD").expect("NR_PASSWORD must be set");
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let status_file = args.status_file.clone();
+    let started = Instant::now();
+    let result = run(args);
+    if let Err(e) = &result {
+        eprintln!("Error: {:?}", e);
+    }
+    let status = Status::of(&result, started.elapsed());
+    if let Some(path) = status_file
+        && let Err(e) = status.write(&path)
+    {
+        eprintln!("Error: {:?}", e);
+    }
+    status.exit_code()
+}
 
-    let output_dir = "./gtfs_output";
+fn run(mut args: Args) -> Result<()> {
+    let credentials = Credentials::load(args.config.as_deref())?;
+    match args.command.take() {
+        Some(Command::Realtime(realtime_args)) => {
+            return realtime::run(realtime_args, &credentials);
+        }
+        Some(Command::Alerts(alerts_args)) => {
+            return alerts::run(
+                alerts_args,
+                &credentials,
+                &args.http_options(),
+                args.retry_policy(),
+                args.token_cache(),
+            );
+        }
+        Some(Command::Diff(diff_args)) => return diff::run(diff_args),
+        Some(Command::Merge(merge_args)) => return merge::run(merge_args),
+        Some(Command::Export(export_args)) => return reader::run(export_args),
+        Some(Command::Watch(watch_args)) => return watch::run(*watch_args, &args, &credentials),
+        None => {}
+    }
+
+    // A dry run still builds the feed so it can be validated, but in a
+    // scratch directory that's removed at the end. So does an upload, once
+    // the feed is on its way.
+    #[cfg(feature = "object-storage")]
+    let uploading = args.output_uri.is_some();
+    #[cfg(not(feature = "object-storage"))]
+    let uploading = false;
+    let scratch = (args.dry_run || uploading)
+        .then(tempfile::tempdir)
+        .transpose()?;
+    // Otherwise it's built beside gtfs_output and swapped in once it's
+    // complete, so pollers never see a half-written feed and a failed run
+    // leaves the last one as it was
+    let target = Path::new("./gtfs_output");
+    let staging = match scratch {
+        Some(_) => None,
+        None => Some(
+            tempfile::Builder::new()
+                .prefix(".gtfs-staging")
+                .tempdir_in(writer::parent_dir(target))?,
+        ),
+    };
+    let output_path = scratch
+        .as_ref()
+        .or(staging.as_ref())
+        .expect("one of them is made")
+        .path()
+        .to_path_buf();
+    convert(&args, &credentials, &output_path)?;
+    if let Some(staging) = staging {
+        // Only kept once it's been moved in, so a failed swap still removes it
+        writer::replace_dir(staging.path(), target)?;
+        let _ = staging.keep();
+    }
+
+    #[cfg(feature = "object-storage")]
+    if let Some(uri) = &args.output_uri {
+        let client = args.http_options().blocking_client()?;
+        object_store::publish(&output_path, uri, &client, args.retry_policy())?;
+    }
+    if args.dry_run {
+        println!("Dry run complete; nothing was written.");
+    } else {
+        println!("Conversion complete.");
+    }
+    Ok(())
+}
+
+/// Convert the timetable into a feed in `output_path` and validate it,
+/// failing on validation errors if `--strict` was given
+fn convert(
+    args: &Args,
+    credentials: &Credentials,
+    output_path: &Path,
+) -> Result<(Stats, validate::ValidationReport)> {
+    let filters = Filters::from_args(args);
+    let output_options = OutputOptions::from_args(args);
+    let output_dir = output_path
+        .to_str()
+        .context("Output directory path is not UTF-8")?;
     fs::create_dir_all(output_dir)?;
 
-    // 1. Download and Parse OSM CRS Data
-    println!("Downloading OSM CRS Data from {}...", OSM_CRS_URL);
-    let client = reqwest::blocking::Client::builder()
-        .timeout(std::time::Duration::from_secs(300))
-        .build()?;
-
-    // We save PBF to disk temporarily because OsmPbfReader prefers a File or Seekable stream
-    let pbf_path = format!("{}/stations.pbf", output_dir);
-    let mut pbf_file = File::create(&pbf_path)?;
-    let mut pbf_resp = client.get(OSM_CRS_URL).send()?;
-    pbf_resp.copy_to(&mut pbf_file)?;
-
-    println!("Parsing OSM PBF...");
-    let osm_crs_map = parse_osm_crs(&pbf_path)?;
-    println!("Loaded {} stations from OSM.", osm_crs_map.len());
-
-    // 2. Authenticate
-    let token = authenticate(&username, &password)?;
-
-    // 3. Download and Parse Fares Feed (For TOC Names)
-    println!("Downloading Fares Feed from {}...", FARES_URL);
-    let fares_resp = client
-        .get(FARES_URL)
-        .header("X-Auth-Token", &token)
-        .send()
-        .context("Failed to download fares feed")?
-        .bytes()?;
-
-    let mut fares_archive = ZipArchive::new(Cursor::new(fares_resp))?;
-    let mut toc_map: HashMap<String, String> = HashMap::new();
+    let client = args.http_options().client()?;
+    let retry_policy = args.retry_policy();
+    let runtime = nrdp::runtime()?;
+
+    // 1. Authenticate. Darwin users may have no NRDP account, so only sign
+    // in when a feed from it is actually needed.
+    let cif_source = args.source == InputSource::Cif;
+    let needs_fares = cif_source || args.fares_v1 || args.fares_v2;
+    let nrdp = if needs_fares || args.knowledgebase {
+        let (username, password) = credentials.get(credentials::Service::Nrdp)?;
+        Some(Arc::new(runtime.block_on(NrdpClient::connect(
+            client.clone(),
+            retry_policy,
+            username,
+            password,
+            args.token_cache(),
+        ))?))
+    } else {
+        None
+    };
 
-    for i in 0..fares_archive.len() {
-        let mut file = fares_archive.by_index(i)?;
-        if file.name().ends_with(".TOC") {
-            println!("Processing Fares TOC File: {}", file.name());
-            parse_fares_toc(&mut file, &mut toc_map)?;
+    // 2. Fetch OSM, fares and the timetable at once, parsing the OSM
+    // extract while the larger NRDP feeds are still downloading
+    let (osm, fares_file, tt_file) = runtime.block_on(async {
+        let osm = tokio::spawn(load_osm(
+            client.clone(),
+            retry_policy,
+            (!args.no_osm).then(|| args.osm_pbf.clone()),
+        ));
+        let fares_nrdp = nrdp.clone().filter(|_| needs_fares);
+        let fares = tokio::spawn(async move {
+            let Some(nrdp) = fares_nrdp else {
+                return Ok(None);
+            };
+            println!("Downloading Fares Feed from {}...", FARES_URL);
+            let file = nrdp
+                .download_feed(FARES_URL)
+                .await
+                .context("Failed to download fares feed")
+                .stage(Stage::Download)?;
+            Ok(Some(file))
+        });
+        let timetable_zip = args.timetable_zip.as_ref().filter(|_| cif_source);
+        let timetable_nrdp = nrdp
+            .clone()
+            .filter(|_| cif_source && timetable_zip.is_none());
+        let timetable = tokio::spawn(async move {
+            let Some(nrdp) = timetable_nrdp else {
+                return Ok(None);
+            };
+            println!("Downloading Timetable Feed from {}...", TIMETABLE_URL);
+            let file = nrdp
+                .download_feed(TIMETABLE_URL)
+                .await
+                .context("Failed to download timetable feed")
+                .stage(Stage::Download)?;
+            Ok(Some(file))
+        });
+        let tt_file = match timetable_zip {
+            Some(path) => {
+                println!("Reading Timetable Feed from {}...", path.display());
+                Some(
+                    File::open(path)
+                        .with_context(|| format!("Failed to read {}", path.display()))?,
+                )
+            }
+            None => joined(timetable).await?,
+        };
+        Ok::<_, anyhow::Error>((joined(osm).await?, joined(fares).await?, tt_file))
+    })?;
+
+    let corpus = match &args.corpus_path {
+        Some(path) => {
+            println!("Reading CORPUS from {}...", path.display());
+            corpus::parse_corpus(
+                File::open(path).with_context(|| format!("Failed to read {}", path.display()))?,
+            )?
+        }
+        None if args.corpus => {
+            let (username, password) = credentials
+                .get(credentials::Service::Datafeeds)
+                .context("Data feeds credentials are needed for --corpus")?;
+            println!("Downloading CORPUS from {}...", corpus::CORPUS_URL);
+            let file = runtime
+                .block_on(nrdp::download_basic_auth(
+                    &client,
+                    &retry_policy,
+                    corpus::CORPUS_URL,
+                    &username,
+                    &password,
+                ))
+                .context("Failed to download CORPUS")
+                .stage(Stage::Download)?;
+            corpus::parse_corpus(file)?
+        }
+        None => HashMap::new(),
+    };
+    if !corpus.is_empty() {
+        println!("Loaded {} TIPLOCs from CORPUS.", corpus.len());
+    }
+    let bplan = match &args.bplan_path {
+        Some(path) => {
+            println!("Reading BPLAN locations from {}...", path.display());
+            let file =
+                File::open(path).with_context(|| format!("Failed to read {}", path.display()))?;
+            let map = bplan::parse_bplan(BufReader::new(file))?;
+            println!("Loaded {} locations from BPLAN.", map.len());
+            map
+        }
+        None => HashMap::new(),
+    };
+    let naptan = match &args.naptan_path {
+        Some(path) => {
+            println!("Reading NaPTAN from {}...", path.display());
+            naptan::parse_rail_stops(
+                File::open(path).with_context(|| format!("Failed to read {}", path.display()))?,
+            )?
+        }
+        None if args.naptan => {
+            println!("Downloading NaPTAN from {}...", naptan::NAPTAN_URL);
+            let file = tempfile::tempfile()?;
+            runtime
+                .block_on(nrdp::download_public(
+                    &client,
+                    &retry_policy,
+                    naptan::NAPTAN_URL,
+                    &file,
+                ))
+                .context("Failed to download NaPTAN")
+                .stage(Stage::Download)?;
+            naptan::parse_rail_stops(BufReader::new(file))?
         }
+        None => HashMap::new(),
+    };
+    if !naptan.is_empty() {
+        println!("Loaded {} rail stations from NaPTAN.", naptan.len());
     }
+    let bank_holidays = match &args.bank_holidays_path {
+        Some(path) => BankHolidays::parse_gov_uk(
+            File::open(path).with_context(|| format!("Failed to read {}", path.display()))?,
+        )?,
+        None if args.bank_holidays => {
+            println!(
+                "Downloading bank holidays from {}...",
+                bank_holidays::BANK_HOLIDAYS_URL
+            );
+            let file = tempfile::tempfile()?;
+            runtime
+                .block_on(nrdp::download_public(
+                    &client,
+                    &retry_policy,
+                    bank_holidays::BANK_HOLIDAYS_URL,
+                    &file,
+                ))
+                .context("Failed to download bank holidays")
+                .stage(Stage::Download)?;
+            BankHolidays::parse_gov_uk(BufReader::new(file))?
+        }
+        None => BankHolidays::builtin(),
+    };
+    println!("Using {} bank holiday Mondays.", bank_holidays.len());
+    let OsmStations {
+        by_crs: osm_by_crs,
+        entrances: osm_entrances,
+    } = osm;
+    let locations = LocationIndex {
+        osm_by_crs,
+        naptan,
+        bplan,
+        corpus,
+    };
 
-    // 4. Download and Parse Timetable Feed
-    println!("Downloading Timetable Feed from {}...", TIMETABLE_URL);
-    let tt_resp = client
-        .get(TIMETABLE_URL)
-        .header("X-Auth-Token", &token)
-        .send()
-        .context("Failed to download timetable feed")?
-        .bytes()?;
+    // 3. Parse Fares Feed (For TOC Names)
+    let mut toc_map: HashMap<String, String> = HashMap::new();
+    let mut fares_archive = match fares_file {
+        Some(fares_file) => {
+            let mut fares_archive = ZipArchive::new(fares_file)?;
+            for i in 0..fares_archive.len() {
+                let mut file = fares_archive.by_index(i)?;
+                if file.name().ends_with(".TOC") {
+                    println!("Processing Fares TOC File: {}", file.name());
+                    toc_map.extend(parse_fares_toc(&mut file)?);
+                }
+            }
+            Some(fares_archive)
+        }
+        None => None,
+    };
 
-    let mut tt_archive = ZipArchive::new(Cursor::new(tt_resp))?;
     let mut tiploc_map: HashMap<String, ParsedStation> = HashMap::new();
+    // MSN aliases by station name, and interchange status by TIPLOC
+    let mut station_aliases: HashMap<String, Vec<String>> = HashMap::new();
+    let mut interchanges: HashMap<String, Interchange> = HashMap::new();
+    let mut stats = Stats::default();
+
+    // 4. Parse Timetable Feed
+    let timetable_input = match args.source {
+        InputSource::Cif => {
+            let tt_file = tt_file.expect("fetched for CIF input");
+            let mut tt_archive = ZipArchive::new(tt_file)?;
+
+            // 4a. Process Stations (MSN)
+            let policy = CoordinatePolicy {
+                priority: args.coordinate_priority,
+                threshold_m: args.conflict_threshold,
+            };
+            let mut conflicts = Vec::new();
+            for i in 0..tt_archive.len() {
+                let mut file = tt_archive.by_index(i)?;
+                if file.name().ends_with(".MSN") {
+                    println!("Processing Station File: {}", file.name());
+                    let mut msn = Vec::new();
+                    file.read_to_end(&mut msn)?;
+                    for (station, source) in
+                        parse_msn(&mut msn.as_slice(), &locations, &policy, &mut conflicts)?
+                    {
+                        stats.station_located(source);
+                        tiploc_map.insert(station.tiploc.clone(), station);
+                    }
+                    if args.station_aliases {
+                        station_aliases.extend(aliases::parse_msn_aliases(msn.as_slice())?);
+                    }
+                    if args.interchange_transfers || args.min_transfer_times {
+                        interchanges.extend(interchange::parse_msn_interchanges(msn.as_slice()));
+                    }
+                }
+            }
+            stats.coordinate_conflicts = conflicts.len();
+            if !conflicts.is_empty() {
+                let mut writer = writer::create_csv(
+                    output_path,
+                    "coordinate_conflicts.csv",
+                    args.write_buffer_size,
+                )?;
+                for conflict in &conflicts {
+                    writer.serialize(conflict)?;
+                }
+                writer.flush()?;
+                println!(
+                    "{} stations are over {}m from their MSN grid reference in OSM (see coordinate_conflicts.csv).",
+                    conflicts.len(),
+                    args.conflict_threshold
+                );
+            }
 
-    // 4a. Process Stations (MSN)
-    for i in 0..tt_archive.len() {
-        let mut file = tt_archive.by_index(i)?;
-        if file.name().ends_with(".MSN") {
-            println!("Processing Station File: {}", file.name());
-            parse_msn(&mut file, &mut tiploc_map, &osm_crs_map)?;
+            // Any update files are merged onto the extract's MCA up front
+            let merged_mca = if args.cif_updates.is_empty() {
+                None
+            } else {
+                let mut merged = merge_cif_updates(&mut tt_archive, &args.cif_updates)?;
+                if let Some(path) = &args.save_timetable {
+                    println!("Saving merged Timetable Feed to {}...", path.display());
+                    save_timetable_zip(&mut tt_archive, &mut merged, path)?;
+                    merged.rewind()?;
+                }
+                Some(merged)
+            };
+            TimetableInput::Cif {
+                archive: tt_archive,
+                merged_mca,
+            }
         }
-    }
+        InputSource::Darwin => {
+            if !args.cif_updates.is_empty() {
+                bail!("--cif-update only applies to --source cif");
+            }
+            let reference_path = args.darwin_reference.as_deref().expect("required by clap");
+            println!(
+                "Reading Darwin reference from {}...",
+                reference_path.display()
+            );
+            let reference =
+                darwin::parse_reference(darwin::open(reference_path)?).stage(Stage::Parse)?;
+            toc_map.extend(reference.tocs);
+            let timetable_path = args.darwin_timetable.as_deref().expect("required by clap");
+            println!(
+                "Reading Darwin timetable from {}...",
+                timetable_path.display()
+            );
+            // Reference locations stand in for the TI records of a CIF extract
+            let mut records: Vec<CifRecord> = reference
+                .locations
+                .into_iter()
+                .map(CifRecord::TiplocInsert)
+                .collect();
+            records.extend(
+                darwin::parse_timetable(darwin::open(timetable_path)?).stage(Stage::Parse)?,
+            );
+            TimetableInput::Darwin(records)
+        }
+    };
 
-    // 5. Initialize CSV Writers
-    let mut stops_writer = Writer::from_path(format!("{}/stops.txt", output_dir))?;
-    let mut trips_writer = Writer::from_path(format!("{}/trips.txt", output_dir))?;
-    let mut st_writer = Writer::from_path(format!("{}/stop_times.txt", output_dir))?;
-    let mut cal_writer = Writer::from_path(format!("{}/calendar.txt", output_dir))?;
-    let mut routes_writer = Writer::from_path(format!("{}/routes.txt", output_dir))?;
-    let mut agency_writer = Writer::from_path(format!("{}/agency.txt", output_dir))?;
-    let mut assoc_writer = Writer::from_path(format!("{}/associations.txt", output_dir))?;
+    let station_access = if args.knowledgebase {
+        println!(
+            "Downloading Knowledgebase Stations from {}...",
+            KB_STATIONS_URL
+        );
+        let kb_file = runtime
+            .block_on(
+                nrdp.as_ref()
+                    .expect("NRDP is connected for the Knowledgebase")
+                    .download_feed(KB_STATIONS_URL),
+            )
+            .context("Failed to download Knowledgebase stations feed")
+            .stage(Stage::Download)?;
+        let map = knowledgebase::parse_station_access(BufReader::new(kb_file))?;
+        println!("Loaded accessibility for {} stations.", map.len());
+        map
+    } else {
+        HashMap::new()
+    };
 
-    // Write Stops
-    for station in tiploc_map.values() {
-        stops_writer.serialize(Stop {
-            stop_id: station.tiploc.clone(),
-            stop_name: station.name.clone(),
-            stop_lat: station.lat,
-            stop_lon: station.lon,
-        })?;
+    let mut branding = BrandingTable::builtin();
+    if let Some(path) = &args.branding {
+        branding.load_overrides(path)?;
+    }
+    let mut service_groups = ServiceGroups::builtin();
+    if let Some(path) = &args.service_groups {
+        service_groups.load_overrides(path)?;
     }
+    let line_rules = match &args.line_rules {
+        Some(path) => {
+            let rules = LineRules::load(path)?;
+            println!("Loaded {} line rules.", rules.len());
+            rules
+        }
+        None => LineRules::default(),
+    };
 
-    let mut agencies: HashSet<Agency> = HashSet::new();
-    let mut routes: HashMap<String, Route> = HashMap::new();
+    let mut sqlite = args.sqlite.as_deref().map(SqliteSink::create).transpose()?;
 
-    // 4b. Process Timetable (MCA)
-    for i in 0..tt_archive.len() {
-        let mut file = tt_archive.by_index(i)?;
-        if file.name().ends_with(".MCA") {
-            println!("Processing Timetable File: {}", file.name());
-            parse_mca(
-                &mut file,
-                &mut trips_writer,
-                &mut st_writer,
-                &mut cal_writer,
-                &mut assoc_writer,
-                &tiploc_map,
-                &mut agencies,
-                &mut routes,
-                &toc_map,
-            )?;
+    // 5. Initialize the feed
+    let mut feed = create_feed(args.format, Path::new(output_dir), args.write_buffer_size)?;
+    // Files written here rather than through the feed take the prefix as
+    // they're built
+    let id_prefix = args.id_prefix.as_deref().unwrap_or("");
+    if !id_prefix.is_empty() {
+        feed = Box::new(writer::PrefixedFeed::new(feed, id_prefix));
+    }
+    let prefixed = |id: &str| format!("{}{}", id_prefix, id);
+
+    let mut station_calls: StationCalls = HashMap::new();
+    // TIPLOCs each route calls at, for fare networks
+    let mut route_tiplocs: HashMap<String, HashSet<String>> = HashMap::new();
+
+    let ctx = TimetableContext {
+        locations: &locations,
+        toc_lookup: &toc_map,
+        branding: &branding,
+        service_groups: &service_groups,
+        line_rules: &line_rules,
+        filters: &filters,
+        output_options: &output_options,
+        bank_holidays: &bank_holidays,
+        on_error: args.on_cif_error,
+        on_bad_times: args.on_bad_times,
+    };
+    let mut trip_id_map = writer::create_csv(
+        Path::new(output_dir),
+        "trip_id_map.csv",
+        args.write_buffer_size,
+    )?;
+    let mut formations = args
+        .formations
+        .then(|| {
+            writer::create_csv(
+                Path::new(output_dir),
+                "formations.txt",
+                args.write_buffer_size,
+            )
+        })
+        .transpose()?;
+    // With --use-frequencies, trips are only written once all are known
+    let mut frequency_trips = args.use_frequencies.then(FrequencyTrips::default);
+    let mut write_trip = |converted: ConvertedTrip| -> Result<()> {
+        for stop in &converted.stop_times {
+            station_calls
+                .entry(stop.tiploc.clone())
+                .or_default()
+                .insert(stop.platform.clone());
+        }
+        if args.fare_networks {
+            route_tiplocs
+                .entry(converted.trip.route_id.clone())
+                .or_default()
+                .extend(converted.stop_times.iter().map(|s| s.tiploc.clone()));
+        }
+        if let Some(db) = sqlite.as_mut() {
+            let schedule = ScheduleRow {
+                uid: &converted.uid,
+                start_date: &converted.date_start,
+                end_date: &converted.date_end,
+                stp_indicator: &converted.stp_indicator,
+                atoc_code: &converted.atoc_code,
+                train_identity: &converted.trip.trip_short_name,
+                trip_id: &converted.trip.trip_id,
+                route_id: &converted.trip.route_id,
+                service_id: &converted.trip.service_id,
+            };
+            db.insert_schedule(&schedule, &converted.stop_times)?;
+        }
+        match frequency_trips.as_mut() {
+            Some(held) => held.push(converted),
+            None => {
+                write_converted_trip(feed.as_mut(), &converted)?;
+                trip_id_map.serialize(converted.id_map_row(id_prefix)?)?;
+                if let Some(formations) = formations.as_mut() {
+                    let trip_id = prefixed(&converted.trip.trip_id);
+                    formations.serialize(converted.formation.row(trip_id))?;
+                }
+            }
+        }
+        Ok(())
+    };
+    // 4b. Process Timetable (MCA), or the records converted from Darwin
+    let mut timetable = TimetableSummary::default();
+    match timetable_input {
+        TimetableInput::Cif {
+            merged_mca: Some(mut merged),
+            ..
+        } => {
+            println!("Processing merged Timetable");
+            // Line numbers are within the MCA as rewritten by the updates
+            timetable.merge(
+                parse_mca(
+                    &mut merged,
+                    "merged MCA",
+                    &mut tiploc_map,
+                    &ctx,
+                    &mut stats,
+                    &mut write_trip,
+                )
+                .stage(Stage::Parse)?,
+            );
+        }
+        TimetableInput::Cif {
+            mut archive,
+            merged_mca: None,
+        } => {
+            for i in 0..archive.len() {
+                let mut file = archive.by_index(i)?;
+                if file.name().ends_with(".MCA") {
+                    let name = file.name().to_string();
+                    println!("Processing Timetable File: {}", name);
+                    timetable.merge(
+                        parse_mca(
+                            &mut file,
+                            &name,
+                            &mut tiploc_map,
+                            &ctx,
+                            &mut stats,
+                            &mut write_trip,
+                        )
+                        .stage(Stage::Parse)?,
+                    );
+                }
+            }
+        }
+        TimetableInput::Darwin(records) => {
+            println!("Processing Darwin Timetable");
+            timetable.merge(
+                convert_records(
+                    records.into_iter().map(Ok),
+                    "Darwin timetable",
+                    &mut tiploc_map,
+                    &ctx,
+                    &mut stats,
+                    &mut write_trip,
+                )
+                .stage(Stage::Parse)?,
+            );
         }
     }
 
-    // Write aggregated Agencies and Routes
-    for agency in agencies {
-        agency_writer.serialize(agency)?;
+    // Darwin files have no header to identify them by, and updates are
+    // read through the merged MCA, so they're listed by name
+    let mut sources = std::mem::take(&mut timetable.sources);
+    let named = match args.source {
+        InputSource::Cif => args.cif_updates.clone(),
+        InputSource::Darwin => [&args.darwin_reference, &args.darwin_timetable]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect(),
+    };
+    sources.extend(
+        named
+            .iter()
+            .map(|path| manifest::Source::file(&path.display().to_string())),
+    );
+
+    if let Some(held) = frequency_trips {
+        let folded = held.finish(&timetable.transfers);
+        for (converted, frequency) in &folded.trips {
+            write_converted_trip(feed.as_mut(), converted)?;
+            if let Some(frequency) = frequency {
+                feed.frequency(frequency)?;
+                stats.frequencies += 1;
+            }
+            let mut row = converted.id_map_row(id_prefix)?;
+            row.start_time = frequency.as_ref().map(|f| f.start_time.clone());
+            trip_id_map.serialize(row)?;
+            if let Some(formations) = formations.as_mut() {
+                let trip_id = prefixed(&converted.trip.trip_id);
+                formations.serialize(converted.formation.row(trip_id))?;
+            }
+        }
+        // Folded schedules map to the trip whose frequency they run in, at
+        // their own start time
+        for (converted, trip_id) in &folded.folded {
+            let mut row = converted.id_map_row(id_prefix)?;
+            row.trip_id = prefixed(trip_id);
+            row.start_time = converted
+                .stop_times
+                .first()
+                .map(|stop| stop.departure_time.clone());
+            trip_id_map.serialize(row)?;
+        }
+        stats.trips_folded = folded.folded.len();
+        println!(
+            "Folded {} trips into {} frequencies.",
+            stats.trips_folded, stats.frequencies
+        );
+    }
+    trip_id_map.flush()?;
+    if let Some(formations) = formations.as_mut() {
+        formations.flush()?;
+    }
+    stats.rejected_records = timetable.rejects.len();
+    if !timetable.rejects.is_empty() {
+        let mut rejects =
+            writer::create_csv(Path::new(output_dir), "rejects.csv", args.write_buffer_size)?;
+        for reject in &timetable.rejects {
+            rejects.serialize(reject)?;
+        }
+        rejects.flush()?;
     }
-    for route in routes.values() {
-        routes_writer.serialize(route)?;
+    if !timetable.dropped_stops.is_empty() {
+        let mut dropped = writer::create_csv(
+            Path::new(output_dir),
+            "dropped_stops.csv",
+            args.write_buffer_size,
+        )?;
+        for stop in &timetable.dropped_stops {
+            dropped.serialize(stop)?;
+        }
+        dropped.flush()?;
+        println!(
+            "Left out {} calls at unknown locations and {} trips with fewer than two stops (see dropped_stops.csv).",
+            timetable.dropped_stops.len(),
+            stats.short_trips_dropped
+        );
+    }
+    for transfer in &timetable.transfers {
+        feed.transfer(transfer)?;
+    }
+    write_calendars(
+        feed.as_mut(),
+        &timetable.calendars,
+        output_options.calendar_mode,
+    )?;
+    stats.calendars = timetable.calendars.len();
+
+    // Stations are only final once TI/TD records have been applied
+    if let Some(mut db) = sqlite {
+        for station in tiploc_map.values() {
+            db.insert_station(station)?;
+        }
+        for association in &timetable.associations {
+            db.insert_association(association)?;
+        }
+        for service in timetable.calendars.values() {
+            db.insert_calendar(&service.calendar)?;
+        }
+        println!("Writing SQLite database...");
+        db.finish()?;
     }
 
-    println!("Conversion complete.");
-    Ok(())
-}
+    // Write Stops, trimmed to those the kept trips call at. The MSN lists
+    // every station and its subsidiary TIPLOCs whether or not anything calls.
+    // Output files are written in a fixed order so identical input gives
+    // byte-identical output: stations by TIPLOC, agencies and routes by id,
+    // calendars by service id. Trips and stop_times are streamed as they're
+    // converted and put in order of trip id by the feed.
+    let mut kept_stations: Vec<&ParsedStation> = tiploc_map
+        .values()
+        .filter(|station| args.keep_all_stops || station_calls.contains_key(&station.tiploc))
+        .collect();
+    kept_stations.sort_by(|a, b| a.tiploc.cmp(&b.tiploc));
+    stats.stations_pruned = tiploc_map.len() - kept_stations.len();
+    if !args.keep_all_stops {
+        println!(
+            "Pruned {} stations no trip calls at.",
+            stats.stations_pruned
+        );
+    }
+    let today = timezone::today();
+    let fare_zones = if args.fares_v1 {
+        fares::station_fare_zones(
+            fares_archive
+                .as_mut()
+                .expect("fares are downloaded for --fares-v1"),
+            kept_stations.iter().copied(),
+            today,
+        )?
+    } else {
+        HashMap::new()
+    };
+    // Stops trips can call at, per station, for fare areas
+    let mut boarding_stops: HashMap<String, Vec<String>> = HashMap::new();
+    let mut welsh_stops: Vec<(String, &'static str)> = Vec::new();
+    let mut alias_stops: Vec<(String, String)> = Vec::new();
+    for station in kept_stations.iter().copied() {
+        let welsh_name = translations::welsh_name(&station.crs).filter(|_| args.welsh_translations);
+        let crs = (!station.crs.is_empty()).then(|| station.crs.clone());
+        let stop_code = match locations.naptan.get(&station.tiploc) {
+            Some(stop) => Some(stop.atco_code.clone()),
+            None => crs.clone(),
+        };
+        let access = station_access.get(&station.crs);
+        let details = StationDetails {
+            pretty_name: args.pretty_names.then(|| names::pretty_name(&station.name)),
+            stop_code,
+            stop_url: crs.as_deref().map(station_url),
+            zone_id: fare_zones.get(&station.tiploc).cloned(),
+            wheelchair_boarding: access.map(|access| access.wheelchair_boarding),
+        };
+        let entrances = osm_entrances
+            .get(&station.crs)
+            .map_or(&[][..], Vec::as_slice);
+        let mut stops = station_stops(
+            station,
+            station_calls.get(&station.tiploc),
+            &details,
+            entrances,
+        );
+        if args.pathways
+            && let Some(route) = access.and_then(|access| access.route)
+        {
+            let added = pathways::station_pathways(&mut stops, route);
+            stops.extend(added.entrance);
+            for pathway in &added.pathways {
+                feed.pathway(pathway)?;
+            }
+            if let Some(level) = &added.level {
+                feed.level(level)?;
+            }
+        }
+        for stop in stops {
+            if matches!(stop.location_type, None | Some(0)) {
+                boarding_stops
+                    .entry(station.tiploc.clone())
+                    .or_default()
+                    .push(prefixed(&stop.stop_id));
+            }
+            if let Some(name) = welsh_name {
+                welsh_stops.push((prefixed(&stop.stop_id), name));
+            }
+            // Aliases and interchanges are of the station, not its platforms or entrances
+            if stop.parent_station.is_none() {
+                for alias in station_aliases.get(&station.name).into_iter().flatten() {
+                    alias_stops.push((prefixed(&stop.stop_id), alias.clone()));
+                }
+                if let Some((transfer_type, min_transfer_time)) =
+                    interchanges.get(&station.tiploc).and_then(|interchange| {
+                        interchange.transfer(args.interchange_transfers, args.min_transfer_times)
+                    })
+                {
+                    feed.transfer(&Transfer {
+                        from_stop_id: stop.stop_id.clone(),
+                        to_stop_id: stop.stop_id.clone(),
+                        from_trip_id: None,
+                        to_trip_id: None,
+                        transfer_type,
+                        min_transfer_time,
+                    })?;
+                }
+            }
+            feed.stop(&stop)?;
+        }
+    }
+    if args.station_aliases {
+        println!("Writing {} station aliases...", alias_stops.len());
+        aliases::write_aliases(Path::new(output_dir), args.write_buffer_size, &alias_stops)?;
+    }
+    if args.welsh_translations {
+        println!("Writing Welsh names for {} stops...", welsh_stops.len());
+        translations::write_translations(
+            Path::new(output_dir),
+            args.write_buffer_size,
+            &welsh_stops,
+        )?;
+    }
 
-// --- Parsing Logic ---
+    if args.fares_v1 {
+        println!("Deriving Fares v1 from anytime single fares...");
+        fares::write_fares_v1(
+            fares_archive
+                .as_mut()
+                .expect("fares are downloaded for --fares-v1"),
+            &fare_zones,
+            output_dir,
+            args.write_buffer_size,
+            today,
+        )?;
+    }
 
-/// Parse OSM PBF to get CRS -> Lat/Lon map
-fn parse_osm_crs(path: &str) -> Result<HashMap<String, (f64, f64)>> {
-    let file = File::open(path)?;
-    let mut reader = OsmPbfReader::new(file);
-    let mut map = HashMap::new();
+    if args.fares_v2 {
+        println!("Converting Fares Feed to GTFS-Fares v2...");
+        let route_stations: Option<BTreeMap<String, BTreeSet<String>>> =
+            args.fare_networks.then(|| {
+                route_tiplocs
+                    .iter()
+                    .map(|(route_id, tiplocs)| {
+                        let crs = tiplocs
+                            .iter()
+                            .filter_map(|tiploc| tiploc_map.get(tiploc))
+                            .filter(|station| !station.crs.is_empty())
+                            .map(|station| station.crs.clone())
+                            .collect();
+                        (prefixed(route_id), crs)
+                    })
+                    .collect()
+            });
+        fares::write_fares_v2(
+            fares_archive
+                .as_mut()
+                .expect("fares are downloaded for --fares-v2"),
+            kept_stations.iter().copied(),
+            &boarding_stops,
+            id_prefix,
+            route_stations.as_ref(),
+            output_dir,
+            args.write_buffer_size,
+            today,
+        )?;
+    }
 
-    for obj in reader.iter().flatten() {
-        if let OsmObj::Node(node) = obj {
-            // Look for ref:crs tag
-            if let Some(crs) = node.tags.get("ref:crs") {
-                // Some CRS might be comma separated or slight variations, taking direct 3-char match usually
-                // The provided PBF is filtered for CRS, so we trust it.
-                // We store the lat/lon directly from the node.
-                map.insert(crs.to_string(), (node.lat(), node.lon()));
-            }
+    // Write aggregated Agencies and Routes
+    for agency in timetable.agencies.values() {
+        feed.agency(agency)?;
+    }
+    for route in timetable.routes.values() {
+        feed.route(route)?;
+    }
+    for point in timetable.shapes.values().flatten() {
+        feed.shape(point)?;
+    }
+    feed.finish()?;
+    attributions::write_attributions(
+        Path::new(output_dir),
+        args.write_buffer_size,
+        &attributions::DataSources {
+            network_rail: !locations.corpus.is_empty() || !locations.bplan.is_empty(),
+            naptan: !locations.naptan.is_empty(),
+        },
+    )?;
+
+    if stats.rejected_records > 0 {
+        println!(
+            "Rejected {} malformed CIF records (see rejects.csv).",
+            stats.rejected_records
+        );
+    }
+    if stats.unknown_operator_trips > 0 {
+        println!(
+            "Wrote {} trips with no BX record under the unknown operator.",
+            stats.unknown_operator_trips
+        );
+    }
+    println!(
+        "Wrote {} trips on {} calendars.",
+        stats.trips(),
+        stats.calendars
+    );
+
+    if args.dry_run {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+    } else {
+        stats.write(&output_path.join("stats.json"))?;
+    }
+
+    let report = if args.format == OutputFormat::Gtfs {
+        println!("Validating output...");
+        validate::validate(validate::FeedFiles::open(output_path)?)?
+    } else {
+        println!("Skipping GTFS validation of {:?} output.", args.format);
+        validate::ValidationReport::default()
+    };
+    if args.dry_run {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        report.write(&output_path.join("report.json"))?;
+        for (category, count) in &report.errors {
+            println!("  {}: {}", category, count);
         }
     }
-    Ok(map)
+    // Last, so it covers every other file
+    if !args.dry_run {
+        manifest::build(output_path, sources)?.write(&output_path.join(manifest::MANIFEST_FILE))?;
+    }
+    if args.strict && report.error_count() > 0 {
+        return Err(Stage::Validation.tag(anyhow::anyhow!(
+            "Validation found {} errors{}",
+            report.error_count(),
+            if args.dry_run {
+                ""
+            } else {
+                " (see report.json)"
+            }
+        )));
+    }
+    Ok((stats, report))
 }
 
-fn parse_fares_toc<R: Read>(reader: &mut R, map: &mut HashMap<String, String>) -> Result<()> {
+/// The result of a download thread
+async fn joined<T>(handle: tokio::task::JoinHandle<Result<T>>) -> Result<T> {
+    handle.await.context("Download task failed")?
+}
+
+/// Download the OSM extract at `source` if it's a URL, and parse it off the
+/// runtime's threads. `None` skips OSM altogether.
+async fn load_osm(
+    client: reqwest::Client,
+    retry_policy: RetryPolicy,
+    source: Option<String>,
+) -> Result<OsmStations> {
+    let Some(source) = source else {
+        return Ok(OsmStations::default());
+    };
+    let osm = if source.starts_with("http://") || source.starts_with("https://") {
+        println!("Downloading OSM CRS Data from {}...", source);
+        // We save PBF to disk temporarily because OsmPbfReader prefers a File or Seekable stream.
+        // It's removed once parsed.
+        let pbf_file = tempfile::NamedTempFile::new()?;
+        nrdp::download_public(&client, &retry_policy, &source, pbf_file.as_file())
+            .await
+            .context("Failed to download OSM CRS data")
+            .stage(Stage::Download)?;
+        tokio::task::spawn_blocking(move || {
+            println!("Parsing OSM PBF...");
+            osm::parse_osm_crs(pbf_file.path())
+        })
+        .await??
+    } else {
+        tokio::task::spawn_blocking(move || {
+            println!("Parsing OSM PBF {}...", source);
+            osm::parse_osm_crs(Path::new(&source))
+                .with_context(|| format!("Failed to read OSM PBF {}", source))
+        })
+        .await??
+    };
+    println!(
+        "Loaded {} stations and {} entrances from OSM.",
+        osm.by_crs.len(),
+        osm.entrances.values().map(Vec::len).sum::<usize>()
+    );
+    Ok(osm)
+}
+
+// --- Parsing Logic ---
+
+/// Operator names by ATOC code from a fares TOC file
+fn parse_fares_toc<R: Read>(reader: &mut R) -> Result<HashMap<String, String>> {
+    let mut map = HashMap::new();
     let buf_reader = BufReader::new(reader);
-    for line in buf_reader.lines().flatten() {
+    for line in buf_reader.lines().map_while(Result::ok) {
         if line.starts_with('T') {
             let id = line.get(1..3).unwrap_or("").trim().to_string();
             let name = line.get(3..33).unwrap_or("").trim().to_string();
@@ -291,543 +2056,1388 @@ fn parse_fares_toc<R: Read>(reader: &mut R, map: &mut HashMap<String, String>) -
             }
         }
     }
-    Ok(())
+    Ok(map)
 }
 
 /// Parse Master Station Names
-/// Prioritizes OSM coordinates if CRS matches, otherwise falls back to OSGB36 conversion
+/// Prioritizes OSM coordinates if CRS matches, then BPLAN, otherwise falls back to OSGB36 conversion.
+/// Each station comes with where its coordinates were taken from. Stations
+/// whose OSM node is far from their grid reference are added to `conflicts`,
+/// and placed as the policy says.
 fn parse_msn<R: Read>(
     reader: &mut R,
-    map: &mut HashMap<String, ParsedStation>,
-    osm_lookup: &HashMap<String, (f64, f64)>,
-) -> Result<()> {
+    locations: &LocationIndex,
+    policy: &CoordinatePolicy,
+    conflicts: &mut Vec<CoordinateConflict>,
+) -> Result<Vec<(ParsedStation, &'static str)>> {
+    let mut stations = Vec::new();
     let buf_reader = BufReader::new(reader);
-    for line in buf_reader.lines().flatten() {
-        if line.starts_with('A') {
+    for line in buf_reader.lines().map_while(Result::ok) {
+        // The file header is an A record too
+        if line.starts_with('A') && line.get(30..40) != Some("FILE-SPEC=") {
             // RSPS5046 Page 33
-            // Name: 6-31 (0-based 5..31)
-            let name = line.get(5..31).unwrap_or("").trim().to_string();
+            // Name: 6-35 (0-based 5..35)
+            let name = line.get(5..35).unwrap_or("").trim().to_string();
             // TIPLOC: 37-43 (0-based 36..43)
             let tiploc = line.get(36..43).unwrap_or("").trim().to_string();
             // CRS Code: 50-52 (0-based 49..52)
             let crs = line.get(49..52).unwrap_or("").trim().to_string();
 
-            // Easting: 53-57 (52..57)
-            let easting_str = line.get(52..57).unwrap_or("0");
-            // Northing: 59-63 (58..63)
-            let northing_str = line.get(58..63).unwrap_or("0");
-
-            let (lat, lon) = if let Some(coords) = osm_lookup.get(&crs) {
-                // 1. Priority: OSM Match via CRS
-                *coords
-            } else {
-                // 2. Fallback: OSGB36 Conversion
-                let easting = easting_str.trim().parse::<f64>().unwrap_or(0.0) * 100.0;
-                let northing = northing_str.trim().parse::<f64>().unwrap_or(0.0) * 100.0;
-                match convert_osgb36_to_ll(easting, northing) {
-                    Ok(coords) => coords,
-                    Err(_) => (0.0, 0.0),
+            // 1. Priority: OSM Match via CRS, then NaPTAN or BPLAN via TIPLOC
+            // 2. Fallback: the MSN's own grid reference
+            let grid = msn_grid_reference(&line)
+                .and_then(|(easting, northing)| osgb36_to_lat_lon(easting, northing));
+            let mut located = locations.locate(&tiploc, Some(&crs));
+            if let Some((osm, "osm")) = located
+                && let Some(msn) = grid
+            {
+                let distance_m = osm::distance_m(osm, msn);
+                if distance_m > policy.threshold_m {
+                    if policy.priority == CoordinatePriority::Msn {
+                        located = Some((msn, "msn_grid"));
+                    }
+                    conflicts.push(CoordinateConflict {
+                        tiploc: tiploc.clone(),
+                        crs: crs.clone(),
+                        name: name.clone(),
+                        osm_lat: osm.0,
+                        osm_lon: osm.1,
+                        msn_lat: msn.0,
+                        msn_lon: msn.1,
+                        distance_m: distance_m.round(),
+                        chosen: located.map_or("osm", |(_, source)| source),
+                    });
                 }
-            };
+            }
+            let ((lat, lon), source) = located
+                .or_else(|| Some((grid?, "msn_grid")))
+                .unwrap_or(((0.0, 0.0), "unplaced"));
 
             if !tiploc.is_empty() {
-                map.insert(
-                    tiploc.clone(),
-                    ParsedStation {
-                        tiploc,
-                        name,
-                        lat,
-                        lon,
-                    },
-                );
+                let station = ParsedStation {
+                    tiploc,
+                    name,
+                    crs,
+                    lat,
+                    lon,
+                };
+                stations.push((station, source));
             }
         }
     }
+    Ok(stations)
+}
+
+/// The OSGB36 easting and northing, in metres, of an MSN station record.
+///
+/// Both are given in units of 100 m behind a prefix digit: the easting as
+/// `1EEEE` at 53-57 and the northing as `6NNNN` at 59-63, so a northing
+/// beyond 1000 km (Orkney and Shetland) carries into the prefix as `7NNNN`.
+fn msn_grid_reference(line: &str) -> Option<(f64, f64)> {
+    const EASTING_PREFIX: u32 = 10000;
+    const NORTHING_PREFIX: u32 = 60000;
+
+    let easting: u32 = line.get(52..57)?.trim().parse().ok()?;
+    let northing: u32 = line.get(58..63)?.trim().parse().ok()?;
+    let easting = easting.checked_sub(EASTING_PREFIX)?;
+    let northing = northing.checked_sub(NORTHING_PREFIX)?;
+    // Stations without a grid reference are left as zero
+    if easting == 0 || northing == 0 {
+        return None;
+    }
+    Some((f64::from(easting) * 100.0, f64::from(northing) * 100.0))
+}
+
+/// The station's page on the National Rail website
+fn station_url(crs: &str) -> String {
+    format!(
+        "https://www.nationalrail.co.uk/stations/{}/",
+        crs.to_ascii_lowercase()
+    )
+}
+
+/// Convert an OSGB36 grid reference in metres to WGS84 latitude and longitude
+fn osgb36_to_lat_lon(easting: f64, northing: f64) -> Option<(f64, f64)> {
+    let (lon, lat) = convert_osgb36_to_ll(easting, northing).ok()?;
+    Some((lat, lon))
+}
+
+/// A station for a TIPLOC defined only in the timetable, if it can be
+/// placed, with the source of its coordinates
+fn cif_location_station(
+    location: &TiplocInsert,
+    locations: &LocationIndex,
+) -> Option<(ParsedStation, &'static str)> {
+    let ((lat, lon), source) = locations.locate(&location.tiploc, location.crs.as_deref())?;
+    let corpus = locations.corpus.get(&location.tiploc);
+    let name = location
+        .tps_description
+        .clone()
+        .or_else(|| location.description.clone())
+        .or_else(|| corpus?.description.clone())
+        .or_else(|| Some(locations.bplan.get(&location.tiploc)?.name.clone()))
+        .unwrap_or_else(|| location.tiploc.clone());
+    let crs = locations.crs(&location.tiploc, location.crs.as_deref());
+    let station = ParsedStation {
+        tiploc: location.tiploc.clone(),
+        name,
+        crs: crs.unwrap_or_default().to_string(),
+        lat,
+        lon,
+    };
+    Some((station, source))
+}
+
+/// The writer for an output format, writing into `dir`
+fn create_feed(
+    format: OutputFormat,
+    dir: &Path,
+    buffer_size: usize,
+) -> Result<Box<dyn GtfsWriter>> {
+    Ok(match format {
+        OutputFormat::Gtfs => Box::new(DirectoryFeed::create(dir, buffer_size)?),
+        OutputFormat::Netex => Box::new(netex::NetexFeed::create(&dir.join(netex::NETEX_FILE))?),
+        OutputFormat::Parquet => Box::new(parquet_feed::ParquetFeed::create(dir, buffer_size)?),
+        OutputFormat::Txc => Box::new(txc::TxcFeed::create(&dir.join(txc::TXC_DIR))?),
+    })
+}
+
+/// Load the extract's MCA, apply each CIF update file in order and return
+/// the merged result as a full CIF in a temporary file
+fn merge_cif_updates<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    updates: &[PathBuf],
+) -> Result<File> {
+    let mut store = CifStore::default();
+    for i in 0..archive.len() {
+        let file = archive.by_index(i)?;
+        if file.name().ends_with(".MCA") {
+            println!("Loading Timetable File: {}", file.name());
+            store.apply(file)?;
+        }
+    }
+    for path in updates {
+        println!("Applying CIF Update: {}", path.display());
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        store.apply(file)?;
+    }
+    println!(
+        "Merged timetable holds {} schedules.",
+        store.schedule_count()
+    );
+
+    let mut merged = tempfile::tempfile()?;
+    store.write_to(BufWriter::new(&mut merged))?;
+    merged.rewind()?;
+    Ok(merged)
+}
+
+/// Write a copy of the timetable ZIP with its MCA replaced by the merged extract
+fn save_timetable_zip<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    merged_mca: &mut File,
+    path: &Path,
+) -> Result<()> {
+    let mut writer = zip::ZipWriter::new(File::create(path)?);
+    for i in 0..archive.len() {
+        let file = archive.by_index(i)?;
+        if file.name().ends_with(".MCA") {
+            let name = file.name().to_string();
+            writer.start_file(name, FileOptions::default())?;
+            std::io::copy(merged_mca, &mut writer)?;
+        } else {
+            writer.raw_copy_file(file)?;
+        }
+    }
+    writer.finish()?;
     Ok(())
 }
 
-fn parse_mca<R: Read>(
-    reader: &mut R,
-    trips_w: &mut Writer<File>,
-    st_w: &mut Writer<File>,
-    cal_w: &mut Writer<File>,
-    assoc_w: &mut Writer<File>,
-    tiploc_map: &HashMap<String, ParsedStation>,
-    agencies_set: &mut HashSet<Agency>,
-    routes_map: &mut HashMap<String, Route>,
-    toc_lookup: &HashMap<String, String>,
+/// Write a trip with its stop times
+fn write_converted_trip(feed: &mut dyn GtfsWriter, converted: &ConvertedTrip) -> Result<()> {
+    feed.trip(&converted.trip)?;
+    for stop_time in &converted.stop_times {
+        feed.stop_time(stop_time)?;
+    }
+    Ok(())
+}
+
+/// Write every calendar the trips use, by service id, as `mode` asks. A
+/// service that never runs keeps its calendar row so trips can refer to it.
+fn write_calendars(
+    feed: &mut dyn GtfsWriter,
+    calendars: &BTreeMap<String, ServiceCalendar>,
+    mode: CalendarMode,
 ) -> Result<()> {
-    let buf_reader = BufReader::new(reader);
-    let mut current_trip: Option<TripState> = None;
-    let mut seq_counter = 0;
-
-    for line in buf_reader.lines().flatten() {
-        if line.len() < 2 {
-            continue;
-        }
-        let record_type = &line[0..2];
-
-        match record_type {
-            "BS" => {
-                let uid = line.get(3..9).unwrap_or("").to_string();
-                let d_start = line.get(9..15).unwrap_or("");
-                let d_end = line.get(15..21).unwrap_or("");
-                let days = line.get(21..28).unwrap_or("0000000");
-                let train_id = line.get(32..36).unwrap_or("").trim().to_string();
-                let stp = line.get(79..80).unwrap_or("P");
-
-                if stp == "C" {
-                    current_trip = None;
-                    continue;
+    for service in calendars.values() {
+        let expanded = match mode {
+            CalendarMode::Calendar => None,
+            CalendarMode::DatesOnly => service.expanded(),
+            CalendarMode::Hybrid => service
+                .expanded()
+                .filter(|dates| dates.len() < 1 + service.dates.len()),
+        };
+        match expanded {
+            Some(dates) => {
+                for date in &dates {
+                    feed.calendar_date(date)?;
                 }
-
-                current_trip = Some(TripState {
-                    uid: uid.clone(),
-                    date_start: d_start.to_string(),
-                    stp_ind: stp.to_string(),
-                    atoc_code: "NR".to_string(),
-                    train_identity: train_id,
-                    origin_name: String::new(),
-                    dest_name: String::new(),
-                    stops: Vec::new(),
-                });
-
-                let service_id = format!("{}_{}_{}", uid, d_start, stp);
-                let d_vec: Vec<u8> = days.chars().map(|c| if c == '1' { 1 } else { 0 }).collect();
-                cal_w.serialize(Calendar {
-                    service_id,
-                    monday: *d_vec.get(0).unwrap_or(&0),
-                    tuesday: *d_vec.get(1).unwrap_or(&0),
-                    wednesday: *d_vec.get(2).unwrap_or(&0),
-                    thursday: *d_vec.get(3).unwrap_or(&0),
-                    friday: *d_vec.get(4).unwrap_or(&0),
-                    saturday: *d_vec.get(5).unwrap_or(&0),
-                    sunday: *d_vec.get(6).unwrap_or(&0),
-                    start_date: format!("20{}", d_start),
-                    end_date: format!("20{}", d_end),
-                })?;
-                seq_counter = 1;
             }
-            "BX" => {
-                if let Some(trip) = &mut current_trip {
-                    let atoc = line.get(11..13).unwrap_or("NR").trim().to_string();
-                    if !atoc.is_empty() {
-                        trip.atoc_code = atoc;
-                    }
+            None => {
+                feed.calendar(&service.calendar)?;
+                for date in &service.dates {
+                    feed.calendar_date(date)?;
                 }
             }
-            "LO" => {
-                if let Some(trip) = &mut current_trip {
-                    let tiploc = line.get(2..9).unwrap_or("").trim();
-                    let dep_sched = format_time(line.get(10..15).unwrap_or("00000"));
-                    let dep_pub = line.get(15..19).unwrap_or("0000");
-
-                    // Filter operational stops if necessary, currently strictly filtering on MSN existence
-                    if let Some(station) = tiploc_map.get(tiploc) {
-                        trip.origin_name = station.name.clone();
-                        trip.stops.push(StopTime {
-                            trip_id: format!("{}_{}", trip.uid, trip.date_start),
-                            arrival_time: dep_sched.clone(),
-                            departure_time: dep_sched,
-                            stop_id: tiploc.to_string(),
-                            stop_sequence: seq_counter,
-                        });
-                        seq_counter += 1;
-                    }
+        }
+    }
+    Ok(())
+}
+
+/// Convert a CIF timetable, handing each trip to `on_trip` as it's
+/// completed. Agencies, routes and transfers are only known in full at the
+/// end, so they come back in the summary.
+fn parse_mca<R: Read + ?Sized>(
+    reader: &mut R,
+    file_name: &str,
+    tiploc_map: &mut HashMap<String, ParsedStation>,
+    ctx: &TimetableContext,
+    stats: &mut Stats,
+    on_trip: impl FnMut(ConvertedTrip) -> Result<()>,
+) -> Result<TimetableSummary> {
+    convert_records(
+        CifReader::new(BufReader::new(reader)),
+        file_name,
+        tiploc_map,
+        ctx,
+        stats,
+        on_trip,
+    )
+}
+
+/// Convert timetable records, in CIF order, into trips. `file_name` is
+/// where they came from, for rejects.csv.
+fn convert_records(
+    records: impl IntoIterator<Item = Result<CifRecord, CifError>>,
+    file_name: &str,
+    tiploc_map: &mut HashMap<String, ParsedStation>,
+    ctx: &TimetableContext,
+    stats: &mut Stats,
+    mut on_trip: impl FnMut(ConvertedTrip) -> Result<()>,
+) -> Result<TimetableSummary> {
+    let mut builder = ScheduleBuilder::new(*ctx);
+    // TIPLOCs added from TI/TA records, which later TA/TD records may change
+    let mut cif_tiplocs: HashSet<String> = HashSet::new();
+    let mut sources = Vec::new();
+
+    for record in records {
+        stats.records_read += 1;
+        let record = match record {
+            Ok(record) => record,
+            Err(CifError::Parse {
+                line,
+                record_type,
+                text,
+                source: error,
+            }) if ctx.on_error == CifErrorPolicy::Skip => {
+                if builder.rejected() < MAX_CIF_WARNINGS {
+                    println!("Warning: skipping CIF line {}: {}", line, error);
                 }
+                let schedule_record = matches!(
+                    record_type.as_str(),
+                    "BS" | "BX" | "LO" | "LI" | "CR" | "LT"
+                );
+                builder.reject(
+                    RejectedRecord {
+                        file: file_name.to_string(),
+                        line,
+                        record_type,
+                        reason: error.to_string(),
+                        text,
+                    },
+                    schedule_record,
+                );
+                continue;
             }
-            "LI" => {
-                if let Some(trip) = &mut current_trip {
-                    let tiploc = line.get(2..9).unwrap_or("").trim();
-                    let arr_sched = format_time(line.get(10..15).unwrap_or("00000"));
-                    let dep_sched = format_time(line.get(15..20).unwrap_or("00000"));
-
-                    let pub_arr = line.get(25..29).unwrap_or("0000");
-                    let pub_dep = line.get(29..33).unwrap_or("0000");
-
-                    // Filter operational stops: Must have public times AND exist in station map
-                    if pub_arr == "0000" && pub_dep == "0000" {
-                        continue;
-                    }
+            Err(e) => return Err(e).context("Failed to read timetable"),
+        };
 
-                    if tiploc_map.contains_key(tiploc) {
-                        trip.stops.push(StopTime {
-                            trip_id: format!("{}_{}", trip.uid, trip.date_start),
-                            arrival_time: arr_sched,
-                            departure_time: dep_sched,
-                            stop_id: tiploc.to_string(),
-                            stop_sequence: seq_counter,
-                        });
-                        seq_counter += 1;
+        match record {
+            CifRecord::TiplocInsert(ti) => {
+                if tiploc_map.contains_key(&ti.tiploc) {
+                    continue;
+                }
+                match cif_location_station(&ti, ctx.locations) {
+                    Some((station, source)) => {
+                        stats.station_located(source);
+                        cif_tiplocs.insert(ti.tiploc.clone());
+                        tiploc_map.insert(ti.tiploc, station);
                     }
+                    None => stats.unlocated_tiplocs += 1,
                 }
             }
-            "LT" => {
-                if let Some(trip) = &mut current_trip {
-                    let tiploc = line.get(2..9).unwrap_or("").trim();
-                    let arr_sched = format_time(line.get(10..15).unwrap_or("00000"));
-
-                    if let Some(station) = tiploc_map.get(tiploc) {
-                        trip.dest_name = station.name.clone();
-                        trip.stops.push(StopTime {
-                            trip_id: format!("{}_{}", trip.uid, trip.date_start),
-                            arrival_time: arr_sched.clone(),
-                            departure_time: arr_sched,
-                            stop_id: tiploc.to_string(),
-                            stop_sequence: seq_counter,
-                        });
-
-                        // Routes & Agencies
-                        let agency_name = toc_lookup
-                            .get(&trip.atoc_code)
-                            .cloned()
-                            .unwrap_or_else(|| format!("National Rail ({})", trip.atoc_code));
-
-                        let mut route_id = format!("{}_{}", trip.atoc_code, trip.origin_name);
-                        let mut route_name = format!("{} to {}", trip.origin_name, trip.dest_name);
-                        let mut route_short_name = "".to_string();
-                        let mut route_color = "".to_string(); // Default (or undefined)
-                        let mut route_text_color = "000000".to_string(); // Default Black
-
-                        if trip.atoc_code == "XR" {
-                            route_id = "XR-ELIZABETH".to_string();
-                            route_name = "Elizabeth line".to_string();
-                            route_color = "6950a1".to_string(); // TfL Purple
-                            route_text_color = "FFFFFF".to_string();
-                        }
-
-                        if trip.atoc_code == "LO" {
-                            let (name, id, color) = get_lo_line_details(&trip.stops, tiploc_map);
-                            if !name.is_empty() {
-                                route_id = id;
-                                route_name = name;
-                                route_color = color;
-                                route_text_color = "FFFFFF".to_string();
-                            }
-                        }
-
-                        if trip.atoc_code == "GW" {
-                            route_color = "0a493e".to_string();
-                            route_text_color = "FFFFFF".to_string();
-                        }
-
-                        if trip.atoc_code == "GX" {
-                            route_id = "GX-GATWICK".to_string();
-                            route_name = "Gatwick Express".to_string();
-                            route_color = "DC0A1E".to_string();
-                            route_text_color = "000000".to_string();
-                        }
-
-                        if trip.atoc_code == "HX" {
-                            route_id = "HX-HEATHROW".to_string();
-                            route_name = "Heathrow Express".to_string();
-                        }
-
-                        if trip.atoc_code == "ME" {
-                            let (name, id) = get_me_line_details(&trip.stops, tiploc_map);
-                            route_id = id;
-                            route_name = name;
-                        }
-
-                        if trip.atoc_code == "GW" {
-                            route_color = "0a493e".to_string();
-                            route_text_color = "FFFFFF".to_string();
-                        }
-                        if trip.atoc_code == "GX" {
-                            route_id = "GX-GATWICK".to_string();
-                            route_name = "Gatwick Express".to_string();
-                            route_color = "DC0A1E".to_string();
-                            route_text_color = "000000".to_string();
-                        }
-
-                        if trip.atoc_code == "HX" {
-                            route_id = "HX-HEATHROW".to_string();
-                            route_name = "Heathrow Express".to_string();
-                        }
-
-                        if trip.atoc_code == "ME" {
-                            let (name, id) = get_me_line_details(&trip.stops, tiploc_map);
-                            route_id = id;
-                            route_name = name;
-                        }
-
-                        agencies_set.insert(Agency {
-                            agency_id: trip.atoc_code.clone(),
-                            agency_name: agency_name,
-                            agency_url: "http://www.nationalrail.co.uk".to_string(),
-                            agency_timezone: "Europe/London".to_string(),
-                        });
-
-                        routes_map.entry(route_id.clone()).or_insert(Route {
-                            route_id: route_id.clone(),
-                            agency_id: trip.atoc_code.clone(),
-                            route_short_name,
-                            route_long_name: route_name,
-                            route_type: 2,
-                            route_color,
-                            route_text_color,
-                        });
-
-                        trips_w.serialize(Trip {
-                            route_id: route_id,
-                            service_id: format!(
-                                "{}_{}_{}",
-                                trip.uid, trip.date_start, trip.stp_ind
-                            ),
-                            trip_id: format!("{}_{}", trip.uid, trip.date_start),
-                            trip_headsign: trip.dest_name.clone(),
-                            trip_short_name: trip.train_identity.clone(),
-                        })?;
-
-                        for stop in &trip.stops {
-                            st_w.serialize(stop)?;
-                        }
-                    }
+            CifRecord::TiplocAmend(ta) => {
+                let mut location = ta.location;
+                if !cif_tiplocs.contains(&location.tiploc) {
+                    continue;
+                }
+                if let Some(new_tiploc) = ta.new_tiploc {
+                    cif_tiplocs.remove(&location.tiploc);
+                    tiploc_map.remove(&location.tiploc);
+                    location.tiploc = new_tiploc;
                 }
+                if let Some((station, _)) = cif_location_station(&location, ctx.locations) {
+                    cif_tiplocs.insert(location.tiploc.clone());
+                    tiploc_map.insert(location.tiploc, station);
+                }
+            }
+            CifRecord::TiplocDelete(td) if cif_tiplocs.remove(&td.tiploc) => {
+                tiploc_map.remove(&td.tiploc);
             }
-            "AA" => {
-                assoc_w.serialize(Association {
-                    base_uid: line.get(3..9).unwrap_or("").to_string(),
-                    assoc_uid: line.get(9..15).unwrap_or("").to_string(),
-                    start_date: format!("20{}", line.get(15..21).unwrap_or("")),
-                    end_date: format!("20{}", line.get(21..27).unwrap_or("")),
-                    days_run: line.get(27..34).unwrap_or("").to_string(),
-                    category: line.get(34..36).unwrap_or("").to_string(),
-                    location: line.get(37..44).unwrap_or("").trim().to_string(),
-                    assoc_type: line.get(47..48).unwrap_or("").to_string(),
-                    stp_indicator: line.get(79..80).unwrap_or("").to_string(),
-                })?;
+            CifRecord::Association(aa) => builder.push_association(aa),
+            CifRecord::BasicSchedule(bs) => {
+                for trip in builder.push_bs(bs, tiploc_map, stats) {
+                    on_trip(trip)?;
+                }
             }
+            CifRecord::BasicScheduleExtra(bx) => builder.push_bx(bx),
+            CifRecord::OriginLocation(lo) => builder.push_lo(lo, tiploc_map),
+            CifRecord::IntermediateLocation(li) => builder.push_li(li, tiploc_map),
+            CifRecord::TerminatingLocation(lt) => builder.push_lt(lt, tiploc_map, stats),
+            CifRecord::Header(hd) => sources.push(manifest::Source::cif(file_name, &hd)),
             _ => {}
         }
     }
-    Ok(())
+    for trip in builder.flush(tiploc_map, stats) {
+        on_trip(trip)?;
+    }
+    for trip in builder.finish_portions() {
+        on_trip(trip)?;
+    }
+
+    if stats.bad_time_trips > 0 {
+        println!(
+            "{} schedules had times running backwards ({}).",
+            stats.bad_time_trips,
+            match ctx.on_bad_times {
+                BadTimesPolicy::Keep => "kept",
+                BadTimesPolicy::Drop => "dropped",
+            }
+        );
+    }
+    if !cif_tiplocs.is_empty() || stats.unlocated_tiplocs > 0 {
+        println!(
+            "Added {} TIPLOCs from the timetable; {} more have no known coordinates.",
+            cif_tiplocs.len(),
+            stats.unlocated_tiplocs
+        );
+    }
+    let mut summary = builder.finish();
+    summary.sources = sources;
+    Ok(summary)
 }
 
-fn format_time(raw: &str) -> String {
-    let clean: String = raw.chars().filter(|c| c.is_numeric()).collect();
-    if clean.len() >= 4 {
-        format!("{}:{}:00", &clean[0..2], &clean[2..4])
-    } else {
-        "00:00:00".to_string()
+/// Format a date the way CIF writes it, yymmdd
+fn cif_date(date: NaiveDate) -> String {
+    date.format("%y%m%d").to_string()
+}
+
+/// A hash of the calendar itself rather than the order schedules are read
+/// in, so it's the same from build to build. The first 128 bits of a
+/// SHA-256, as calendars sharing an id are taken to be the same one and a
+/// national timetable has too many for 64 bits to be safe. Schedules kept
+/// off bank holidays, or that give dates up to another schedule, get a
+/// calendar of their own.
+fn service_hash(
+    days_run: &str,
+    start: NaiveDate,
+    end: NaiveDate,
+    excludes_bank_holidays: bool,
+    superseded: &BTreeSet<NaiveDate>,
+) -> u128 {
+    let mut signature = format!("{}_{}_{}", days_run, start, end);
+    if excludes_bank_holidays {
+        signature.push_str("_X");
     }
+    for date in superseded {
+        signature.push_str(&format!("_-{}", date));
+    }
+    hash128(signature.as_bytes())
+}
+
+/// The first 128 bits of the SHA-256 of `bytes`, for ids that have to stay
+/// the same from build to build
+fn hash128(bytes: &[u8]) -> u128 {
+    let digest = Sha256::digest(bytes);
+    let mut hash = [0; 16];
+    hash.copy_from_slice(&digest[..16]);
+    u128::from_be_bytes(hash)
+}
+
+/// The service id of a calendar: its hash in hex
+fn service_id(hash: u128) -> String {
+    format!("{:032x}", hash)
+}
+
+/// Removals for the bank holidays a service would otherwise run on
+fn bank_holiday_exceptions(
+    service_id: &str,
+    days_run: &str,
+    start: NaiveDate,
+    end: NaiveDate,
+    bank_holidays: &BankHolidays,
+) -> Vec<CalendarDate> {
+    bank_holidays
+        .between(start, end)
+        .filter(|date| {
+            let day = date.weekday().num_days_from_monday() as usize;
+            days_run.as_bytes().get(day) == Some(&b'1')
+        })
+        .map(|date| CalendarDate {
+            service_id: service_id.to_string(),
+            date: date.format("%Y%m%d").to_string(),
+            exception_type: 2,
+        })
+        .collect()
 }
 
-fn get_lo_line_details(
-    stops: &[StopTime],
-    tiploc_map: &HashMap<String, ParsedStation>,
-) -> (String, String, String) {
-    let mut names: HashSet<String> = HashSet::new();
+/// The narrowest calendar running on the same dates: the range trimmed to
+/// days the service runs, and days of the week it never reaches cleared.
+/// Schedules whose calendars differ only in these ways then share one.
+fn normalise_calendar(
+    days_run: &str,
+    start: NaiveDate,
+    end: NaiveDate,
+) -> (String, NaiveDate, NaiveDate) {
+    let runs_on = |day: u32| days_run.as_bytes().get(day as usize) == Some(&b'1');
+    let runs = |date: &NaiveDate| runs_on(date.weekday().num_days_from_monday());
+    let Some(first) = start.iter_days().take_while(|d| *d <= end).find(runs) else {
+        return (days_run.to_string(), start, end);
+    };
+    let mut last = end;
+    while !runs(&last) {
+        last = last.pred_opt().expect("first is a running day before last");
+    }
+    let reached: HashSet<u32> = first
+        .iter_days()
+        .take_while(|d| *d <= last)
+        .take(7)
+        .map(|d| d.weekday().num_days_from_monday())
+        .collect();
+    let days = (0..7)
+        .map(|day| {
+            if runs_on(day) && reached.contains(&day) {
+                '1'
+            } else {
+                '0'
+            }
+        })
+        .collect();
+    (days, first, last)
+}
 
+/// Build a calendar row from a CIF days-run mask and date range
+fn build_calendar(service_id: &str, days_run: &str, start: NaiveDate, end: NaiveDate) -> Calendar {
+    let d_vec: Vec<u8> = days_run
+        .chars()
+        .map(|c| if c == '1' { 1 } else { 0 })
+        .collect();
+    Calendar {
+        service_id: service_id.to_string(),
+        monday: *d_vec.first().unwrap_or(&0),
+        tuesday: *d_vec.get(1).unwrap_or(&0),
+        wednesday: *d_vec.get(2).unwrap_or(&0),
+        thursday: *d_vec.get(3).unwrap_or(&0),
+        friday: *d_vec.get(4).unwrap_or(&0),
+        saturday: *d_vec.get(5).unwrap_or(&0),
+        sunday: *d_vec.get(6).unwrap_or(&0),
+        start_date: start.format("%Y%m%d").to_string(),
+        end_date: end.format("%Y%m%d").to_string(),
+    }
+}
+
+/// Seconds past midnight of a GTFS `HH:MM:SS` time. Feeds read back may pad
+/// it with spaces.
+fn gtfs_seconds(time: &str) -> Option<u32> {
+    let mut parts = time.trim().split(':').map(|p| p.parse::<u32>().ok());
+    Some(parts.next()?? * 3600 + parts.next()?? * 60 + parts.next()??)
+}
+
+fn gtfs_time(secs: u32) -> String {
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Carry times past midnight into 24:00:00 and beyond, as GTFS expects.
+/// The CIF gives only the time of day, so a large step backwards is the
+/// train running past midnight. Returns false if times still run backwards
+/// after that, which is a data error.
+fn repair_times(stops: &mut [StopTime]) -> bool {
+    let mut monotonic = true;
+    let mut offset = 0;
+    let mut previous: Option<u32> = None;
     for stop in stops {
-        if let Some(station) = tiploc_map.get(&stop.stop_id) {
-            names.insert(station.name.clone());
+        for time in [&mut stop.arrival_time, &mut stop.departure_time] {
+            let Some(secs) = gtfs_seconds(time) else {
+                continue;
+            };
+            let mut secs = secs + offset;
+            if let Some(prev) = previous
+                && secs < prev
+            {
+                if prev - secs >= MIDNIGHT_ROLLOVER_SECS {
+                    offset += 24 * 3600;
+                    secs += 24 * 3600;
+                } else {
+                    monotonic = false;
+                }
+            }
+            if offset > 0 {
+                *time = gtfs_time(secs);
+            }
+            previous = Some(previous.map_or(secs, |prev| prev.max(secs)));
         }
     }
+    monotonic
+}
 
-    let has = |s: &str| -> bool {
-        names
-            .iter()
-            .any(|n| n.to_uppercase().contains(&s.to_uppercase()))
+/// Stop rows for a station: a plain stop, or a parent station with a child
+/// stop per platform called at and its OSM entrances. A station also called
+/// at without a platform keeps its TIPLOC stop under a separate
+/// `{TIPLOC}-STN` parent, as stop_times may not reference a parent station.
+fn station_stops(
+    station: &ParsedStation,
+    calls: Option<&BTreeSet<Option<String>>>,
+    details: &StationDetails,
+    entrances: &[Entrance],
+) -> Vec<Stop> {
+    let stop = |stop_id: String, location_type, parent_station, platform_code| Stop {
+        stop_id,
+        stop_code: details.stop_code.clone(),
+        stop_name: details
+            .pretty_name
+            .clone()
+            .unwrap_or_else(|| station.name.clone()),
+        stop_lat: station.lat,
+        stop_lon: station.lon,
+        zone_id: details.zone_id.clone(),
+        stop_url: details.stop_url.clone(),
+        location_type,
+        parent_station,
+        platform_code,
+        wheelchair_boarding: details.wheelchair_boarding,
+        level_id: None,
+        stop_name_raw: details.pretty_name.as_ref().map(|_| station.name.clone()),
     };
 
-    if has("GOSPEL OAK") && has("BARKING") {
-        return (
-            "Suffragette Line".to_string(),
-            "LO-SUFFRAGETTE".to_string(),
-            "008163".to_string(),
-        );
+    let platforms: Vec<&String> = calls.into_iter().flatten().flatten().collect();
+    if platforms.is_empty() && entrances.is_empty() {
+        return vec![stop(station.tiploc.clone(), None, None, None)];
     }
 
-    if has("ROMFORD") && has("UPMINSTER") {
-        return (
-            "Liberty Line".to_string(),
-            "LO-LIBERTY".to_string(),
-            "676767".to_string(),
+    // Entrances need a parent station even where there are no platforms
+    let called_without_platform = platforms.is_empty() || calls.is_some_and(|c| c.contains(&None));
+    let parent_id = if called_without_platform {
+        format!("{}-STN", station.tiploc)
+    } else {
+        station.tiploc.clone()
+    };
+    let mut stops = vec![stop(parent_id.clone(), Some(1), None, None)];
+    if called_without_platform {
+        stops.push(stop(
+            station.tiploc.clone(),
+            Some(0),
+            Some(parent_id.clone()),
+            None,
+        ));
+    }
+    for platform in platforms {
+        stops.push(stop(
+            format!("{}_{}", station.tiploc, platform),
+            Some(0),
+            Some(parent_id.clone()),
+            Some(platform.clone()),
+        ));
+    }
+    for entrance in entrances {
+        let base = stop(
+            format!("{}-ENT-{}", station.tiploc, entrance.osm_id),
+            Some(2),
+            Some(parent_id.clone()),
+            None,
         );
+        // OSM names are already written as they should be
+        stops.push(Stop {
+            stop_name: entrance.name.clone().unwrap_or(base.stop_name.clone()),
+            stop_name_raw: base
+                .stop_name_raw
+                .clone()
+                .map(|raw| entrance.name.clone().unwrap_or(raw)),
+            stop_lat: entrance.lat,
+            stop_lon: entrance.lon,
+            wheelchair_boarding: entrance.wheelchair_boarding.or(details.wheelchair_boarding),
+            ..base
+        });
     }
+    stops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::Fixture;
+    use writer::MemoryFeed;
 
-    if has("LIVERPOOL STREET") && (has("CHESHUNT") || has("ENFIELD TOWN") || has("CHINGFORD")) {
-        return (
-            "Weaver Line".to_string(),
-            "LO-WEAVER".to_string(),
-            "a90068".to_string(),
+    #[test]
+    fn test_calendars_running_on_the_same_dates_are_merged() {
+        let date = |s: &str| NaiveDate::parse_from_str(s, "%Y%m%d").unwrap();
+        let weekdays = normalise_calendar("1111100", date("20240101"), date("20240331"));
+        assert_eq!(
+            weekdays,
+            ("1111100".to_string(), date("20240101"), date("20240329"))
+        );
+        // Contained in the above, starting on a Sunday
+        assert_eq!(
+            normalise_calendar("1111100", date("20231231"), date("20240329")),
+            weekdays
+        );
+
+        // A two-day overlay only reaches two days of the week
+        assert_eq!(
+            normalise_calendar("1111111", date("20240106"), date("20240107")),
+            ("0000011".to_string(), date("20240106"), date("20240107"))
         );
     }
 
-    if has("EUSTON") && has("WATFORD JUNCTION") {
-        return (
-            "Lioness Line".to_string(),
-            "LO-LIONESS".to_string(),
-            "f1b41c".to_string(),
+    #[test]
+    fn test_service_id_is_derived_from_calendar() {
+        let date = |s: &str| NaiveDate::parse_from_str(s, "%Y%m%d").unwrap();
+        let service_id = |days_run, start, end, excludes_bank_holidays| {
+            service_id(service_hash(
+                days_run,
+                date(start),
+                date(end),
+                excludes_bank_holidays,
+                &BTreeSet::new(),
+            ))
+        };
+        let weekdays = service_id("1111100", "20240101", "20240331", false);
+        assert_eq!(
+            weekdays,
+            service_id("1111100", "20240101", "20240331", false)
+        );
+        assert_eq!(weekdays, "92d4f3b6d5d26c04d0d144e0520c252d");
+        assert_ne!(
+            weekdays,
+            service_id("1111110", "20240101", "20240331", false)
         );
+        assert_ne!(
+            weekdays,
+            service_id("1111100", "20240101", "20240330", false)
+        );
+        assert_ne!(
+            weekdays,
+            service_id("1111100", "20240101", "20240331", true)
+        );
+    }
+
+    #[test]
+    fn test_bank_holiday_exceptions_fall_on_running_days() {
+        let date = |s: &str| NaiveDate::parse_from_str(s, "%Y%m%d").unwrap();
+        let holidays = BankHolidays::builtin();
+        let removed = |days_run: &str| -> Vec<String> {
+            bank_holiday_exceptions("S", days_run, date("20250401"), date("20250531"), &holidays)
+                .into_iter()
+                .map(|cd| cd.date)
+                .collect()
+        };
+        assert_eq!(removed("1111100"), ["20250421", "20250505", "20250526"]);
+        assert!(removed("0000011").is_empty());
     }
 
-    if has("SHOREDITCH HIGH STREET") {
-        return (
-            "Windrush Line".to_string(),
-            "LO-WINDRUSH".to_string(),
-            "dc2517".to_string(),
+    #[test]
+    fn test_repair_times_rolls_over_midnight() {
+        let call = |arrival: &str, departure: &str| StopTime {
+            trip_id: "t".to_string(),
+            arrival_time: arrival.to_string(),
+            departure_time: departure.to_string(),
+            stop_id: String::new(),
+            stop_sequence: 0,
+            stop_headsign: None,
+            pickup_type: None,
+            drop_off_type: None,
+            shape_dist_traveled: None,
+            tiploc: String::new(),
+            platform: None,
+        };
+        let times = |stops: &[StopTime]| -> Vec<(String, String)> {
+            stops
+                .iter()
+                .map(|s| (s.arrival_time.clone(), s.departure_time.clone()))
+                .collect()
+        };
+
+        let mut overnight = vec![
+            call("23:40:00", "23:40:00"),
+            call("23:58:00", "00:01:00"),
+            call("00:30:00", "00:30:00"),
+        ];
+        assert!(repair_times(&mut overnight));
+        assert_eq!(
+            times(&overnight),
+            vec![
+                ("23:40:00".to_string(), "23:40:00".to_string()),
+                ("23:58:00".to_string(), "24:01:00".to_string()),
+                ("24:30:00".to_string(), "24:30:00".to_string()),
+            ]
         );
+
+        let mut backwards = vec![call("10:00:00", "10:05:00"), call("09:50:00", "09:50:00")];
+        assert!(!repair_times(&mut backwards));
+        assert_eq!(backwards[1].arrival_time, "09:50:00");
     }
 
-    if has("STRATFORD")
-        || (has("RICHMOND") && has("WILLESDEN JUNCTION"))
-        || has("CAMDEN ROAD")
-        || has("HACKNEY CENTRAL")
-    {
-        return (
-            "Mildmay Line".to_string(),
-            "LO-MILDMAY".to_string(),
-            "437ec1".to_string(),
+    #[test]
+    fn test_clip_dates_to_window() {
+        let date = |s: &str| NaiveDate::parse_from_str(s, "%Y%m%d").unwrap();
+        let filters = Filters {
+            from_date: Some(date("20240301")),
+            to_date: Some(date("20240310")),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            filters.clip_dates(date("20240101"), date("20240601"), "1111100"),
+            Some((date("20240301"), date("20240310")))
+        );
+        assert_eq!(
+            filters.clip_dates(date("20240101"), date("20240228"), "1111111"),
+            None
+        );
+        // 2024-03-01 is a Friday; a Sunday-only schedule ending that day never runs
+        assert_eq!(
+            filters.clip_dates(date("20240101"), date("20240301"), "0000001"),
+            None
         );
     }
 
-    (
-        "London Overground".to_string(),
-        "LO-GENERIC".to_string(),
-        "E66A1F".to_string(),
-    )
-}
+    #[test]
+    fn test_station_stops_for_platforms() {
+        let station = ParsedStation {
+            tiploc: "KNGX".to_string(),
+            name: "London Kings Cross".to_string(),
+            crs: "KGX".to_string(),
+            lat: 51.53,
+            lon: -0.12,
+        };
+        let ids = |stops: &[Stop]| -> Vec<(String, Option<String>)> {
+            stops
+                .iter()
+                .map(|s| (s.stop_id.clone(), s.parent_station.clone()))
+                .collect()
+        };
+
+        let details = StationDetails {
+            stop_code: Some("KGX".to_string()),
+            stop_url: Some(station_url("KGX")),
+            ..Default::default()
+        };
+        let plain = station_stops(&station, None, &details, &[]);
+        assert_eq!(ids(&plain), vec![("KNGX".to_string(), None)]);
+        assert_eq!(plain[0].stop_code.as_deref(), Some("KGX"));
+        assert_eq!(
+            plain[0].stop_url.as_deref(),
+            Some("https://www.nationalrail.co.uk/stations/kgx/")
+        );
 
-fn get_me_line_details(
-    stops: &[StopTime],
-    tiploc_map: &HashMap<String, ParsedStation>,
-) -> (String, String) {
-    let mut names: HashSet<String> = HashSet::new();
-    let mut tiplocs: HashSet<String> = HashSet::new();
+        let calls: BTreeSet<Option<String>> = [Some("8".to_string())].into();
+        let stops = station_stops(&station, Some(&calls), &StationDetails::default(), &[]);
+        assert_eq!(
+            ids(&stops),
+            vec![
+                ("KNGX".to_string(), None),
+                ("KNGX_8".to_string(), Some("KNGX".to_string())),
+            ]
+        );
+        assert_eq!(stops[0].location_type, Some(1));
+        assert_eq!(stops[1].platform_code.as_deref(), Some("8"));
+
+        // Calls without a platform still need a stop that isn't the parent
+        let calls: BTreeSet<Option<String>> = [None, Some("8".to_string())].into();
+        let stops = station_stops(&station, Some(&calls), &StationDetails::default(), &[]);
+        assert_eq!(
+            ids(&stops),
+            vec![
+                ("KNGX-STN".to_string(), None),
+                ("KNGX".to_string(), Some("KNGX-STN".to_string())),
+                ("KNGX_8".to_string(), Some("KNGX-STN".to_string())),
+            ]
+        );
 
-    for stop in stops {
-        tiplocs.insert(stop.stop_id.clone());
-        if let Some(station) = tiploc_map.get(&stop.stop_id) {
-            names.insert(station.name.to_uppercase());
-        }
+        // Entrances give a station without platforms a parent
+        let entrance = Entrance {
+            osm_id: 42,
+            lat: 51.5316,
+            lon: -0.1236,
+            name: Some("Euston Road".to_string()),
+            wheelchair_boarding: None,
+        };
+        let stops = station_stops(&station, None, &StationDetails::default(), &[entrance]);
+        assert_eq!(
+            ids(&stops),
+            vec![
+                ("KNGX-STN".to_string(), None),
+                ("KNGX".to_string(), Some("KNGX-STN".to_string())),
+                ("KNGX-ENT-42".to_string(), Some("KNGX-STN".to_string())),
+            ]
+        );
+        assert_eq!(stops[2].location_type, Some(2));
+        assert_eq!(stops[2].stop_name, "Euston Road");
+        assert_eq!(stops[2].stop_lat, 51.5316);
     }
 
-    let has_name = |s: &str| -> bool { names.iter().any(|n| n.contains(&s.to_uppercase())) };
+    #[test]
+    fn test_parse_msn_grid_references() {
+        let record = |name: &str, tiploc: &str, crs: &str, easting: &str, northing: &str| {
+            format!(
+                "A    {:<30}2{:<7}{:<3}   {:<3}{} {}15",
+                name, tiploc, crs, crs, easting, northing
+            )
+        };
+        let msn = [
+            record("LONDON KINGS CROSS", "KNGX", "KGX", "15303", "61832"),
+            record("EDINBURGH", "EDINBUR", "EDB", "13257", "66739"),
+            record("UNSURVEYED HALT", "NOWHERE", "ZZZ", "00000", "00000"),
+        ]
+        .join("\n");
+        let stations = parse_msn(
+            &mut msn.as_bytes(),
+            &LocationIndex::default(),
+            &CoordinatePolicy::default(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        let sources: Vec<&str> = stations.iter().map(|&(_, source)| source).collect();
+        assert_eq!(sources, ["msn_grid", "msn_grid", "unplaced"]);
+        let map: HashMap<String, ParsedStation> = stations
+            .into_iter()
+            .map(|(station, _)| (station.tiploc.clone(), station))
+            .collect();
+
+        let kgx = &map["KNGX"];
+        assert_eq!(kgx.name, "LONDON KINGS CROSS");
+        assert_eq!(kgx.crs, "KGX");
+        assert!((kgx.lat - 51.531).abs() < 0.005, "lat {}", kgx.lat);
+        assert!((kgx.lon - -0.123).abs() < 0.005, "lon {}", kgx.lon);
+
+        let edb = &map["EDINBUR"];
+        assert!((edb.lat - 55.952).abs() < 0.005, "lat {}", edb.lat);
+        assert!((edb.lon - -3.189).abs() < 0.005, "lon {}", edb.lon);
+
+        assert_eq!((map["NOWHERE"].lat, map["NOWHERE"].lon), (0.0, 0.0));
+
+        // Northings beyond 1000 km carry into the prefix digit
+        let lerwick = record("LERWICK", "LERWICK", "", "14478", "71415");
+        assert_eq!(msn_grid_reference(&lerwick), Some((447800.0, 1141500.0)));
+    }
 
-    let has_loc = |s: &str| -> bool { tiplocs.contains(s) };
+    #[test]
+    fn test_parse_msn_coordinate_conflicts() {
+        let msn = "A    LONDON KINGS CROSS            2KNGX   KGX   KGX15303 6183215\n\
+                   A    EDINBURGH                     2EDINBUREDB   EDB13257 6673915";
+        // Kings Cross is right; Edinburgh's node is tagged in Glasgow
+        let locations = LocationIndex {
+            osm_by_crs: HashMap::from([
+                ("KGX".to_string(), (51.5320, -0.1233)),
+                ("EDB".to_string(), (55.8587, -4.2583)),
+            ]),
+            ..Default::default()
+        };
+        let parse = |priority| {
+            let policy = CoordinatePolicy {
+                priority,
+                ..Default::default()
+            };
+            let mut conflicts = Vec::new();
+            let stations = parse_msn(&mut msn.as_bytes(), &locations, &policy, &mut conflicts);
+            (stations.unwrap(), conflicts)
+        };
+
+        let (stations, conflicts) = parse(CoordinatePriority::Osm);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].tiploc, "EDINBUR");
+        assert!(conflicts[0].distance_m > 60_000.0);
+        assert_eq!(conflicts[0].chosen, "osm");
+        assert_eq!(stations[1].1, "osm");
+
+        let (stations, conflicts) = parse(CoordinatePriority::Msn);
+        assert_eq!(conflicts[0].chosen, "msn_grid");
+        let sources: Vec<&str> = stations.iter().map(|&(_, source)| source).collect();
+        assert_eq!(sources, ["osm", "msn_grid"]);
+        assert!((stations[1].0.lon - -3.189).abs() < 0.005);
+    }
 
-    // Wirral Line
-    // "Ellesmere port station has the stop id ELSMPRT"
-    // "west kirby has WKIRBY"
-    // "New brighton is NBTN"
-    if has_loc("ELSMPRT") || has_loc("WKIRBY") || has_loc("NBTN") || has_name("CHESTER") {
-        return ("Wirral line".to_string(), "ME-WIRRAL".to_string());
+    #[test]
+    fn test_cif_location_station() {
+        let locations = LocationIndex {
+            osm_by_crs: HashMap::from([("SRA".to_string(), (51.5419, -0.0034))]),
+            naptan: HashMap::new(),
+            bplan: HashMap::new(),
+            corpus: HashMap::from([(
+                "STFDHL".to_string(),
+                CorpusEntry {
+                    crs: Some("SRA".to_string()),
+                    description: Some("STRATFORD HIGH LEVEL".to_string()),
+                },
+            )]),
+        };
+        let mut location = TiplocInsert {
+            tiploc: "STFD".to_string(),
+            nalco: None,
+            tps_description: Some("STRATFORD".to_string()),
+            stanox: None,
+            crs: Some("SRA".to_string()),
+            description: None,
+        };
+        let (station, _) = cif_location_station(&location, &locations).unwrap();
+        assert_eq!(station.name, "STRATFORD");
+        assert_eq!((station.lat, station.lon), (51.5419, -0.0034));
+
+        location.crs = None;
+        assert!(cif_location_station(&location, &locations).is_none());
+
+        // CORPUS fills in the CRS the TI record left out
+        location.tiploc = "STFDHL".to_string();
+        let (station, _) = cif_location_station(&location, &locations).unwrap();
+        assert_eq!(station.crs, "SRA");
     }
 
-    // Northern Line
-    // Southport, Ormskirk, Kirkby, Hunts Cross
-    if has_name("SOUTHPORT")
-        || has_name("ORMSKIRK")
-        || has_name("KIRKBY")
-        || has_name("HUNTS CROSS")
-    {
-        return ("Northern line".to_string(), "ME-NORTHERN".to_string());
+    #[test]
+    fn test_parse_bbox() {
+        let bbox = parse_bbox_arg("-7.6,54.6,-0.7,60.9").unwrap();
+        assert!(bbox.contains(55.95, -3.19)); // Edinburgh
+        assert!(!bbox.contains(51.53, -0.12)); // London
+        assert!(parse_bbox_arg("-7.6,54.6,-0.7").is_err());
+        assert!(parse_bbox_arg("-0.7,54.6,-7.6,60.9").is_err());
     }
 
-    // City Line
-    // Common stops: Huyton, St Helens, etc. (Heuristic fallback)
-    if has_name("HUYTON")
-        || has_name("ST HELENS")
-        || has_name("NEWTON-LE-WILLOWS")
-        || has_name("LIVERPOOL LIME STREET")
-    {
-        return ("City line".to_string(), "ME-CITY".to_string());
+    #[test]
+    fn test_fixture_stations_skip_the_header() {
+        let stations = Fixture::default().stations("sample.MSN");
+        let mut tiplocs: Vec<&str> = stations.keys().map(String::as_str).collect();
+        tiplocs.sort();
+        assert_eq!(tiplocs, ["BHAMNWS", "KNGX", "PBRO", "YORK"]);
     }
 
-    // Default
-    ("Merseyrail".to_string(), "ME-GENERIC".to_string())
-}
+    #[test]
+    fn test_convert_fixture_timetable() {
+        let (trips, summary, stats) = Fixture::default().convert("sample.MSN", "sample.MCA");
+
+        let ids: Vec<&str> = trips.iter().map(|t| t.trip.trip_id.as_str()).collect();
+        assert_eq!(ids, ["C10001_240101_P", "C20001_240101_P"]);
+        assert_eq!(stats.cancelled_schedules_skipped, 1);
+        assert_eq!(stats.unlocated_tiplocs, 1);
+
+        // The passing point and the sidings aren't calls
+        let lner = &trips[0];
+        let calls: Vec<&str> = lner.stop_times.iter().map(|s| s.tiploc.as_str()).collect();
+        assert_eq!(calls, ["KNGX", "PBRO", "YORK"]);
+        assert_eq!(lner.trip.trip_headsign, "YORK");
+        assert_eq!(lner.trip.block_id.as_deref(), Some("C10001_C20001"));
+        assert_eq!(lner.stop_times[1].stop_id, "PBRO_2");
+        assert_eq!(lner.trip.train_service_code.as_deref(), Some("23456789"));
+        let row = lner.id_map_row("gbrail:").unwrap();
+        assert_eq!((row.uid, row.stp_indicator), ("C10001", "P"));
+        assert_eq!(row.trip_id, format!("gbrail:{}", lner.trip.trip_id));
+        assert_eq!(row.start_date, "20240101");
+
+        // Both trips are timetabled on the same days, but the LNER one is
+        // cancelled on 1 March so needs a calendar of its own
+        let xc = &trips[1];
+        assert_ne!(xc.trip.service_id, lner.trip.service_id);
+        let lner_calendar = &summary.calendars[&lner.trip.service_id];
+        let removed: Vec<&str> = lner_calendar
+            .dates
+            .iter()
+            .map(|d| d.date.as_str())
+            .collect();
+        assert_eq!(removed, ["20240301"]);
+        assert!(summary.calendars[&xc.trip.service_id].dates.is_empty());
+        assert_eq!(xc.stop_times[1].arrival_time, "25:15:00");
+
+        let agencies: Vec<&str> = summary.agencies.keys().map(String::as_str).collect();
+        assert_eq!(agencies, ["GR", "XC"]);
+        assert_eq!(summary.agencies["XC"].agency_name, "CrossCountry");
+        assert_eq!(summary.associations.len(), 1);
+        assert!(summary.rejects.is_empty());
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_fixture_shapes_go_by_passing_points() {
+        let mut fixture = Fixture::default();
+        fixture.output_options.shapes = true;
+        let huntingdon = BplanLocation {
+            name: "HUNTINGDON".to_string(),
+            lat: 52.33,
+            lon: -0.19,
+        };
+        fixture
+            .locations
+            .bplan
+            .insert("HUNTNGN".to_string(), huntingdon);
+        let (trips, summary, _) = fixture.convert("sample.MSN", "sample.MCA");
+
+        let lner = &trips[0];
+        let shape = &summary.shapes[lner.trip.shape_id.as_deref().unwrap()];
+        assert_eq!(shape.len(), 4);
+        assert_eq!(shape[2].shape_pt_lat, 52.33);
+        let dists: Vec<f64> = lner
+            .stop_times
+            .iter()
+            .map(|s| s.shape_dist_traveled.unwrap())
+            .collect();
+        assert_eq!(dists[0], 0.0);
+        assert!(dists[1] < dists[2]);
+        assert_eq!(Some(dists[2]), shape[3].shape_dist_traveled);
+
+        let (trips, summary, _) = Fixture::default().convert("sample.MSN", "sample.MCA");
+        assert!(trips[0].trip.shape_id.is_none());
+        assert!(summary.shapes.is_empty());
+    }
 
     #[test]
-    fn test_merseyrail_wirral_line() {
-        let mut tiploc_map = HashMap::new();
-        tiploc_map.insert(
-            "WKIRBY".to_string(),
-            ParsedStation {
-                tiploc: "WKIRBY".to_string(),
-                name: "West Kirby".to_string(),
-                lat: 0.0,
-                lon: 0.0,
-            },
+    fn test_request_stops() {
+        let mca = test_support::fixture_text("sample.MCA").replace("FL     T", "FL     R");
+        let mut fixture = Fixture::default();
+        let (trips, _, _) = fixture.convert_text("sample.MSN", "sample.MCA", &mca);
+        let peterborough = &trips[0].stop_times[1];
+        assert_eq!(peterborough.tiploc, "PBRO");
+        assert_eq!(
+            (peterborough.pickup_type, peterborough.drop_off_type),
+            (Some(3), Some(3))
         );
+        assert_eq!(trips[0].stop_times[0].pickup_type, None);
 
-        let stops = vec![StopTime {
-            trip_id: "t1".to_string(),
-            arrival_time: "00:00".to_string(),
-            departure_time: "00:00".to_string(),
-            stop_id: "WKIRBY".to_string(),
-            stop_sequence: 1,
-        }];
+        fixture.output_options.request_stops = RequestStopPolicy::Omit;
+        let (trips, _, _) = fixture.convert_text("sample.MSN", "sample.MCA", &mca);
+        let calls: Vec<&str> = trips[0]
+            .stop_times
+            .iter()
+            .map(|s| s.tiploc.as_str())
+            .collect();
+        assert_eq!(calls, ["KNGX", "YORK"]);
+    }
 
-        let (name, id) = get_me_line_details(&stops, &tiploc_map);
-        assert_eq!(name, "Wirral line");
-        assert_eq!(id, "ME-WIRRAL");
+    #[test]
+    fn test_write_fixture_trips_to_memory() {
+        let (trips, summary, _) = Fixture::default().convert("sample.MSN", "sample.MCA");
+        let mut feed = MemoryFeed::default();
+        for trip in &trips {
+            write_converted_trip(&mut feed, trip).unwrap();
+        }
+        write_calendars(&mut feed, &summary.calendars, CalendarMode::Calendar).unwrap();
+        feed.finish().unwrap();
+
+        assert_eq!(feed.trips.len(), 2);
+        assert_eq!(feed.calendars.len(), 2);
+        assert_eq!(feed.calendar_dates.len(), 1);
+        assert_eq!(feed.stop_times.len(), 5);
+        assert!(
+            feed.stop_times
+                .iter()
+                .all(|st| feed.trips.iter().any(|t| t.trip_id == st.trip_id))
+        );
     }
 
     #[test]
-    fn test_merseyrail_northern_line() {
-        let mut tiploc_map = HashMap::new();
-        tiploc_map.insert(
-            "SOUTHPORT".to_string(),
-            ParsedStation {
-                tiploc: "SOUTHPORT".to_string(),
-                name: "Southport".to_string(),
-                lat: 0.0,
-                lon: 0.0,
+    fn test_malformed_record_is_rejected_with_its_schedule() {
+        let mca = test_support::fixture_text("sample.MCA")
+            .replace("LOYORK    2330 23305", "LOYORK    23x0 23305");
+        let fixture = Fixture::default();
+        let ctx = TimetableContext {
+            on_error: CifErrorPolicy::Skip,
+            ..fixture.context()
+        };
+        let mut tiploc_map = fixture.stations("sample.MSN");
+        let mut trips = Vec::new();
+        let summary = parse_mca(
+            &mut mca.as_bytes(),
+            "sample.MCA",
+            &mut tiploc_map,
+            &ctx,
+            &mut Stats::default(),
+            |trip| {
+                trips.push(trip);
+                Ok(())
             },
+        )
+        .unwrap();
+
+        // The rest of the broken schedule is dropped, the next one kept
+        let ids: Vec<&str> = trips.iter().map(|t| t.trip.trip_id.as_str()).collect();
+        assert_eq!(ids, ["C10001_240101_P"]);
+        assert_eq!(summary.rejects.len(), 1);
+        let reject = &summary.rejects[0];
+        assert_eq!(
+            (
+                reject.file.as_str(),
+                reject.line,
+                reject.record_type.as_str()
+            ),
+            ("sample.MCA", 13, "LO")
+        );
+        assert!(reject.text.starts_with("LOYORK    23x0"));
+    }
+
+    #[test]
+    fn test_trip_without_a_placed_origin_is_dropped() {
+        let mca = test_support::fixture_text("sample.MCA");
+        // An unknown origin leaves the CrossCountry trip a single call
+        let mca = mca.replace("LOYORK    2330", "LOYORKNY  2330");
+        let (trips, summary, stats) =
+            Fixture::default().convert_text("sample.MSN", "sample.MCA", &mca);
+
+        let ids: Vec<&str> = trips.iter().map(|t| t.trip.trip_id.as_str()).collect();
+        assert_eq!(ids, ["C10001_240101_P"]);
+        assert_eq!(stats.short_trips_dropped, 1);
+        assert_eq!(summary.dropped_stops.len(), 1);
+        let dropped = &summary.dropped_stops[0];
+        assert_eq!(
+            (
+                dropped.tiploc.as_str(),
+                dropped.uid.as_str(),
+                dropped.reason
+            ),
+            ("YORKNY", "C20001", "unknown_origin")
         );
+        assert!(dropped.trip_dropped);
+    }
 
-        let stops = vec![StopTime {
-            trip_id: "t2".to_string(),
-            arrival_time: "00:00".to_string(),
-            departure_time: "00:00".to_string(),
-            stop_id: "SOUTHPORT".to_string(),
-            stop_sequence: 1,
-        }];
+    #[test]
+    fn test_calendar_modes() {
+        let (trips, summary, _) = Fixture::default().convert("sample.MSN", "sample.MCA");
+        let write = |mode| {
+            let mut feed = MemoryFeed::default();
+            write_calendars(&mut feed, &summary.calendars, mode).unwrap();
+            feed
+        };
+
+        let dates_only = write(CalendarMode::DatesOnly);
+        assert!(dates_only.calendars.is_empty());
+        assert!(
+            dates_only
+                .calendar_dates
+                .iter()
+                .all(|d| d.exception_type == 1)
+        );
+        let lner_dates: Vec<&str> = dates_only
+            .calendar_dates
+            .iter()
+            .filter(|d| d.service_id == trips[0].trip.service_id)
+            .map(|d| d.date.as_str())
+            .collect();
+        assert_eq!(lner_dates.first(), Some(&"20240101"));
+        assert!(lner_dates.contains(&"20240229") && !lner_dates.contains(&"20240301"));
+
+        // A year of weekdays is shorter as a pattern
+        let hybrid = write(CalendarMode::Hybrid);
+        assert_eq!(hybrid.calendars.len(), 2);
+        assert_eq!(hybrid.calendar_dates.len(), 1);
+    }
 
-        let (name, id) = get_me_line_details(&stops, &tiploc_map);
-        assert_eq!(name, "Northern line");
-        assert_eq!(id, "ME-NORTHERN");
+    #[test]
+    fn test_overlay_replaces_the_permanent_schedule_on_its_dates() {
+        let mca = test_support::fixture_text("sample.MCA");
+        // A week's overlay of the LNER train, retimed at Peterborough
+        let permanent =
+            "BSNC100012401012412141111100 POO1A01    123456789 IEMU   100                   P";
+        let overlay = format!(
+            "{}\n{}",
+            permanent
+                .replace("240101241214", "240108240112")
+                .replace("   P", "   O"),
+            "BX         GRY\nLOKNGX    0900 09001         TB\nLIPBRO    1015H1016      10151016 2  FL     T\nLTYORK    1050 10503     TF"
+        );
+        let mca = mca.replacen(
+            "BSNC100012403012403010000100",
+            &format!("{}\nBSNC100012403012403010000100", overlay),
+            1,
+        );
+        let (trips, summary, stats) =
+            Fixture::default().convert_text("sample.MSN", "sample.MCA", &mca);
+
+        let ids: Vec<&str> = trips.iter().map(|t| t.trip.trip_id.as_str()).collect();
+        assert_eq!(
+            ids,
+            ["C10001_240101_P", "C10001_240108_O", "C20001_240101_P"]
+        );
+        let removed = |trip: &ConvertedTrip| -> Vec<String> {
+            summary.calendars[&trip.trip.service_id]
+                .dates
+                .iter()
+                .map(|d| d.date.clone())
+                .collect()
+        };
+        assert_eq!(
+            removed(&trips[0]),
+            [
+                "20240108", "20240109", "20240110", "20240111", "20240112", "20240301"
+            ]
+        );
+        assert!(removed(&trips[1]).is_empty());
+        assert_eq!(stats.superseded_schedules, 0);
     }
 
     #[test]
-    fn test_merseyrail_city_line() {
-        let mut tiploc_map = HashMap::new();
-        tiploc_map.insert(
-            "HUYTON".to_string(),
-            ParsedStation {
-                tiploc: "HUYTON".to_string(),
-                name: "Huyton".to_string(),
-                lat: 0.0,
-                lon: 0.0,
-            },
+    fn test_overlay_cut_short_is_marked_as_a_short_working() {
+        let mca = test_support::fixture_text("sample.MCA");
+        // A week's overlay of the LNER train terminating at Peterborough
+        let permanent =
+            "BSNC100012401012412141111100 POO1A01    123456789 IEMU   100                   P";
+        let overlay = format!(
+            "{}\n{}",
+            permanent
+                .replace("240101241214", "240108240112")
+                .replace("   P", "   O"),
+            "BX         GRY\nLOKNGX    0900 09001         TB\nLTPBRO    1015 10152     TF"
+        );
+        let mca = mca.replacen(
+            "BSNC100012403012403010000100",
+            &format!("{}\nBSNC100012403012403010000100", overlay),
+            1,
         );
+        let headsigns = |short_workings| -> Vec<String> {
+            let mut fixture = Fixture::default();
+            fixture.output_options.headsign = HeadsignPolicy {
+                via: false,
+                short_workings,
+            };
+            let (trips, _, _) = fixture.convert_text("sample.MSN", "sample.MCA", &mca);
+            trips.into_iter().map(|t| t.trip.trip_headsign).collect()
+        };
+
+        assert_eq!(
+            headsigns(true),
+            [
+                "YORK",
+                "PETERBOROUGH (short working)",
+                "BIRMINGHAM NEW STREET"
+            ]
+        );
+        assert_eq!(headsigns(false)[1], "PETERBOROUGH");
+    }
 
-        let stops = vec![StopTime {
-            trip_id: "t3".to_string(),
-            arrival_time: "00:00".to_string(),
-            departure_time: "00:00".to_string(),
-            stop_id: "HUYTON".to_string(),
-            stop_sequence: 1,
-        }];
+    #[test]
+    fn test_overnight_trip_across_a_clock_change() {
+        let mca = test_support::fixture_text("sample.MCA");
+        // Run the CrossCountry train every night, arriving at 03:00
+        let mca = mca
+            .replace(
+                "BSNC200012401012412141111100",
+                "BSNC200012401012412141111111",
+            )
+            .replace("LTBHAMNWS 0115 01152", "LTBHAMNWS 0300 03002");
+        let (trips, summary, stats) =
+            Fixture::default().convert_text("sample.MSN", "sample.MCA", &mca);
+
+        let ids: Vec<&str> = trips.iter().map(|t| t.trip.trip_id.as_str()).collect();
+        assert_eq!(
+            ids,
+            [
+                "C10001_240101_P",
+                "C20001_240101_P",
+                "C20001_240101_P_20240330",
+                "C20001_240101_P_20241026"
+            ]
+        );
+        assert_eq!(stats.clock_change_trips, 2);
+        let arrival = |trip: &ConvertedTrip| trip.stop_times[1].arrival_time.clone();
+        assert_eq!(arrival(&trips[1]), "27:00:00");
+        // An hour less on the night the clocks go forward, and more when
+        // they go back
+        assert_eq!(arrival(&trips[2]), "26:00:00");
+        assert_eq!(arrival(&trips[3]), "28:00:00");
+        assert_eq!(trips[2].stop_times[0].trip_id, "C20001_240101_P_20240330");
+
+        let removed: Vec<&str> = summary.calendars[&trips[1].trip.service_id]
+            .dates
+            .iter()
+            .map(|d| d.date.as_str())
+            .collect();
+        assert_eq!(removed, ["20240330", "20241026"]);
+        let spring = &summary.calendars[&trips[2].trip.service_id].calendar;
+        assert_eq!(
+            (spring.start_date.as_str(), spring.end_date.as_str()),
+            ("20240330", "20240330")
+        );
+    }
 
-        let (name, id) = get_me_line_details(&stops, &tiploc_map);
-        assert_eq!(name, "City line");
-        assert_eq!(id, "ME-CITY");
+    #[test]
+    fn test_trip_before_the_autumn_service_day_keeps_its_times() {
+        let mca = test_support::fixture_text("sample.MCA");
+        // The CrossCountry train every night just after midnight
+        let mca = mca
+            .replace(
+                "BSNC200012401012412141111100",
+                "BSNC200012401012412141111111",
+            )
+            .replace("LOYORK    2330 23305", "LOYORK    0030 00305")
+            .replace("LTBHAMNWS 0115 01152", "LTBHAMNWS 0300 03002");
+        let (trips, _, stats) = Fixture::default().convert_text("sample.MSN", "sample.MCA", &mca);
+
+        // Shifted on the Sunday the clocks go forward, but 00:30 comes
+        // before the service day starts when they go back
+        assert_eq!(stats.clock_change_trips, 1);
+        assert_eq!(stats.clock_change_dates_unshifted, 1);
+        assert_eq!(
+            trips.last().unwrap().trip.trip_id,
+            "C20001_240101_P_20240331"
+        );
     }
 
+    /// The whole conversion of a 100,000-line extract. Time it with
+    /// `cargo test --release -- --ignored --nocapture`.
     #[test]
-    fn test_merseyrail_generic() {
-        let tiploc_map = HashMap::new();
-        let stops = vec![];
-        let (name, id) = get_me_line_details(&stops, &tiploc_map);
-        assert_eq!(name, "Merseyrail");
-        assert_eq!(id, "ME-GENERIC");
+    #[ignore]
+    fn test_convert_synthetic_extract() {
+        let mca = test_support::synthetic_mca(100_000);
+        let started = std::time::Instant::now();
+        let (trips, _, _) = Fixture::default().convert_text("sample.MSN", "synthetic.MCA", &mca);
+        println!(
+            "Converted {} lines into {} trips in {:?}",
+            mca.lines().count(),
+            trips.len(),
+            started.elapsed()
+        );
+        // Each copy of the sample adds the LNER and CrossCountry trains
+        assert_eq!(trips.len(), 2 * mca.matches("BX         XCY").count());
+    }
+
+    #[test]
+    fn test_operator_of_schedule_without_bx() {
+        let sample = test_support::fixture_text("sample.MCA");
+        let mca = sample.replace("BX         XCY\n", "");
+
+        // The LNER train shares its service code, but not its operator
+        let (trips, summary, stats) =
+            Fixture::default().convert_text("sample.MSN", "sample.MCA", &mca);
+        assert_eq!(trips[1].atoc_code, "ZZ");
+        assert_eq!(summary.agencies["ZZ"].agency_name, "Unknown operator");
+        assert_eq!(stats.operators_inferred, 0);
+        assert_eq!(stats.unknown_operator_trips, 1);
+
+        // An overlay without one takes the operator of the schedule it changes
+        let overlay = "BSNC200012403042403041000000 POO1S99    123456789 IEMU   100                   O\n\
+                       LOYORK    2340 23405         TB\n\
+                       LTBHAMNWS 0125 01252     TF\n";
+        let mca = sample.replace("ZZ", &format!("{}ZZ", overlay));
+        let (trips, _, stats) = Fixture::default().convert_text("sample.MSN", "sample.MCA", &mca);
+        let overlay = trips
+            .iter()
+            .find(|t| t.trip.trip_id.contains("_O"))
+            .unwrap();
+        assert_eq!(overlay.atoc_code, "XC");
+        assert_eq!(stats.operators_inferred, 1);
+        assert_eq!(stats.unknown_operator_trips, 0);
     }
 }
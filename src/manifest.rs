@@ -0,0 +1,161 @@
+//! `manifest.json`: what a converted feed is made of.
+//!
+//! Every file in the output directory is listed with its size, SHA-256 and,
+//! for CSV files, its row count (header excluded), so a consumer can tell
+//! a complete feed from a partial upload. The timetable extracts it was
+//! built from are listed too, by their CIF header where they have one.
+
+use anyhow::{Context, Result};
+use chrono::{NaiveDate, Utc};
+use nationalrail_gtfs::cif::Header;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::Path;
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// An input the feed was built from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Source {
+    pub file: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mainframe_identity: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_file_reference: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_file_reference: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extract_date: Option<NaiveDate>,
+    /// `F` for a full extract, `U` for an update
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub update_indicator: Option<char>,
+}
+
+impl Source {
+    /// A file known only by its name
+    pub fn file(file: &str) -> Self {
+        Source {
+            file: file.to_string(),
+            mainframe_identity: None,
+            current_file_reference: None,
+            last_file_reference: None,
+            extract_date: None,
+            update_indicator: None,
+        }
+    }
+
+    /// A CIF file, identified by its `HD` record
+    pub fn cif(file: &str, header: &Header) -> Self {
+        Source {
+            mainframe_identity: Some(header.mainframe_identity.clone()),
+            current_file_reference: Some(header.current_file_reference.clone()),
+            last_file_reference: header.last_file_reference.clone(),
+            extract_date: Some(header.extract_date),
+            update_indicator: Some(header.update_indicator),
+            ..Source::file(file)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct FileEntry {
+    pub bytes: u64,
+    pub sha256: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rows: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    pub generated_at: String,
+    pub files: BTreeMap<String, FileEntry>,
+    pub sources: Vec<Source>,
+}
+
+fn file_entry(path: &Path) -> Result<FileEntry> {
+    let mut hasher = Sha256::new();
+    let bytes = std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    let csv = path
+        .extension()
+        .is_some_and(|ext| ext == "txt" || ext == "csv");
+    let rows = if csv {
+        let mut reader = csv::Reader::from_path(path)?;
+        let mut record = csv::ByteRecord::new();
+        let mut rows = 0;
+        while reader.read_byte_record(&mut record)? {
+            rows += 1;
+        }
+        Some(rows)
+    } else {
+        None
+    };
+    Ok(FileEntry {
+        bytes,
+        sha256: hex::encode(hasher.finalize()),
+        rows,
+    })
+}
+
+/// List the files of the feed in `dir`, which should be complete
+pub fn build(dir: &Path, sources: Vec<Source>) -> Result<Manifest> {
+    let mut files = BTreeMap::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name == MANIFEST_FILE || !entry.file_type()?.is_file() {
+            continue;
+        }
+        let file = file_entry(&entry.path()).with_context(|| format!("Failed to read {}", name))?;
+        files.insert(name, file);
+    }
+    Ok(Manifest {
+        generated_at: Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        files,
+        sources,
+    })
+}
+
+impl Manifest {
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to write {}", path.display()))?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_lists_files_with_checksums_and_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("stops.txt"),
+            "stop_id,stop_name\nYORK,\"York, North Yorkshire\"\nKNGX,London Kings Cross\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("stats.json"), "{}").unwrap();
+        fs::write(dir.path().join(MANIFEST_FILE), "stale").unwrap();
+
+        let manifest = build(dir.path(), vec![Source::file("RJTTF123.ZIP")]).unwrap();
+        assert_eq!(
+            manifest.files.keys().collect::<Vec<_>>(),
+            ["stats.json", "stops.txt"]
+        );
+        assert_eq!(
+            manifest.files["stats.json"],
+            FileEntry {
+                bytes: 2,
+                sha256: "44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+                    .to_string(),
+                rows: None,
+            }
+        );
+        assert_eq!(manifest.files["stops.txt"].rows, Some(2));
+        assert_eq!(manifest.sources[0].file, "RJTTF123.ZIP");
+    }
+}
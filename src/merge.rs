@@ -0,0 +1,596 @@
+//! The `merge` subcommand: combine the converted feed with other GTFS feeds.
+//!
+//! Each input is given as `PREFIX=PATH` (or just a path, prefixed by its
+//! file name), and every id in it becomes `PREFIX:id` so ids from different
+//! feeds can't collide. An empty prefix (`=PATH`) leaves a feed's ids as
+//! they are, to keep them matching its realtime feed.
+//!
+//! Stations in more than one feed are merged into the first feed's. They're
+//! matched by CRS when given as `stop_code`, or by NaPTAN rail code (the
+//! `9100`/`910G` ATCO codes, in `stop_code` or `stop_id`), so a feed
+//! converted with `--naptan` matches others keyed by NaPTAN. A station row
+//! matched to an earlier one is dropped and references to it point there
+//! instead; a plain stop matched to an earlier parent station becomes one
+//! of its children.
+
+use crate::diff::Feed;
+use anyhow::{Context, Result, bail};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(clap::Args)]
+pub struct MergeArgs {
+    /// Feeds to merge, as directories or ZIPs, each optionally as PREFIX=PATH;
+    /// stations shared with earlier feeds are merged into the earlier one
+    #[arg(required = true, num_args = 2.., value_name = "[PREFIX=]PATH")]
+    feeds: Vec<Input>,
+
+    /// Directory to write the merged feed to
+    #[arg(long, default_value = "./gtfs_merged")]
+    output: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Input {
+    prefix: String,
+    path: PathBuf,
+}
+
+impl FromStr for Input {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((prefix, path)) = s.split_once('=') {
+            return Ok(Input {
+                prefix: prefix.to_string(),
+                path: PathBuf::from(path),
+            });
+        }
+        let path = PathBuf::from(s);
+        let prefix = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .ok_or_else(|| format!("can't name a prefix for '{}'", s))?;
+        Ok(Input { prefix, path })
+    }
+}
+
+/// Columns holding ids, and which ids they are
+const ID_COLUMNS: &[(&str, &str)] = &[
+    ("agency_id", "agency"),
+    ("route_id", "route"),
+    ("service_id", "service"),
+    ("trip_id", "trip"),
+    ("from_trip_id", "trip"),
+    ("to_trip_id", "trip"),
+    ("block_id", "block"),
+    ("shape_id", "shape"),
+    ("stop_id", "stop"),
+    ("parent_station", "stop"),
+    ("from_stop_id", "stop"),
+    ("to_stop_id", "stop"),
+    ("level_id", "level"),
+    ("pathway_id", "pathway"),
+    ("zone_id", "zone"),
+    ("origin_id", "zone"),
+    ("destination_id", "zone"),
+    ("contains_id", "zone"),
+    ("fare_id", "fare"),
+    ("area_id", "area"),
+    ("from_area_id", "area"),
+    ("to_area_id", "area"),
+    ("network_id", "network"),
+    ("fare_product_id", "fare_product"),
+    ("leg_group_id", "leg_group"),
+    ("rider_category_id", "rider_category"),
+    ("fare_media_id", "fare_media"),
+];
+
+/// Which ids the `record_id` of a translations.txt row are, by `table_name`
+const TRANSLATED_TABLES: &[(&str, &str)] = &[
+    ("agency", "agency"),
+    ("routes", "route"),
+    ("trips", "trip"),
+    ("stops", "stop"),
+    ("levels", "level"),
+    ("pathways", "pathway"),
+];
+
+/// Files with one row describing the whole feed, taken from the first
+/// feed that has them
+const FEED_WIDE_FILES: &[&str] = &["feed_info.txt"];
+
+/// How one input's rows change on the way into the merged feed
+#[derive(Debug, Default)]
+struct Remap {
+    prefix: String,
+    /// Stops dropped for a station in an earlier feed, and the merged id
+    /// of that station
+    merged_stops: HashMap<String, String>,
+    /// Plain stops that become children of a station in an earlier feed
+    new_parents: HashMap<String, String>,
+    /// The id given to the agency of a feed whose only agency has none,
+    /// before prefixing
+    default_agency: Option<String>,
+}
+
+impl Remap {
+    fn id(&self, namespace: &str, id: &str) -> String {
+        if id.is_empty() {
+            return String::new();
+        }
+        if namespace == "stop"
+            && let Some(merged) = self.merged_stops.get(id)
+        {
+            return merged.clone();
+        }
+        if self.prefix.is_empty() {
+            id.to_string()
+        } else {
+            format!("{}:{}", self.prefix, id)
+        }
+    }
+
+    /// Rewrite a row of `file` in place, or return false to leave it out
+    fn row(&self, file: &str, headers: &[String], row: &mut [String]) -> bool {
+        let column = |name: &str| headers.iter().position(|header| header == name);
+        let original_stop = column("stop_id").map(|i| row[i].clone());
+        if file == "stops.txt"
+            && let Some(stop_id) = &original_stop
+            && self.merged_stops.contains_key(stop_id)
+        {
+            return false;
+        }
+        if let Some(agency) = &self.default_agency
+            && let Some(i) = column("agency_id")
+            && row[i].is_empty()
+            && matches!(file, "agency.txt" | "routes.txt" | "fare_attributes.txt")
+        {
+            row[i] = agency.clone();
+        }
+        let namespaces = |header: &str| -> Option<&str> {
+            if file == "translations.txt" && header == "record_id" {
+                let table = &row[column("table_name")?];
+                return TRANSLATED_TABLES
+                    .iter()
+                    .find(|(name, _)| name == table)
+                    .map(|&(_, namespace)| namespace);
+            }
+            ID_COLUMNS
+                .iter()
+                .find(|(name, _)| *name == header)
+                .map(|&(_, namespace)| namespace)
+        };
+        let remapped: Vec<(usize, String)> = headers
+            .iter()
+            .enumerate()
+            .filter_map(|(i, header)| Some((i, self.id(namespaces(header)?, &row[i]))))
+            .collect();
+        for (i, value) in remapped {
+            row[i] = value;
+        }
+        if file == "stops.txt"
+            && let Some(stop_id) = &original_stop
+            && let Some(parent) = self.new_parents.get(stop_id)
+            && let Some(i) = column("parent_station")
+        {
+            row[i] = parent.clone();
+        }
+        true
+    }
+}
+
+/// The CRS or NaPTAN code a station is known by across feeds
+fn station_key(stop_code: &str, stop_id: &str) -> Option<String> {
+    let crs = stop_code.len() == 3 && stop_code.bytes().all(|b| b.is_ascii_uppercase());
+    if crs {
+        return Some(format!("crs:{}", stop_code));
+    }
+    // Rail access areas (9100...) and stop areas (910G...) of a station
+    // share the code after the prefix
+    [stop_code, stop_id].iter().find_map(|code| {
+        let rest = code.strip_prefix("9100").or(code.strip_prefix("910G"))?;
+        (!rest.is_empty()).then(|| format!("naptan:{}", rest))
+    })
+}
+
+/// A CSV file as header names and rows
+type Table = (Vec<String>, Vec<Vec<String>>);
+
+fn read_table(feed: &mut Feed, name: &str) -> Result<Option<Table>> {
+    feed.read(name, |reader| {
+        let mut csv = csv::Reader::from_reader(reader);
+        let headers = clean_headers(csv.headers()?);
+        let rows = csv
+            .records()
+            .map(|record| Ok(record?.iter().map(str::to_string).collect()))
+            .collect::<Result<_>>()?;
+        Ok((headers, rows))
+    })
+}
+
+/// Header names without a byte order mark or stray spaces
+fn clean_headers(headers: &csv::StringRecord) -> Vec<String> {
+    headers
+        .iter()
+        .map(|header| header.trim_start_matches('\u{feff}').trim().to_string())
+        .collect()
+}
+
+/// Work out which stations are shared between feeds, filling in each
+/// feed's remapping of stops. Returns the number of stations merged.
+fn match_stations(feeds: &mut [(Feed, Remap)]) -> Result<usize> {
+    // Merged id of each station seen so far, and whether it's a parent
+    let mut seen: HashMap<String, (String, bool)> = HashMap::new();
+    let mut merged = 0;
+    for (feed, remap) in feeds.iter_mut() {
+        let Some((headers, rows)) = read_table(feed, "stops.txt")? else {
+            continue;
+        };
+        let column = |name: &str| headers.iter().position(|header| header == name);
+        let field = |row: &[String], name: &str| -> String {
+            column(name)
+                .and_then(|i| row.get(i).cloned())
+                .unwrap_or_default()
+        };
+        let mut found = Vec::new();
+        for row in &rows {
+            let location_type = field(row, "location_type");
+            let is_parent = location_type == "1";
+            let is_plain = matches!(location_type.as_str(), "" | "0")
+                && field(row, "parent_station").is_empty();
+            if !is_parent && !is_plain {
+                continue;
+            }
+            let stop_id = field(row, "stop_id");
+            let Some(key) = station_key(&field(row, "stop_code"), &stop_id) else {
+                continue;
+            };
+            match seen.get(&key) {
+                Some((earlier, earlier_is_parent)) if *earlier_is_parent == is_parent => {
+                    remap.merged_stops.insert(stop_id, earlier.clone());
+                    merged += 1;
+                }
+                Some((earlier, true)) => {
+                    remap.new_parents.insert(stop_id, earlier.clone());
+                    merged += 1;
+                }
+                Some(_) => {}
+                None => found.push((key, (remap.id("stop", &stop_id), is_parent))),
+            }
+        }
+        // Stations are only merged with other feeds'
+        for (key, station) in found {
+            seen.entry(key).or_insert(station);
+        }
+    }
+    Ok(merged)
+}
+
+/// Give the agency of each single-agency feed without an `agency_id` one,
+/// as it needs one among several
+fn name_agencies(feeds: &mut [(Feed, Remap)]) -> Result<()> {
+    for (feed, remap) in feeds.iter_mut() {
+        if let Some((headers, rows)) = read_table(feed, "agency.txt")? {
+            let id = headers.iter().position(|header| header == "agency_id");
+            if rows.len() == 1 && id.is_none_or(|i| rows[0][i].is_empty()) {
+                remap.default_agency = Some("agency".to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write one file of the merged feed from every feed that has it,
+/// returning the number of rows written
+fn merge_file(feeds: &mut [(Feed, Remap)], name: &str, output: &Path) -> Result<usize> {
+    // Every column any feed has, in the order they're first seen
+    let mut headers: Vec<String> = Vec::new();
+    for (feed, remap) in feeds.iter_mut() {
+        let feed_headers = feed.read(name, |reader| {
+            Ok(clean_headers(csv::Reader::from_reader(reader).headers()?))
+        })?;
+        for header in feed_headers.into_iter().flatten() {
+            if !headers.contains(&header) {
+                headers.push(header);
+            }
+        }
+        if name == "agency.txt"
+            && remap.default_agency.is_some()
+            && !headers.iter().any(|header| header == "agency_id")
+        {
+            headers.insert(0, "agency_id".to_string());
+        }
+    }
+    if name == "routes.txt"
+        && feeds
+            .iter()
+            .any(|(_, remap)| remap.default_agency.is_some())
+        && !headers.iter().any(|header| header == "agency_id")
+    {
+        headers.push("agency_id".to_string());
+    }
+
+    let mut writer = csv::Writer::from_path(output.join(name))?;
+    writer.write_record(&headers)?;
+    let mut rows = 0;
+    for (feed, remap) in feeds.iter_mut() {
+        let written = feed.read(name, |reader| {
+            let mut csv = csv::Reader::from_reader(reader);
+            let positions: Vec<Option<usize>> = {
+                let feed_headers = clean_headers(csv.headers()?);
+                headers
+                    .iter()
+                    .map(|header| feed_headers.iter().position(|h| h == header))
+                    .collect()
+            };
+            let mut written = 0;
+            for record in csv.records() {
+                let record = record?;
+                let mut row: Vec<String> = positions
+                    .iter()
+                    .map(|&i| i.and_then(|i| record.get(i)).unwrap_or("").to_string())
+                    .collect();
+                if remap.row(name, &headers, &mut row) {
+                    writer.write_record(&row)?;
+                    written += 1;
+                }
+            }
+            Ok(written)
+        })?;
+        rows += written.unwrap_or(0);
+        if written.is_some() && FEED_WIDE_FILES.contains(&name) {
+            break;
+        }
+    }
+    writer.flush()?;
+    Ok(rows)
+}
+
+pub fn merge_feeds(inputs: &[Input], output: &Path) -> Result<()> {
+    let prefixes: HashSet<&str> = inputs.iter().map(|input| input.prefix.as_str()).collect();
+    if prefixes.len() < inputs.len() {
+        bail!("Each feed needs its own prefix");
+    }
+    let mut feeds = inputs
+        .iter()
+        .map(|input| {
+            let feed = Feed::open(&input.path)?;
+            let remap = Remap {
+                prefix: input.prefix.clone(),
+                ..Default::default()
+            };
+            Ok((feed, remap))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let merged = match_stations(&mut feeds)?;
+    println!("Merging {} stations shared between feeds.", merged);
+    name_agencies(&mut feeds)?;
+
+    let mut names: Vec<String> = Vec::new();
+    for (feed, _) in &feeds {
+        for name in feed.file_names()? {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    fs::create_dir_all(output).with_context(|| format!("Failed to create {}", output.display()))?;
+    for name in &names {
+        let rows = merge_file(&mut feeds, name, output)
+            .with_context(|| format!("Failed to merge {}", name))?;
+        println!("{}: {} rows", name, rows);
+    }
+    Ok(())
+}
+
+pub fn run(args: MergeArgs) -> Result<()> {
+    merge_feeds(&args.feeds, &args.output)?;
+    println!("Merged feed written to {}.", args.output.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_feed(dir: &Path, files: &[(&str, &str)]) {
+        for (name, text) in files {
+            fs::write(dir.join(name), text).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_parse_input() {
+        assert_eq!(
+            "tfl=feeds/tfl.zip".parse(),
+            Ok(Input {
+                prefix: "tfl".to_string(),
+                path: PathBuf::from("feeds/tfl.zip"),
+            })
+        );
+        assert_eq!("feeds/bus.zip".parse::<Input>().unwrap().prefix, "bus");
+        assert_eq!("=gtfs_output".parse::<Input>().unwrap().prefix, "");
+    }
+
+    #[test]
+    fn test_station_key() {
+        assert_eq!(station_key("KGX", "KNGX"), Some("crs:KGX".to_string()));
+        assert_eq!(
+            station_key("9100KNGX", "KNGX"),
+            Some("naptan:KNGX".to_string())
+        );
+        assert_eq!(station_key("", "910GKNGX"), Some("naptan:KNGX".to_string()));
+        assert_eq!(station_key("490000123", "490000123"), None);
+    }
+
+    #[test]
+    fn test_merge_feeds() {
+        let rail = tempfile::tempdir().unwrap();
+        let bus = tempfile::tempdir().unwrap();
+        let output = tempfile::tempdir().unwrap();
+        write_feed(
+            rail.path(),
+            &[
+                ("agency.txt", "agency_id,agency_name\nGR,LNER\n"),
+                (
+                    "stops.txt",
+                    "stop_id,stop_code,stop_name,location_type,parent_station\n\
+                     KNGX,9100KNGX,London Kings Cross,1,\n\
+                     KNGX_8,9100KNGX,London Kings Cross,0,KNGX\n",
+                ),
+                ("routes.txt", "route_id,agency_id,route_type\nGR,GR,2\n"),
+                ("trips.txt", "route_id,service_id,trip_id\nGR,S1,T1\n"),
+                ("feed_info.txt", "feed_publisher_name\nnationalrail-gtfs\n"),
+            ],
+        );
+        write_feed(
+            bus.path(),
+            &[
+                ("agency.txt", "\u{feff}agency_name\nBuses\n"),
+                (
+                    "stops.txt",
+                    "stop_id,stop_name\n910GKNGX,Kings Cross\n490000123,Pancras Road\n",
+                ),
+                ("routes.txt", "route_id,route_type\n73,3\n"),
+                ("trips.txt", "route_id,service_id,trip_id\n73,S1,T1\n"),
+                (
+                    "stop_times.txt",
+                    "trip_id,stop_id,stop_sequence\nT1,910GKNGX,1\nT1,490000123,2\n",
+                ),
+                ("feed_info.txt", "feed_publisher_name\nBuses\n"),
+            ],
+        );
+        let inputs = [
+            Input {
+                prefix: String::new(),
+                path: rail.path().to_path_buf(),
+            },
+            Input {
+                prefix: "bus".to_string(),
+                path: bus.path().to_path_buf(),
+            },
+        ];
+        merge_feeds(&inputs, output.path()).unwrap();
+        let read = |name: &str| fs::read_to_string(output.path().join(name)).unwrap();
+
+        assert_eq!(
+            read("agency.txt"),
+            "agency_id,agency_name\nGR,LNER\nbus:agency,Buses\n"
+        );
+        assert_eq!(
+            read("routes.txt"),
+            "route_id,agency_id,route_type\nGR,GR,2\nbus:73,bus:agency,3\n"
+        );
+        assert_eq!(
+            read("trips.txt"),
+            "route_id,service_id,trip_id\nGR,S1,T1\nbus:73,bus:S1,bus:T1\n"
+        );
+        // The bus stop at Kings Cross station joins the rail station
+        assert_eq!(
+            read("stops.txt"),
+            "stop_id,stop_code,stop_name,location_type,parent_station\n\
+             KNGX,9100KNGX,London Kings Cross,1,\n\
+             KNGX_8,9100KNGX,London Kings Cross,0,KNGX\n\
+             bus:910GKNGX,,Kings Cross,,KNGX\n\
+             bus:490000123,,Pancras Road,,\n"
+        );
+        assert_eq!(
+            read("stop_times.txt"),
+            "trip_id,stop_id,stop_sequence\nbus:T1,bus:910GKNGX,1\nbus:T1,bus:490000123,2\n"
+        );
+        assert_eq!(
+            read("feed_info.txt"),
+            "feed_publisher_name\nnationalrail-gtfs\n"
+        );
+    }
+
+    #[test]
+    fn test_merge_fares_v2() {
+        let south = tempfile::tempdir().unwrap();
+        let north = tempfile::tempdir().unwrap();
+        let output = tempfile::tempdir().unwrap();
+        // Both feeds number their areas and networks the same way
+        let fares = |stop_id: &str| {
+            [
+                ("areas.txt", "area_id,area_name\n0001,Station\n".to_string()),
+                (
+                    "stop_areas.txt",
+                    format!("area_id,stop_id\n0001,{}\n", stop_id),
+                ),
+                (
+                    "networks.txt",
+                    "network_id,network_name\nN1,Rail\n".to_string(),
+                ),
+                (
+                    "route_networks.txt",
+                    "network_id,route_id\nN1,R1\n".to_string(),
+                ),
+                (
+                    "fare_leg_rules.txt",
+                    "leg_group_id,network_id,from_area_id,to_area_id,fare_product_id\n\
+                     L1,N1,0001,0001,P1\n"
+                        .to_string(),
+                ),
+                (
+                    "fare_products.txt",
+                    "fare_product_id,rider_category_id,fare_media_id,amount,currency\n\
+                     P1,ADULT,PAPER,1.00,GBP\n"
+                        .to_string(),
+                ),
+                (
+                    "rider_categories.txt",
+                    "rider_category_id,rider_category_name\nADULT,Adult\n".to_string(),
+                ),
+                (
+                    "fare_media.txt",
+                    "fare_media_id,fare_media_type\nPAPER,1\n".to_string(),
+                ),
+            ]
+        };
+        for (dir, stop_id) in [(&south, "KNGX"), (&north, "YORK")] {
+            let files = fares(stop_id);
+            let files: Vec<(&str, &str)> = files
+                .iter()
+                .map(|(name, text)| (*name, text.as_str()))
+                .collect();
+            write_feed(dir.path(), &files);
+        }
+        let inputs = [
+            Input {
+                prefix: "south".to_string(),
+                path: south.path().to_path_buf(),
+            },
+            Input {
+                prefix: "north".to_string(),
+                path: north.path().to_path_buf(),
+            },
+        ];
+        merge_feeds(&inputs, output.path()).unwrap();
+        let read = |name: &str| fs::read_to_string(output.path().join(name)).unwrap();
+
+        assert_eq!(
+            read("stop_areas.txt"),
+            "area_id,stop_id\nsouth:0001,south:KNGX\nnorth:0001,north:YORK\n"
+        );
+        assert_eq!(
+            read("route_networks.txt"),
+            "network_id,route_id\nsouth:N1,south:R1\nnorth:N1,north:R1\n"
+        );
+        assert_eq!(
+            read("fare_leg_rules.txt"),
+            "leg_group_id,network_id,from_area_id,to_area_id,fare_product_id\n\
+             south:L1,south:N1,south:0001,south:0001,south:P1\n\
+             north:L1,north:N1,north:0001,north:0001,north:P1\n"
+        );
+        assert_eq!(
+            read("fare_products.txt"),
+            "fare_product_id,rider_category_id,fare_media_id,amount,currency\n\
+             south:P1,south:ADULT,south:PAPER,1.00,GBP\n\
+             north:P1,north:ADULT,north:PAPER,1.00,GBP\n"
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! Prometheus metrics for the long-running modes.
+//!
+//! `--serve-metrics :9090` serves `/metrics` in the Prometheus text format
+//! on a port of its own, so it can be scraped without going near the feed.
+//! Every update (a Push Port message applied, an incidents feed polled, a
+//! feed rebuilt by `watch`) is counted the same way whatever the mode, so
+//! one set of alerts covers them all.
+
+use anyhow::{Result, anyhow};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Counters kept since startup. Gauges hold the value of the latest update.
+#[derive(Default)]
+pub struct Metrics {
+    /// Duration of the latest update, in microseconds
+    update_duration_micros: AtomicU64,
+    records_parsed: AtomicU64,
+    trips_emitted: AtomicU64,
+    errors: AtomicU64,
+    last_success: AtomicU64,
+}
+
+impl Metrics {
+    /// Count a successful update that parsed `records` records, leaving
+    /// `trips` trips in the feed
+    pub fn record_update(&self, duration: Duration, records: usize, trips: usize) {
+        self.update_duration_micros
+            .store(duration.as_micros() as u64, Ordering::Relaxed);
+        self.records_parsed
+            .fetch_add(records as u64, Ordering::Relaxed);
+        self.trips_emitted.store(trips as u64, Ordering::Relaxed);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.last_success.store(now, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let get = |value: &AtomicU64| value.load(Ordering::Relaxed);
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(out, "# HELP nationalrail_gtfs_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE nationalrail_gtfs_{} {}", name, kind);
+            let _ = writeln!(out, "nationalrail_gtfs_{} {}", name, value);
+        };
+        metric(
+            "update_duration_seconds",
+            "gauge",
+            "Time taken by the latest update.",
+            (get(&self.update_duration_micros) as f64 / 1e6).to_string(),
+        );
+        metric(
+            "records_parsed_total",
+            "counter",
+            "Records parsed from the input feed.",
+            get(&self.records_parsed).to_string(),
+        );
+        metric(
+            "trips_emitted",
+            "gauge",
+            "Trips (or alerts) in the feed after the latest update.",
+            get(&self.trips_emitted).to_string(),
+        );
+        metric(
+            "errors_total",
+            "counter",
+            "Failed updates, unreadable messages and lost connections.",
+            get(&self.errors).to_string(),
+        );
+        metric(
+            "last_success_timestamp_seconds",
+            "gauge",
+            "Unix time of the latest successful update.",
+            get(&self.last_success).to_string(),
+        );
+        out
+    }
+}
+
+/// Serve `/metrics` on `listen` from a thread of its own. An address
+/// without a host (`:9090`) listens on every interface.
+pub fn serve(metrics: Arc<Metrics>, listen: &str) -> Result<()> {
+    let addr = match listen.strip_prefix(':') {
+        Some(port) => format!("0.0.0.0:{}", port),
+        None => listen.to_string(),
+    };
+    let server = tiny_http::Server::http(&addr)
+        .map_err(|e| anyhow!("Failed to listen on {}: {}", addr, e))?;
+    println!("Serving metrics on http://{}/metrics", addr);
+    let content_type = tiny_http::Header::from_bytes("Content-Type", "text/plain; version=0.0.4")
+        .expect("static header is valid");
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = if request.url() == "/metrics" {
+                tiny_http::Response::from_string(metrics.render()).with_header(content_type.clone())
+            } else {
+                tiny_http::Response::from_string("Not found").with_status_code(404)
+            };
+            if let Err(e) = request.respond(response) {
+                println!("Warning: failed to answer a metrics request: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_after_updates() {
+        let metrics = Metrics::default();
+        metrics.record_update(Duration::from_millis(250), 40, 12);
+        metrics.record_update(Duration::from_millis(500), 2, 13);
+        metrics.record_error();
+        let text = metrics.render();
+        let values: Vec<&str> = text.lines().filter(|line| !line.starts_with('#')).collect();
+        assert_eq!(
+            values[..4],
+            [
+                "nationalrail_gtfs_update_duration_seconds 0.5",
+                "nationalrail_gtfs_records_parsed_total 42",
+                "nationalrail_gtfs_trips_emitted 13",
+                "nationalrail_gtfs_errors_total 1",
+            ]
+        );
+        assert!(values[4].starts_with("nationalrail_gtfs_last_success_timestamp_seconds 1"));
+        assert!(text.contains("# TYPE nationalrail_gtfs_errors_total counter\n"));
+    }
+}
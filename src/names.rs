@@ -0,0 +1,123 @@
+//! Station names as passengers know them, for `--pretty-names`.
+//!
+//! MSN and TPS names are upper case and squeezed into 26 or so characters,
+//! so words are cut short: `BIRMINGHAM INTL`, `CLAPHAM JN`, `MOOR ST`. Known
+//! abbreviations are spelled out and the rest title-cased, keeping the
+//! small words of names like `STOKE-ON-TRENT` in lower case.
+
+/// Abbreviations spelled out wherever they appear
+const ABBREVIATIONS: &[(&str, &str)] = &[
+    ("AIRPT", "Airport"),
+    ("ARPT", "Airport"),
+    ("BDGE", "Bridge"),
+    ("BRDG", "Bridge"),
+    ("CNTRL", "Central"),
+    ("CTL", "Central"),
+    ("GDNS", "Gardens"),
+    ("GRN", "Green"),
+    ("HBR", "Harbour"),
+    ("HL", "High Level"),
+    ("INT", "International"),
+    ("INTL", "International"),
+    ("JCN", "Junction"),
+    ("JN", "Junction"),
+    ("LL", "Low Level"),
+    ("LN", "Lane"),
+    ("MKT", "Market"),
+    ("PK", "Park"),
+    ("PKWY", "Parkway"),
+    ("RD", "Road"),
+    ("SQ", "Square"),
+    ("STN", "Station"),
+];
+
+/// Initialisms kept in capitals
+const ACRONYMS: &[&str] = &["DLR", "NEC", "RAF", "UK"];
+
+/// Words left in lower case unless they start the name
+const SMALL_WORDS: &[&str] = &[
+    "and", "at", "by", "de", "en", "in", "le", "next", "of", "on", "super", "the", "under", "upon",
+];
+
+/// Title-case one word, keeping any brackets or apostrophes around it
+fn title_case(word: &str) -> String {
+    let mut cased = String::with_capacity(word.len());
+    let mut start = true;
+    for c in word.chars() {
+        if start && c.is_alphabetic() {
+            cased.extend(c.to_uppercase());
+            start = false;
+        } else {
+            cased.extend(c.to_lowercase());
+        }
+    }
+    cased
+}
+
+/// Spell out or case a single word, `first` being whether it starts the name
+fn pretty_word(word: &str, first: bool) -> String {
+    let bare = word.trim_matches(|c: char| !c.is_alphanumeric());
+    let (before, after) = word.split_at(word.find(bare).unwrap_or(0));
+    let after = &after[bare.len()..];
+    let pretty = if let Some(&(_, full)) = ABBREVIATIONS.iter().find(|(abbr, _)| *abbr == bare) {
+        full.to_string()
+    } else if bare == "ST" {
+        // St Albans, but Moor Street
+        if first { "St" } else { "Street" }.to_string()
+    } else if ACRONYMS.contains(&bare) {
+        bare.to_string()
+    } else if !first && SMALL_WORDS.contains(&bare.to_lowercase().as_str()) {
+        bare.to_lowercase()
+    } else {
+        title_case(bare)
+    };
+    format!("{}{}{}", before, pretty, after)
+}
+
+/// A station name title-cased, with its abbreviations spelled out
+pub fn pretty_name(raw: &str) -> String {
+    let mut first = true;
+    raw.split_whitespace()
+        .map(|word| {
+            let parts: Vec<String> = word
+                .split('-')
+                .map(|part| {
+                    let pretty = pretty_word(part, first);
+                    first = false;
+                    pretty
+                })
+                .collect();
+            parts.join("-")
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pretty_names() {
+        let cases = [
+            ("LONDON KINGS CROSS", "London Kings Cross"),
+            ("BIRMINGHAM INTL", "Birmingham International"),
+            ("CLAPHAM JN", "Clapham Junction"),
+            ("ST ALBANS CITY", "St Albans City"),
+            ("BIRMINGHAM MOOR ST", "Birmingham Moor Street"),
+            ("STOKE-ON-TRENT", "Stoke-on-Trent"),
+            ("WESTON-SUPER-MARE", "Weston-super-Mare"),
+            (
+                "GLASGOW QUEEN ST (LOW LEVEL)",
+                "Glasgow Queen Street (Low Level)",
+            ),
+            ("HEATHROW TERMINAL 5", "Heathrow Terminal 5"),
+            ("ELEPHANT & CASTLE", "Elephant & Castle"),
+            ("KING'S LYNN", "King's Lynn"),
+            ("RAF BRIZE NORTON", "RAF Brize Norton"),
+        ];
+        for (raw, pretty) in cases {
+            assert_eq!(pretty_name(raw), pretty, "{}", raw);
+        }
+    }
+}
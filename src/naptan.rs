@@ -0,0 +1,88 @@
+//! DfT NaPTAN stop data.
+//!
+//! Every railway station has a NaPTAN access area (stop type `RLY`) whose
+//! ATCO code is `9100` followed by the station's TIPLOC, with hand-curated
+//! WGS84 coordinates.
+
+use anyhow::Result;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Read;
+
+pub const NAPTAN_URL: &str = "https://naptan.api.dft.gov.uk/v1/access-nodes?dataFormat=csv";
+
+const RAIL_ACCESS_AREA_PREFIX: &str = "9100";
+
+#[derive(Deserialize)]
+struct NaptanRow {
+    #[serde(rename = "ATCOCode")]
+    atco_code: String,
+    #[serde(rename = "StopType")]
+    stop_type: String,
+    #[serde(rename = "Latitude")]
+    latitude: Option<f64>,
+    #[serde(rename = "Longitude")]
+    longitude: Option<f64>,
+    #[serde(rename = "Status", default)]
+    status: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RailStop {
+    pub atco_code: String,
+    pub lat: f64,
+    pub lon: f64,
+}
+
+/// Active rail station access areas, by TIPLOC
+pub fn parse_rail_stops<R: Read>(reader: R) -> Result<HashMap<String, RailStop>> {
+    let mut csv = csv::Reader::from_reader(reader);
+    let mut map = HashMap::new();
+    for row in csv.deserialize::<NaptanRow>() {
+        let row = row?;
+        if row.stop_type != "RLY" || row.status.as_deref().is_some_and(|s| s != "active") {
+            continue;
+        }
+        let Some(tiploc) = row.atco_code.strip_prefix(RAIL_ACCESS_AREA_PREFIX) else {
+            continue;
+        };
+        let (Some(lat), Some(lon)) = (row.latitude, row.longitude) else {
+            continue;
+        };
+        map.insert(
+            tiploc.to_string(),
+            RailStop {
+                atco_code: row.atco_code.clone(),
+                lat,
+                lon,
+            },
+        );
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rail_stops() {
+        let csv = "\
+ATCOCode,NaptanCode,CommonName,Longitude,Latitude,StopType,Status
+9100KNGX,,London Kings Cross Rail Station,-0.12383,51.53197,RLY,active
+9100OLDSTN,,Closed Rail Station,-1.0,52.0,RLY,inactive
+9400ZZLUKSX1,,Kings Cross St Pancras,-0.12382,51.53038,PLT,active
+490000000A,,Bus Stop,-0.1,51.5,BCT,active
+";
+        let stops = parse_rail_stops(csv.as_bytes()).unwrap();
+        assert_eq!(stops.len(), 1);
+        assert_eq!(
+            stops["KNGX"],
+            RailStop {
+                atco_code: "9100KNGX".to_string(),
+                lat: 51.53197,
+                lon: -0.12383,
+            }
+        );
+    }
+}
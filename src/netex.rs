@@ -0,0 +1,684 @@
+//! NeTEx output, for `--format netex`.
+//!
+//! The same records that make up the GTFS feed are written as a single
+//! `netex.xml` publication delivery, loosely following the UK profile: a
+//! resource frame of operators, a site frame of stations (stop places)
+//! with their platforms (quays), a service frame of lines, scheduled stop
+//! points and a journey pattern per trip, a service calendar frame of day
+//! types, and a timetable frame of service journeys and the interchanges
+//! between them.
+//!
+//! Trips are written to scratch files as they come, so the timetable isn't
+//! held in memory, and copied in behind the frames known only at the end.
+//! Station entrances, pathways, levels, shapes and station-level transfers
+//! have no counterpart written here, and frequencies can't be used with
+//! NeTEx.
+
+use crate::frequencies::Frequency;
+use crate::pathways::{Level, Pathway};
+use crate::shapes::ShapePoint;
+use crate::writer::GtfsWriter;
+use crate::{Agency, Calendar, CalendarDate, Route, Stop, StopTime, Transfer, Trip};
+use anyhow::{Context, Result, bail};
+use chrono::{NaiveDate, SecondsFormat, Utc};
+use quick_xml::Writer;
+use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, Write};
+use std::path::{Path, PathBuf};
+
+pub const NETEX_FILE: &str = "netex.xml";
+
+/// Prefix of every id in the document
+const CODESPACE: &str = "nr";
+const CODESPACE_URL: &str = "https://github.com/catenarytransit/nationalrail-gtfs";
+
+type XmlWriter<W> = Writer<W>;
+
+/// `{codespace}:{type}:{id}`, the form of NeTEx ids
+fn netex_id(kind: &str, id: &str) -> String {
+    format!("{}:{}:{}", CODESPACE, kind, id)
+}
+
+/// Open an element
+pub fn start<W: Write>(w: &mut XmlWriter<W>, name: &str) -> io::Result<()> {
+    w.write_event(Event::Start(BytesStart::new(name)))
+}
+
+/// Open an entity, an element with an id and version, plus any attributes
+fn start_entity<W: Write>(
+    w: &mut XmlWriter<W>,
+    name: &str,
+    id: &str,
+    attributes: &[(&str, &str)],
+) -> io::Result<()> {
+    let id = netex_id(name, id);
+    let element = BytesStart::new(name)
+        .with_attributes([("id", id.as_str()), ("version", "1")])
+        .with_attributes(attributes.iter().copied());
+    w.write_event(Event::Start(element))
+}
+
+pub fn end<W: Write>(w: &mut XmlWriter<W>, name: &str) -> io::Result<()> {
+    w.write_event(Event::End(BytesEnd::new(name)))
+}
+
+/// An empty element referring to an entity of type `kind`
+fn reference<W: Write>(w: &mut XmlWriter<W>, name: &str, kind: &str, id: &str) -> io::Result<()> {
+    let target = netex_id(kind, id);
+    let element =
+        BytesStart::new(name).with_attributes([("ref", target.as_str()), ("version", "1")]);
+    w.write_event(Event::Empty(element))
+}
+
+pub fn text<W: Write>(w: &mut XmlWriter<W>, name: &str, value: &str) -> io::Result<()> {
+    start(w, name)?;
+    w.write_event(Event::Text(BytesText::new(value)))?;
+    end(w, name)
+}
+
+/// A GTFS time, which runs past 24:00 for trips after midnight, as a NeTEx
+/// time of day and the number of days after the trip's service day
+pub fn time_and_offset(time: &str) -> (String, u32) {
+    let (hours, rest) = time.split_once(':').unwrap_or((time, "00:00"));
+    let hours: u32 = hours.parse().unwrap_or(0);
+    (format!("{:02}:{}", hours % 24, rest), hours / 24)
+}
+
+/// A GTFS `YYYYMMDD` date as a NeTEx date
+fn netex_date(gtfs: &str) -> Result<String> {
+    let date =
+        NaiveDate::parse_from_str(gtfs, "%Y%m%d").with_context(|| format!("Bad date {}", gtfs))?;
+    Ok(date.format("%Y-%m-%d").to_string())
+}
+
+pub fn transport_mode(route_type: u8) -> &'static str {
+    match route_type {
+        3 => "bus",
+        4 => "water",
+        _ => "rail",
+    }
+}
+
+/// A scratch file one collection of elements is written to
+fn scratch() -> Result<XmlWriter<BufWriter<File>>> {
+    Ok(Writer::new(BufWriter::new(tempfile::tempfile()?)))
+}
+
+/// Copy a scratch file's elements into the document
+fn copy_scratch<W: Write>(scratch: XmlWriter<BufWriter<File>>, w: &mut XmlWriter<W>) -> Result<()> {
+    let mut file = scratch
+        .into_inner()
+        .into_inner()
+        .map_err(|e| e.into_error())?;
+    file.rewind()?;
+    io::copy(&mut file, w.get_mut())?;
+    Ok(())
+}
+
+/// A NeTEx document built from the GTFS records, written on `finish`
+pub struct NetexFeed {
+    path: PathBuf,
+    agencies: Vec<Agency>,
+    stops: Vec<Stop>,
+    routes: Vec<Route>,
+    calendars: Vec<Calendar>,
+    calendar_dates: Vec<CalendarDate>,
+    transfers: Vec<Transfer>,
+    /// The trip whose stop times are being given
+    current: Option<(Trip, Vec<StopTime>)>,
+    journey_patterns: Option<XmlWriter<BufWriter<File>>>,
+    service_journeys: Option<XmlWriter<BufWriter<File>>>,
+}
+
+/// The stop point of a trip's journey pattern at one of its calls
+fn stop_point(trip_id: &str, stop_sequence: u32) -> String {
+    format!("{}-{}", trip_id, stop_sequence)
+}
+
+impl NetexFeed {
+    pub fn create(path: &Path) -> Result<Self> {
+        Ok(NetexFeed {
+            path: path.to_path_buf(),
+            agencies: Vec::new(),
+            stops: Vec::new(),
+            routes: Vec::new(),
+            calendars: Vec::new(),
+            calendar_dates: Vec::new(),
+            transfers: Vec::new(),
+            current: None,
+            journey_patterns: Some(scratch()?),
+            service_journeys: Some(scratch()?),
+        })
+    }
+
+    /// Write out the trip whose stop times have all been given
+    fn finish_trip(&mut self) -> Result<()> {
+        let Some((trip, stop_times)) = self.current.take() else {
+            return Ok(());
+        };
+        let (Some(patterns), Some(journeys)) =
+            (&mut self.journey_patterns, &mut self.service_journeys)
+        else {
+            bail!("NeTEx feed already finished");
+        };
+
+        let w = patterns;
+        start_entity(w, "JourneyPattern", &trip.trip_id, &[])?;
+        start(w, "pointsInSequence")?;
+        for stop_time in &stop_times {
+            let order = stop_time.stop_sequence.to_string();
+            start_entity(
+                w,
+                "StopPointInJourneyPattern",
+                &stop_point(&trip.trip_id, stop_time.stop_sequence),
+                &[("order", &order)],
+            )?;
+            reference(
+                w,
+                "ScheduledStopPointRef",
+                "ScheduledStopPoint",
+                &stop_time.stop_id,
+            )?;
+            if stop_time.pickup_type == Some(3) {
+                text(w, "RequestStop", "true")?;
+            }
+            end(w, "StopPointInJourneyPattern")?;
+        }
+        end(w, "pointsInSequence")?;
+        end(w, "JourneyPattern")?;
+
+        let w = journeys;
+        start_entity(w, "ServiceJourney", &trip.trip_id, &[])?;
+        text(w, "Name", &trip.trip_headsign)?;
+        text(w, "PrivateCode", &trip.trip_short_name)?;
+        start(w, "dayTypes")?;
+        reference(w, "DayTypeRef", "DayType", &trip.service_id)?;
+        end(w, "dayTypes")?;
+        reference(w, "JourneyPatternRef", "JourneyPattern", &trip.trip_id)?;
+        reference(w, "LineRef", "Line", &trip.route_id)?;
+        start(w, "passingTimes")?;
+        for stop_time in &stop_times {
+            w.write_event(Event::Start(
+                BytesStart::new("TimetabledPassingTime").with_attributes([("version", "1")]),
+            ))?;
+            reference(
+                w,
+                "StopPointInJourneyPatternRef",
+                "StopPointInJourneyPattern",
+                &stop_point(&trip.trip_id, stop_time.stop_sequence),
+            )?;
+            for (kind, time) in [
+                ("Arrival", &stop_time.arrival_time),
+                ("Departure", &stop_time.departure_time),
+            ] {
+                let (time, offset) = time_and_offset(time);
+                text(w, &format!("{}Time", kind), &time)?;
+                if offset > 0 {
+                    text(w, &format!("{}DayOffset", kind), &offset.to_string())?;
+                }
+            }
+            end(w, "TimetabledPassingTime")?;
+        }
+        end(w, "passingTimes")?;
+        end(w, "ServiceJourney")?;
+        Ok(())
+    }
+
+    fn write_resource_frame<W: Write>(&self, w: &mut XmlWriter<W>) -> Result<()> {
+        start_entity(w, "ResourceFrame", "1", &[])?;
+        start(w, "organisations")?;
+        for agency in &self.agencies {
+            start_entity(w, "Operator", &agency.agency_id, &[])?;
+            text(w, "Name", &agency.agency_name)?;
+            start(w, "ContactDetails")?;
+            if let Some(phone) = &agency.agency_phone {
+                text(w, "Phone", phone)?;
+            }
+            text(w, "Url", &agency.agency_url)?;
+            end(w, "ContactDetails")?;
+            end(w, "Operator")?;
+        }
+        end(w, "organisations")?;
+        end(w, "ResourceFrame")?;
+        Ok(())
+    }
+
+    fn write_centroid<W: Write>(w: &mut XmlWriter<W>, stop: &Stop) -> Result<()> {
+        start(w, "Centroid")?;
+        start(w, "Location")?;
+        text(w, "Longitude", &stop.stop_lon.to_string())?;
+        text(w, "Latitude", &stop.stop_lat.to_string())?;
+        end(w, "Location")?;
+        end(w, "Centroid")?;
+        Ok(())
+    }
+
+    /// Stations as stop places, with their platforms as quays. Plain stops
+    /// are stop places without quays.
+    fn write_site_frame<W: Write>(&self, w: &mut XmlWriter<W>) -> Result<()> {
+        let mut quays: HashMap<&str, Vec<&Stop>> = HashMap::new();
+        for stop in &self.stops {
+            if stop.location_type == Some(0)
+                && let Some(parent) = &stop.parent_station
+            {
+                quays.entry(parent).or_default().push(stop);
+            }
+        }
+        start_entity(w, "SiteFrame", "1", &[])?;
+        start(w, "stopPlaces")?;
+        let stations = self
+            .stops
+            .iter()
+            .filter(|stop| matches!(stop.location_type, None | Some(1)));
+        for station in stations {
+            start_entity(w, "StopPlace", &station.stop_id, &[])?;
+            text(w, "Name", &station.stop_name)?;
+            Self::write_centroid(w, station)?;
+            if let Some(code) = &station.stop_code {
+                text(w, "PublicCode", code)?;
+            }
+            text(w, "TransportMode", "rail")?;
+            text(w, "StopPlaceType", "railStation")?;
+            if let Some(platforms) = quays.get(station.stop_id.as_str()) {
+                start(w, "quays")?;
+                for quay in platforms {
+                    start_entity(w, "Quay", &quay.stop_id, &[])?;
+                    text(w, "Name", &quay.stop_name)?;
+                    Self::write_centroid(w, quay)?;
+                    if let Some(platform) = &quay.platform_code {
+                        text(w, "PublicCode", platform)?;
+                    }
+                    end(w, "Quay")?;
+                }
+                end(w, "quays")?;
+            }
+            end(w, "StopPlace")?;
+        }
+        end(w, "stopPlaces")?;
+        end(w, "SiteFrame")?;
+        Ok(())
+    }
+
+    /// Lines, the stops trips call at as scheduled stop points, assigned to
+    /// their stop places and quays, and the trips' journey patterns
+    fn write_service_frame<W: Write>(
+        &self,
+        w: &mut XmlWriter<W>,
+        journey_patterns: XmlWriter<BufWriter<File>>,
+    ) -> Result<()> {
+        let boardable: Vec<&Stop> = self
+            .stops
+            .iter()
+            .filter(|stop| matches!(stop.location_type, None | Some(0)))
+            .collect();
+        start_entity(w, "ServiceFrame", "1", &[])?;
+        start(w, "lines")?;
+        for route in &self.routes {
+            start_entity(w, "Line", &route.route_id, &[])?;
+            text(w, "Name", &route.route_long_name)?;
+            text(w, "TransportMode", transport_mode(route.route_type))?;
+            text(w, "PublicCode", &route.route_short_name)?;
+            reference(w, "OperatorRef", "Operator", &route.agency_id)?;
+            end(w, "Line")?;
+        }
+        end(w, "lines")?;
+        start(w, "scheduledStopPoints")?;
+        for stop in &boardable {
+            start_entity(w, "ScheduledStopPoint", &stop.stop_id, &[])?;
+            text(w, "Name", &stop.stop_name)?;
+            end(w, "ScheduledStopPoint")?;
+        }
+        end(w, "scheduledStopPoints")?;
+        start(w, "stopAssignments")?;
+        for (i, stop) in boardable.iter().enumerate() {
+            let order = (i + 1).to_string();
+            start_entity(
+                w,
+                "PassengerStopAssignment",
+                &stop.stop_id,
+                &[("order", &order)],
+            )?;
+            reference(
+                w,
+                "ScheduledStopPointRef",
+                "ScheduledStopPoint",
+                &stop.stop_id,
+            )?;
+            match &stop.parent_station {
+                Some(parent) => {
+                    reference(w, "StopPlaceRef", "StopPlace", parent)?;
+                    reference(w, "QuayRef", "Quay", &stop.stop_id)?;
+                }
+                None => reference(w, "StopPlaceRef", "StopPlace", &stop.stop_id)?,
+            }
+            end(w, "PassengerStopAssignment")?;
+        }
+        end(w, "stopAssignments")?;
+        start(w, "journeyPatterns")?;
+        copy_scratch(journey_patterns, w)?;
+        end(w, "journeyPatterns")?;
+        end(w, "ServiceFrame")?;
+        Ok(())
+    }
+
+    /// A day type per service, on the days of the week of its calendar.txt
+    /// row, assigned to its period and to each calendar_dates.txt date
+    fn write_service_calendar_frame<W: Write>(&self, w: &mut XmlWriter<W>) -> Result<()> {
+        let services: BTreeSet<&str> = self
+            .calendars
+            .iter()
+            .map(|calendar| calendar.service_id.as_str())
+            .chain(
+                self.calendar_dates
+                    .iter()
+                    .map(|date| date.service_id.as_str()),
+            )
+            .collect();
+        let calendars: HashMap<&str, &Calendar> = self
+            .calendars
+            .iter()
+            .map(|calendar| (calendar.service_id.as_str(), calendar))
+            .collect();
+
+        start_entity(w, "ServiceCalendarFrame", "1", &[])?;
+        start(w, "dayTypes")?;
+        for service in &services {
+            start_entity(w, "DayType", service, &[])?;
+            if let Some(calendar) = calendars.get(service) {
+                let days: Vec<&str> = [
+                    (calendar.monday, "Monday"),
+                    (calendar.tuesday, "Tuesday"),
+                    (calendar.wednesday, "Wednesday"),
+                    (calendar.thursday, "Thursday"),
+                    (calendar.friday, "Friday"),
+                    (calendar.saturday, "Saturday"),
+                    (calendar.sunday, "Sunday"),
+                ]
+                .into_iter()
+                .filter(|&(runs, _)| runs == 1)
+                .map(|(_, day)| day)
+                .collect();
+                start(w, "properties")?;
+                start(w, "PropertyOfDay")?;
+                text(w, "DaysOfWeek", &days.join(" "))?;
+                end(w, "PropertyOfDay")?;
+                end(w, "properties")?;
+            }
+            end(w, "DayType")?;
+        }
+        end(w, "dayTypes")?;
+
+        start(w, "operatingPeriods")?;
+        for calendar in &self.calendars {
+            start_entity(w, "OperatingPeriod", &calendar.service_id, &[])?;
+            let from = netex_date(&calendar.start_date)?;
+            let to = netex_date(&calendar.end_date)?;
+            text(w, "FromDate", &format!("{}T00:00:00", from))?;
+            text(w, "ToDate", &format!("{}T00:00:00", to))?;
+            end(w, "OperatingPeriod")?;
+        }
+        end(w, "operatingPeriods")?;
+
+        start(w, "dayTypeAssignments")?;
+        let mut order = 0;
+        for calendar in &self.calendars {
+            order += 1;
+            let key = format!("{}-period", calendar.service_id);
+            start_entity(
+                w,
+                "DayTypeAssignment",
+                &key,
+                &[("order", &order.to_string())],
+            )?;
+            reference(
+                w,
+                "OperatingPeriodRef",
+                "OperatingPeriod",
+                &calendar.service_id,
+            )?;
+            reference(w, "DayTypeRef", "DayType", &calendar.service_id)?;
+            end(w, "DayTypeAssignment")?;
+        }
+        for date in &self.calendar_dates {
+            order += 1;
+            let key = format!("{}-{}", date.service_id, date.date);
+            start_entity(
+                w,
+                "DayTypeAssignment",
+                &key,
+                &[("order", &order.to_string())],
+            )?;
+            text(w, "Date", &netex_date(&date.date)?)?;
+            reference(w, "DayTypeRef", "DayType", &date.service_id)?;
+            let available = if date.exception_type == 1 {
+                "true"
+            } else {
+                "false"
+            };
+            text(w, "isAvailable", available)?;
+            end(w, "DayTypeAssignment")?;
+        }
+        end(w, "dayTypeAssignments")?;
+        end(w, "ServiceCalendarFrame")?;
+        Ok(())
+    }
+
+    /// Service journeys, and the joins and splits between them as
+    /// interchanges where passengers stay seated
+    fn write_timetable_frame<W: Write>(
+        &self,
+        w: &mut XmlWriter<W>,
+        service_journeys: XmlWriter<BufWriter<File>>,
+    ) -> Result<()> {
+        start_entity(w, "TimetableFrame", "1", &[])?;
+        start(w, "vehicleJourneys")?;
+        copy_scratch(service_journeys, w)?;
+        end(w, "vehicleJourneys")?;
+        let interchanges: Vec<(&Transfer, &str, &str)> = self
+            .transfers
+            .iter()
+            .filter_map(|transfer| {
+                let from = transfer.from_trip_id.as_deref()?;
+                let to = transfer.to_trip_id.as_deref()?;
+                Some((transfer, from, to))
+            })
+            .collect();
+        if !interchanges.is_empty() {
+            start(w, "journeyInterchanges")?;
+            for (i, (transfer, from, to)) in interchanges.into_iter().enumerate() {
+                start_entity(w, "ServiceJourneyInterchange", &(i + 1).to_string(), &[])?;
+                // transfer_type 4 is an in-seat transfer; 5 keeps passengers off
+                text(w, "StaySeated", &(transfer.transfer_type == 4).to_string())?;
+                reference(
+                    w,
+                    "FromPointRef",
+                    "ScheduledStopPoint",
+                    &transfer.from_stop_id,
+                )?;
+                reference(w, "ToPointRef", "ScheduledStopPoint", &transfer.to_stop_id)?;
+                reference(w, "FromJourneyRef", "ServiceJourney", from)?;
+                reference(w, "ToJourneyRef", "ServiceJourney", to)?;
+                end(w, "ServiceJourneyInterchange")?;
+            }
+            end(w, "journeyInterchanges")?;
+        }
+        end(w, "TimetableFrame")?;
+        Ok(())
+    }
+}
+
+impl GtfsWriter for NetexFeed {
+    fn agency(&mut self, agency: &Agency) -> Result<()> {
+        self.agencies.push(agency.clone());
+        Ok(())
+    }
+
+    fn stop(&mut self, stop: &Stop) -> Result<()> {
+        self.stops.push(stop.clone());
+        Ok(())
+    }
+
+    fn route(&mut self, route: &Route) -> Result<()> {
+        self.routes.push(route.clone());
+        Ok(())
+    }
+
+    fn trip(&mut self, trip: &Trip) -> Result<()> {
+        self.finish_trip()?;
+        self.current = Some((trip.clone(), Vec::new()));
+        Ok(())
+    }
+
+    fn stop_time(&mut self, stop_time: &StopTime) -> Result<()> {
+        match &mut self.current {
+            Some((trip, stop_times)) if trip.trip_id == stop_time.trip_id => {
+                stop_times.push(stop_time.clone());
+                Ok(())
+            }
+            _ => bail!(
+                "Stop time of trip {} given apart from its trip",
+                stop_time.trip_id
+            ),
+        }
+    }
+
+    fn calendar(&mut self, calendar: &Calendar) -> Result<()> {
+        self.calendars.push(calendar.clone());
+        Ok(())
+    }
+
+    fn calendar_date(&mut self, calendar_date: &CalendarDate) -> Result<()> {
+        self.calendar_dates.push(calendar_date.clone());
+        Ok(())
+    }
+
+    fn transfer(&mut self, transfer: &Transfer) -> Result<()> {
+        self.transfers.push(transfer.clone());
+        Ok(())
+    }
+
+    fn frequency(&mut self, _frequency: &Frequency) -> Result<()> {
+        bail!("Frequencies can't be written to NeTEx")
+    }
+
+    fn pathway(&mut self, _pathway: &Pathway) -> Result<()> {
+        Ok(())
+    }
+
+    fn level(&mut self, _level: &Level) -> Result<()> {
+        Ok(())
+    }
+
+    fn shape(&mut self, _shape: &ShapePoint) -> Result<()> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finish_trip()?;
+        let (Some(journey_patterns), Some(service_journeys)) =
+            (self.journey_patterns.take(), self.service_journeys.take())
+        else {
+            return Ok(());
+        };
+        let file = File::create(&self.path)
+            .with_context(|| format!("Failed to create {}", self.path.display()))?;
+        let w = &mut Writer::new(BufWriter::new(file));
+        w.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+        w.write_event(Event::Start(
+            BytesStart::new("PublicationDelivery").with_attributes([
+                ("xmlns", "http://www.netex.org.uk/netex"),
+                ("xmlns:gml", "http://www.opengis.net/gml/3.2"),
+                ("xmlns:siri", "http://www.siri.org.uk/siri"),
+                ("version", "1.1:GB-1.1"),
+            ]),
+        ))?;
+        text(
+            w,
+            "PublicationTimestamp",
+            &Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        )?;
+        text(w, "ParticipantRef", "nationalrail-gtfs")?;
+        start(w, "dataObjects")?;
+        start_entity(w, "CompositeFrame", "1", &[])?;
+        start(w, "codespaces")?;
+        w.write_event(Event::Start(
+            BytesStart::new("Codespace").with_attributes([("id", CODESPACE)]),
+        ))?;
+        text(w, "Xmlns", CODESPACE)?;
+        text(w, "XmlnsUrl", CODESPACE_URL)?;
+        end(w, "Codespace")?;
+        end(w, "codespaces")?;
+        start(w, "frames")?;
+        self.write_resource_frame(w)?;
+        self.write_site_frame(w)?;
+        self.write_service_frame(w, journey_patterns)?;
+        self.write_service_calendar_frame(w)?;
+        self.write_timetable_frame(w, service_journeys)?;
+        end(w, "frames")?;
+        end(w, "CompositeFrame")?;
+        end(w, "dataObjects")?;
+        end(w, "PublicationDelivery")?;
+        w.get_mut().flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::Fixture;
+    use crate::{CalendarMode, write_calendars, write_converted_trip};
+    use quick_xml::Reader;
+
+    #[test]
+    fn test_time_and_offset() {
+        assert_eq!(time_and_offset("09:05:00"), ("09:05:00".to_string(), 0));
+        assert_eq!(time_and_offset("25:15:30"), ("01:15:30".to_string(), 1));
+    }
+
+    #[test]
+    fn test_write_fixture_trips_as_netex() {
+        let (trips, summary, _) = Fixture::default().convert("sample.MSN", "sample.MCA");
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(NETEX_FILE);
+        let mut feed = NetexFeed::create(&path).unwrap();
+        for trip in &trips {
+            write_converted_trip(&mut feed, trip).unwrap();
+        }
+        write_calendars(&mut feed, &summary.calendars, CalendarMode::Calendar).unwrap();
+        for agency in summary.agencies.values() {
+            feed.agency(agency).unwrap();
+        }
+        for route in summary.routes.values() {
+            feed.route(route).unwrap();
+        }
+        feed.finish().unwrap();
+
+        let xml = std::fs::read_to_string(&path).unwrap();
+        // Well formed, with every element closed
+        let mut reader = Reader::from_str(&xml);
+        let mut journeys = 0;
+        loop {
+            match reader.read_event().unwrap() {
+                Event::Start(e) if e.name().as_ref() == b"ServiceJourney" => journeys += 1,
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        assert_eq!(journeys, trips.len());
+        let trip = &trips[0].trip;
+        assert!(xml.contains(&format!(
+            "<ServiceJourney id=\"nr:ServiceJourney:{}\" version=\"1\">",
+            trip.trip_id
+        )));
+        assert!(xml.contains("<Operator id=\"nr:Operator:GR\" version=\"1\"><Name>"));
+        assert!(
+            xml.contains(
+                "<ArrivalTime>01:15:00</ArrivalTime><ArrivalDayOffset>1</ArrivalDayOffset>"
+            )
+        );
+        assert!(xml.contains("<journeyPatterns><JourneyPattern "));
+    }
+}
@@ -0,0 +1,629 @@
+//! National Rail Data Portal access.
+//!
+//! NRDP is unreliable overnight, when most scheduled conversions run, so
+//! authentication and every download are retried with exponential backoff.
+//! A token that stops being accepted mid-run is renewed and the download
+//! tried again.
+//!
+//! Tokens are cached between runs until shortly before they expire, so
+//! repeated local runs don't authenticate every time. Downloads are async,
+//! so one session can fetch several feeds at once; code outside a runtime
+//! drives them on one from [`runtime`].
+//!
+//! Other open data downloads (OSM extracts, Network Rail's datafeeds) go
+//! through the same retry handling, and every download uses a client built
+//! from the same [`HttpOptions`]. Webhooks and object storage uploads use a
+//! blocking client built from them too.
+
+use crate::status::Stage;
+use anyhow::{Context, Result, anyhow};
+use chrono::Utc;
+use reqwest::{Certificate, Client, Proxy, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::runtime::Runtime;
+use tokio::sync::Mutex;
+
+const AUTH_URL: &str = "https://opendata.nationalrail.co.uk/authenticate";
+
+/// Don't reuse a cached token this close to its expiry
+const TOKEN_EXPIRY_MARGIN_SECS: i64 = 300;
+/// Lifetime assumed for tokens whose expiry can't be read
+const DEFAULT_TOKEN_LIFETIME_SECS: i64 = 3600;
+
+#[derive(Deserialize)]
+struct AuthResponse {
+    token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedToken {
+    username: String,
+    token: String,
+    /// Unix timestamp, seconds
+    expires_at: i64,
+}
+
+/// Tokens take the form `username:expiry:signature`, the expiry being in
+/// milliseconds since the epoch
+fn token_expiry(token: &str) -> Option<i64> {
+    let mut parts = token.rsplitn(3, ':');
+    let _signature = parts.next()?;
+    let expiry_ms: i64 = parts.next()?.parse().ok()?;
+    parts.next()?;
+    Some(expiry_ms / 1000)
+}
+
+fn load_cached_token(path: &Path, username: &str) -> Option<String> {
+    let cached: CachedToken = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+    let fresh = cached.expires_at - TOKEN_EXPIRY_MARGIN_SECS > Utc::now().timestamp();
+    (cached.username == username && fresh).then_some(cached.token)
+}
+
+fn save_cached_token(path: &Path, username: &str, token: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let cached = CachedToken {
+        username: username.to_string(),
+        token: token.to_string(),
+        expires_at: token_expiry(token)
+            .unwrap_or_else(|| Utc::now().timestamp() + DEFAULT_TOKEN_LIFETIME_SECS),
+    };
+    // The token is as good as the password until it expires, so the file is
+    // never readable by others, even for a moment
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    // The mode only applies to a new file, so tighten one left from before
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(&serde_json::to_vec(&cached)?)?;
+    Ok(())
+}
+
+/// How the HTTP client is set up, for networks that need a proxy or their
+/// own certificate authority
+#[derive(Debug, Clone)]
+pub struct HttpOptions {
+    /// Proxy for all requests, in place of `HTTPS_PROXY` and friends
+    pub proxy: Option<String>,
+    pub connect_timeout: Option<Duration>,
+    /// Limit on a whole request, including reading the body
+    pub timeout: Duration,
+    pub user_agent: String,
+    /// PEM files of extra root certificates to trust
+    pub ca_certs: Vec<PathBuf>,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        HttpOptions {
+            proxy: None,
+            connect_timeout: None,
+            timeout: Duration::from_secs(300),
+            user_agent: concat!("nationalrail-gtfs/", env!("CARGO_PKG_VERSION")).to_string(),
+            ca_certs: Vec::new(),
+        }
+    }
+}
+
+impl HttpOptions {
+    /// The client downloads use
+    pub fn client(&self) -> Result<Client> {
+        let mut builder = Client::builder()
+            .timeout(self.timeout)
+            .user_agent(&self.user_agent);
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(proxy) = self.proxy()? {
+            builder = builder.proxy(proxy);
+        }
+        for certificate in self.certificates()? {
+            builder = builder.add_root_certificate(certificate);
+        }
+        Ok(builder.build()?)
+    }
+
+    /// A client for code that isn't async. Don't make or drop one on a
+    /// runtime thread.
+    pub fn blocking_client(&self) -> Result<reqwest::blocking::Client> {
+        let mut builder = reqwest::blocking::Client::builder()
+            .timeout(self.timeout)
+            .user_agent(&self.user_agent);
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(proxy) = self.proxy()? {
+            builder = builder.proxy(proxy);
+        }
+        for certificate in self.certificates()? {
+            builder = builder.add_root_certificate(certificate);
+        }
+        Ok(builder.build()?)
+    }
+
+    fn proxy(&self) -> Result<Option<Proxy>> {
+        self.proxy
+            .as_deref()
+            .map(|proxy| Proxy::all(proxy).with_context(|| format!("Invalid proxy URL {}", proxy)))
+            .transpose()
+    }
+
+    fn certificates(&self) -> Result<Vec<Certificate>> {
+        self.ca_certs
+            .iter()
+            .map(|path| {
+                let pem =
+                    fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+                Certificate::from_pem(&pem)
+                    .with_context(|| format!("Invalid certificate in {}", path.display()))
+            })
+            .collect()
+    }
+}
+
+/// A runtime to drive downloads on, with threads for them and for parsing
+/// what they fetch while others are still going
+pub fn runtime() -> Result<Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start the download runtime")
+}
+
+/// How many times, and how patiently, to try a request
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub initial_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Wait before the attempt after `attempt` (1-based), doubling each time
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+    }
+}
+
+/// Why a request attempt failed
+enum Failure {
+    /// Network errors, 429s and 5xx responses, worth another attempt
+    Transient(anyhow::Error),
+    /// The auth token was rejected
+    Unauthorized,
+    Fatal(anyhow::Error),
+}
+
+impl Failure {
+    fn into_error(self) -> anyhow::Error {
+        match self {
+            Failure::Transient(e) | Failure::Fatal(e) => e,
+            Failure::Unauthorized => {
+                Stage::Auth.tag(anyhow!("NRDP rejected the authentication token"))
+            }
+        }
+    }
+}
+
+fn transient(e: impl Into<anyhow::Error>) -> Failure {
+    Failure::Transient(e.into())
+}
+
+fn fatal(e: impl Into<anyhow::Error>) -> Failure {
+    Failure::Fatal(e.into())
+}
+
+async fn check_status(res: Response) -> Result<Response, Failure> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        return Err(Failure::Unauthorized);
+    }
+    let error = anyhow!("HTTP {}: {}", status, res.text().await.unwrap_or_default());
+    if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+        Err(Failure::Transient(error))
+    } else {
+        Err(Failure::Fatal(error))
+    }
+}
+
+/// Run `op` until it succeeds or fails for a reason retrying won't fix
+async fn retry<T, F: Future<Output = Result<T, Failure>>>(
+    policy: &RetryPolicy,
+    what: &str,
+    mut op: impl FnMut() -> F,
+) -> Result<T, Failure> {
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Err(Failure::Transient(e)) if attempt < policy.attempts => {
+                let wait = policy.backoff(attempt);
+                println!(
+                    "{} failed (attempt {}/{}): {:#}. Retrying in {}s...",
+                    what,
+                    attempt,
+                    policy.attempts,
+                    e,
+                    wait.as_secs()
+                );
+                tokio::time::sleep(wait).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Credentials sent with a download
+#[derive(Clone, Copy)]
+enum Auth<'a> {
+    None,
+    /// An NRDP session token
+    Token(&'a str),
+    /// HTTP basic authentication, as used by Network Rail's datafeeds
+    Basic(&'a str, &'a str),
+}
+
+/// GET `url` into `file`, replacing its contents on each attempt. It's
+/// left rewound, ready to read.
+async fn fetch_to_file(
+    client: &Client,
+    policy: &RetryPolicy,
+    url: &str,
+    auth: Auth<'_>,
+    file: &File,
+) -> Result<(), Failure> {
+    retry(policy, &format!("Download of {}", url), || async move {
+        // Shares the file's offset, so the caller sees it rewound
+        let mut out = tokio::fs::File::from_std(file.try_clone().map_err(fatal)?);
+        out.set_len(0).await.map_err(fatal)?;
+        out.rewind().await.map_err(fatal)?;
+        let request = match auth {
+            Auth::None => client.get(url),
+            Auth::Token(token) => client.get(url).header("X-Auth-Token", token),
+            Auth::Basic(username, password) => client.get(url).basic_auth(username, Some(password)),
+        };
+        let res = request.send().await.map_err(transient)?;
+        let mut res = check_status(res).await?;
+        while let Some(chunk) = res.chunk().await.map_err(transient)? {
+            out.write_all(&chunk).await.map_err(fatal)?;
+        }
+        out.flush().await.map_err(fatal)?;
+        out.rewind().await.map_err(fatal)?;
+        Ok(())
+    })
+    .await
+}
+
+/// Download a public file that needs no NRDP token
+pub async fn download_public(
+    client: &Client,
+    policy: &RetryPolicy,
+    url: &str,
+    file: &File,
+) -> Result<()> {
+    fetch_to_file(client, policy, url, Auth::None, file)
+        .await
+        .map_err(Failure::into_error)
+}
+
+/// Download a file protected by HTTP basic authentication into a temporary file
+pub async fn download_basic_auth(
+    client: &Client,
+    policy: &RetryPolicy,
+    url: &str,
+    username: &str,
+    password: &str,
+) -> Result<File> {
+    let file = tempfile::tempfile()?;
+    fetch_to_file(client, policy, url, Auth::Basic(username, password), &file)
+        .await
+        .map_err(Failure::into_error)?;
+    Ok(file)
+}
+
+async fn authenticate(
+    client: &Client,
+    policy: &RetryPolicy,
+    username: &str,
+    password: &str,
+) -> Result<String> {
+    println!("Authenticating with NRDP...");
+    let params = [("username", username), ("password", password)];
+
+    let params = &params;
+    let auth_data: AuthResponse = retry(policy, "Authentication", || async move {
+        let res = client
+            .post(AUTH_URL)
+            .form(params)
+            .send()
+            .await
+            .map_err(transient)?;
+        check_status(res).await?.json().await.map_err(fatal)
+    })
+    .await
+    .map_err(|failure| match failure {
+        Failure::Unauthorized => {
+            Stage::Auth.tag(anyhow!("Authentication failed: credentials rejected"))
+        }
+        other => other.into_error().context("Authentication failed"),
+    })?;
+
+    println!("Authentication successful.");
+    Ok(auth_data.token)
+}
+
+/// The validators NRDP sent with a feed, to ask whether it has changed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FeedVersion {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    content_length: Option<u64>,
+}
+
+impl FeedVersion {
+    fn of(res: &Response) -> Self {
+        let header = |name| {
+            res.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        FeedVersion {
+            etag: header(reqwest::header::ETAG),
+            last_modified: header(reqwest::header::LAST_MODIFIED),
+            content_length: res.content_length(),
+        }
+    }
+}
+
+/// An authenticated NRDP session
+pub struct NrdpClient {
+    client: Client,
+    policy: RetryPolicy,
+    username: String,
+    password: String,
+    token: Mutex<String>,
+    token_cache: Option<PathBuf>,
+}
+
+impl NrdpClient {
+    /// Start a session, reusing the token cached at `token_cache` if it's
+    /// still valid for this user
+    pub async fn connect(
+        client: Client,
+        policy: RetryPolicy,
+        username: String,
+        password: String,
+        token_cache: Option<PathBuf>,
+    ) -> Result<Self> {
+        let cached = token_cache
+            .as_deref()
+            .and_then(|path| load_cached_token(path, &username));
+        let session = NrdpClient {
+            client,
+            policy,
+            username,
+            password,
+            token: Mutex::new(String::new()),
+            token_cache,
+        };
+        match cached {
+            Some(token) => {
+                println!("Using cached NRDP token.");
+                *session.token.lock().await = token;
+            }
+            None => session.renew_token("").await?,
+        }
+        Ok(session)
+    }
+
+    /// Replace the `rejected` token, unless another download already has
+    async fn renew_token(&self, rejected: &str) -> Result<()> {
+        let mut token = self.token.lock().await;
+        if *token != rejected {
+            return Ok(());
+        }
+        *token = authenticate(&self.client, &self.policy, &self.username, &self.password).await?;
+        if let Some(path) = &self.token_cache
+            && let Err(e) = save_cached_token(path, &self.username, &token)
+        {
+            println!("Warning: could not cache NRDP token: {:#}", e);
+        }
+        Ok(())
+    }
+
+    /// Ask with a conditional GET whether the feed at `url` has changed
+    /// since `since`, returning its new version if it has. Servers that
+    /// send no validators answer in full, so the versions are compared too.
+    pub async fn feed_changed(
+        &self,
+        url: &str,
+        since: Option<&FeedVersion>,
+    ) -> Result<Option<FeedVersion>> {
+        let mut reauthenticated = false;
+        loop {
+            let token = self.token.lock().await.clone();
+            let token = token.as_str();
+            let result = retry(&self.policy, &format!("Check of {}", url), || async move {
+                let mut request = self.client.get(url).header("X-Auth-Token", token);
+                if let Some(etag) = since.and_then(|v| v.etag.as_deref()) {
+                    request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+                }
+                if let Some(modified) = since.and_then(|v| v.last_modified.as_deref()) {
+                    request = request.header(reqwest::header::IF_MODIFIED_SINCE, modified);
+                }
+                let res = request.send().await.map_err(transient)?;
+                if res.status() == StatusCode::NOT_MODIFIED {
+                    return Ok(None);
+                }
+                // The body is dropped unread
+                Ok(Some(FeedVersion::of(&check_status(res).await?)))
+            })
+            .await;
+            match result {
+                Ok(version) => return Ok(version.filter(|version| Some(version) != since)),
+                Err(Failure::Unauthorized) if !reauthenticated => {
+                    println!("NRDP token rejected, re-authenticating...");
+                    self.renew_token(token)
+                        .await
+                        .context("Failed to renew NRDP token")?;
+                    reauthenticated = true;
+                }
+                Err(failure) => return Err(failure.into_error()),
+            }
+        }
+    }
+
+    /// Stream a feed download into an anonymous temporary file, so the
+    /// archives are read from disk instead of being held in memory
+    pub async fn download_feed(&self, url: &str) -> Result<File> {
+        let file = tempfile::tempfile()?;
+        let mut reauthenticated = false;
+        loop {
+            let token = self.token.lock().await.clone();
+            match fetch_to_file(&self.client, &self.policy, url, Auth::Token(&token), &file).await {
+                Ok(()) => return Ok(file),
+                Err(Failure::Unauthorized) if !reauthenticated => {
+                    println!("NRDP token rejected, re-authenticating...");
+                    self.renew_token(&token)
+                        .await
+                        .context("Failed to renew NRDP token")?;
+                    reauthenticated = true;
+                }
+                Err(failure) => return Err(failure.into_error()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_backs_off_on_transient_failures() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            initial_backoff: Duration::ZERO,
+        };
+        let runtime = runtime().unwrap();
+        let mut calls = 0;
+        let result = runtime.block_on(retry(&policy, "test", || {
+            calls += 1;
+            let calls = calls;
+            async move {
+                if calls < 3 {
+                    Err(transient(anyhow!("connection reset")))
+                } else {
+                    Ok(calls)
+                }
+            }
+        }));
+        assert!(matches!(result, Ok(3)));
+
+        calls = 0;
+        let result: Result<(), Failure> = runtime.block_on(retry(&policy, "test", || {
+            calls += 1;
+            async { Err(fatal(anyhow!("404"))) }
+        }));
+        assert!(matches!(result, Err(Failure::Fatal(_))));
+        assert_eq!(calls, 1);
+
+        let backoff = RetryPolicy {
+            attempts: 5,
+            initial_backoff: Duration::from_secs(2),
+        };
+        assert_eq!(backoff.backoff(1), Duration::from_secs(2));
+        assert_eq!(backoff.backoff(3), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn test_download_retries_transient_failures() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/feed", server.server_addr().to_ip().unwrap());
+        let serving = std::thread::spawn(move || {
+            let busy = server.recv().unwrap();
+            busy.respond(tiny_http::Response::from_string("busy").with_status_code(503))
+                .unwrap();
+            let ok = server.recv().unwrap();
+            ok.respond(tiny_http::Response::from_string("feed"))
+                .unwrap();
+        });
+        let policy = RetryPolicy {
+            attempts: 2,
+            initial_backoff: Duration::ZERO,
+        };
+        let client = HttpOptions::default().client().unwrap();
+        let mut file = tempfile::tempfile().unwrap();
+        runtime()
+            .unwrap()
+            .block_on(download_public(&client, &policy, &url, &file))
+            .unwrap();
+        serving.join().unwrap();
+
+        let mut body = String::new();
+        std::io::Read::read_to_string(&mut file, &mut body).unwrap();
+        assert_eq!(body, "feed");
+    }
+
+    #[test]
+    fn test_http_options_build_a_client() {
+        assert!(HttpOptions::default().client().is_ok());
+        assert!(HttpOptions::default().blocking_client().is_ok());
+        let proxied = HttpOptions {
+            proxy: Some("http://proxy.example.com:3128".to_string()),
+            connect_timeout: Some(Duration::from_secs(10)),
+            ..HttpOptions::default()
+        };
+        assert!(proxied.client().is_ok());
+        let missing_cert = HttpOptions {
+            ca_certs: vec![PathBuf::from("/nonexistent/ca.pem")],
+            ..HttpOptions::default()
+        };
+        assert!(missing_cert.client().is_err());
+    }
+
+    #[test]
+    fn test_token_cache_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token.json");
+        let expiry_ms = (Utc::now().timestamp() + 3600) * 1000;
+        let token = format!("user@example.com:{}:c2lnbmF0dXJl", expiry_ms);
+        assert_eq!(token_expiry(&token), Some(expiry_ms / 1000));
+
+        save_cached_token(&path, "user@example.com", &token).unwrap();
+        assert_eq!(
+            load_cached_token(&path, "user@example.com").as_deref(),
+            Some(token.as_str())
+        );
+        assert!(load_cached_token(&path, "someone@example.com").is_none());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let expired = format!("user@example.com:{}:c2lnbmF0dXJl", 1000);
+        save_cached_token(&path, "user@example.com", &expired).unwrap();
+        assert!(load_cached_token(&path, "user@example.com").is_none());
+    }
+}
@@ -0,0 +1,597 @@
+//! Operator-specific route classification.
+//!
+//! Most operators run heavy-rail routes named after their end points, but
+//! metro-style operators publish named lines and are better described to
+//! riders as suburban railways.
+
+use crate::line_rules::LineRules;
+use crate::routes::{RouteKey, RouteTrip};
+use crate::{ParsedStation, StopTime};
+use std::collections::{HashMap, HashSet};
+
+/// GTFS basic route type for rail
+pub const ROUTE_TYPE_RAIL: u8 = 2;
+/// Extended (HVT) route type for suburban railways
+pub const ROUTE_TYPE_SUBURBAN_RAILWAY: u8 = 109;
+
+/// How a trip's route is identified and shown
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteClass {
+    pub route_id: String,
+    pub long_name: String,
+    pub route_type: u8,
+    /// Line colour, overriding the operator's branding
+    pub color: Option<String>,
+    pub text_color: Option<String>,
+}
+
+/// Classify a trip's route from its operator and calls. User rules come
+/// first, then operators with named lines keep them; everything else is
+/// grouped by `grouping`.
+pub fn classify_route(
+    trip: &RouteTrip,
+    stops: &[StopTime],
+    tiploc_map: &HashMap<String, ParsedStation>,
+    rules: &LineRules,
+    grouping: &dyn RouteKey,
+) -> RouteClass {
+    if let Some(class) = rules.classify(trip.atoc_code, trip.train_identity, stops, tiploc_map) {
+        return class;
+    }
+    let named = |route_id: &str, long_name: &str, route_type| RouteClass {
+        route_id: route_id.to_string(),
+        long_name: long_name.to_string(),
+        route_type,
+        color: None,
+        text_color: None,
+    };
+    match trip.atoc_code {
+        "XR" => {
+            let (name, id) = xr_line_details(stops, tiploc_map);
+            RouteClass {
+                color: Some(ELIZABETH_LINE_COLOR.to_string()),
+                text_color: Some("FFFFFF".to_string()),
+                ..named(&id, &name, ROUTE_TYPE_SUBURBAN_RAILWAY)
+            }
+        }
+        "LO" => {
+            let (name, id, color) = lo_line_details(stops, tiploc_map);
+            RouteClass {
+                color: Some(color),
+                text_color: Some("FFFFFF".to_string()),
+                ..named(&id, &name, ROUTE_TYPE_SUBURBAN_RAILWAY)
+            }
+        }
+        "ME" => {
+            let (name, id) = me_line_details(stops, tiploc_map);
+            named(&id, &name, ROUTE_TYPE_SUBURBAN_RAILWAY)
+        }
+        "GX" => named("GX-GATWICK", "Gatwick Express", ROUTE_TYPE_RAIL),
+        "HX" => named("HX-HEATHROW", "Heathrow Express", ROUTE_TYPE_RAIL),
+        _ => named(
+            &grouping.route_id(trip),
+            &grouping.long_name(trip),
+            ROUTE_TYPE_RAIL,
+        ),
+    }
+}
+
+const ELIZABETH_LINE_COLOR: &str = "6950A1";
+
+/// Elizabeth line branches outside the central section: the route name
+/// given to the end, its part of the route id, and the stations (by CRS)
+/// only trains on that branch call at
+#[rustfmt::skip]
+const ELIZABETH_WEST_BRANCHES: &[(&str, &str, &[&str])] = &[
+    ("Heathrow", "HEATHROW", &["HXX", "HAF", "HWV"]),
+    ("Reading", "READING", &["RDG", "TWY", "MAI", "TAP", "BNM", "SLO", "LNY", "IVR", "WDT"]),
+];
+
+#[rustfmt::skip]
+const ELIZABETH_EAST_BRANCHES: &[(&str, &str, &[&str])] = &[
+    ("Abbey Wood", "ABBEYWOOD", &["CWX", "CUS", "WWC", "ABW"]),
+    ("Shenfield", "SHENFIELD", &["SRA", "MYL", "FOG", "MNP", "IFD", "SVK", "GMY", "CTH", "RMF",
+                                 "GDP", "HRO", "BRE", "SNF"]),
+];
+
+/// The Elizabeth line route for a trip, named after the branch it serves
+/// at each end. Trains short of a branch turn back at Paddington or
+/// Liverpool Street, the latter also where Shenfield trains that never
+/// enter the tunnel terminate.
+fn xr_line_details(
+    stops: &[StopTime],
+    tiploc_map: &HashMap<String, ParsedStation>,
+) -> (String, String) {
+    let stations: HashSet<&str> = stops
+        .iter()
+        .filter_map(|stop| tiploc_map.get(&stop.tiploc))
+        .map(|station| station.crs.as_str())
+        .collect();
+    let branch = |branches: &[(&'static str, &'static str, &[&str])]| {
+        branches
+            .iter()
+            .find(|(_, _, crs)| crs.iter().any(|crs| stations.contains(crs)))
+            .map(|&(name, id, _)| (name, id))
+    };
+    let west = branch(ELIZABETH_WEST_BRANCHES);
+    let east = branch(ELIZABETH_EAST_BRANCHES);
+    if west.is_none() && east.is_none() {
+        return ("Elizabeth line".to_string(), "XR-ELIZABETH".to_string());
+    }
+    let terminus = |crs: &str| match crs {
+        "PAD" => ("Paddington", "PADDINGTON"),
+        _ => ("Liverpool Street", "LIVERPOOLST"),
+    };
+    let (west_name, west_id) = west.unwrap_or_else(|| {
+        terminus(if stations.contains("PAD") {
+            "PAD"
+        } else {
+            "LST"
+        })
+    });
+    let (east_name, east_id) = east.unwrap_or_else(|| {
+        terminus(if stations.contains("LST") {
+            "LST"
+        } else {
+            "PAD"
+        })
+    });
+    (
+        format!("Elizabeth line: {} - {}", west_name, east_name),
+        format!("XR-{}-{}", west_id, east_id),
+    )
+}
+
+/// A London Overground line and the stations (by CRS) it serves.
+/// Stations are listed by CRS since one station can have several TIPLOCs.
+struct OvergroundLine {
+    name: &'static str,
+    route_id: &'static str,
+    color: &'static str,
+    stations: &'static [&'static str],
+}
+
+#[rustfmt::skip]
+const OVERGROUND_LINES: &[OvergroundLine] = &[
+    OvergroundLine {
+        name: "Suffragette Line", route_id: "LO-SUFFRAGETTE", color: "008163",
+        stations: &["GPO", "UHL", "CRH", "HRY", "STO", "BHO", "WMW", "LEM", "LER", "WNP",
+                    "WGR", "BKG", "BGV"],
+    },
+    OvergroundLine {
+        name: "Liberty Line", route_id: "LO-LIBERTY", color: "676767",
+        stations: &["RMF", "EMP", "UPM"],
+    },
+    OvergroundLine {
+        name: "Weaver Line", route_id: "LO-WEAVER", color: "a90068",
+        stations: &["LST", "BET", "CBH", "LOF", "HAC", "REC", "SKW", "SMH", "SVS", "BCV",
+                    "WHL", "SIL", "EDR", "BHK", "ENF", "SBU", "TUR", "TEO", "CHN", "CPT",
+                    "SJS", "WHC", "WST", "HIP", "CHI"],
+    },
+    OvergroundLine {
+        name: "Lioness Line", route_id: "LO-LIONESS", color: "f1b41c",
+        stations: &["EUS", "SOH", "KBN", "QPW", "KNL", "WIJ", "HDN", "SBP", "WMB", "NWB",
+                    "SOK", "KNT", "HRW", "HTE", "HEH", "CPK", "BSH", "WFH", "WFJ"],
+    },
+    OvergroundLine {
+        name: "Windrush Line", route_id: "LO-WINDRUSH", color: "dc2517",
+        stations: &["HHY", "CNN", "DLJ", "HGG", "HOX", "SDC", "ZLW", "SDE", "WPE", "ROE",
+                    "ZCW", "SQE", "NXG", "NWX", "BCY", "HPA", "FOH", "SYD", "PNW", "ANZ",
+                    "NWD", "WCY", "CYP", "QRP", "PMR", "DMK", "CLP", "WWR", "CLJ"],
+    },
+    OvergroundLine {
+        name: "Mildmay Line", route_id: "LO-MILDMAY", color: "437ec1",
+        stations: &["SRA", "HKW", "HMN", "HKC", "DLK", "CNN", "HHY", "CIR", "CMD", "KTW",
+                    "GPO", "HDH", "FNY", "WHD", "BSY", "BSP", "KNR", "WIJ", "ACC", "SAT",
+                    "GUN", "KWG", "RMD", "SPB", "KPA", "WBP", "IMW", "CLJ"],
+    },
+];
+
+/// Share of a trip's stations a line must serve for the trip to be put on it
+const OVERGROUND_MIN_CONFIDENCE: f64 = 0.5;
+
+/// The Overground line serving most of a trip's stations. Diverted and
+/// short workings still land on their line as long as most of their calls
+/// are on it; a tie, or a trip mostly off every line, is left generic.
+fn lo_line_details(
+    stops: &[StopTime],
+    tiploc_map: &HashMap<String, ParsedStation>,
+) -> (String, String, String) {
+    let stations: HashSet<&str> = stops
+        .iter()
+        .filter_map(|stop| tiploc_map.get(&stop.tiploc))
+        .map(|station| station.crs.as_str())
+        .filter(|crs| !crs.is_empty())
+        .collect();
+
+    let mut scores: Vec<(usize, &OvergroundLine)> = OVERGROUND_LINES
+        .iter()
+        .map(|line| {
+            let served = line
+                .stations
+                .iter()
+                .filter(|crs| stations.contains(*crs))
+                .count();
+            (served, line)
+        })
+        .collect();
+    scores.sort_by_key(|(served, _)| std::cmp::Reverse(*served));
+    let (best, line) = scores[0];
+    let runner_up = scores[1].0;
+    let confidence = best as f64 / stations.len().max(1) as f64;
+    if best > runner_up && confidence >= OVERGROUND_MIN_CONFIDENCE {
+        return (
+            line.name.to_string(),
+            line.route_id.to_string(),
+            line.color.to_string(),
+        );
+    }
+
+    (
+        "London Overground".to_string(),
+        "LO-GENERIC".to_string(),
+        "E66A1F".to_string(),
+    )
+}
+
+fn me_line_details(
+    stops: &[StopTime],
+    tiploc_map: &HashMap<String, ParsedStation>,
+) -> (String, String) {
+    let mut names: HashSet<String> = HashSet::new();
+    let mut tiplocs: HashSet<String> = HashSet::new();
+
+    for stop in stops {
+        tiplocs.insert(stop.tiploc.clone());
+        if let Some(station) = tiploc_map.get(&stop.tiploc) {
+            names.insert(station.name.to_uppercase());
+        }
+    }
+
+    let has_name = |s: &str| -> bool { names.iter().any(|n| n.contains(&s.to_uppercase())) };
+
+    let has_loc = |s: &str| -> bool { tiplocs.contains(s) };
+
+    // Wirral Line
+    // "Ellesmere port station has the stop id ELSMPRT"
+    // "west kirby has WKIRBY"
+    // "New brighton is NBTN"
+    if has_loc("ELSMPRT") || has_loc("WKIRBY") || has_loc("NBTN") || has_name("CHESTER") {
+        return ("Wirral line".to_string(), "ME-WIRRAL".to_string());
+    }
+
+    // Northern Line
+    // Southport, Ormskirk, Kirkby, Hunts Cross
+    if has_name("SOUTHPORT")
+        || has_name("ORMSKIRK")
+        || has_name("KIRKBY")
+        || has_name("HUNTS CROSS")
+    {
+        return ("Northern line".to_string(), "ME-NORTHERN".to_string());
+    }
+
+    // City Line
+    // Common stops: Huyton, St Helens, etc. (Heuristic fallback)
+    if has_name("HUYTON")
+        || has_name("ST HELENS")
+        || has_name("NEWTON-LE-WILLOWS")
+        || has_name("LIVERPOOL LIME STREET")
+    {
+        return ("City line".to_string(), "ME-CITY".to_string());
+    }
+
+    // Default
+    ("Merseyrail".to_string(), "ME-GENERIC".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::ByOrigin;
+
+    #[test]
+    fn test_classify_route() {
+        let tiploc_map = HashMap::new();
+        let trip = |atoc_code, origin_name, dest_name| RouteTrip {
+            atoc_code,
+            agency_name: "",
+            origin_tiploc: "",
+            origin_name,
+            dest_tiploc: "",
+            dest_name,
+            train_identity: "",
+            train_service_code: None,
+        };
+        let rules = LineRules::default();
+        let classify = |trip| classify_route(&trip, &[], &tiploc_map, &rules, &ByOrigin);
+
+        let elizabeth = classify(trip("XR", "Reading", "Abbey Wood"));
+        assert_eq!(elizabeth.route_id, "XR-ELIZABETH");
+        assert_eq!(elizabeth.route_type, ROUTE_TYPE_SUBURBAN_RAILWAY);
+
+        let merseyrail = classify(trip("ME", "Southport", "Hunts Cross"));
+        assert_eq!(merseyrail.route_id, "ME-GENERIC");
+        assert_eq!(merseyrail.route_type, ROUTE_TYPE_SUBURBAN_RAILWAY);
+
+        let gwr = classify(trip("GW", "London Paddington", "Bristol Temple Meads"));
+        assert_eq!(gwr.route_id, "GW_London Paddington");
+        assert_eq!(gwr.long_name, "London Paddington to Bristol Temple Meads");
+        assert_eq!(gwr.route_type, ROUTE_TYPE_RAIL);
+        assert_eq!(gwr.color, None);
+    }
+
+    #[test]
+    fn test_merseyrail_wirral_line() {
+        let mut tiploc_map = HashMap::new();
+        tiploc_map.insert(
+            "WKIRBY".to_string(),
+            ParsedStation {
+                tiploc: "WKIRBY".to_string(),
+                name: "West Kirby".to_string(),
+                crs: String::new(),
+                lat: 0.0,
+                lon: 0.0,
+            },
+        );
+
+        let stops = vec![StopTime {
+            trip_id: "t1".to_string(),
+            arrival_time: "00:00".to_string(),
+            departure_time: "00:00".to_string(),
+            stop_id: "WKIRBY".to_string(),
+            stop_sequence: 1,
+            stop_headsign: None,
+            pickup_type: None,
+            drop_off_type: None,
+            shape_dist_traveled: None,
+            tiploc: "WKIRBY".to_string(),
+            platform: None,
+        }];
+
+        let (name, id) = me_line_details(&stops, &tiploc_map);
+        assert_eq!(name, "Wirral line");
+        assert_eq!(id, "ME-WIRRAL");
+    }
+
+    #[test]
+    fn test_merseyrail_northern_line() {
+        let mut tiploc_map = HashMap::new();
+        tiploc_map.insert(
+            "SOUTHPORT".to_string(),
+            ParsedStation {
+                tiploc: "SOUTHPORT".to_string(),
+                name: "Southport".to_string(),
+                crs: String::new(),
+                lat: 0.0,
+                lon: 0.0,
+            },
+        );
+
+        let stops = vec![StopTime {
+            trip_id: "t2".to_string(),
+            arrival_time: "00:00".to_string(),
+            departure_time: "00:00".to_string(),
+            stop_id: "SOUTHPORT".to_string(),
+            stop_sequence: 1,
+            stop_headsign: None,
+            pickup_type: None,
+            drop_off_type: None,
+            shape_dist_traveled: None,
+            tiploc: "SOUTHPORT".to_string(),
+            platform: None,
+        }];
+
+        let (name, id) = me_line_details(&stops, &tiploc_map);
+        assert_eq!(name, "Northern line");
+        assert_eq!(id, "ME-NORTHERN");
+    }
+
+    #[test]
+    fn test_merseyrail_city_line() {
+        let mut tiploc_map = HashMap::new();
+        tiploc_map.insert(
+            "HUYTON".to_string(),
+            ParsedStation {
+                tiploc: "HUYTON".to_string(),
+                name: "Huyton".to_string(),
+                crs: String::new(),
+                lat: 0.0,
+                lon: 0.0,
+            },
+        );
+
+        let stops = vec![StopTime {
+            trip_id: "t3".to_string(),
+            arrival_time: "00:00".to_string(),
+            departure_time: "00:00".to_string(),
+            stop_id: "HUYTON".to_string(),
+            stop_sequence: 1,
+            stop_headsign: None,
+            pickup_type: None,
+            drop_off_type: None,
+            shape_dist_traveled: None,
+            tiploc: "HUYTON".to_string(),
+            platform: None,
+        }];
+
+        let (name, id) = me_line_details(&stops, &tiploc_map);
+        assert_eq!(name, "City line");
+        assert_eq!(id, "ME-CITY");
+    }
+
+    #[test]
+    fn test_merseyrail_generic() {
+        let tiploc_map = HashMap::new();
+        let stops = vec![];
+        let (name, id) = me_line_details(&stops, &tiploc_map);
+        assert_eq!(name, "Merseyrail");
+        assert_eq!(id, "ME-GENERIC");
+    }
+
+    /// Stops calling at stations with the given CRS codes, one TIPLOC each
+    fn overground_trip(calls: &[&str]) -> (Vec<StopTime>, HashMap<String, ParsedStation>) {
+        let mut tiploc_map = HashMap::new();
+        let mut stops = Vec::new();
+        for (i, crs) in calls.iter().enumerate() {
+            let tiploc = format!("T{}", crs);
+            tiploc_map.insert(
+                tiploc.clone(),
+                ParsedStation {
+                    tiploc: tiploc.clone(),
+                    name: String::new(),
+                    crs: crs.to_string(),
+                    lat: 0.0,
+                    lon: 0.0,
+                },
+            );
+            stops.push(StopTime {
+                trip_id: "lo".to_string(),
+                arrival_time: "00:00".to_string(),
+                departure_time: "00:00".to_string(),
+                stop_id: tiploc.clone(),
+                stop_sequence: i as u32 + 1,
+                stop_headsign: None,
+                pickup_type: None,
+                drop_off_type: None,
+                shape_dist_traveled: None,
+                tiploc,
+                platform: None,
+            });
+        }
+        (stops, tiploc_map)
+    }
+
+    fn overground_line(calls: &[&str]) -> String {
+        let (stops, tiploc_map) = overground_trip(calls);
+        lo_line_details(&stops, &tiploc_map).1
+    }
+
+    #[test]
+    fn test_overground_suffragette_line() {
+        assert_eq!(
+            overground_line(&[
+                "GPO", "UHL", "CRH", "HRY", "STO", "BHO", "WMW", "BKG", "BGV"
+            ]),
+            "LO-SUFFRAGETTE"
+        );
+        // Short working turning back at Barking
+        assert_eq!(
+            overground_line(&["GPO", "UHL", "STO", "LEM", "LER", "BKG"]),
+            "LO-SUFFRAGETTE"
+        );
+    }
+
+    #[test]
+    fn test_overground_liberty_line() {
+        assert_eq!(overground_line(&["RMF", "EMP", "UPM"]), "LO-LIBERTY");
+        assert_eq!(overground_line(&["EMP", "UPM"]), "LO-LIBERTY");
+    }
+
+    #[test]
+    fn test_overground_weaver_line() {
+        assert_eq!(
+            overground_line(&["LST", "BET", "HAC", "SKW", "SVS", "EDR", "CHN"]),
+            "LO-WEAVER"
+        );
+        // Short working to Enfield Town, and a Chingford branch train
+        assert_eq!(
+            overground_line(&["HAC", "SKW", "SVS", "EDR", "BHK", "ENF"]),
+            "LO-WEAVER"
+        );
+        assert_eq!(
+            overground_line(&["LST", "HAC", "CPT", "WHC", "CHI"]),
+            "LO-WEAVER"
+        );
+    }
+
+    #[test]
+    fn test_overground_lioness_line() {
+        assert_eq!(
+            overground_line(&["EUS", "SOH", "QPW", "WIJ", "WMB", "HRW", "BSH", "WFJ"]),
+            "LO-LIONESS"
+        );
+        // Short working from Harrow & Wealdstone
+        assert_eq!(overground_line(&["HRW", "HTE", "HEH", "CPK"]), "LO-LIONESS");
+    }
+
+    #[test]
+    fn test_overground_windrush_line() {
+        assert_eq!(
+            overground_line(&[
+                "HHY", "CNN", "DLJ", "SDC", "ZLW", "ZCW", "NXG", "SYD", "WCY"
+            ]),
+            "LO-WINDRUSH"
+        );
+        // Short working from Dalston Junction to New Cross
+        assert_eq!(
+            overground_line(&["DLJ", "HGG", "HOX", "SDC", "SQE", "NWX"]),
+            "LO-WINDRUSH"
+        );
+    }
+
+    #[test]
+    fn test_overground_mildmay_line() {
+        assert_eq!(
+            overground_line(&[
+                "SRA", "HKC", "DLK", "CNN", "HHY", "CMD", "GPO", "WHD", "WIJ", "RMD"
+            ]),
+            "LO-MILDMAY"
+        );
+        // Short working from Willesden Junction to Clapham Junction
+        assert_eq!(
+            overground_line(&["WIJ", "SPB", "KPA", "WBP", "IMW", "CLJ"]),
+            "LO-MILDMAY"
+        );
+    }
+
+    #[test]
+    fn test_overground_ambiguous_trip_is_generic() {
+        // Highbury & Islington and Canonbury are on both Windrush and Mildmay
+        assert_eq!(overground_line(&["HHY", "CNN"]), "LO-GENERIC");
+        // Diverted mostly off the network
+        assert_eq!(overground_line(&["RMF", "SRA", "ZZA", "ZZB"]), "LO-GENERIC");
+    }
+
+    #[test]
+    fn test_elizabeth_line_branches() {
+        let elizabeth = |calls: &[&str]| {
+            let (stops, tiploc_map) = overground_trip(calls);
+            classify_route(
+                &RouteTrip {
+                    atoc_code: "XR",
+                    agency_name: "Elizabeth line",
+                    origin_tiploc: "",
+                    origin_name: "",
+                    dest_tiploc: "",
+                    dest_name: "",
+                    train_identity: "9T01",
+                    train_service_code: None,
+                },
+                &stops,
+                &tiploc_map,
+                &LineRules::default(),
+                &ByOrigin,
+            )
+        };
+
+        let heathrow = elizabeth(&["HWV", "HAF", "HXX", "PAD", "TCR", "LST", "CWX", "ABW"]);
+        assert_eq!(heathrow.route_id, "XR-HEATHROW-ABBEYWOOD");
+        assert_eq!(heathrow.long_name, "Elizabeth line: Heathrow - Abbey Wood");
+        assert_eq!(heathrow.color.as_deref(), Some("6950A1"));
+        assert_eq!(heathrow.route_type, ROUTE_TYPE_SUBURBAN_RAILWAY);
+
+        // Either direction gives the same route
+        let reading = elizabeth(&["SNF", "RMF", "SRA", "LST", "PAD", "SLO", "RDG"]);
+        assert_eq!(reading.route_id, "XR-READING-SHENFIELD");
+
+        let shenfield = elizabeth(&["LST", "SRA", "IFD", "GDP"]);
+        assert_eq!(shenfield.route_id, "XR-LIVERPOOLST-SHENFIELD");
+        assert_eq!(
+            shenfield.long_name,
+            "Elizabeth line: Liverpool Street - Shenfield"
+        );
+
+        let reading = elizabeth(&["RDG", "MAI", "SLO", "PAD"]);
+        assert_eq!(reading.route_id, "XR-READING-PADDINGTON");
+    }
+}
@@ -0,0 +1,253 @@
+//! Stations and their entrances from the OSM extract.
+//!
+//! Stations are matched to MSN stations by their `ref:crs` tag, whether
+//! mapped as a node or as a way or relation (placed at the centroid of its
+//! nodes). A station node wins over an outline of the same station.
+//! Entrance nodes (`railway=subway_entrance`, `railway=train_station_entrance`
+//! or any public `entrance=*`) carry no CRS, so each is given to the nearest
+//! station within [`ENTRANCE_RADIUS_M`], and become `location_type=2` stops.
+
+use anyhow::Result;
+use osmpbfreader::{OsmId, OsmObj, OsmPbfReader};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::path::Path;
+
+/// How far relations are followed into member relations for a centroid
+const MAX_RELATION_DEPTH: usize = 2;
+
+/// Furthest an entrance may be from the station node it belongs to
+const ENTRANCE_RADIUS_M: f64 = 200.0;
+
+/// `entrance=*` values that aren't for passengers
+const PRIVATE_ENTRANCES: &[&str] = &["no", "service", "emergency", "garage", "staircase"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entrance {
+    pub osm_id: i64,
+    pub lat: f64,
+    pub lon: f64,
+    pub name: Option<String>,
+    /// GTFS `wheelchair_boarding`, from the `wheelchair` tag
+    pub wheelchair_boarding: Option<u8>,
+}
+
+/// What the extract says about stations
+#[derive(Debug, Default)]
+pub struct OsmStations {
+    /// Station node coordinates by CRS
+    pub by_crs: HashMap<String, (f64, f64)>,
+    /// Entrances by the CRS of the station they're nearest
+    pub entrances: HashMap<String, Vec<Entrance>>,
+}
+
+/// Whether a node with these `railway` and `entrance` tags is a way into a
+/// station
+fn is_entrance(railway: Option<&str>, entrance: Option<&str>) -> bool {
+    matches!(railway, Some("subway_entrance" | "train_station_entrance"))
+        || entrance.is_some_and(|value| !PRIVATE_ENTRANCES.contains(&value))
+}
+
+/// Great-circle distance in metres
+pub fn distance_m((lat1, lon1): (f64, f64), (lat2, lon2): (f64, f64)) -> f64 {
+    const EARTH_RADIUS_M: f64 = 6_371_000.0;
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let d_phi = (lat2 - lat1).to_radians();
+    let d_lambda = (lon2 - lon1).to_radians();
+    let a = (d_phi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (d_lambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * a.sqrt().asin()
+}
+
+/// Mean position of an object's nodes, following ways and relations down to
+/// their nodes. Members missing from the extract are skipped.
+fn centroid(id: OsmId, objs: &BTreeMap<OsmId, OsmObj>) -> Option<(f64, f64)> {
+    fn collect(
+        id: OsmId,
+        objs: &BTreeMap<OsmId, OsmObj>,
+        depth: usize,
+        points: &mut Vec<(f64, f64)>,
+    ) {
+        match objs.get(&id) {
+            Some(OsmObj::Node(node)) => points.push((node.lat(), node.lon())),
+            Some(OsmObj::Way(way)) => {
+                for &node in &way.nodes {
+                    collect(node.into(), objs, depth, points);
+                }
+            }
+            Some(OsmObj::Relation(relation)) if depth < MAX_RELATION_DEPTH => {
+                for member in &relation.refs {
+                    collect(member.member, objs, depth + 1, points);
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut points = Vec::new();
+    collect(id, objs, 0, &mut points);
+    if points.is_empty() {
+        return None;
+    }
+    let n = points.len() as f64;
+    let (lat, lon) = points.iter().fold((0.0, 0.0), |(lat, lon), point| {
+        (lat + point.0, lon + point.1)
+    });
+    Some((lat / n, lon / n))
+}
+
+/// Give each entrance to the nearest station within range, if any
+fn match_entrances(
+    by_crs: &HashMap<String, (f64, f64)>,
+    candidates: Vec<Entrance>,
+) -> HashMap<String, Vec<Entrance>> {
+    let mut entrances: HashMap<String, Vec<Entrance>> = HashMap::new();
+    for entrance in candidates {
+        let nearest = by_crs
+            .iter()
+            .map(|(crs, &coords)| (distance_m(coords, (entrance.lat, entrance.lon)), crs))
+            .filter(|&(distance, _)| distance <= ENTRANCE_RADIUS_M)
+            .min_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(b.1)));
+        if let Some((_, crs)) = nearest {
+            entrances.entry(crs.clone()).or_default().push(entrance);
+        }
+    }
+    for list in entrances.values_mut() {
+        list.sort_by_key(|entrance| entrance.osm_id);
+    }
+    entrances
+}
+
+/// Parse OSM PBF to get CRS -> Lat/Lon map, and the entrances of those stations
+pub fn parse_osm_crs(path: &Path) -> Result<OsmStations> {
+    let file = File::open(path)?;
+    let mut reader = OsmPbfReader::new(file);
+    let entrance_tags = |obj: &OsmObj| {
+        obj.is_node()
+            && is_entrance(
+                obj.tags().get("railway").map(|v| v.as_str()),
+                obj.tags().get("entrance").map(|v| v.as_str()),
+            )
+    };
+    // Stations and entrances, with the nodes and members that place them
+    let objs =
+        reader.get_objs_and_deps(|obj| obj.tags().contains_key("ref:crs") || entrance_tags(obj))?;
+    let mut by_crs = HashMap::new();
+    let mut candidates = Vec::new();
+
+    // Nodes come first, so a station node wins over its outline
+    for (&id, obj) in &objs {
+        // The provided PBF is filtered for CRS, so we trust the tag
+        if let Some(crs) = obj.tags().get("ref:crs") {
+            if let Some(coords) = centroid(id, &objs) {
+                by_crs.entry(crs.to_string()).or_insert(coords);
+            }
+        } else if let OsmObj::Node(node) = obj
+            && entrance_tags(obj)
+        {
+            let name = node.tags.get("name").or_else(|| node.tags.get("ref"));
+            candidates.push(Entrance {
+                osm_id: node.id.0,
+                lat: node.lat(),
+                lon: node.lon(),
+                name: name.map(|name| name.to_string()),
+                wheelchair_boarding: match node.tags.get("wheelchair").map(|v| v.as_str()) {
+                    Some("yes" | "designated") => Some(1),
+                    Some("no") => Some(2),
+                    _ => None,
+                },
+            });
+        }
+    }
+    let entrances = match_entrances(&by_crs, candidates);
+    Ok(OsmStations { by_crs, entrances })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entrance(osm_id: i64, lat: f64, lon: f64) -> Entrance {
+        Entrance {
+            osm_id,
+            lat,
+            lon,
+            name: None,
+            wheelchair_boarding: None,
+        }
+    }
+
+    #[test]
+    fn test_centroid_of_ways_and_relations() {
+        use osmpbfreader::{Node, NodeId, Ref, Relation, RelationId, Tags, Way, WayId};
+        let node = |id: i64, lat: f64, lon: f64| {
+            let node = Node {
+                id: NodeId(id),
+                tags: Tags::new(),
+                decimicro_lat: (lat * 1e7) as i32,
+                decimicro_lon: (lon * 1e7) as i32,
+            };
+            (OsmId::Node(node.id), OsmObj::Node(node))
+        };
+        let way = Way {
+            id: WayId(10),
+            tags: Tags::new(),
+            nodes: vec![NodeId(1), NodeId(2), NodeId(3), NodeId(4)],
+        };
+        let relation = Relation {
+            id: RelationId(20),
+            tags: Tags::new(),
+            refs: vec![
+                Ref {
+                    member: OsmId::Way(way.id),
+                    role: "outer".into(),
+                },
+                // Not in the extract
+                Ref {
+                    member: OsmId::Node(NodeId(99)),
+                    role: "".into(),
+                },
+            ],
+        };
+        let objs = BTreeMap::from([
+            node(1, 51.0, -1.0),
+            node(2, 51.0, -0.9),
+            node(3, 51.2, -0.9),
+            node(4, 51.2, -1.0),
+            (OsmId::Way(way.id), OsmObj::Way(way)),
+            (OsmId::Relation(relation.id), OsmObj::Relation(relation)),
+        ]);
+        let (lat, lon) = centroid(OsmId::Relation(RelationId(20)), &objs).unwrap();
+        assert!((lat - 51.1).abs() < 1e-6 && (lon - -0.95).abs() < 1e-6);
+        assert_eq!(centroid(OsmId::Node(NodeId(1)), &objs), Some((51.0, -1.0)));
+        assert_eq!(centroid(OsmId::Way(WayId(11)), &objs), None);
+    }
+
+    #[test]
+    fn test_is_entrance() {
+        assert!(is_entrance(Some("train_station_entrance"), None));
+        assert!(is_entrance(None, Some("main")));
+        assert!(!is_entrance(None, Some("service")));
+        assert!(!is_entrance(Some("station"), None));
+    }
+
+    #[test]
+    fn test_entrances_go_to_the_nearest_station() {
+        let by_crs = HashMap::from([
+            ("KGX".to_string(), (51.5320, -0.1233)),
+            ("STP".to_string(), (51.5309, -0.1262)),
+        ]);
+        let entrances = match_entrances(
+            &by_crs,
+            vec![
+                // On Euston Road, nearer St Pancras
+                entrance(2, 51.5302, -0.1255),
+                entrance(1, 51.5316, -0.1236),
+                // Half a kilometre away
+                entrance(3, 51.5275, -0.1300),
+            ],
+        );
+        let ids = |crs: &str| -> Vec<i64> { entrances[crs].iter().map(|e| e.osm_id).collect() };
+        assert_eq!(ids("KGX"), [1]);
+        assert_eq!(ids("STP"), [2]);
+        assert_eq!(entrances.len(), 2);
+    }
+}
@@ -0,0 +1,415 @@
+//! Parquet output, for `--format parquet`.
+//!
+//! The timetable tables, trips, stop_times, calendar and calendar_dates, are
+//! written as Parquet files with typed columns instead of CSV, so they can
+//! be queried in DuckDB or Spark without parsing text. Times are seconds
+//! since the start of the service day, as GTFS times run past 24:00, and
+//! dates are dates. The rest of the feed is written as GTFS text as
+//! usual.
+
+use crate::frequencies::Frequency;
+use crate::pathways::{Level, Pathway};
+use crate::shapes::ShapePoint;
+use crate::writer::{DirectoryFeed, GtfsWriter};
+use crate::{Agency, Calendar, CalendarDate, Route, Stop, StopTime, Transfer, Trip};
+use anyhow::{Context, Result};
+use arrow_array::{
+    ArrayRef, Date32Array, Float64Array, RecordBatch, StringArray, UInt8Array, UInt32Array,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use chrono::NaiveDate;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Rows held before they're written as a record batch
+const BATCH_ROWS: usize = 64 * 1024;
+
+/// The CSV files the Parquet tables stand in for
+const REPLACED_FILES: [&str; 4] = [
+    "trips.txt",
+    "stop_times.txt",
+    "calendar.txt",
+    "calendar_dates.txt",
+];
+
+fn strings<T>(rows: &[T], column: impl Fn(&T) -> &str) -> ArrayRef {
+    Arc::new(StringArray::from_iter_values(rows.iter().map(column)))
+}
+
+fn optional_strings<T>(rows: &[T], column: impl Fn(&T) -> Option<&str>) -> ArrayRef {
+    Arc::new(StringArray::from_iter(rows.iter().map(column)))
+}
+
+fn bytes<T>(rows: &[T], column: impl Fn(&T) -> Option<u8>) -> ArrayRef {
+    Arc::new(UInt8Array::from_iter(rows.iter().map(column)))
+}
+
+/// A GTFS time as seconds since the start of the service day
+fn seconds<T>(rows: &[T], column: impl Fn(&T) -> &str) -> ArrayRef {
+    Arc::new(UInt32Array::from_iter(
+        rows.iter().map(|row| crate::gtfs_seconds(column(row))),
+    ))
+}
+
+/// A GTFS `YYYYMMDD` date as days since 1970-01-01
+fn dates<T>(rows: &[T], column: impl Fn(&T) -> &str) -> Result<ArrayRef> {
+    let epoch = NaiveDate::default();
+    let days = rows
+        .iter()
+        .map(|row| {
+            let date = NaiveDate::parse_from_str(column(row), "%Y%m%d")
+                .with_context(|| format!("Bad date {}", column(row)))?;
+            Ok((date - epoch).num_days() as i32)
+        })
+        .collect::<Result<Vec<i32>>>()?;
+    Ok(Arc::new(Date32Array::from(days)))
+}
+
+fn trip_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("route_id", DataType::Utf8, false),
+        Field::new("service_id", DataType::Utf8, false),
+        Field::new("trip_id", DataType::Utf8, false),
+        Field::new("trip_headsign", DataType::Utf8, false),
+        Field::new("trip_short_name", DataType::Utf8, false),
+        Field::new("direction_id", DataType::UInt8, true),
+        Field::new("block_id", DataType::Utf8, true),
+        Field::new("shape_id", DataType::Utf8, true),
+        Field::new("wheelchair_accessible", DataType::UInt8, true),
+        Field::new("trip_category", DataType::Utf8, true),
+        Field::new("train_service_code", DataType::Utf8, true),
+        Field::new("vehicle_type", DataType::Utf8, false),
+        Field::new("first_class", DataType::UInt8, false),
+        Field::new("sleepers", DataType::Utf8, true),
+        Field::new("reservations", DataType::Utf8, true),
+        Field::new("catering", DataType::Utf8, true),
+    ])
+}
+
+fn trip_columns(rows: &[Trip]) -> Result<Vec<ArrayRef>> {
+    Ok(vec![
+        strings(rows, |t| &t.route_id),
+        strings(rows, |t| &t.service_id),
+        strings(rows, |t| &t.trip_id),
+        strings(rows, |t| &t.trip_headsign),
+        strings(rows, |t| &t.trip_short_name),
+        bytes(rows, |t| t.direction_id),
+        optional_strings(rows, |t| t.block_id.as_deref()),
+        optional_strings(rows, |t| t.shape_id.as_deref()),
+        bytes(rows, |t| t.wheelchair_accessible),
+        optional_strings(rows, |t| t.trip_category.as_deref()),
+        optional_strings(rows, |t| t.train_service_code.as_deref()),
+        strings(rows, |t| t.vehicle_type),
+        bytes(rows, |t| Some(t.first_class)),
+        optional_strings(rows, |t| t.sleepers),
+        optional_strings(rows, |t| t.reservations),
+        optional_strings(rows, |t| t.catering.as_deref()),
+    ])
+}
+
+fn stop_time_schema() -> Schema {
+    let time = DataType::UInt32;
+    Schema::new(vec![
+        Field::new("trip_id", DataType::Utf8, false),
+        Field::new("arrival_time", time.clone(), true),
+        Field::new("departure_time", time, true),
+        Field::new("stop_id", DataType::Utf8, false),
+        Field::new("stop_sequence", DataType::UInt32, false),
+        Field::new("stop_headsign", DataType::Utf8, true),
+        Field::new("pickup_type", DataType::UInt8, true),
+        Field::new("drop_off_type", DataType::UInt8, true),
+        Field::new("shape_dist_traveled", DataType::Float64, true),
+    ])
+}
+
+fn stop_time_columns(rows: &[StopTime]) -> Result<Vec<ArrayRef>> {
+    Ok(vec![
+        strings(rows, |s| &s.trip_id),
+        seconds(rows, |s| &s.arrival_time),
+        seconds(rows, |s| &s.departure_time),
+        strings(rows, |s| &s.stop_id),
+        Arc::new(UInt32Array::from_iter_values(
+            rows.iter().map(|s| s.stop_sequence),
+        )),
+        optional_strings(rows, |s| s.stop_headsign.as_deref()),
+        bytes(rows, |s| s.pickup_type),
+        bytes(rows, |s| s.drop_off_type),
+        Arc::new(Float64Array::from_iter(
+            rows.iter().map(|s| s.shape_dist_traveled),
+        )),
+    ])
+}
+
+fn calendar_schema() -> Schema {
+    let day = |name| Field::new(name, DataType::UInt8, false);
+    Schema::new(vec![
+        Field::new("service_id", DataType::Utf8, false),
+        day("monday"),
+        day("tuesday"),
+        day("wednesday"),
+        day("thursday"),
+        day("friday"),
+        day("saturday"),
+        day("sunday"),
+        Field::new("start_date", DataType::Date32, false),
+        Field::new("end_date", DataType::Date32, false),
+    ])
+}
+
+fn calendar_columns(rows: &[Calendar]) -> Result<Vec<ArrayRef>> {
+    Ok(vec![
+        strings(rows, |c| &c.service_id),
+        bytes(rows, |c| Some(c.monday)),
+        bytes(rows, |c| Some(c.tuesday)),
+        bytes(rows, |c| Some(c.wednesday)),
+        bytes(rows, |c| Some(c.thursday)),
+        bytes(rows, |c| Some(c.friday)),
+        bytes(rows, |c| Some(c.saturday)),
+        bytes(rows, |c| Some(c.sunday)),
+        dates(rows, |c| &c.start_date)?,
+        dates(rows, |c| &c.end_date)?,
+    ])
+}
+
+fn calendar_date_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("service_id", DataType::Utf8, false),
+        Field::new("date", DataType::Date32, false),
+        Field::new("exception_type", DataType::UInt8, false),
+    ])
+}
+
+fn calendar_date_columns(rows: &[CalendarDate]) -> Result<Vec<ArrayRef>> {
+    Ok(vec![
+        strings(rows, |d| &d.service_id),
+        dates(rows, |d| &d.date)?,
+        bytes(rows, |d| Some(d.exception_type)),
+    ])
+}
+
+/// One Parquet file, filled a record batch at a time
+struct Table<T> {
+    writer: ArrowWriter<File>,
+    schema: SchemaRef,
+    rows: Vec<T>,
+    columns: fn(&[T]) -> Result<Vec<ArrayRef>>,
+}
+
+impl<T: Clone> Table<T> {
+    fn create(
+        path: &Path,
+        schema: Schema,
+        columns: fn(&[T]) -> Result<Vec<ArrayRef>>,
+    ) -> Result<Self> {
+        let schema = Arc::new(schema);
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        Ok(Table {
+            writer: ArrowWriter::try_new(file, schema.clone(), Some(properties))?,
+            schema,
+            rows: Vec::new(),
+            columns,
+        })
+    }
+
+    fn push(&mut self, row: &T) -> Result<()> {
+        self.rows.push(row.clone());
+        if self.rows.len() >= BATCH_ROWS {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        let batch = RecordBatch::try_new(self.schema.clone(), (self.columns)(&self.rows)?)?;
+        self.writer.write(&batch)?;
+        self.rows.clear();
+        Ok(())
+    }
+
+    fn close(mut self) -> Result<()> {
+        self.flush()?;
+        self.writer.close()?;
+        Ok(())
+    }
+}
+
+struct Tables {
+    trips: Table<Trip>,
+    stop_times: Table<StopTime>,
+    calendar: Table<Calendar>,
+    calendar_dates: Table<CalendarDate>,
+}
+
+/// A feed directory with its timetable tables in Parquet
+pub struct ParquetFeed {
+    dir: PathBuf,
+    gtfs: DirectoryFeed,
+    /// Taken when they're closed on `finish`
+    tables: Option<Tables>,
+}
+
+impl ParquetFeed {
+    pub fn create(dir: &Path, buffer_size: usize) -> Result<Self> {
+        Ok(ParquetFeed {
+            dir: dir.to_path_buf(),
+            gtfs: DirectoryFeed::create(dir, buffer_size)?,
+            tables: Some(Tables {
+                trips: Table::create(&dir.join("trips.parquet"), trip_schema(), trip_columns)?,
+                stop_times: Table::create(
+                    &dir.join("stop_times.parquet"),
+                    stop_time_schema(),
+                    stop_time_columns,
+                )?,
+                calendar: Table::create(
+                    &dir.join("calendar.parquet"),
+                    calendar_schema(),
+                    calendar_columns,
+                )?,
+                calendar_dates: Table::create(
+                    &dir.join("calendar_dates.parquet"),
+                    calendar_date_schema(),
+                    calendar_date_columns,
+                )?,
+            }),
+        })
+    }
+
+    fn tables(&mut self) -> Result<&mut Tables> {
+        self.tables
+            .as_mut()
+            .context("Parquet feed already finished")
+    }
+}
+
+impl GtfsWriter for ParquetFeed {
+    fn agency(&mut self, agency: &Agency) -> Result<()> {
+        self.gtfs.agency(agency)
+    }
+
+    fn stop(&mut self, stop: &Stop) -> Result<()> {
+        self.gtfs.stop(stop)
+    }
+
+    fn route(&mut self, route: &Route) -> Result<()> {
+        self.gtfs.route(route)
+    }
+
+    fn trip(&mut self, trip: &Trip) -> Result<()> {
+        self.tables()?.trips.push(trip)
+    }
+
+    fn stop_time(&mut self, stop_time: &StopTime) -> Result<()> {
+        self.tables()?.stop_times.push(stop_time)
+    }
+
+    fn calendar(&mut self, calendar: &Calendar) -> Result<()> {
+        self.tables()?.calendar.push(calendar)
+    }
+
+    fn calendar_date(&mut self, calendar_date: &CalendarDate) -> Result<()> {
+        self.tables()?.calendar_dates.push(calendar_date)
+    }
+
+    fn transfer(&mut self, transfer: &Transfer) -> Result<()> {
+        self.gtfs.transfer(transfer)
+    }
+
+    fn frequency(&mut self, frequency: &Frequency) -> Result<()> {
+        self.gtfs.frequency(frequency)
+    }
+
+    fn pathway(&mut self, pathway: &Pathway) -> Result<()> {
+        self.gtfs.pathway(pathway)
+    }
+
+    fn level(&mut self, level: &Level) -> Result<()> {
+        self.gtfs.level(level)
+    }
+
+    fn shape(&mut self, shape: &ShapePoint) -> Result<()> {
+        self.gtfs.shape(shape)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.gtfs.finish()?;
+        if let Some(tables) = self.tables.take() {
+            tables.trips.close()?;
+            tables.stop_times.close()?;
+            tables.calendar.close()?;
+            tables.calendar_dates.close()?;
+            // The directory feed creates every core file up front; these
+            // were never written to
+            for name in REPLACED_FILES {
+                fs::remove_file(self.dir.join(name))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::Fixture;
+    use crate::{CalendarMode, write_calendars, write_converted_trip};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    fn read(path: &Path) -> Vec<RecordBatch> {
+        ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn test_write_fixture_trips_as_parquet() {
+        let (trips, summary, _) = Fixture::default().convert("sample.MSN", "sample.MCA");
+        let dir = tempfile::tempdir().unwrap();
+        let mut feed = ParquetFeed::create(dir.path(), 1024).unwrap();
+        for trip in &trips {
+            write_converted_trip(&mut feed, trip).unwrap();
+        }
+        write_calendars(&mut feed, &summary.calendars, CalendarMode::Calendar).unwrap();
+        feed.finish().unwrap();
+
+        assert!(!dir.path().join("stop_times.txt").exists());
+        assert!(dir.path().join("agency.txt").exists());
+
+        let batches = read(&dir.path().join("stop_times.parquet"));
+        let rows: usize = batches.iter().map(RecordBatch::num_rows).sum();
+        assert_eq!(rows, 5);
+        let departures = batches[0]
+            .column_by_name("departure_time")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<UInt32Array>()
+            .unwrap();
+        assert_eq!(departures.value(0), 9 * 3600);
+
+        let calendars = read(&dir.path().join("calendar.parquet"));
+        assert_eq!(calendars[0].num_rows(), 2);
+        assert_eq!(
+            calendars[0]
+                .schema()
+                .field_with_name("start_date")
+                .unwrap()
+                .data_type(),
+            &DataType::Date32
+        );
+        let trips_read = read(&dir.path().join("trips.parquet"));
+        assert_eq!(trips_read[0].num_rows(), trips.len());
+    }
+}
@@ -0,0 +1,195 @@
+//! `pathways.txt` and `levels.txt` for stations with platforms.
+//!
+//! The Knowledgebase doesn't map stations out, so the graph is the simplest
+//! one GTFS allows: the station's OSM entrances, or failing those one at the
+//! station's coordinates, each with a pathway to each platform, walked level, by lift or by stairs as its step-free
+//! access notes say. Stations only partly step-free are left out, as there's
+//! no telling which platforms can be reached without steps. Entrances are
+//! put on a street level, and so are platforms reached level; for the rest
+//! the Knowledgebase doesn't say whether they're above or below the street.
+
+use crate::Stop;
+use crate::knowledgebase::AccessRoute;
+use serde::{Deserialize, Serialize};
+
+/// GTFS `pathway_mode`
+const WALKWAY: u8 = 1;
+const STAIRS: u8 = 2;
+const ELEVATOR: u8 = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pathway {
+    pub pathway_id: String,
+    pub from_stop_id: String,
+    pub to_stop_id: String,
+    pub pathway_mode: u8,
+    pub is_bidirectional: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Level {
+    pub level_id: String,
+    pub level_index: f64,
+    pub level_name: String,
+}
+
+/// What a station adds to the feed
+#[derive(Debug, Default)]
+pub struct StationPathways {
+    /// An entrance made up for a station without any from OSM
+    pub entrance: Option<Stop>,
+    pub pathways: Vec<Pathway>,
+    pub level: Option<Level>,
+}
+
+/// Connect the platforms among a station's `stops` to its entrances, adding
+/// one if there are none, and put them on the street level where they're
+/// reached level. Stations without a parent have no platforms to connect.
+pub fn station_pathways(stops: &mut [Stop], route: AccessRoute) -> StationPathways {
+    let Some(parent) = stops
+        .iter()
+        .find(|stop| stop.location_type == Some(1))
+        .cloned()
+    else {
+        return StationPathways::default();
+    };
+    let parent_id = parent.stop_id.clone();
+    let level = Level {
+        level_id: format!("{}-STREET", parent_id),
+        level_index: 0.0,
+        level_name: "Street".to_string(),
+    };
+    let mut entrance_ids = Vec::new();
+    for stop in stops
+        .iter_mut()
+        .filter(|stop| stop.location_type == Some(2))
+    {
+        stop.level_id = Some(level.level_id.clone());
+        entrance_ids.push(stop.stop_id.clone());
+    }
+    let entrance = entrance_ids.is_empty().then(|| Stop {
+        stop_id: format!("{}-ENT", parent_id),
+        location_type: Some(2),
+        parent_station: Some(parent_id.clone()),
+        platform_code: None,
+        level_id: Some(level.level_id.clone()),
+        ..parent
+    });
+    entrance_ids.extend(entrance.iter().map(|entrance| entrance.stop_id.clone()));
+    let pathway_mode = match route {
+        AccessRoute::Level => WALKWAY,
+        AccessRoute::Lift => ELEVATOR,
+        AccessRoute::Stairs => STAIRS,
+    };
+    let mut pathways = Vec::new();
+    for stop in stops
+        .iter_mut()
+        .filter(|stop| stop.location_type == Some(0))
+    {
+        for entrance_id in &entrance_ids {
+            pathways.push(Pathway {
+                pathway_id: format!("{}-{}", entrance_id, stop.stop_id),
+                from_stop_id: entrance_id.clone(),
+                to_stop_id: stop.stop_id.clone(),
+                pathway_mode,
+                is_bidirectional: 1,
+            });
+        }
+        if route == AccessRoute::Level {
+            stop.level_id = Some(level.level_id.clone());
+        }
+    }
+    StationPathways {
+        entrance,
+        pathways,
+        level: Some(level),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stop(stop_id: &str, location_type: Option<u8>, parent: Option<&str>) -> Stop {
+        Stop {
+            stop_id: stop_id.to_string(),
+            stop_code: Some("KGX".to_string()),
+            stop_name: "LONDON KINGS CROSS".to_string(),
+            stop_lat: 51.5308,
+            stop_lon: -0.1238,
+            zone_id: None,
+            stop_url: None,
+            location_type,
+            parent_station: parent.map(str::to_string),
+            platform_code: None,
+            wheelchair_boarding: Some(1),
+            level_id: None,
+            stop_name_raw: None,
+        }
+    }
+
+    #[test]
+    fn test_entrance_connects_to_every_platform() {
+        let mut stops = vec![
+            stop("KNGX", Some(1), None),
+            stop("KNGX_1", Some(0), Some("KNGX")),
+            stop("KNGX_8", Some(0), Some("KNGX")),
+        ];
+        let added = station_pathways(&mut stops, AccessRoute::Lift);
+        let entrance = added.entrance.unwrap();
+        assert_eq!(
+            (entrance.stop_id.as_str(), entrance.location_type),
+            ("KNGX-ENT", Some(2))
+        );
+        assert_eq!(entrance.parent_station.as_deref(), Some("KNGX"));
+        let pathways: Vec<(&str, &str, u8)> = added
+            .pathways
+            .iter()
+            .map(|p| {
+                (
+                    p.from_stop_id.as_str(),
+                    p.to_stop_id.as_str(),
+                    p.pathway_mode,
+                )
+            })
+            .collect();
+        assert_eq!(
+            pathways,
+            [
+                ("KNGX-ENT", "KNGX_1", ELEVATOR),
+                ("KNGX-ENT", "KNGX_8", ELEVATOR)
+            ]
+        );
+        // Up or down, the lift doesn't say
+        assert_eq!(stops[1].level_id, None);
+
+        station_pathways(&mut stops, AccessRoute::Level);
+        assert_eq!(stops[1].level_id.as_deref(), Some("KNGX-STREET"));
+    }
+
+    #[test]
+    fn test_osm_entrances_are_used_instead() {
+        let mut stops = vec![
+            stop("KNGX", Some(1), None),
+            stop("KNGX_1", Some(0), Some("KNGX")),
+            stop("KNGX-ENT-7", Some(2), Some("KNGX")),
+            stop("KNGX-ENT-9", Some(2), Some("KNGX")),
+        ];
+        let added = station_pathways(&mut stops, AccessRoute::Stairs);
+        assert!(added.entrance.is_none());
+        let from: Vec<&str> = added
+            .pathways
+            .iter()
+            .map(|p| p.from_stop_id.as_str())
+            .collect();
+        assert_eq!(from, ["KNGX-ENT-7", "KNGX-ENT-9"]);
+        assert_eq!(stops[2].level_id.as_deref(), Some("KNGX-STREET"));
+    }
+
+    #[test]
+    fn test_stations_without_platforms_have_no_pathways() {
+        let mut stops = vec![stop("YORK", None, None)];
+        let added = station_pathways(&mut stops, AccessRoute::Stairs);
+        assert!(added.entrance.is_none() && added.pathways.is_empty());
+    }
+}
@@ -0,0 +1,298 @@
+//! Reading a generated feed back into the records it was written from.
+//!
+//! [`read_feed`] loads a feed directory or ZIP, such as a published build,
+//! as the same records the conversion writes, and [`GtfsFeed::write`]
+//! passes them to a [`GtfsWriter`] again, so a feed read and rewritten as
+//! GTFS comes out as it went in. The `export` subcommand uses this to write
+//! a published feed in another `--format` without converting it again.
+//!
+//! Feeds from elsewhere read too, as long as they have the files and
+//! columns GTFS requires; extension columns of ours they lack are left
+//! empty.
+
+use crate::diff::Feed;
+use crate::frequencies::Frequency;
+use crate::pathways::{Level, Pathway};
+use crate::shapes::ShapePoint;
+use crate::writer::{DEFAULT_BUFFER_SIZE, GtfsWriter};
+use crate::{Agency, Calendar, CalendarDate, OutputFormat, Route, Stop, StopTime, Transfer, Trip};
+use anyhow::{Context, Result};
+use serde::de::{DeserializeOwned, Error};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+#[derive(clap::Args)]
+pub struct ExportArgs {
+    /// The feed to export, as a directory or ZIP
+    feed: PathBuf,
+
+    /// What to write it as
+    #[arg(long, value_enum)]
+    format: OutputFormat,
+
+    /// Directory to write the export to
+    #[arg(long, default_value = "./gtfs_export")]
+    output: PathBuf,
+}
+
+/// One of a fixed set of words. Under an alias so serde doesn't take the
+/// `&'static str` fields of `Trip` as borrowed from the input.
+pub type Word = &'static str;
+
+/// `vehicle_type` as the conversion writes it
+pub fn vehicle_type<'de, D: Deserializer<'de>>(deserializer: D) -> Result<&'static str, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    ["train", "bus", "ship"]
+        .into_iter()
+        .find(|word| *word == value)
+        .ok_or_else(|| D::Error::custom(format!("Unknown vehicle_type {}", value)))
+}
+
+/// A word one of the amenity decoders gives, back as that decoder's own
+fn amenity<'de, D: Deserializer<'de>>(
+    deserializer: D,
+    decode: fn(Option<char>) -> Option<&'static str>,
+) -> Result<Option<&'static str>, D::Error> {
+    let Some(value) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    ('A'..='Z')
+        .chain('0'..='9')
+        .find_map(|code| decode(Some(code)).filter(|word| *word == value))
+        .map(Some)
+        .ok_or_else(|| D::Error::custom(format!("Unknown amenity {}", value)))
+}
+
+pub fn sleepers<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<&'static str>, D::Error> {
+    amenity(deserializer, crate::amenities::sleepers)
+}
+
+pub fn reservations<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<&'static str>, D::Error> {
+    amenity(deserializer, crate::amenities::reservations)
+}
+
+pub fn default_vehicle_type() -> &'static str {
+    "train"
+}
+
+/// A feed's records, each trip with its stop times in order
+#[derive(Debug, Default)]
+pub struct GtfsFeed {
+    pub agencies: Vec<Agency>,
+    pub stops: Vec<Stop>,
+    pub routes: Vec<Route>,
+    pub trips: Vec<(Trip, Vec<StopTime>)>,
+    pub calendars: Vec<Calendar>,
+    pub calendar_dates: Vec<CalendarDate>,
+    pub transfers: Vec<Transfer>,
+    pub frequencies: Vec<Frequency>,
+    pub pathways: Vec<Pathway>,
+    pub levels: Vec<Level>,
+    pub shapes: Vec<ShapePoint>,
+}
+
+fn read_rows<T: DeserializeOwned>(feed: &mut Feed, name: &str) -> Result<Option<Vec<T>>> {
+    feed.read(name, |reader: &mut dyn Read| {
+        csv::Reader::from_reader(reader)
+            .deserialize()
+            .collect::<Result<Vec<T>, _>>()
+            .with_context(|| format!("Failed to read {}", name))
+    })
+}
+
+/// Load a feed directory or ZIP
+pub fn read_feed(path: &Path) -> Result<GtfsFeed> {
+    let mut feed = Feed::open(path)?;
+    let mut required = |name: &str| -> Result<()> {
+        feed.read(name, |_| Ok(()))?
+            .with_context(|| format!("{} has no {}", path.display(), name))
+    };
+    for name in [
+        "agency.txt",
+        "stops.txt",
+        "routes.txt",
+        "trips.txt",
+        "stop_times.txt",
+    ] {
+        required(name)?;
+    }
+
+    let trips: Vec<Trip> = read_rows(&mut feed, "trips.txt")?.unwrap_or_default();
+    let mut stop_times: HashMap<String, Vec<StopTime>> = HashMap::new();
+    for stop_time in read_rows::<StopTime>(&mut feed, "stop_times.txt")?.unwrap_or_default() {
+        stop_times
+            .entry(stop_time.trip_id.clone())
+            .or_default()
+            .push(stop_time);
+    }
+    let trips = trips
+        .into_iter()
+        .map(|trip| {
+            let mut calls = stop_times.remove(&trip.trip_id).unwrap_or_default();
+            calls.sort_by_key(|stop_time| stop_time.stop_sequence);
+            (trip, calls)
+        })
+        .collect();
+
+    Ok(GtfsFeed {
+        agencies: read_rows(&mut feed, "agency.txt")?.unwrap_or_default(),
+        stops: read_rows(&mut feed, "stops.txt")?.unwrap_or_default(),
+        routes: read_rows(&mut feed, "routes.txt")?.unwrap_or_default(),
+        trips,
+        calendars: read_rows(&mut feed, "calendar.txt")?.unwrap_or_default(),
+        calendar_dates: read_rows(&mut feed, "calendar_dates.txt")?.unwrap_or_default(),
+        transfers: read_rows(&mut feed, "transfers.txt")?.unwrap_or_default(),
+        frequencies: read_rows(&mut feed, "frequencies.txt")?.unwrap_or_default(),
+        pathways: read_rows(&mut feed, "pathways.txt")?.unwrap_or_default(),
+        levels: read_rows(&mut feed, "levels.txt")?.unwrap_or_default(),
+        shapes: read_rows(&mut feed, "shapes.txt")?.unwrap_or_default(),
+    })
+}
+
+impl GtfsFeed {
+    /// Pass every record to `writer`, each trip followed by its stop times,
+    /// in the order the conversion writes them
+    pub fn write(&self, writer: &mut dyn GtfsWriter) -> Result<()> {
+        for (trip, stop_times) in &self.trips {
+            writer.trip(trip)?;
+            for stop_time in stop_times {
+                writer.stop_time(stop_time)?;
+            }
+        }
+        for frequency in &self.frequencies {
+            writer.frequency(frequency)?;
+        }
+        for transfer in &self.transfers {
+            writer.transfer(transfer)?;
+        }
+        for calendar in &self.calendars {
+            writer.calendar(calendar)?;
+        }
+        for calendar_date in &self.calendar_dates {
+            writer.calendar_date(calendar_date)?;
+        }
+        for pathway in &self.pathways {
+            writer.pathway(pathway)?;
+        }
+        for level in &self.levels {
+            writer.level(level)?;
+        }
+        for stop in &self.stops {
+            writer.stop(stop)?;
+        }
+        for agency in &self.agencies {
+            writer.agency(agency)?;
+        }
+        for route in &self.routes {
+            writer.route(route)?;
+        }
+        for shape in &self.shapes {
+            writer.shape(shape)?;
+        }
+        writer.finish()
+    }
+}
+
+pub fn run(args: ExportArgs) -> Result<()> {
+    let feed = read_feed(&args.feed)?;
+    fs::create_dir_all(&args.output)?;
+    let mut writer = crate::create_feed(args.format, &args.output, DEFAULT_BUFFER_SIZE)?;
+    feed.write(writer.as_mut())?;
+    println!(
+        "Exported {} trips from {} to {}.",
+        feed.trips.len(),
+        args.feed.display(),
+        args.output.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::Fixture;
+    use crate::writer::DirectoryFeed;
+    use crate::{CalendarMode, write_calendars, write_converted_trip};
+
+    #[test]
+    fn test_read_feed_round_trips() {
+        let (trips, summary, _) = Fixture::default().convert("sample.MSN", "sample.MCA");
+        let first = tempfile::tempdir().unwrap();
+        let mut feed = DirectoryFeed::create(first.path(), 1024).unwrap();
+        for trip in &trips {
+            write_converted_trip(&mut feed, trip).unwrap();
+        }
+        write_calendars(&mut feed, &summary.calendars, CalendarMode::Calendar).unwrap();
+        for agency in summary.agencies.values() {
+            feed.agency(agency).unwrap();
+        }
+        for route in summary.routes.values() {
+            feed.route(route).unwrap();
+        }
+        feed.finish().unwrap();
+
+        let read = read_feed(first.path()).unwrap();
+        assert_eq!(read.trips.len(), trips.len());
+        assert_eq!(read.trips[0].1.len(), trips[0].stop_times.len());
+
+        let second = tempfile::tempdir().unwrap();
+        let mut feed = DirectoryFeed::create(second.path(), 1024).unwrap();
+        read.write(&mut feed).unwrap();
+        for name in [
+            "agency.txt",
+            "routes.txt",
+            "trips.txt",
+            "stop_times.txt",
+            "calendar.txt",
+            "calendar_dates.txt",
+        ] {
+            assert_eq!(
+                fs::read_to_string(first.path().join(name)).unwrap(),
+                fs::read_to_string(second.path().join(name)).unwrap(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_feed_from_elsewhere() {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, text) in [
+            (
+                "agency.txt",
+                "agency_name,agency_url,agency_timezone\nBus Co,https://bus.example,Europe/London\n",
+            ),
+            (
+                "stops.txt",
+                "stop_id,stop_name,stop_lat,stop_lon\nA,Alpha,51.5,-0.1\nB,Beta,51.6,-0.2\n",
+            ),
+            (
+                "routes.txt",
+                "route_id,route_short_name,route_type\n1,1,3\n",
+            ),
+            ("trips.txt", "route_id,service_id,trip_id\n1,WK,T1\n"),
+            (
+                "stop_times.txt",
+                "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+                 T1,08:05:00,08:05:00,B,2\nT1,08:00:00,08:00:00,A,1\n",
+            ),
+        ] {
+            fs::write(dir.path().join(name), text).unwrap();
+        }
+        let feed = read_feed(dir.path()).unwrap();
+        let (trip, stop_times) = &feed.trips[0];
+        assert_eq!(trip.vehicle_type, "train");
+        assert_eq!(trip.sleepers, None);
+        assert_eq!(stop_times[0].stop_id, "A");
+        assert_eq!(feed.agencies[0].agency_id, "");
+        assert!(feed.calendars.is_empty());
+    }
+}
@@ -0,0 +1,771 @@
+//! The `realtime` subcommand: GTFS-Realtime TripUpdates from the Darwin
+//! Push Port.
+//!
+//! Darwin's train status (`TS`) messages carry forecast and actual times at
+//! each location of a run, identified by RID, UID and start date. Each run
+//! is matched to the static trip that has its UID on that date, found through
+//! the `trip_id_map.csv` of a feed this tool wrote, and served as delays
+//! against the timetable. A run folded into another trip's frequency is
+//! sent as that trip with the start time of its departure.
+
+use crate::credentials::{self, Credentials};
+use crate::darwin;
+use crate::gtfs_rt::{
+    FeedEntity, FeedHeader, FeedMessage, GTFS_REALTIME_VERSION, Incrementality, StopTimeEvent,
+    StopTimeUpdate, TripDescriptor, TripUpdate,
+};
+use crate::metrics::{self, Metrics};
+use crate::stomp::StompClient;
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{Datelike, NaiveDate};
+use flate2::read::GzDecoder;
+use nationalrail_gtfs::cif::CifTime;
+use prost::Message;
+use quick_xml::Reader;
+use quick_xml::events::{BytesStart, Event};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DARWIN_BROKER: &str = "darwin-dist-44ae45.nationalrail.co.uk:61613";
+const DARWIN_TOPIC: &str = "/topic/darwin.pushport-v16";
+
+/// Wait before reconnecting to the broker after losing the connection
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(clap::Args)]
+pub struct RealtimeArgs {
+    /// Directory holding the static feed the updates refer to
+    #[arg(long, default_value = "./gtfs_output")]
+    gtfs_dir: PathBuf,
+
+    /// Address to serve the TripUpdates feed on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    listen: String,
+
+    /// Darwin Push Port STOMP broker (needs DARWIN_USERNAME and DARWIN_PASSWORD)
+    #[arg(long, default_value = DARWIN_BROKER)]
+    broker: String,
+
+    /// Push Port topic to subscribe to
+    #[arg(long, default_value = DARWIN_TOPIC)]
+    topic: String,
+
+    /// Address to serve Prometheus metrics on (e.g. :9090)
+    #[arg(long, value_name = "ADDR")]
+    serve_metrics: Option<String>,
+
+    /// The `--id-prefix` the static feed was converted with
+    #[arg(long, value_name = "PREFIX", default_value = "")]
+    id_prefix: String,
+}
+
+// --- Static feed ---
+
+#[derive(Deserialize)]
+struct TripRow {
+    trip_id: String,
+    route_id: String,
+    service_id: String,
+}
+
+#[derive(Deserialize)]
+struct TripIdMapRow {
+    trip_id: String,
+    uid: String,
+    stp_indicator: String,
+    /// Set for schedules folded into a frequency; maps from before
+    /// `--use-frequencies` have no such column
+    #[serde(default)]
+    start_time: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CalendarRow {
+    service_id: String,
+    monday: u8,
+    tuesday: u8,
+    wednesday: u8,
+    thursday: u8,
+    friday: u8,
+    saturday: u8,
+    sunday: u8,
+    start_date: String,
+    end_date: String,
+}
+
+#[derive(Deserialize)]
+struct CalendarDateRow {
+    service_id: String,
+    date: String,
+    exception_type: u8,
+}
+
+#[derive(Deserialize)]
+struct StopTimeRow {
+    trip_id: String,
+    stop_sequence: u32,
+    stop_id: String,
+}
+
+#[derive(Default)]
+struct Service {
+    /// Days of the week from Monday, and the date range they apply in
+    days: [bool; 7],
+    range: Option<(NaiveDate, NaiveDate)>,
+    added: HashSet<NaiveDate>,
+    removed: HashSet<NaiveDate>,
+}
+
+impl Service {
+    fn runs_on(&self, date: NaiveDate) -> bool {
+        if self.added.contains(&date) {
+            return true;
+        }
+        if self.removed.contains(&date) {
+            return false;
+        }
+        self.range.is_some_and(|(start, end)| {
+            start <= date
+                && date <= end
+                && self.days[date.weekday().num_days_from_monday() as usize]
+        })
+    }
+}
+
+struct StaticTrip {
+    trip_id: String,
+    route_id: String,
+    service_id: String,
+    stp_indicator: String,
+    /// `(stop_sequence, stop_id)` in order
+    stops: Vec<(u32, String)>,
+}
+
+/// The trips of a converted feed, by UID, with the calendars they run on
+#[derive(Default)]
+pub struct StaticIndex {
+    trips: Vec<StaticTrip>,
+    /// Each UID's trips, with the start time of the departure it runs as
+    /// if it's folded into a frequency
+    by_uid: HashMap<String, Vec<(usize, Option<String>)>>,
+    services: HashMap<String, Service>,
+    /// Prefix of the feed's ids, before the TIPLOC its stop ids start with
+    id_prefix: String,
+}
+
+fn gtfs_date(value: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(value, "%Y%m%d").with_context(|| format!("Invalid date {}", value))
+}
+
+impl StaticIndex {
+    pub fn load(dir: &Path, id_prefix: &str) -> Result<Self> {
+        let open = |name: &str| {
+            let path = dir.join(name);
+            csv::Reader::from_path(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))
+        };
+        let mut index = StaticIndex {
+            id_prefix: id_prefix.to_string(),
+            ..StaticIndex::default()
+        };
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        for row in open("trips.txt")?.deserialize() {
+            let row: TripRow = row?;
+            positions.insert(row.trip_id.clone(), index.trips.len());
+            index.trips.push(StaticTrip {
+                trip_id: row.trip_id,
+                route_id: row.route_id,
+                service_id: row.service_id,
+                stp_indicator: String::new(),
+                stops: Vec::new(),
+            });
+        }
+        for row in open("trip_id_map.csv")?.deserialize() {
+            let row: TripIdMapRow = row?;
+            if let Some(&i) = positions.get(&row.trip_id) {
+                index.trips[i].stp_indicator = row.stp_indicator;
+                index
+                    .by_uid
+                    .entry(row.uid)
+                    .or_default()
+                    .push((i, row.start_time));
+            }
+        }
+        for row in open("stop_times.txt")?.deserialize() {
+            let row: StopTimeRow = row?;
+            if let Some(&i) = positions.get(&row.trip_id) {
+                index.trips[i].stops.push((row.stop_sequence, row.stop_id));
+            }
+        }
+        for row in open("calendar.txt")?.deserialize() {
+            let row: CalendarRow = row?;
+            let service = index.services.entry(row.service_id).or_default();
+            service.days = [
+                row.monday,
+                row.tuesday,
+                row.wednesday,
+                row.thursday,
+                row.friday,
+                row.saturday,
+                row.sunday,
+            ]
+            .map(|day| day == 1);
+            service.range = Some((gtfs_date(&row.start_date)?, gtfs_date(&row.end_date)?));
+        }
+        for row in open("calendar_dates.txt")?.deserialize() {
+            let row: CalendarDateRow = row?;
+            let service = index.services.entry(row.service_id).or_default();
+            let date = gtfs_date(&row.date)?;
+            match row.exception_type {
+                1 => service.added.insert(date),
+                _ => service.removed.insert(date),
+            };
+        }
+        for trip in &mut index.trips {
+            trip.stops.sort_by_key(|&(sequence, _)| sequence);
+        }
+        Ok(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.trips.len()
+    }
+
+    /// The trip a UID runs as on a date: an overlay over a new schedule
+    /// over the permanent one, as in the CIF. With it, the start time of
+    /// the UID's departure if the trip is a frequency.
+    fn trip_on(&self, uid: &str, date: NaiveDate) -> Option<(&StaticTrip, Option<&str>)> {
+        self.by_uid
+            .get(uid)?
+            .iter()
+            .map(|(i, start_time)| (&self.trips[*i], start_time.as_deref()))
+            .filter(|(trip, _)| {
+                self.services
+                    .get(&trip.service_id)
+                    .is_some_and(|service| service.runs_on(date))
+            })
+            .min_by_key(|(trip, _)| match trip.stp_indicator.as_str() {
+                "O" => 0,
+                "N" => 1,
+                _ => 2,
+            })
+    }
+}
+
+// --- Push Port messages ---
+
+/// A `TS` message: forecasts for some locations of one run
+#[derive(Debug)]
+pub struct TrainStatus {
+    pub rid: String,
+    pub uid: String,
+    pub ssd: NaiveDate,
+    pub locations: Vec<LocationForecast>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct LocationForecast {
+    pub tiploc: String,
+    /// Seconds late against the timetable
+    pub arrival_delay: Option<i32>,
+    pub departure_delay: Option<i32>,
+}
+
+fn seconds(time: CifTime) -> i32 {
+    i32::from(time.hour) * 3600
+        + i32::from(time.minute) * 60
+        + if time.half_minute { 30 } else { 0 }
+}
+
+/// Seconds from a scheduled time to a forecast one, which may be either
+/// side of midnight
+fn delay(scheduled: CifTime, forecast: CifTime) -> i32 {
+    const DAY: i32 = 24 * 3600;
+    let delay = seconds(forecast) - seconds(scheduled);
+    if delay > DAY / 2 {
+        delay - DAY
+    } else if delay < -DAY / 2 {
+        delay + DAY
+    } else {
+        delay
+    }
+}
+
+fn attributes(e: &BytesStart) -> Result<HashMap<String, String>> {
+    let mut map = HashMap::new();
+    for attr in e.attributes() {
+        let attr = attr?;
+        let key = String::from_utf8_lossy(attr.key.local_name().as_ref()).into_owned();
+        map.insert(key, attr.unescape_value()?.into_owned());
+    }
+    Ok(map)
+}
+
+/// Read the train status messages out of a Push Port `Pport` document
+pub fn parse_push_port(xml: &[u8]) -> Result<Vec<TrainStatus>> {
+    let mut reader = Reader::from_reader(xml);
+    let mut statuses = Vec::new();
+    let mut current: Option<TrainStatus> = None;
+    // The open location's timetable times, and its forecast so far
+    let mut location: Option<(HashMap<String, String>, LocationForecast)> = None;
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) | Event::Empty(e) => match e.local_name().as_ref() {
+                b"TS" => {
+                    let mut attrs = attributes(&e)?;
+                    let ssd = attrs.get("ssd").context("TS without an ssd")?;
+                    current = Some(TrainStatus {
+                        ssd: NaiveDate::parse_from_str(ssd, "%Y-%m-%d")?,
+                        rid: attrs.remove("rid").context("TS without a rid")?,
+                        uid: attrs.remove("uid").context("TS without a uid")?,
+                        locations: Vec::new(),
+                    });
+                }
+                b"Location" if current.is_some() => {
+                    let mut attrs = attributes(&e)?;
+                    let tiploc = attrs.remove("tpl").unwrap_or_default();
+                    location = Some((
+                        attrs,
+                        LocationForecast {
+                            tiploc,
+                            arrival_delay: None,
+                            departure_delay: None,
+                        },
+                    ));
+                }
+                kind @ (b"arr" | b"dep") => {
+                    let Some((times, forecast)) = location.as_mut() else {
+                        continue;
+                    };
+                    let attrs = attributes(&e)?;
+                    // An actual time replaces the estimate once recorded
+                    let Some(actual) = darwin::time(attrs.get("at").or(attrs.get("et"))) else {
+                        continue;
+                    };
+                    let (public, working) = if kind == b"arr" {
+                        ("pta", "wta")
+                    } else {
+                        ("ptd", "wtd")
+                    };
+                    let scheduled = darwin::time(times.get(public).or(times.get(working)));
+                    let value = scheduled.map(|scheduled| delay(scheduled, actual));
+                    if kind == b"arr" {
+                        forecast.arrival_delay = value;
+                    } else {
+                        forecast.departure_delay = value;
+                    }
+                }
+                _ => {}
+            },
+            Event::End(e) => match e.local_name().as_ref() {
+                b"Location" => {
+                    if let (Some((_, forecast)), Some(status)) = (location.take(), current.as_mut())
+                        && (forecast.arrival_delay.is_some() || forecast.departure_delay.is_some())
+                    {
+                        status.locations.push(forecast);
+                    }
+                }
+                b"TS" => statuses.extend(current.take()),
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(statuses)
+}
+
+// --- Feed state ---
+
+struct TrackedTrain {
+    ssd: NaiveDate,
+    trip: TripDescriptor,
+    /// By stop_sequence, each replaced as newer forecasts arrive
+    updates: BTreeMap<u32, StopTimeUpdate>,
+    timestamp: u64,
+}
+
+/// The latest forecasts for every run matched to the static feed
+pub struct Realtime {
+    index: StaticIndex,
+    trains: HashMap<String, TrackedTrain>,
+}
+
+impl Realtime {
+    pub fn new(index: StaticIndex) -> Self {
+        Realtime {
+            index,
+            trains: HashMap::new(),
+        }
+    }
+
+    /// Merge a train status into its run's trip update. Runs with no trip in
+    /// the static feed are ignored.
+    pub fn apply(&mut self, status: TrainStatus, now: u64) {
+        let Some((trip, start_time)) = self.index.trip_on(&status.uid, status.ssd) else {
+            return;
+        };
+        let train = self
+            .trains
+            .entry(status.rid)
+            .or_insert_with(|| TrackedTrain {
+                ssd: status.ssd,
+                trip: TripDescriptor {
+                    trip_id: Some(trip.trip_id.clone()),
+                    start_time: start_time.map(str::to_string),
+                    start_date: Some(status.ssd.format("%Y%m%d").to_string()),
+                    route_id: Some(trip.route_id.clone()),
+                },
+                updates: BTreeMap::new(),
+                timestamp: now,
+            });
+        train.timestamp = now;
+        // Locations come in calling order, so each is looked for after the
+        // last one matched, for trains calling at a station twice
+        let mut next = 0;
+        for forecast in status.locations {
+            let Some(offset) = trip.stops[next..].iter().position(|(_, stop_id)| {
+                let stop_id = stop_id
+                    .strip_prefix(&self.index.id_prefix)
+                    .unwrap_or(stop_id);
+                stop_id.split('_').next() == Some(forecast.tiploc.as_str())
+            }) else {
+                continue;
+            };
+            let (sequence, stop_id) = &trip.stops[next + offset];
+            next += offset + 1;
+            let event =
+                |delay: Option<i32>| delay.map(|delay| StopTimeEvent { delay: Some(delay) });
+            let update = train
+                .updates
+                .entry(*sequence)
+                .or_insert_with(|| StopTimeUpdate {
+                    stop_sequence: Some(*sequence),
+                    arrival: None,
+                    departure: None,
+                    stop_id: Some(stop_id.clone()),
+                });
+            if forecast.arrival_delay.is_some() {
+                update.arrival = event(forecast.arrival_delay);
+            }
+            if forecast.departure_delay.is_some() {
+                update.departure = event(forecast.departure_delay);
+            }
+        }
+    }
+
+    /// Trains with updates to serve
+    fn updated_trains(&self) -> usize {
+        self.trains
+            .values()
+            .filter(|train| !train.updates.is_empty())
+            .count()
+    }
+
+    /// Forget runs that started before yesterday
+    pub fn expire(&mut self, today: NaiveDate) {
+        self.trains
+            .retain(|_, train| train.ssd >= today - chrono::Days::new(1));
+    }
+
+    pub fn feed(&self, now: u64) -> FeedMessage {
+        let mut entity: Vec<FeedEntity> = self
+            .trains
+            .iter()
+            .filter(|(_, train)| !train.updates.is_empty())
+            .map(|(rid, train)| FeedEntity {
+                id: rid.clone(),
+                trip_update: Some(TripUpdate {
+                    trip: train.trip.clone(),
+                    stop_time_update: train.updates.values().cloned().collect(),
+                    timestamp: Some(train.timestamp),
+                }),
+                alert: None,
+            })
+            .collect();
+        entity.sort_by(|a, b| a.id.cmp(&b.id));
+        FeedMessage {
+            header: FeedHeader {
+                gtfs_realtime_version: GTFS_REALTIME_VERSION.to_string(),
+                incrementality: Some(Incrementality::FullDataset as i32),
+                timestamp: Some(now),
+            },
+            entity,
+        }
+    }
+}
+
+// --- Running the server ---
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Push Port message bodies are gzipped XML
+fn decompress(body: &[u8]) -> Result<Vec<u8>> {
+    if !body.starts_with(&[0x1f, 0x8b]) {
+        return Ok(body.to_vec());
+    }
+    let mut xml = Vec::new();
+    GzDecoder::new(body).read_to_end(&mut xml)?;
+    Ok(xml)
+}
+
+fn consume(
+    broker: &str,
+    topic: &str,
+    username: &str,
+    password: &str,
+    state: &Mutex<Realtime>,
+    metrics: &Metrics,
+) -> Result<()> {
+    let mut client = StompClient::connect(broker, username, password)?;
+    client.subscribe(topic)?;
+    println!("Subscribed to {} on {}", topic, broker);
+    loop {
+        let frame = client.next_frame()?;
+        match frame.command.as_str() {
+            "MESSAGE" => {}
+            "ERROR" => bail!(
+                "Broker error: {}",
+                frame.header("message").unwrap_or("no reason given")
+            ),
+            _ => continue,
+        }
+        let started = Instant::now();
+        let statuses = match decompress(&frame.body).and_then(|xml| parse_push_port(&xml)) {
+            Ok(statuses) => statuses,
+            Err(e) => {
+                println!("Warning: skipping unreadable Push Port message: {:#}", e);
+                metrics.record_error();
+                continue;
+            }
+        };
+        let records = statuses.len();
+        let now = unix_now();
+        let mut realtime = state.lock().unwrap();
+        for status in statuses {
+            realtime.apply(status, now);
+        }
+        realtime.expire(crate::timezone::today());
+        metrics.record_update(started.elapsed(), records, realtime.updated_trains());
+    }
+}
+
+pub fn run(args: RealtimeArgs, credentials: &Credentials) -> Result<()> {
+    let (username, password) = credentials.get(credentials::Service::Darwin)?;
+
+    println!("Loading static feed from {}...", args.gtfs_dir.display());
+    let index = StaticIndex::load(&args.gtfs_dir, &args.id_prefix)?;
+    println!("Loaded {} trips.", index.len());
+    let state = Arc::new(Mutex::new(Realtime::new(index)));
+    let metrics = Arc::new(Metrics::default());
+    if let Some(listen) = &args.serve_metrics {
+        metrics::serve(Arc::clone(&metrics), listen)?;
+    }
+
+    let server = tiny_http::Server::http(&args.listen)
+        .map_err(|e| anyhow!("Failed to listen on {}: {}", args.listen, e))?;
+
+    let consumer_state = Arc::clone(&state);
+    let (broker, topic) = (args.broker, args.topic);
+    std::thread::spawn(move || {
+        loop {
+            if let Err(e) = consume(
+                &broker,
+                &topic,
+                &username,
+                &password,
+                &consumer_state,
+                &metrics,
+            ) {
+                metrics.record_error();
+                println!(
+                    "Push Port connection lost ({:#}); reconnecting in {}s",
+                    e,
+                    RECONNECT_DELAY.as_secs()
+                );
+            }
+            std::thread::sleep(RECONNECT_DELAY);
+        }
+    });
+
+    println!("Serving TripUpdates on http://{}/", args.listen);
+    let content_type = tiny_http::Header::from_bytes("Content-Type", "application/x-protobuf")
+        .expect("static header is valid");
+    for request in server.incoming_requests() {
+        let body = state.lock().unwrap().feed(unix_now()).encode_to_vec();
+        let response = tiny_http::Response::from_data(body).with_header(content_type.clone());
+        if let Err(e) = request.respond(response) {
+            println!("Warning: failed to answer a request: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_train_status_becomes_trip_update() {
+        let dir = tempfile::tempdir().unwrap();
+        let write =
+            |name: &str, contents: &str| fs::write(dir.path().join(name), contents).unwrap();
+        write(
+            "trips.txt",
+            "route_id,service_id,trip_id\nR1,S1,C10001_240101_P\nR1,S2,C10001_240101_O\n",
+        );
+        write(
+            "stop_times.txt",
+            "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+             C10001_240101_P,09:00:00,09:00:00,KNGX_8,1\n\
+             C10001_240101_P,10:12:00,10:13:00,PBRO_2,2\n\
+             C10001_240101_P,10:50:00,10:50:00,YORK,3\n\
+             C10001_240101_O,09:00:00,09:00:00,KNGX,1\n",
+        );
+        write(
+            "calendar.txt",
+            "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date\n\
+             S1,1,1,1,1,1,0,0,20240101,20240131\n",
+        );
+        write(
+            "trip_id_map.csv",
+            "trip_id,uid,stp_indicator,start_date,end_date\n\
+             C10001_240101_P,C10001,P,20240101,20241214\n\
+             C10001_240101_O,C10001,O,20240101,20241214\n",
+        );
+        // The overlay only runs on the Tuesday
+        write(
+            "calendar_dates.txt",
+            "service_id,date,exception_type\nS2,20240102,1\n",
+        );
+        let index = StaticIndex::load(dir.path(), "").unwrap();
+        assert_eq!(index.len(), 2);
+
+        let xml = br#"<Pport xmlns="http://www.thalesgroup.com/rtti/PushPort/v16" xmlns:fc="http://www.thalesgroup.com/rtti/PushPort/Forecasts/v3" ts="2024-01-01T10:00:00" version="16.0">
+  <uR updateOrigin="TD">
+    <TS rid="202401017100001" uid="C10001" ssd="2024-01-01">
+      <fc:Location tpl="HUNTNGN" wtp="09:40:30"><fc:pass et="09:42" /></fc:Location>
+      <fc:Location tpl="PBRO" wta="10:12" wtd="10:13:30" pta="10:12" ptd="10:13">
+        <fc:arr at="10:15" et="10:14" /><fc:dep et="10:16" />
+      </fc:Location>
+      <fc:Location tpl="YORK" wta="10:50" pta="10:50"><fc:arr et="10:48" /></fc:Location>
+    </TS>
+  </uR>
+</Pport>"#;
+        let statuses = parse_push_port(xml).unwrap();
+        assert_eq!(statuses.len(), 1);
+        assert_eq!(
+            statuses[0].locations[0],
+            LocationForecast {
+                tiploc: "PBRO".to_string(),
+                arrival_delay: Some(180),
+                departure_delay: Some(180),
+            }
+        );
+
+        let mut realtime = Realtime::new(index);
+        for status in statuses {
+            realtime.apply(status, 1_704_103_200);
+        }
+        let feed = realtime.feed(1_704_103_200);
+        assert_eq!(feed.entity.len(), 1);
+        let update = feed.entity[0].trip_update.as_ref().unwrap();
+        assert_eq!(update.trip.trip_id.as_deref(), Some("C10001_240101_P"));
+        assert_eq!(update.trip.start_date.as_deref(), Some("20240101"));
+        let stops: Vec<(Option<u32>, Option<&str>, Option<i32>)> = update
+            .stop_time_update
+            .iter()
+            .map(|u| {
+                (
+                    u.stop_sequence,
+                    u.stop_id.as_deref(),
+                    u.arrival.as_ref().and_then(|a| a.delay),
+                )
+            })
+            .collect();
+        assert_eq!(
+            stops,
+            [
+                (Some(2), Some("PBRO_2"), Some(180)),
+                (Some(3), Some("YORK"), Some(-120))
+            ]
+        );
+        assert!(FeedMessage::decode(feed.encode_to_vec().as_slice()).is_ok());
+
+        // On the Tuesday the overlay wins
+        assert_eq!(
+            realtime
+                .index
+                .trip_on("C10001", NaiveDate::from_ymd_opt(2024, 1, 2).unwrap())
+                .map(|(t, _)| t.trip_id.as_str()),
+            Some("C10001_240101_O")
+        );
+    }
+
+    #[test]
+    fn test_folded_run_is_sent_with_its_start_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let write =
+            |name: &str, contents: &str| fs::write(dir.path().join(name), contents).unwrap();
+        write(
+            "trips.txt",
+            "route_id,service_id,trip_id\nR1,S1,C10001_240101_P\n",
+        );
+        write(
+            "stop_times.txt",
+            "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+             C10001_240101_P,09:00:00,09:00:00,KNGX,1\n\
+             C10001_240101_P,10:12:00,10:13:00,PBRO,2\n",
+        );
+        write(
+            "calendar.txt",
+            "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date\n\
+             S1,1,1,1,1,1,0,0,20240101,20240131\n",
+        );
+        write("calendar_dates.txt", "service_id,date,exception_type\n");
+        // C10002 runs half an hour later in C10001's frequency
+        write(
+            "trip_id_map.csv",
+            "trip_id,uid,stp_indicator,start_date,end_date,start_time\n\
+             C10001_240101_P,C10001,P,20240101,20241214,09:00:00\n\
+             C10001_240101_P,C10002,P,20240101,20241214,09:30:00\n",
+        );
+        let mut realtime = Realtime::new(StaticIndex::load(dir.path(), "").unwrap());
+        let xml = br#"<Pport xmlns="http://www.thalesgroup.com/rtti/PushPort/v16" xmlns:fc="http://www.thalesgroup.com/rtti/PushPort/Forecasts/v3" ts="2024-01-01T10:00:00" version="16.0">
+  <uR updateOrigin="TD">
+    <TS rid="202401017100002" uid="C10002" ssd="2024-01-01">
+      <fc:Location tpl="PBRO" wta="10:42" pta="10:42"><fc:arr et="10:45" /></fc:Location>
+    </TS>
+  </uR>
+</Pport>"#;
+        for status in parse_push_port(xml).unwrap() {
+            realtime.apply(status, 1_704_103_200);
+        }
+        let feed = realtime.feed(1_704_103_200);
+        let trip = &feed.entity[0].trip_update.as_ref().unwrap().trip;
+        assert_eq!(trip.trip_id.as_deref(), Some("C10001_240101_P"));
+        assert_eq!(trip.start_time.as_deref(), Some("09:30:00"));
+    }
+
+    #[test]
+    fn test_delay_across_midnight() {
+        let t = |hour, minute| CifTime {
+            hour,
+            minute,
+            half_minute: false,
+        };
+        assert_eq!(delay(t(23, 58), t(0, 3)), 300);
+        assert_eq!(delay(t(0, 2), t(23, 59)), -180);
+    }
+}
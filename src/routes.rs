@@ -0,0 +1,230 @@
+//! How trips are grouped into GTFS routes.
+//!
+//! The CIF has no notion of a route, so one has to be made up from what a
+//! schedule does carry. Each strategy trades route count against how
+//! similar the trips sharing a route are.
+
+use clap::ValueEnum;
+
+/// The parts of a trip a route can be derived from
+pub struct RouteTrip<'a> {
+    pub atoc_code: &'a str,
+    pub agency_name: &'a str,
+    pub origin_tiploc: &'a str,
+    pub origin_name: &'a str,
+    pub dest_tiploc: &'a str,
+    pub dest_name: &'a str,
+    pub train_identity: &'a str,
+    pub train_service_code: Option<&'a str>,
+}
+
+impl RouteTrip<'_> {
+    fn origin_to_destination(&self) -> String {
+        format!("{} to {}", self.origin_name, self.dest_name)
+    }
+}
+
+/// A way of assigning trips to routes
+pub trait RouteKey {
+    fn route_id(&self, trip: &RouteTrip) -> String;
+
+    fn long_name(&self, trip: &RouteTrip) -> String {
+        trip.origin_to_destination()
+    }
+}
+
+/// One route per operator and origin station (the original behaviour)
+pub struct ByOrigin;
+
+impl RouteKey for ByOrigin {
+    fn route_id(&self, trip: &RouteTrip) -> String {
+        format!("{}_{}", trip.atoc_code, trip.origin_name)
+    }
+}
+
+/// One route per operator
+pub struct ByToc;
+
+impl RouteKey for ByToc {
+    fn route_id(&self, trip: &RouteTrip) -> String {
+        trip.atoc_code.to_string()
+    }
+
+    fn long_name(&self, trip: &RouteTrip) -> String {
+        trip.agency_name.to_string()
+    }
+}
+
+/// One route per operator and pair of end points, whichever way the trip runs
+pub struct ByEndpoints;
+
+impl ByEndpoints {
+    fn ordered<'a>(trip: &RouteTrip<'a>) -> [(&'a str, &'a str); 2] {
+        let mut ends = [
+            (trip.origin_tiploc, trip.origin_name),
+            (trip.dest_tiploc, trip.dest_name),
+        ];
+        ends.sort();
+        ends
+    }
+}
+
+impl RouteKey for ByEndpoints {
+    fn route_id(&self, trip: &RouteTrip) -> String {
+        let [(a, _), (b, _)] = Self::ordered(trip);
+        format!("{}_{}_{}", trip.atoc_code, a, b)
+    }
+
+    fn long_name(&self, trip: &RouteTrip) -> String {
+        let [(_, a), (_, b)] = Self::ordered(trip);
+        format!("{} - {}", a, b)
+    }
+}
+
+/// One route per operator and train service code, falling back to the
+/// end points for schedules without one
+pub struct ByServiceGroup;
+
+impl RouteKey for ByServiceGroup {
+    fn route_id(&self, trip: &RouteTrip) -> String {
+        match trip.train_service_code {
+            Some(code) => format!("{}_{}", trip.atoc_code, code),
+            None => ByEndpoints.route_id(trip),
+        }
+    }
+
+    fn long_name(&self, trip: &RouteTrip) -> String {
+        match trip.train_service_code {
+            Some(_) => trip.origin_to_destination(),
+            None => ByEndpoints.long_name(trip),
+        }
+    }
+}
+
+/// One route per operator and headcode prefix (train class and
+/// destination area, e.g. `1A`)
+pub struct ByTrainIdPrefix;
+
+impl RouteKey for ByTrainIdPrefix {
+    fn route_id(&self, trip: &RouteTrip) -> String {
+        let prefix = trip.train_identity.get(..2).unwrap_or(trip.train_identity);
+        format!("{}_{}", trip.atoc_code, prefix)
+    }
+}
+
+/// Charing Cross, where railway mileages are traditionally measured from
+const LONDON: (f64, f64) = (51.5073, -0.1246);
+
+/// The GTFS direction of a trip from the coordinates of its calls: 0 for
+/// towards London, the railway's "up" direction, and 1 for away. Trips
+/// ending where they start are judged by their second and penultimate
+/// calls instead. The two directions of any route come out opposite.
+pub fn direction_id(calls: &[(f64, f64)]) -> Option<u8> {
+    let distance = |(lat, lon): (f64, f64)| {
+        let dx = (lon - LONDON.1) * lat.to_radians().cos();
+        let dy = lat - LONDON.0;
+        dx * dx + dy * dy
+    };
+    let ends = [
+        (calls.first()?, calls.last()?),
+        (calls.get(1)?, calls.get(calls.len().checked_sub(2)?)?),
+    ];
+    ends.into_iter().find_map(|(&first, &last)| {
+        let (from, to) = (distance(first), distance(last));
+        if to < from {
+            Some(0)
+        } else if to > from {
+            Some(1)
+        } else {
+            None
+        }
+    })
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum RouteGrouping {
+    /// Operator and origin station
+    #[default]
+    Origin,
+    /// Operator only
+    Toc,
+    /// Operator and end points, in either direction
+    Endpoints,
+    /// Operator and train service code
+    ServiceGroup,
+    /// Operator and the first two characters of the headcode
+    TrainIdPrefix,
+}
+
+impl RouteGrouping {
+    pub fn strategy(self) -> Box<dyn RouteKey> {
+        match self {
+            RouteGrouping::Origin => Box::new(ByOrigin),
+            RouteGrouping::Toc => Box::new(ByToc),
+            RouteGrouping::Endpoints => Box::new(ByEndpoints),
+            RouteGrouping::ServiceGroup => Box::new(ByServiceGroup),
+            RouteGrouping::TrainIdPrefix => Box::new(ByTrainIdPrefix),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_keys() {
+        let outbound = RouteTrip {
+            atoc_code: "GW",
+            agency_name: "Great Western Railway",
+            origin_tiploc: "PADTON",
+            origin_name: "London Paddington",
+            dest_tiploc: "BRSTLTM",
+            dest_name: "Bristol Temple Meads",
+            train_identity: "1C23",
+            train_service_code: Some("25471001"),
+        };
+        let inbound = RouteTrip {
+            origin_tiploc: "BRSTLTM",
+            origin_name: "Bristol Temple Meads",
+            dest_tiploc: "PADTON",
+            dest_name: "London Paddington",
+            train_identity: "1A45",
+            train_service_code: None,
+            ..outbound
+        };
+
+        assert_eq!(ByOrigin.route_id(&outbound), "GW_London Paddington");
+        assert_eq!(ByToc.route_id(&inbound), "GW");
+        assert_eq!(ByToc.long_name(&inbound), "Great Western Railway");
+        assert_eq!(ByEndpoints.route_id(&outbound), "GW_BRSTLTM_PADTON");
+        assert_eq!(
+            ByEndpoints.route_id(&outbound),
+            ByEndpoints.route_id(&inbound)
+        );
+        assert_eq!(
+            ByEndpoints.long_name(&inbound),
+            "Bristol Temple Meads - London Paddington"
+        );
+        assert_eq!(ByServiceGroup.route_id(&outbound), "GW_25471001");
+        assert_eq!(ByServiceGroup.route_id(&inbound), "GW_BRSTLTM_PADTON");
+        assert_eq!(ByTrainIdPrefix.route_id(&outbound), "GW_1C");
+    }
+
+    #[test]
+    fn test_direction_towards_london_is_zero() {
+        let kings_cross = (51.5320, -0.1233);
+        let peterborough = (52.5749, -0.2502);
+        let york = (53.9580, -1.0930);
+        assert_eq!(direction_id(&[york, peterborough, kings_cross]), Some(0));
+        assert_eq!(direction_id(&[kings_cross, peterborough, york]), Some(1));
+
+        // A loop out of York and back goes by the way it comes home:
+        // from the south here
+        let leeds = (53.7950, -1.5478);
+        assert_eq!(direction_id(&[york, leeds, peterborough, york]), Some(0));
+        assert_eq!(direction_id(&[york, peterborough, leeds, york]), Some(1));
+        assert_eq!(direction_id(&[york, york]), None);
+        assert_eq!(direction_id(&[york]), None);
+    }
+}
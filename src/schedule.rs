@@ -0,0 +1,1339 @@
+//! Assembly of GTFS trips from the records of a CIF schedule.
+//!
+//! A schedule arrives as a BS record, an optional BX, then its LO, LI and
+//! LT calling points. [`ScheduleBuilder`] takes them one at a time and
+//! hands back a trip once the LT closes the schedule. Associations come
+//! first in an extract, so blocks and splits are known by then; trains that
+//! divide or join are held to the end, for the other portion's destination.
+
+use crate::formations::Formation;
+use crate::headsign;
+use crate::routes::{self, RouteKey, RouteTrip};
+use crate::shapes::{self, PathPoint};
+use crate::stats::Stats;
+use crate::timezone::ShiftedTimes;
+use crate::{
+    Agency, BadTimesPolicy, CalendarDate, ConvertedTrip, DroppedStop, MAX_CIF_WARNINGS,
+    ParsedStation, RejectedRecord, RequestStopPolicy, Route, ServiceCalendar, StopTime,
+    TimetableContext, TimetableSummary, Transfer, Trip, amenities, bank_holiday_exceptions,
+    branding, build_calendar, cif_date, gtfs_seconds, gtfs_time, knowledgebase, normalise_calendar,
+    operators, repair_times, service_hash, service_id, timezone,
+};
+use chrono::{Datelike, Days, NaiveDate};
+use nationalrail_gtfs::cif::{
+    self, Association, BasicSchedule, BasicScheduleExtra, CifTime, IntermediateLocation,
+    OriginLocation, TerminatingLocation, Transaction,
+};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+
+/// Agency id for schedules whose operator can't be worked out
+const UNKNOWN_OPERATOR: &str = "ZZ";
+
+/// The schedule currently being read
+#[derive(Clone)]
+struct TripState {
+    uid: String,
+    date_start: String,
+    date_end: String,
+    days_run: String,
+    calendar_start: NaiveDate,
+    calendar_end: NaiveDate,
+    /// Days the schedule is valid for as published, before date filters
+    valid_days: i64,
+    stp_ind: String,
+    /// Empty until a BX record gives the operator
+    atoc_code: String,
+    train_identity: String,
+    train_service_code: Option<String>,
+    formation: Formation,
+    bank_holiday_running: Option<char>,
+    train_category: Option<String>,
+    vehicle_type: &'static str,
+    first_class: u8,
+    sleepers: Option<&'static str>,
+    reservations: Option<&'static str>,
+    catering: Option<String>,
+    origin_name: String,
+    dest_name: String,
+    stops: Vec<StopTime>,
+    /// Every location passed, calls or not, for the trip's shape
+    path: Vec<PathPoint>,
+    /// Public calls left out because their TIPLOC has no station, with why
+    dropped_calls: Vec<(String, &'static str)>,
+    /// Sequence number for the next call
+    next_sequence: u32,
+    /// Set on a copy of the trip for a date the clocks change, whose
+    /// times are shifted to match
+    clock_change_date: Option<NaiveDate>,
+    /// An STP schedule starting or ending part way along the permanent one
+    short_working: bool,
+}
+
+impl TripState {
+    fn claim(&self) -> StpClaim {
+        StpClaim {
+            stp_indicator: self.stp_ind.chars().next().unwrap_or('P'),
+            days_run: self.days_run.clone(),
+            start: self.calendar_start,
+            end: self.calendar_end,
+            valid_days: self.valid_days,
+        }
+    }
+
+    /// UID, start date and STP indicator identify a schedule uniquely, so
+    /// the same input always gives the same trip ids
+    fn trip_id(&self) -> String {
+        let id = format!("{}_{}_{}", self.uid, self.date_start, self.stp_ind);
+        match self.clock_change_date {
+            Some(date) => format!("{}_{}", id, date.format("%Y%m%d")),
+            None => id,
+        }
+    }
+
+    /// Rows of dropped_stops.csv for the calls this trip lost
+    fn dropped_stops(&self, trip_dropped: bool) -> impl Iterator<Item = DroppedStop> + '_ {
+        self.dropped_calls
+            .iter()
+            .map(move |(tiploc, reason)| DroppedStop {
+                tiploc: tiploc.clone(),
+                uid: self.uid.clone(),
+                start_date: self.date_start.clone(),
+                stp_indicator: self.stp_ind.clone(),
+                reason,
+                trip_dropped,
+            })
+    }
+
+    /// Whether this starts or ends at a station the permanent schedule
+    /// calls at part way along
+    fn stops_short_of(&self, permanent: &TripState) -> bool {
+        let calls: Vec<&str> = permanent.stops.iter().map(|s| s.tiploc.as_str()).collect();
+        let (Some(first), Some(last)) = (self.stops.first(), self.stops.last()) else {
+            return false;
+        };
+        let (Some((&base_first, after_first)), Some((&base_last, before_last))) =
+            (calls.split_first(), calls.split_last())
+        else {
+            return false;
+        };
+        (last.tiploc != base_last && before_last.contains(&last.tiploc.as_str()))
+            || (first.tiploc != base_first && after_first.contains(&first.tiploc.as_str()))
+    }
+
+    /// Add a call at a station, made at its platform child stop when the
+    /// platform is known
+    fn call(&mut self, tiploc: &str, platform: Option<&str>, arrival: String, departure: String) {
+        let stop_id = match platform {
+            Some(platform) => format!("{}_{}", tiploc, platform),
+            None => tiploc.to_string(),
+        };
+        self.stops.push(StopTime {
+            trip_id: self.trip_id(),
+            arrival_time: arrival,
+            departure_time: departure,
+            stop_id,
+            stop_sequence: self.next_sequence,
+            stop_headsign: None,
+            pickup_type: None,
+            drop_off_type: None,
+            shape_dist_traveled: None,
+            tiploc: tiploc.to_string(),
+            platform: platform.map(str::to_string),
+        });
+        self.next_sequence += 1;
+        self.path.push(PathPoint {
+            tiploc: tiploc.to_string(),
+            call: true,
+        });
+    }
+
+    /// Pass a location without calling there
+    fn pass(&mut self, tiploc: &str) {
+        self.path.push(PathPoint {
+            tiploc: tiploc.to_string(),
+            call: false,
+        });
+    }
+}
+
+/// The dates one schedule of a train runs on, for STP precedence
+#[derive(Debug, Clone)]
+struct StpClaim {
+    stp_indicator: char,
+    days_run: String,
+    start: NaiveDate,
+    end: NaiveDate,
+    valid_days: i64,
+}
+
+impl StpClaim {
+    fn runs_on(&self, date: NaiveDate) -> bool {
+        let day = date.weekday().num_days_from_monday() as usize;
+        self.start <= date && date <= self.end && self.days_run.as_bytes().get(day) == Some(&b'1')
+    }
+
+    fn dates(&self) -> impl Iterator<Item = NaiveDate> + '_ {
+        self.start
+            .iter_days()
+            .take_while(|date| *date <= self.end)
+            .filter(|date| self.runs_on(*date))
+    }
+}
+
+/// The dates each of a train's schedules loses to another, so exactly one
+/// runs on any date. An STP schedule (cancellation, new or overlay) beats
+/// the permanent one; of several, the one valid for the fewest days is the
+/// most specific and wins, and on a tie the later claim.
+fn superseded_dates(claims: &[StpClaim]) -> Vec<BTreeSet<NaiveDate>> {
+    let rank = |i: usize| {
+        let claim = &claims[i];
+        (claim.stp_indicator != 'P', Reverse(claim.valid_days), i)
+    };
+    let mut winners: HashMap<NaiveDate, usize> = HashMap::new();
+    for (i, claim) in claims.iter().enumerate() {
+        for date in claim.dates() {
+            let winner = winners.entry(date).or_insert(i);
+            if rank(i) > rank(*winner) {
+                *winner = i;
+            }
+        }
+    }
+    claims
+        .iter()
+        .enumerate()
+        .map(|(i, claim)| claim.dates().filter(|date| winners[date] != i).collect())
+        .collect()
+}
+
+/// A next-working (NP) association placing a schedule into a shared block
+struct BlockLink {
+    block_id: String,
+    start_date: String,
+    end_date: String,
+}
+
+/// A join (JJ) or divide (VV) association between two schedules at a location
+struct SplitJoin {
+    base_uid: String,
+    assoc_uid: String,
+    start_date: String,
+    end_date: String,
+    is_join: bool,
+    location: String,
+    passenger: bool,
+}
+
+/// An emitted trip whose UID takes part in a split or join
+struct AssociatedTrip {
+    trip_id: String,
+    start_date: String,
+    end_date: String,
+    /// Stop used at each station called at
+    stop_ids: HashMap<String, String>,
+    first_tiploc: String,
+    last_tiploc: String,
+    dest_name: String,
+}
+
+pub struct ScheduleBuilder<'a> {
+    ctx: TimetableContext<'a>,
+    route_grouping: Box<dyn RouteKey>,
+    current: Option<TripState>,
+    blocks: HashMap<String, Vec<BlockLink>>,
+    splits_joins: Vec<SplitJoin>,
+    associated_trips: HashMap<String, Vec<AssociatedTrip>>,
+    /// Trips of trains that divide, or join another, held until the end
+    /// so the other portion's destination is known
+    portion_trips: Vec<ConvertedTrip>,
+    /// The schedules of the train being read, held until the next UID so
+    /// precedence between them can be resolved. An extract lists each
+    /// UID's schedules together, so this is never more than a few.
+    pending_uid: String,
+    pending: Vec<TripState>,
+    /// Schedules of the same train that take dates without adding a trip:
+    /// cancellations, and overlays that aren't converted
+    withdrawn: Vec<StpClaim>,
+    /// Dates the clocks change, by year
+    clock_changes: HashMap<i32, Vec<NaiveDate>>,
+    /// The operator in the last UID's BX record, for its schedules that
+    /// lack one, since overlays follow the schedule they change
+    last_uid_operator: Option<(String, String)>,
+    summary: TimetableSummary,
+}
+
+impl<'a> ScheduleBuilder<'a> {
+    pub fn new(ctx: TimetableContext<'a>) -> Self {
+        ScheduleBuilder {
+            ctx,
+            route_grouping: ctx.output_options.route_grouping.strategy(),
+            current: None,
+            blocks: HashMap::new(),
+            splits_joins: Vec::new(),
+            associated_trips: HashMap::new(),
+            portion_trips: Vec::new(),
+            pending_uid: String::new(),
+            pending: Vec::new(),
+            withdrawn: Vec::new(),
+            clock_changes: HashMap::new(),
+            last_uid_operator: None,
+            summary: TimetableSummary::default(),
+        }
+    }
+
+    /// Keep a malformed record for rejects.csv. If it belonged to a
+    /// schedule, the rest of that schedule is ignored, since it can't be
+    /// trusted.
+    pub fn reject(&mut self, record: RejectedRecord, schedule_record: bool) {
+        self.summary.rejects.push(record);
+        if schedule_record {
+            self.current = None;
+        }
+    }
+
+    pub fn rejected(&self) -> usize {
+        self.summary.rejects.len()
+    }
+
+    pub fn push_association(&mut self, aa: Association) {
+        if aa.transaction != Transaction::Delete {
+            self.summary.associations.push(aa.clone());
+        }
+        let Some(end_date) = aa.end_date else {
+            return;
+        };
+        if aa.transaction == Transaction::Delete || aa.stp_indicator == 'C' {
+            return;
+        }
+
+        let base_uid = aa.base_uid;
+        let assoc_uid = aa.assoc_uid;
+        let start_date = cif_date(aa.start_date);
+        let end_date = cif_date(end_date);
+
+        if aa.category == "JJ" || aa.category == "VV" {
+            let passenger = aa.assoc_type == 'P';
+            // Both portions are the same train either side of the
+            // association, so they share a block like a next working
+            if passenger {
+                link_block(
+                    &mut self.blocks,
+                    &base_uid,
+                    &assoc_uid,
+                    &start_date,
+                    &end_date,
+                );
+            }
+            self.splits_joins.push(SplitJoin {
+                base_uid,
+                assoc_uid,
+                start_date,
+                end_date,
+                is_join: aa.category == "JJ",
+                location: aa.location,
+                passenger,
+            });
+            return;
+        }
+        if aa.category != "NP" {
+            return;
+        }
+        link_block(
+            &mut self.blocks,
+            &base_uid,
+            &assoc_uid,
+            &start_date,
+            &end_date,
+        );
+    }
+
+    /// Start a schedule, returning the trips of the previous train if this
+    /// is a new one
+    pub fn push_bs(
+        &mut self,
+        bs: BasicSchedule,
+        tiploc_map: &HashMap<String, ParsedStation>,
+        stats: &mut Stats,
+    ) -> Vec<ConvertedTrip> {
+        self.current = None;
+        let finished = if bs.uid == self.pending_uid {
+            Vec::new()
+        } else {
+            let finished = self.flush(tiploc_map, stats);
+            self.pending_uid = bs.uid.clone();
+            finished
+        };
+
+        let withdrawn = || {
+            bs.end_date.map(|end| StpClaim {
+                stp_indicator: bs.stp_indicator,
+                days_run: bs.days_run.clone(),
+                start: bs.start_date,
+                end,
+                valid_days: (end - bs.start_date).num_days(),
+            })
+        };
+        if bs.stp_indicator == 'C' {
+            stats.cancelled_schedules_skipped += 1;
+            self.withdrawn.extend(withdrawn());
+            return finished;
+        }
+        if !bs.is_passenger() && !self.ctx.filters.include_non_passenger {
+            stats.non_passenger_schedules_skipped += 1;
+            // An overlay running the train empty still takes its dates
+            if bs.stp_indicator != 'P' {
+                self.withdrawn.extend(withdrawn());
+            }
+            return finished;
+        }
+
+        let clipped = bs.end_date.and_then(|end| {
+            self.ctx
+                .filters
+                .clip_dates(bs.start_date, end, &bs.days_run)
+        });
+        let (Some((calendar_start, calendar_end)), Some(end_date)) = (clipped, bs.end_date) else {
+            return finished;
+        };
+
+        let vehicle_type = bs.vehicle_type();
+        self.current = Some(TripState {
+            uid: bs.uid,
+            date_start: cif_date(bs.start_date),
+            date_end: cif_date(end_date),
+            days_run: bs.days_run,
+            calendar_start,
+            calendar_end,
+            valid_days: (end_date - bs.start_date).num_days(),
+            stp_ind: bs.stp_indicator.to_string(),
+            atoc_code: String::new(),
+            train_identity: bs.train_identity,
+            train_service_code: bs.train_service_code,
+            formation: Formation {
+                power_type: bs.power_type,
+                timing_load: bs.timing_load,
+                portion_id: bs.portion_id,
+            },
+            bank_holiday_running: bs.bank_holiday_running,
+            vehicle_type,
+            first_class: amenities::first_class(bs.seating_class),
+            sleepers: amenities::sleepers(bs.sleepers),
+            reservations: amenities::reservations(bs.reservations),
+            catering: amenities::catering(bs.catering_code.as_deref()),
+            train_category: bs.train_category,
+            origin_name: String::new(),
+            dest_name: String::new(),
+            stops: Vec::new(),
+            path: Vec::new(),
+            dropped_calls: Vec::new(),
+            next_sequence: 1,
+            clock_change_date: None,
+            short_working: false,
+        });
+        finished
+    }
+
+    pub fn push_bx(&mut self, bx: BasicScheduleExtra) {
+        let Some(trip) = &mut self.current else {
+            return;
+        };
+        if bx.atoc_code.is_empty() {
+            return;
+        }
+        trip.atoc_code = bx.atoc_code;
+        self.last_uid_operator = Some((trip.uid.clone(), trip.atoc_code.clone()));
+        if !self.ctx.filters.allows_toc(&trip.atoc_code) {
+            self.current = None;
+        }
+    }
+
+    /// The operator of a schedule with no BX record: that of another
+    /// schedule for the same train, or [`UNKNOWN_OPERATOR`]. A service code
+    /// can be shared by trains of different operators, so it's no guide.
+    fn infer_operator(&self, trip: &TripState, stats: &mut Stats) -> String {
+        let same_uid = self
+            .last_uid_operator
+            .as_ref()
+            .filter(|(uid, _)| *uid == trip.uid)
+            .map(|(_, atoc_code)| atoc_code);
+        if let Some(atoc_code) = same_uid {
+            stats.operators_inferred += 1;
+            return atoc_code.clone();
+        }
+        stats.unknown_operator_trips += 1;
+        if stats.unknown_operator_trips <= MAX_CIF_WARNINGS {
+            println!(
+                "Warning: no operator for schedule {} ({})",
+                trip.uid, trip.date_start
+            );
+        }
+        UNKNOWN_OPERATOR.to_string()
+    }
+
+    pub fn push_lo(&mut self, lo: OriginLocation, tiploc_map: &HashMap<String, ParsedStation>) {
+        let Some(trip) = &mut self.current else {
+            return;
+        };
+        // Only locations with a station can be called at
+        let Some(station) = tiploc_map.get(&lo.tiploc) else {
+            trip.pass(&lo.tiploc);
+            trip.dropped_calls.push((lo.tiploc, "unknown_origin"));
+            return;
+        };
+        trip.origin_name = station.name.clone();
+        let departure = lo.scheduled_departure.to_gtfs();
+        trip.call(
+            &lo.tiploc,
+            lo.platform.as_deref(),
+            departure.clone(),
+            departure,
+        );
+    }
+
+    pub fn push_li(
+        &mut self,
+        li: IntermediateLocation,
+        tiploc_map: &HashMap<String, ParsedStation>,
+    ) {
+        let Some(trip) = &mut self.current else {
+            return;
+        };
+        // Operational stops have no public times
+        if !li.is_public() {
+            trip.pass(&li.tiploc);
+            return;
+        }
+        if !tiploc_map.contains_key(&li.tiploc) {
+            trip.pass(&li.tiploc);
+            trip.dropped_calls.push((li.tiploc, "unknown_intermediate"));
+            return;
+        }
+        let on_request = cif::activities(&li.activity).any(|code| code == "R");
+        let policy = self.ctx.output_options.request_stops;
+        if on_request && policy == RequestStopPolicy::Omit {
+            trip.pass(&li.tiploc);
+            return;
+        }
+        let time = |t: Option<CifTime>| t.map_or_else(String::new, CifTime::to_gtfs);
+        trip.call(
+            &li.tiploc,
+            li.platform.as_deref(),
+            time(li.scheduled_arrival),
+            time(li.scheduled_departure),
+        );
+        if on_request && let Some(stop) = trip.stops.last_mut() {
+            stop.pickup_type = Some(3);
+            stop.drop_off_type = Some(3);
+        }
+    }
+
+    /// Close the current schedule. Its trip is held with the train's other
+    /// schedules until [`Self::flush`].
+    pub fn push_lt(
+        &mut self,
+        lt: TerminatingLocation,
+        tiploc_map: &HashMap<String, ParsedStation>,
+        stats: &mut Stats,
+    ) {
+        let Some(mut trip) = self.current.take() else {
+            return;
+        };
+        if trip.atoc_code.is_empty() {
+            trip.atoc_code = self.infer_operator(&trip, stats);
+        }
+        if !self.ctx.filters.allows_toc(&trip.atoc_code) {
+            return;
+        }
+        match tiploc_map.get(&lt.tiploc) {
+            Some(station) => {
+                trip.dest_name = station.name.clone();
+                let arrival = lt.scheduled_arrival.to_gtfs();
+                trip.call(&lt.tiploc, lt.platform.as_deref(), arrival.clone(), arrival);
+            }
+            None => {
+                trip.pass(&lt.tiploc);
+                trip.dropped_calls.push((lt.tiploc, "unknown_destination"));
+            }
+        }
+        self.pending.push(trip);
+    }
+
+    /// Resolve precedence between the schedules of the train last read,
+    /// and convert those left with a date to run on
+    pub fn flush(
+        &mut self,
+        tiploc_map: &HashMap<String, ParsedStation>,
+        stats: &mut Stats,
+    ) -> Vec<ConvertedTrip> {
+        let trips = std::mem::take(&mut self.pending);
+        // Withdrawn schedules come last, so a cancellation wins a tie
+        let mut claims: Vec<StpClaim> = trips.iter().map(TripState::claim).collect();
+        claims.append(&mut self.withdrawn);
+        let superseded = superseded_dates(&claims);
+        let permanent = trips.iter().find(|trip| trip.stp_ind == "P");
+        let short_workings: Vec<bool> = trips
+            .iter()
+            .map(|trip| {
+                permanent
+                    .is_some_and(|permanent| trip.stp_ind != "P" && trip.stops_short_of(permanent))
+            })
+            .collect();
+
+        let mut converted = Vec::new();
+        for (((mut trip, claim), mut lost), short_working) in trips
+            .into_iter()
+            .zip(&claims)
+            .zip(superseded)
+            .zip(short_workings)
+        {
+            trip.short_working = short_working;
+            let dates = claim.dates().count();
+            if lost.len() == dates {
+                stats.superseded_schedules += 1;
+                continue;
+            }
+            // Dates the clocks change on run to other times, as trips of
+            // their own
+            let shifted = self.clock_change_trips(&trip, claim, &lost, stats);
+            lost.extend(shifted.iter().map(|trip| trip.calendar_start));
+            if lost.len() < dates {
+                converted.extend(self.finish_trip(trip, lost, tiploc_map, stats));
+            }
+            for trip in shifted {
+                stats.clock_change_trips += 1;
+                converted.extend(self.finish_trip(trip, BTreeSet::new(), tiploc_map, stats));
+            }
+        }
+        let (portions, converted) = converted
+            .into_iter()
+            .partition(|trip| self.has_portions(&trip.uid));
+        self.portion_trips.extend::<Vec<_>>(portions);
+        converted
+    }
+
+    /// Whether a train divides, or joins another, with passengers on board,
+    /// so where it's going depends on the other portion
+    fn has_portions(&self, uid: &str) -> bool {
+        self.splits_joins.iter().any(|sj| {
+            sj.passenger
+                && if sj.is_join {
+                    sj.assoc_uid == uid
+                } else {
+                    sj.base_uid == uid
+                }
+        })
+    }
+
+    /// The trips held back by [`Self::flush`], with stop headsigns naming
+    /// where each call's portion goes. Call once every schedule is read.
+    pub fn finish_portions(&mut self) -> Vec<ConvertedTrip> {
+        let mut trips = std::mem::take(&mut self.portion_trips);
+        let overlaps = |start: &str, end: &str, other_start: &str, other_end: &str| {
+            start <= other_end && other_start <= end
+        };
+        for trip in &mut trips {
+            let trip_dates = (trip.date_start.as_str(), trip.date_end.as_str());
+            let destination = |uid: &str, start: &str, end: &str| {
+                self.associated_trips
+                    .get(uid)?
+                    .iter()
+                    .find(|t| {
+                        overlaps(&t.start_date, &t.end_date, start, end)
+                            && overlaps(&t.start_date, &t.end_date, trip_dates.0, trip_dates.1)
+                    })
+                    .map(|t| t.dest_name.as_str())
+            };
+            let Some(own) = self.associated_trips.get(&trip.uid).and_then(|trips| {
+                trips
+                    .iter()
+                    .find(|t| t.trip_id == trip.trip.trip_id)
+                    .map(|t| t.dest_name.as_str())
+            }) else {
+                continue;
+            };
+            let portions: Vec<Portion> = self
+                .splits_joins
+                .iter()
+                .filter(|sj| sj.passenger)
+                .filter(|sj| overlaps(&sj.start_date, &sj.end_date, trip_dates.0, trip_dates.1))
+                .filter_map(|sj| {
+                    let other = match sj.is_join {
+                        true if sj.assoc_uid == trip.uid => &sj.base_uid,
+                        false if sj.base_uid == trip.uid => &sj.assoc_uid,
+                        _ => return None,
+                    };
+                    Some(Portion {
+                        location: &sj.location,
+                        destination: destination(other, &sj.start_date, &sj.end_date)?,
+                        is_join: sj.is_join,
+                    })
+                })
+                .collect();
+            let headsigns = portion_headsigns(&trip.stop_times, own, &portions);
+            for (stop, headsign) in trip.stop_times.iter_mut().zip(headsigns) {
+                stop.stop_headsign = headsign;
+            }
+        }
+        trips
+    }
+
+    /// Copies of a trip for the dates it runs across a clock change, with
+    /// its times measured from the start of that service day
+    fn clock_change_trips(
+        &mut self,
+        trip: &TripState,
+        claim: &StpClaim,
+        lost: &BTreeSet<NaiveDate>,
+        stats: &mut Stats,
+    ) -> Vec<TripState> {
+        let mut stops = trip.stops.clone();
+        repair_times(&mut stops);
+        let times: Vec<u32> = stops
+            .iter()
+            .flat_map(|stop| [&stop.arrival_time, &stop.departure_time])
+            .filter_map(|time| gtfs_seconds(time))
+            .collect();
+        let last_day = times.iter().max().map_or(0, |secs| secs / (24 * 3600));
+
+        let mut copies = Vec::new();
+        let last_year = (claim.end + Days::new(u64::from(last_day))).year();
+        for year in claim.start.year()..=last_year {
+            let changes = self
+                .clock_changes
+                .entry(year)
+                .or_insert_with(|| timezone::clock_changes(year))
+                .clone();
+            for change in changes {
+                // The change can fall on any day the trip runs over
+                for days_before in 0..=last_day {
+                    let Some(date) = change.checked_sub_days(Days::new(u64::from(days_before)))
+                    else {
+                        continue;
+                    };
+                    if !claim.runs_on(date) || lost.contains(&date) {
+                        continue;
+                    }
+                    let shifted = match timezone::shifted_times(date, &times) {
+                        ShiftedTimes::Shifted(shifted) => shifted,
+                        ShiftedTimes::Unchanged => continue,
+                        ShiftedTimes::BeforeServiceDay => {
+                            stats.clock_change_dates_unshifted += 1;
+                            continue;
+                        }
+                    };
+                    let mut copy = trip.clone();
+                    copy.clock_change_date = Some(date);
+                    let mut days_run = *b"0000000";
+                    days_run[date.weekday().num_days_from_monday() as usize] = b'1';
+                    copy.days_run = String::from_utf8_lossy(&days_run).into_owned();
+                    copy.calendar_start = date;
+                    copy.calendar_end = date;
+                    copy.dropped_calls.clear();
+                    let trip_id = copy.trip_id();
+                    let mut shifted = shifted.into_iter();
+                    copy.stops = stops.clone();
+                    for stop in &mut copy.stops {
+                        stop.trip_id = trip_id.clone();
+                        for time in [&mut stop.arrival_time, &mut stop.departure_time] {
+                            if gtfs_seconds(time).is_some()
+                                && let Some(secs) = shifted.next()
+                            {
+                                *time = gtfs_time(secs);
+                            }
+                        }
+                    }
+                    copies.push(copy);
+                }
+            }
+        }
+        copies
+    }
+
+    /// Turn a complete schedule into a trip, recording its agency, route
+    /// and calendar, or drop it if the filters or time policy reject it
+    fn finish_trip(
+        &mut self,
+        mut trip: TripState,
+        superseded: BTreeSet<NaiveDate>,
+        tiploc_map: &HashMap<String, ParsedStation>,
+        stats: &mut Stats,
+    ) -> Option<ConvertedTrip> {
+        let TimetableContext {
+            locations,
+            toc_lookup,
+            branding,
+            service_groups,
+            line_rules,
+            filters,
+            output_options,
+            bank_holidays,
+            on_bad_times,
+            ..
+        } = self.ctx;
+
+        // A trip needs somewhere to go once unplaced locations are left out
+        if trip.stops.len() < 2 {
+            stats.short_trips_dropped += 1;
+            let dropped = trip.dropped_stops(true);
+            self.summary.dropped_stops.extend(dropped);
+            return None;
+        }
+        // Name the trip after its first and last calls if the real ends
+        // couldn't be placed
+        let station_name = |stop: Option<&StopTime>| {
+            stop.and_then(|s| tiploc_map.get(&s.tiploc))
+                .map(|station| station.name.clone())
+                .unwrap_or_default()
+        };
+        if trip.origin_name.is_empty() {
+            trip.origin_name = station_name(trip.stops.first());
+        }
+        if trip.dest_name.is_empty() {
+            trip.dest_name = station_name(trip.stops.last());
+        }
+
+        if !repair_times(&mut trip.stops) {
+            stats.bad_time_trips += 1;
+            if stats.bad_time_trips <= MAX_CIF_WARNINGS {
+                println!(
+                    "Warning: times run backwards in schedule {} ({})",
+                    trip.uid, trip.date_start
+                );
+            }
+            if on_bad_times == BadTimesPolicy::Drop {
+                return None;
+            }
+        }
+
+        if !filters.touches_bbox(&trip.stops, tiploc_map) {
+            return None;
+        }
+
+        // Routes & Agencies
+        let agency_name = match toc_lookup.get(&trip.atoc_code) {
+            Some(name) => name.clone(),
+            None if trip.atoc_code == UNKNOWN_OPERATOR => "Unknown operator".to_string(),
+            None => format!("National Rail ({})", trip.atoc_code),
+        };
+
+        let brand = branding.get(&trip.atoc_code);
+        let route_trip = RouteTrip {
+            atoc_code: &trip.atoc_code,
+            agency_name: &agency_name,
+            origin_tiploc: trip.stops.first().map_or("", |s| s.tiploc.as_str()),
+            origin_name: &trip.origin_name,
+            dest_tiploc: trip.stops.last().map_or("", |s| s.tiploc.as_str()),
+            dest_name: &trip.dest_name,
+            train_identity: &trip.train_identity,
+            train_service_code: trip.train_service_code.as_deref(),
+        };
+        let class = operators::classify_route(
+            &route_trip,
+            &trip.stops,
+            tiploc_map,
+            line_rules,
+            self.route_grouping.as_ref(),
+        );
+        let route_id = class.route_id;
+        let route_color = class
+            .color
+            .or_else(|| brand.and_then(|b| b.color.clone()))
+            .unwrap_or_default();
+        let route_text_color = class
+            .text_color
+            .or_else(|| brand.and_then(|b| b.text_color.clone()))
+            .unwrap_or_else(|| "000000".to_string());
+
+        self.summary
+            .agencies
+            .entry(trip.atoc_code.clone())
+            .or_insert_with(|| Agency {
+                agency_id: trip.atoc_code.clone(),
+                agency_name,
+                agency_url: brand
+                    .and_then(|b| b.url.clone())
+                    .unwrap_or_else(|| branding::DEFAULT_AGENCY_URL.to_string()),
+                agency_timezone: "Europe/London".to_string(),
+                agency_lang: brand
+                    .and_then(|b| b.lang.clone())
+                    .unwrap_or_else(|| branding::DEFAULT_LANG.to_string()),
+                agency_phone: brand.and_then(|b| b.phone.clone()),
+                agency_fare_url: brand
+                    .and_then(|b| b.fare_url.clone())
+                    .unwrap_or_else(|| branding::DEFAULT_FARE_URL.to_string()),
+            });
+
+        self.summary
+            .routes
+            .entry(route_id.clone())
+            .or_insert(Route {
+                route_id: route_id.clone(),
+                agency_id: trip.atoc_code.clone(),
+                route_short_name: String::new(),
+                route_long_name: class.long_name,
+                // Named for its first trip, like route_long_name
+                route_desc: trip
+                    .train_service_code
+                    .as_deref()
+                    .map(|code| service_groups.describe(code, trip.formation.portion_id))
+                    .unwrap_or_default(),
+                route_type: class.route_type,
+                route_color,
+                route_text_color,
+            });
+
+        let calls: Vec<(f64, f64)> = trip
+            .stops
+            .iter()
+            .filter_map(|stop| tiploc_map.get(&stop.tiploc))
+            .filter(|station| station.lat != 0.0 || station.lon != 0.0)
+            .map(|station| (station.lat, station.lon))
+            .collect();
+        let direction_id = routes::direction_id(&calls);
+        let policy = output_options.headsign;
+        let via = policy
+            .via
+            .then(|| {
+                let named: Vec<(&str, (f64, f64))> = trip
+                    .stops
+                    .iter()
+                    .filter_map(|stop| tiploc_map.get(&stop.tiploc))
+                    .filter(|station| station.lat != 0.0 || station.lon != 0.0)
+                    .map(|station| (station.name.as_str(), (station.lat, station.lon)))
+                    .collect();
+                headsign::via_point(&named)
+            })
+            .flatten();
+        let trip_headsign = headsign::headsign(
+            &trip.dest_name,
+            via,
+            policy.short_workings && trip.short_working,
+        );
+
+        let block_id = find_block(&self.blocks, &trip.uid, &trip.date_start, &trip.date_end)
+            .map(|link| link.block_id.clone());
+
+        // Stations where the MSN places them, and other locations wherever
+        // they're known
+        let locate = |tiploc: &str| {
+            tiploc_map
+                .get(tiploc)
+                .filter(|station| station.lat != 0.0 || station.lon != 0.0)
+                .map(|station| (station.lat, station.lon))
+                .or_else(|| Some(locations.locate(tiploc, None)?.0))
+        };
+        let traced = output_options
+            .shapes
+            .then(|| shapes::trace(&trip.path, locate))
+            .flatten();
+        let shape_id = traced.map(|traced| {
+            for (stop, dist) in trip.stops.iter_mut().zip(traced.call_distances) {
+                stop.shape_dist_traveled = Some(dist);
+            }
+            let shape_id = traced.points[0].shape_id.clone();
+            self.summary
+                .shapes
+                .entry(shape_id.clone())
+                .or_insert(traced.points);
+            shape_id
+        });
+
+        // X: doesn't run on bank holiday Mondays
+        let excludes_bank_holidays = trip.bank_holiday_running == Some('X');
+        // Schedules running on the same dates share a calendar. Calendars
+        // are kept until the end, as trips only need their id.
+        let (days_run, start, end) =
+            normalise_calendar(&trip.days_run, trip.calendar_start, trip.calendar_end);
+        let service_id = service_id(service_hash(
+            &days_run,
+            start,
+            end,
+            excludes_bank_holidays,
+            &superseded,
+        ));
+        self.summary
+            .calendars
+            .entry(service_id.clone())
+            .or_insert_with(|| {
+                let mut dates = if excludes_bank_holidays {
+                    bank_holiday_exceptions(&service_id, &days_run, start, end, bank_holidays)
+                } else {
+                    Vec::new()
+                };
+                // Dates another schedule of the same train runs instead
+                let holidays: BTreeSet<String> = dates.iter().map(|d| d.date.clone()).collect();
+                dates.extend(
+                    superseded
+                        .iter()
+                        .map(|date| date.format("%Y%m%d").to_string())
+                        .filter(|date| !holidays.contains(date))
+                        .map(|date| CalendarDate {
+                            service_id: service_id.clone(),
+                            date,
+                            exception_type: 2,
+                        }),
+                );
+                dates.sort_by(|a, b| a.date.cmp(&b.date));
+                ServiceCalendar {
+                    calendar: build_calendar(&service_id, &days_run, start, end),
+                    dates,
+                }
+            });
+        let dropped = trip.dropped_stops(false);
+        self.summary.dropped_stops.extend(dropped);
+        stats.trip_written(&trip.atoc_code, trip.calendar_start, trip.calendar_end);
+
+        if self
+            .splits_joins
+            .iter()
+            .any(|sj| sj.base_uid == trip.uid || sj.assoc_uid == trip.uid)
+        {
+            self.associated_trips
+                .entry(trip.uid.clone())
+                .or_default()
+                .push(AssociatedTrip {
+                    trip_id: trip.trip_id(),
+                    start_date: trip.date_start.clone(),
+                    end_date: trip.date_end.clone(),
+                    stop_ids: trip
+                        .stops
+                        .iter()
+                        .map(|s| (s.tiploc.clone(), s.stop_id.clone()))
+                        .collect(),
+                    first_tiploc: trip
+                        .stops
+                        .first()
+                        .map(|s| s.tiploc.clone())
+                        .unwrap_or_default(),
+                    last_tiploc: trip
+                        .stops
+                        .last()
+                        .map(|s| s.tiploc.clone())
+                        .unwrap_or_default(),
+                    dest_name: trip.dest_name.clone(),
+                });
+        }
+
+        Some(ConvertedTrip {
+            trip: Trip {
+                route_id,
+                service_id,
+                trip_id: trip.trip_id(),
+                trip_headsign,
+                trip_short_name: trip.train_identity,
+                direction_id,
+                block_id,
+                shape_id,
+                wheelchair_accessible: output_options
+                    .wheelchair_accessible
+                    .then(|| knowledgebase::default_wheelchair_accessible(&trip.atoc_code)),
+                trip_category: trip.train_category,
+                train_service_code: trip.train_service_code,
+                vehicle_type: trip.vehicle_type,
+                first_class: trip.first_class,
+                sleepers: trip.sleepers,
+                reservations: trip.reservations,
+                catering: trip.catering,
+            },
+            stop_times: trip.stops,
+            uid: trip.uid,
+            date_start: trip.date_start,
+            date_end: trip.date_end,
+            stp_indicator: trip.stp_ind,
+            atoc_code: trip.atoc_code,
+            formation: trip.formation,
+        })
+    }
+
+    /// Everything gathered across the timetable, with the split and join
+    /// transfers between the trips written
+    pub fn finish(mut self) -> TimetableSummary {
+        self.summary.transfers = split_join_transfers(&self.splits_joins, &self.associated_trips);
+        self.summary
+    }
+}
+
+/// Emit transfers between the portions of joining and dividing trains. A
+/// divide carries passengers from the base train into the associated one, a
+/// join carries them from the associated train into the base one. It's only
+/// an in-seat transfer where one trip ends where the other starts; a train
+/// running on through the divide or join shares its block with the other
+/// portion and is timed to meet it there.
+fn split_join_transfers(
+    splits_joins: &[SplitJoin],
+    associated_trips: &HashMap<String, Vec<AssociatedTrip>>,
+) -> Vec<Transfer> {
+    let mut transfers = Vec::new();
+    let calling_trips = |uid: &str, sj: &SplitJoin| -> Vec<&AssociatedTrip> {
+        associated_trips
+            .get(uid)
+            .map(|trips| {
+                trips
+                    .iter()
+                    .filter(|t| t.start_date <= sj.end_date && sj.start_date <= t.end_date)
+                    .filter(|t| t.stop_ids.contains_key(&sj.location))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    for sj in splits_joins {
+        for base in calling_trips(&sj.base_uid, sj) {
+            for assoc in calling_trips(&sj.assoc_uid, sj) {
+                if base.start_date > assoc.end_date || assoc.start_date > base.end_date {
+                    continue;
+                }
+                let (from, to) = if sj.is_join {
+                    (assoc, base)
+                } else {
+                    (base, assoc)
+                };
+                transfers.push(Transfer {
+                    from_stop_id: from.stop_ids[&sj.location].clone(),
+                    to_stop_id: to.stop_ids[&sj.location].clone(),
+                    from_trip_id: Some(from.trip_id.clone()),
+                    to_trip_id: Some(to.trip_id.clone()),
+                    // Operating-only associations keep passengers off the other portion
+                    transfer_type: if !sj.passenger {
+                        5
+                    } else if from.last_tiploc == sj.location && to.first_tiploc == sj.location {
+                        4
+                    } else {
+                        1
+                    },
+                    min_transfer_time: None,
+                });
+            }
+        }
+    }
+    transfers
+}
+
+/// Where another portion of a train goes from an association
+struct Portion<'a> {
+    location: &'a str,
+    destination: &'a str,
+    is_join: bool,
+}
+
+/// Stop headsigns for a trip whose train divides or joins another. Until
+/// it divides it's going to both portions' destinations, and a portion
+/// that joins another goes on to wherever that one does.
+fn portion_headsigns(
+    stops: &[StopTime],
+    destination: &str,
+    portions: &[Portion],
+) -> Vec<Option<String>> {
+    (0..stops.len())
+        .map(|i| {
+            let mut destinations = vec![destination];
+            for portion in portions {
+                if !stops[i + 1..].iter().any(|s| s.tiploc == portion.location) {
+                    continue;
+                }
+                if portion.is_join {
+                    destinations[0] = portion.destination;
+                } else if !destinations.contains(&portion.destination) {
+                    destinations.push(portion.destination);
+                }
+            }
+            (destinations != [destination]).then(|| destinations.join(" & "))
+        })
+        .collect()
+}
+
+/// Put two UIDs in one block over the given yymmdd date range. Chaining
+/// onto the block either is already in lets A->B->C workings share one
+/// block_id, and where both are in blocks, associations having come in
+/// out of order, the second is merged into the first.
+fn link_block(
+    blocks: &mut HashMap<String, Vec<BlockLink>>,
+    base_uid: &str,
+    assoc_uid: &str,
+    start_date: &str,
+    end_date: &str,
+) {
+    let block_of = |uid| find_block(blocks, uid, start_date, end_date).map(|l| l.block_id.clone());
+    let base_block = block_of(base_uid);
+    let assoc_block = block_of(assoc_uid);
+    let block_id = base_block
+        .or_else(|| assoc_block.clone())
+        .unwrap_or_else(|| format!("{}_{}", base_uid, assoc_uid));
+
+    if let Some(merged) = assoc_block.filter(|b| *b != block_id) {
+        for link in blocks.values_mut().flatten() {
+            if link.block_id == merged {
+                link.block_id = block_id.clone();
+            }
+        }
+    }
+    for uid in [base_uid, assoc_uid] {
+        blocks.entry(uid.to_string()).or_default().push(BlockLink {
+            block_id: block_id.clone(),
+            start_date: start_date.to_string(),
+            end_date: end_date.to_string(),
+        });
+    }
+}
+
+/// Find the block a UID belongs to over the given yymmdd date range
+fn find_block<'a>(
+    blocks: &'a HashMap<String, Vec<BlockLink>>,
+    uid: &str,
+    start_date: &str,
+    end_date: &str,
+) -> Option<&'a BlockLink> {
+    blocks
+        .get(uid)?
+        .iter()
+        .find(|link| link.start_date.as_str() <= end_date && start_date <= link.end_date.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{Fixture, fixture_text};
+
+    #[test]
+    fn test_find_block_requires_date_overlap() {
+        let mut blocks = HashMap::new();
+        blocks.insert(
+            "C12345".to_string(),
+            vec![BlockLink {
+                block_id: "C12345_C54321".to_string(),
+                start_date: "240101".to_string(),
+                end_date: "240331".to_string(),
+            }],
+        );
+
+        let link = find_block(&blocks, "C12345", "240301", "240601");
+        assert_eq!(link.map(|l| l.block_id.as_str()), Some("C12345_C54321"));
+        assert!(find_block(&blocks, "C12345", "240401", "240601").is_none());
+        assert!(find_block(&blocks, "C99999", "240101", "240331").is_none());
+    }
+
+    #[test]
+    fn test_blocks_merge_when_linked_out_of_order() {
+        let mut blocks = HashMap::new();
+        link_block(&mut blocks, "C10001", "C20001", "240101", "241214");
+        link_block(&mut blocks, "C30001", "C40001", "240101", "241214");
+        // The middle of the chain comes last, joining the two blocks
+        link_block(&mut blocks, "C20001", "C30001", "240101", "241214");
+
+        for uid in ["C10001", "C20001", "C30001", "C40001"] {
+            let link = find_block(&blocks, uid, "240101", "241214");
+            assert_eq!(link.map(|l| l.block_id.as_str()), Some("C10001_C20001"));
+        }
+    }
+
+    #[test]
+    fn test_stp_schedules_take_their_dates_from_the_base() {
+        let date = |s: &str| NaiveDate::parse_from_str(s, "%Y%m%d").unwrap();
+        let claim = |stp_indicator, days_run: &str, start, end| StpClaim {
+            stp_indicator,
+            days_run: days_run.to_string(),
+            start: date(start),
+            end: date(end),
+            valid_days: (date(end) - date(start)).num_days(),
+        };
+        let claims = [
+            claim('P', "1111100", "20240101", "20240331"),
+            claim('O', "1111100", "20240108", "20240112"),
+            claim('O', "0010000", "20240110", "20240110"),
+            claim('N', "0000011", "20240106", "20240107"),
+            claim('C', "0000100", "20240112", "20240112"),
+        ];
+        let superseded = superseded_dates(&claims);
+        let dates = |i: usize| -> Vec<String> {
+            superseded[i]
+                .iter()
+                .map(|d| d.format("%Y%m%d").to_string())
+                .collect()
+        };
+
+        assert_eq!(
+            dates(0),
+            ["20240108", "20240109", "20240110", "20240111", "20240112"]
+        );
+        // The one-day overlay and the cancellation are more specific
+        assert_eq!(dates(1), ["20240110", "20240112"]);
+        assert!(superseded[2].is_empty());
+        // Nothing else runs at weekends
+        assert!(superseded[3].is_empty());
+    }
+
+    #[test]
+    fn test_divide_transfers() {
+        let mca = fixture_text("sample.MCA");
+        let divide_at = |location: &str, origin: &str| {
+            let mca = mca
+                .replacen("NPSYORK   ", &format!("VVS{:<7}", location), 1)
+                .replacen(
+                    "LOYORK    2330 2330",
+                    &format!("LO{:<8}2330 2330", origin),
+                    1,
+                );
+            let (trips, summary, _) =
+                Fixture::default().convert_text("sample.MSN", "sample.MCA", &mca);
+            let blocks: Vec<_> = trips.iter().map(|t| t.trip.block_id.as_deref()).collect();
+            assert_eq!(blocks, [Some("C10001_C20001"); 2]);
+            assert_eq!(summary.transfers.len(), 1);
+            let transfer = &summary.transfers[0];
+            assert_eq!(transfer.from_trip_id.as_deref(), Some("C10001_240101_P"));
+            assert_eq!(transfer.to_trip_id.as_deref(), Some("C20001_240101_P"));
+            transfer.transfer_type
+        };
+
+        // The front portion runs on to York after the rear is left at
+        // Peterborough, so passengers can't stay seated into it
+        assert_eq!(divide_at("PBRO", "PBRO"), 1);
+        // Ending where the other portion starts
+        assert_eq!(divide_at("YORK", "YORK"), 4);
+    }
+
+    #[test]
+    fn test_portion_headsigns() {
+        let stops: Vec<StopTime> = ["KNGX", "PBRO", "YORK"]
+            .iter()
+            .enumerate()
+            .map(|(i, tiploc)| StopTime {
+                trip_id: "t1".to_string(),
+                arrival_time: String::new(),
+                departure_time: String::new(),
+                stop_id: tiploc.to_string(),
+                stop_sequence: i as u32 + 1,
+                stop_headsign: None,
+                pickup_type: None,
+                drop_off_type: None,
+                shape_dist_traveled: None,
+                tiploc: tiploc.to_string(),
+                platform: None,
+            })
+            .collect();
+        let portion = |location, destination, is_join| Portion {
+            location,
+            destination,
+            is_join,
+        };
+
+        // The rear portion divides at Peterborough for Lincoln
+        assert_eq!(
+            portion_headsigns(&stops, "York", &[portion("PBRO", "Lincoln", false)]),
+            [Some("York & Lincoln".to_string()), None, None]
+        );
+        // Joining another train at York, which goes on to Edinburgh
+        assert_eq!(
+            portion_headsigns(&stops, "York", &[portion("YORK", "Edinburgh", true)]),
+            [
+                Some("Edinburgh".to_string()),
+                Some("Edinburgh".to_string()),
+                None
+            ]
+        );
+        // Both portions going to the same place need no headsign
+        assert_eq!(
+            portion_headsigns(&stops, "York", &[portion("PBRO", "York", false)]),
+            [None, None, None]
+        );
+    }
+}
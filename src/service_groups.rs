@@ -0,0 +1,119 @@
+//! Service groups for `route_desc`.
+//!
+//! Every schedule carries an eight-digit train service code, allocated in
+//! blocks to the businesses and service groups that run the trains. The
+//! built-in table names the blocks by code prefix, the longest prefix
+//! matching winning, and a TOML file can add finer groups or rename ours:
+//!
+//! ```toml
+//! "25471" = "Bristol and South Wales main line"
+//! "2547" = "Great Western intercity"
+//! ```
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Train service code prefix, service group
+#[rustfmt::skip]
+const BUILTIN: &[(&str, &str)] = &[
+    ("11", "London commuter services"),
+    ("12", "London commuter services"),
+    ("13", "London commuter services"),
+    ("14", "Merseyside and Greater Manchester local services"),
+    ("15", "West Midlands local services"),
+    ("16", "Yorkshire local services"),
+    ("17", "Scottish local services"),
+    ("18", "Welsh local services"),
+    ("21", "Intercity services"),
+    ("22", "Intercity services"),
+    ("23", "Regional services"),
+    ("24", "Regional services"),
+    ("25", "Intercity services"),
+    ("26", "Regional services"),
+    ("27", "Sleeper services"),
+    ("28", "Airport services"),
+];
+
+pub struct ServiceGroups(HashMap<String, String>);
+
+impl ServiceGroups {
+    pub fn builtin() -> Self {
+        ServiceGroups(
+            BUILTIN
+                .iter()
+                .map(|&(prefix, group)| (prefix.to_string(), group.to_string()))
+                .collect(),
+        )
+    }
+
+    /// Apply a TOML override file on top of the table
+    pub fn load_overrides(&mut self, path: &Path) -> Result<()> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        self.apply_overrides(&text)
+            .with_context(|| format!("Invalid service groups file {}", path.display()))
+    }
+
+    fn apply_overrides(&mut self, text: &str) -> Result<()> {
+        let overrides: HashMap<String, String> = toml::from_str(text)?;
+        self.0.extend(overrides);
+        Ok(())
+    }
+
+    /// The group of the longest prefix of `train_service_code` in the table
+    pub fn get(&self, train_service_code: &str) -> Option<&str> {
+        (1..=train_service_code.len())
+            .rev()
+            .filter_map(|len| train_service_code.get(..len))
+            .find_map(|prefix| self.0.get(prefix))
+            .map(String::as_str)
+    }
+
+    /// A `route_desc` for a train: its service group, code and any portion
+    pub fn describe(&self, train_service_code: &str, portion_id: Option<char>) -> String {
+        let mut desc = match self.get(train_service_code) {
+            Some(group) => format!("{} (service {})", group, train_service_code),
+            None => format!("Service {}", train_service_code),
+        };
+        if let Some(portion) = portion_id {
+            desc.push_str(&format!(", portion {}", portion));
+        }
+        desc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::Fixture;
+
+    #[test]
+    fn test_service_groups() {
+        let mut groups = ServiceGroups::builtin();
+        groups
+            .apply_overrides("\"25471\" = \"Bristol and South Wales main line\"")
+            .unwrap();
+
+        assert_eq!(
+            groups.get("25471001"),
+            Some("Bristol and South Wales main line")
+        );
+        assert_eq!(groups.get("25590001"), Some("Intercity services"));
+        assert_eq!(groups.get("99999999"), None);
+        assert_eq!(
+            groups.describe("25471001", Some('1')),
+            "Bristol and South Wales main line (service 25471001), portion 1"
+        );
+        assert_eq!(groups.describe("99999999", None), "Service 99999999");
+        assert!(groups.apply_overrides("25471 = 1").is_err());
+    }
+
+    #[test]
+    fn test_route_desc_from_fixture() {
+        let (_, summary, _) = Fixture::default().convert("sample.MSN", "sample.MCA");
+        let route = summary.routes.values().next().unwrap();
+        assert_eq!(route.route_desc, "Regional services (service 23456789)");
+    }
+}
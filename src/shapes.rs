@@ -0,0 +1,132 @@
+//! `shapes.txt` from the paths trains take.
+//!
+//! A schedule lists the junctions and loops a train passes as well as the
+//! stations it calls at, so its shape goes by way of them rather than
+//! straight from one call to the next. Passing points have coordinates
+//! from BPLAN or NaPTAN where either knows them, and are left out of the
+//! shape where neither does. `shape_dist_traveled` is in kilometres along
+//! the shape.
+//!
+//! Coordinates are per TIPLOC, so the line and path a schedule gives at each
+//! location, and its allowances, don't change the shape: two trains through
+//! the same TIPLOCs share one whichever tracks they take between them.
+
+use crate::osm::distance_m;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShapePoint {
+    pub shape_id: String,
+    pub shape_pt_lat: f64,
+    pub shape_pt_lon: f64,
+    pub shape_pt_sequence: u32,
+    pub shape_dist_traveled: Option<f64>,
+}
+
+/// A location a schedule passes through, in order
+#[derive(Debug, Clone)]
+pub struct PathPoint {
+    pub tiploc: String,
+    /// Whether it's one of the trip's stop times
+    pub call: bool,
+}
+
+/// A trip's shape and how far along it each of its calls is
+pub struct Traced {
+    pub points: Vec<ShapePoint>,
+    pub call_distances: Vec<f64>,
+}
+
+/// Trace the shape of `path` through the coordinates `locate` gives. Shapes
+/// along the same points get the same id. Needs two points with
+/// coordinates to make a line.
+pub fn trace(path: &[PathPoint], locate: impl Fn(&str) -> Option<(f64, f64)>) -> Option<Traced> {
+    let mut coords: Vec<(f64, f64)> = Vec::new();
+    let mut distances: Vec<f64> = Vec::new();
+    let mut call_distances = Vec::new();
+    let mut travelled = 0.0;
+    for point in path {
+        if let Some(here) = locate(&point.tiploc) {
+            if let Some(&last) = coords.last() {
+                travelled += distance_m(last, here) / 1000.0;
+            }
+            coords.push(here);
+            distances.push(round(travelled));
+        }
+        if point.call {
+            call_distances.push(round(travelled));
+        }
+    }
+    if coords.len() < 2 {
+        return None;
+    }
+
+    let signature: String = coords
+        .iter()
+        .map(|(lat, lon)| format!("{:.5},{:.5};", lat, lon))
+        .collect();
+    let shape_id = format!("{:032x}", crate::hash128(signature.as_bytes()));
+    let points = coords
+        .into_iter()
+        .zip(distances)
+        .enumerate()
+        .map(|(i, ((lat, lon), dist))| ShapePoint {
+            shape_id: shape_id.clone(),
+            shape_pt_lat: lat,
+            shape_pt_lon: lon,
+            shape_pt_sequence: i as u32 + 1,
+            shape_dist_traveled: Some(dist),
+        })
+        .collect();
+    Some(Traced {
+        points,
+        call_distances,
+    })
+}
+
+/// To the metre
+fn round(km: f64) -> f64 {
+    (km * 1000.0).round() / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_goes_by_passing_points() {
+        let point = |tiploc: &str, call| PathPoint {
+            tiploc: tiploc.to_string(),
+            call,
+        };
+        let path = [
+            point("KNGX", true),
+            point("DGLSJN", false),
+            point("NOWHERE", false),
+            point("PBRO", true),
+        ];
+        let locate = |tiploc: &str| match tiploc {
+            "KNGX" => Some((51.53, -0.12)),
+            "DGLSJN" => Some((51.9, -0.5)),
+            "PBRO" => Some((52.57, -0.25)),
+            _ => None,
+        };
+        let traced = trace(&path, locate).unwrap();
+
+        let lats: Vec<f64> = traced.points.iter().map(|p| p.shape_pt_lat).collect();
+        assert_eq!(lats, [51.53, 51.9, 52.57]);
+        assert_eq!(traced.call_distances.len(), 2);
+        assert_eq!(traced.call_distances[0], 0.0);
+        // Further round by the junction than as the crow flies
+        let direct = distance_m((51.53, -0.12), (52.57, -0.25)) / 1000.0;
+        assert!(traced.call_distances[1] > direct);
+        assert_eq!(
+            traced.points.last().unwrap().shape_dist_traveled,
+            Some(traced.call_distances[1])
+        );
+
+        assert!(trace(&path[..2], |_| None).is_none());
+        let again = trace(&path, locate).unwrap();
+        assert_eq!(again.points[0].shape_id, traced.points[0].shape_id);
+    }
+}
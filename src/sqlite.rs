@@ -0,0 +1,264 @@
+//! Normalised SQLite copy of the parsed timetable.
+//!
+//! Calendars and schedules (with their calling points) are written as they
+//! are parsed, stations and associations once the timetable is read, so the database can be queried or exported
+//! to other formats without going back to the CIF.
+
+use crate::{Calendar, ParsedStation, StopTime};
+use anyhow::{Context, Result};
+use nationalrail_gtfs::cif::Association;
+use rusqlite::{Connection, params};
+use std::path::Path;
+
+const SCHEMA: &str = "
+CREATE TABLE stations (
+    tiploc TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    lat REAL NOT NULL,
+    lon REAL NOT NULL
+);
+CREATE TABLE calendars (
+    service_id TEXT PRIMARY KEY,
+    monday INTEGER NOT NULL,
+    tuesday INTEGER NOT NULL,
+    wednesday INTEGER NOT NULL,
+    thursday INTEGER NOT NULL,
+    friday INTEGER NOT NULL,
+    saturday INTEGER NOT NULL,
+    sunday INTEGER NOT NULL,
+    start_date TEXT NOT NULL,
+    end_date TEXT NOT NULL
+);
+CREATE TABLE schedules (
+    schedule_id INTEGER PRIMARY KEY,
+    uid TEXT NOT NULL,
+    start_date TEXT NOT NULL,
+    end_date TEXT NOT NULL,
+    stp_indicator TEXT NOT NULL,
+    atoc_code TEXT NOT NULL,
+    train_identity TEXT NOT NULL,
+    trip_id TEXT NOT NULL,
+    route_id TEXT NOT NULL,
+    service_id TEXT NOT NULL REFERENCES calendars (service_id)
+);
+CREATE TABLE schedule_locations (
+    schedule_id INTEGER NOT NULL REFERENCES schedules (schedule_id),
+    stop_sequence INTEGER NOT NULL,
+    tiploc TEXT NOT NULL REFERENCES stations (tiploc),
+    platform TEXT,
+    arrival_time TEXT NOT NULL,
+    departure_time TEXT NOT NULL,
+    PRIMARY KEY (schedule_id, stop_sequence)
+);
+CREATE TABLE associations (
+    base_uid TEXT NOT NULL,
+    assoc_uid TEXT NOT NULL,
+    start_date TEXT NOT NULL,
+    end_date TEXT NOT NULL,
+    days_run TEXT NOT NULL,
+    category TEXT NOT NULL,
+    location TEXT NOT NULL,
+    assoc_type TEXT NOT NULL,
+    stp_indicator TEXT NOT NULL
+);
+CREATE INDEX schedules_uid ON schedules (uid);
+CREATE INDEX associations_base_uid ON associations (base_uid);
+";
+
+/// A schedule as emitted into the GTFS, keyed back to its CIF identity
+pub struct ScheduleRow<'a> {
+    pub uid: &'a str,
+    pub start_date: &'a str,
+    pub end_date: &'a str,
+    pub stp_indicator: &'a str,
+    pub atoc_code: &'a str,
+    pub train_identity: &'a str,
+    pub trip_id: &'a str,
+    pub route_id: &'a str,
+    pub service_id: &'a str,
+}
+
+pub struct SqliteSink {
+    conn: Connection,
+}
+
+impl SqliteSink {
+    /// Create a fresh database at `path`, replacing any previous one
+    pub fn create(path: &Path) -> Result<Self> {
+        if path.exists() {
+            std::fs::remove_file(path)
+                .with_context(|| format!("Failed to replace {}", path.display()))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open SQLite database {}", path.display()))?;
+        conn.execute_batch(SCHEMA)?;
+        // Everything goes into one transaction; per-row commits are far too slow
+        conn.execute_batch("BEGIN")?;
+        Ok(SqliteSink { conn })
+    }
+
+    pub fn insert_station(&mut self, station: &ParsedStation) -> Result<()> {
+        self.conn
+            .prepare_cached("INSERT OR REPLACE INTO stations VALUES (?1, ?2, ?3, ?4)")?
+            .execute(params![
+                station.tiploc,
+                station.name,
+                station.lat,
+                station.lon
+            ])?;
+        Ok(())
+    }
+
+    pub fn insert_calendar(&mut self, calendar: &Calendar) -> Result<()> {
+        self.conn
+            .prepare_cached(
+                "INSERT OR REPLACE INTO calendars VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            )?
+            .execute(params![
+                calendar.service_id,
+                calendar.monday,
+                calendar.tuesday,
+                calendar.wednesday,
+                calendar.thursday,
+                calendar.friday,
+                calendar.saturday,
+                calendar.sunday,
+                calendar.start_date,
+                calendar.end_date,
+            ])?;
+        Ok(())
+    }
+
+    /// Insert a schedule with its calling points. Its calendar must already
+    /// have been inserted.
+    pub fn insert_schedule(&mut self, schedule: &ScheduleRow, stops: &[StopTime]) -> Result<()> {
+        self.conn
+            .prepare_cached(
+                "INSERT INTO schedules (uid, start_date, end_date, stp_indicator, atoc_code,
+                    train_identity, trip_id, route_id, service_id)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?
+            .execute(params![
+                schedule.uid,
+                schedule.start_date,
+                schedule.end_date,
+                schedule.stp_indicator,
+                schedule.atoc_code,
+                schedule.train_identity,
+                schedule.trip_id,
+                schedule.route_id,
+                schedule.service_id,
+            ])?;
+        let schedule_id = self.conn.last_insert_rowid();
+
+        let mut stmt = self
+            .conn
+            .prepare_cached("INSERT INTO schedule_locations VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?;
+        for stop in stops {
+            stmt.execute(params![
+                schedule_id,
+                stop.stop_sequence,
+                stop.tiploc,
+                stop.platform,
+                stop.arrival_time,
+                stop.departure_time,
+            ])?;
+        }
+        Ok(())
+    }
+
+    pub fn insert_association(&mut self, aa: &Association) -> Result<()> {
+        let date = |d: chrono::NaiveDate| d.format("%Y%m%d").to_string();
+        self.conn
+            .prepare_cached("INSERT INTO associations VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)")?
+            .execute(params![
+                aa.base_uid,
+                aa.assoc_uid,
+                date(aa.start_date),
+                aa.end_date.map(date).unwrap_or_default(),
+                aa.days_run,
+                aa.category,
+                aa.location,
+                aa.assoc_type.to_string(),
+                aa.stp_indicator.to_string(),
+            ])?;
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        self.conn.execute_batch("COMMIT")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("timetable.db");
+        let mut sink = SqliteSink::create(&path).unwrap();
+        sink.insert_station(&ParsedStation {
+            tiploc: "KNGX".to_string(),
+            name: "LONDON KINGS CROSS".to_string(),
+            crs: "KGX".to_string(),
+            lat: 51.53,
+            lon: -0.12,
+        })
+        .unwrap();
+
+        let calendar = Calendar {
+            service_id: "C10001_240101_P".to_string(),
+            monday: 1,
+            tuesday: 1,
+            wednesday: 1,
+            thursday: 1,
+            friday: 1,
+            saturday: 0,
+            sunday: 0,
+            start_date: "20240101".to_string(),
+            end_date: "20241214".to_string(),
+        };
+        let stops = vec![StopTime {
+            trip_id: "C10001_240101".to_string(),
+            arrival_time: "09:00:00".to_string(),
+            departure_time: "09:00:00".to_string(),
+            stop_id: "KNGX_8".to_string(),
+            stop_sequence: 1,
+            stop_headsign: None,
+            pickup_type: None,
+            drop_off_type: None,
+            shape_dist_traveled: None,
+            tiploc: "KNGX".to_string(),
+            platform: Some("8".to_string()),
+        }];
+        let schedule = ScheduleRow {
+            uid: "C10001",
+            start_date: "240101",
+            end_date: "241214",
+            stp_indicator: "P",
+            atoc_code: "GR",
+            train_identity: "1A01",
+            trip_id: "C10001_240101",
+            route_id: "GR_LONDON KINGS CROSS",
+            service_id: &calendar.service_id,
+        };
+        sink.insert_calendar(&calendar).unwrap();
+        sink.insert_schedule(&schedule, &stops).unwrap();
+        sink.finish().unwrap();
+
+        let conn = Connection::open(&path).unwrap();
+        let tiploc: String = conn
+            .query_row(
+                "SELECT l.tiploc FROM schedules s
+                 JOIN schedule_locations l USING (schedule_id)
+                 WHERE s.uid = 'C10001'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(tiploc, "KNGX");
+    }
+}
@@ -0,0 +1,101 @@
+//! Conversion statistics, written as `stats.json` beside the feed.
+//!
+//! Daily builds are compared against each other, so everything here is a
+//! count or a date that should only move when the input does.
+
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+
+#[derive(Debug, Default, Serialize)]
+pub struct Stats {
+    /// Trips written, by ATOC code
+    pub trips_per_toc: BTreeMap<String, usize>,
+    /// Stations by where their coordinates came from (`osm`, `naptan`,
+    /// `bplan`, `msn_grid`) or `unplaced` for MSN stations with none
+    pub stations_by_source: BTreeMap<&'static str, usize>,
+    /// Timetable-only TIPLOCs that couldn't be placed and were left out
+    pub unlocated_tiplocs: usize,
+    /// MSN stations whose OSM and grid reference coordinates disagree by
+    /// more than `--conflict-threshold` (see coordinate_conflicts.csv)
+    pub coordinate_conflicts: usize,
+    /// Stations left out of stops.txt because no trip calls there
+    pub stations_pruned: usize,
+    /// STP cancellations, which remove service rather than adding trips
+    pub cancelled_schedules_skipped: usize,
+    /// Schedules replaced on every date they run by an STP schedule of
+    /// the same train
+    pub superseded_schedules: usize,
+    /// Copies of trips for dates the clocks change while they run, with
+    /// their times shifted to match
+    pub clock_change_trips: usize,
+    /// Dates trips run on across a clock change that are left at their
+    /// station clock times, as they'd start before the service day does
+    pub clock_change_dates_unshifted: usize,
+    /// frequencies.txt rows written with `--use-frequencies`
+    pub frequencies: usize,
+    /// Trips left out of trips.txt as departures of another's frequency
+    pub trips_folded: usize,
+    /// Freight, empty stock and the like, unless asked for
+    pub non_passenger_schedules_skipped: usize,
+    /// Trips left with fewer than two calls once locations without a
+    /// station were taken out (see dropped_stops.csv)
+    pub short_trips_dropped: usize,
+    /// Schedules without a BX record given the operator of the same train
+    /// or service code
+    pub operators_inferred: usize,
+    /// Schedules without a BX record written under the unknown operator
+    pub unknown_operator_trips: usize,
+    /// Timetable records read, rejected ones included
+    pub records_read: usize,
+    pub rejected_records: usize,
+    pub bad_time_trips: usize,
+    pub calendars: usize,
+    pub first_service_date: Option<NaiveDate>,
+    pub last_service_date: Option<NaiveDate>,
+}
+
+impl Stats {
+    pub fn station_located(&mut self, source: &'static str) {
+        *self.stations_by_source.entry(source).or_default() += 1;
+    }
+
+    pub fn trip_written(&mut self, atoc_code: &str, start: NaiveDate, end: NaiveDate) {
+        *self.trips_per_toc.entry(atoc_code.to_string()).or_default() += 1;
+        self.first_service_date = Some(self.first_service_date.map_or(start, |d| d.min(start)));
+        self.last_service_date = Some(self.last_service_date.map_or(end, |d| d.max(end)));
+    }
+
+    pub fn trips(&self) -> usize {
+        self.trips_per_toc.values().sum()
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to write {}", path.display()))?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_date_coverage_spans_all_trips() {
+        let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+        let mut stats = Stats::default();
+        stats.trip_written("GW", date("2024-03-01"), date("2024-05-31"));
+        stats.trip_written("GW", date("2024-01-01"), date("2024-03-31"));
+        stats.trip_written("XC", date("2024-02-01"), date("2024-02-01"));
+
+        assert_eq!(stats.trips(), 3);
+        assert_eq!(stats.trips_per_toc["GW"], 2);
+        assert_eq!(stats.first_service_date, Some(date("2024-01-01")));
+        assert_eq!(stats.last_service_date, Some(date("2024-05-31")));
+    }
+}
@@ -0,0 +1,193 @@
+//! Exit codes and the status file, for runs under a scheduler.
+//!
+//! Each way a conversion can fail has its own exit code, so a job runner
+//! can retry a download failure but page someone for rejected credentials:
+//!
+//! | Code | Status                |
+//! |------|-----------------------|
+//! | 0    | `success`             |
+//! | 1    | `failed` (any other)  |
+//! | 2    | invalid arguments     |
+//! | 3    | `auth_failed`         |
+//! | 4    | `download_failed`     |
+//! | 5    | `parse_failed`        |
+//! | 6    | `validation_failed`   |
+//!
+//! Invalid arguments are reported by clap before a run starts, so never make
+//! it to the status file. Errors are tagged with [`StageContext::stage`]
+//! where they arise; the innermost tag wins, so an NRDP token rejected
+//! during a download is still an authentication failure.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::File;
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
+
+/// The part of a run that failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Auth,
+    Download,
+    Parse,
+    Validation,
+}
+
+impl Stage {
+    fn status(self) -> &'static str {
+        match self {
+            Stage::Auth => "auth_failed",
+            Stage::Download => "download_failed",
+            Stage::Parse => "parse_failed",
+            Stage::Validation => "validation_failed",
+        }
+    }
+
+    fn exit_code(self) -> u8 {
+        match self {
+            Stage::Auth => 3,
+            Stage::Download => 4,
+            Stage::Parse => 5,
+            Stage::Validation => 6,
+        }
+    }
+
+    /// Tag an error with this stage
+    pub fn tag(self, error: impl Into<anyhow::Error>) -> anyhow::Error {
+        anyhow::Error::new(StageError {
+            stage: self,
+            error: error.into(),
+        })
+    }
+}
+
+/// An error tagged with the stage it came from. It reads as the error it
+/// wraps, so tagging doesn't change what's printed.
+#[derive(Debug)]
+struct StageError {
+    stage: Stage,
+    error: anyhow::Error,
+}
+
+impl std::fmt::Display for StageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for StageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+pub trait StageContext<T> {
+    /// Tag an error with the stage of the run it failed
+    fn stage(self, stage: Stage) -> Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> StageContext<T> for Result<T, E> {
+    fn stage(self, stage: Stage) -> Result<T> {
+        self.map_err(|error| stage.tag(error))
+    }
+}
+
+/// How a run ended, written to `--status-file`
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub status: &'static str,
+    pub exit_code: u8,
+    /// The error and its causes, for a failed run
+    pub error: Option<String>,
+    pub duration_secs: f64,
+}
+
+impl Status {
+    pub fn of(result: &Result<()>, duration: Duration) -> Self {
+        match result {
+            Ok(()) => Status {
+                status: "success",
+                exit_code: 0,
+                error: None,
+                duration_secs: duration.as_secs_f64(),
+            },
+            Err(e) => Status::failure(e, duration),
+        }
+    }
+
+    pub fn failure(error: &anyhow::Error, duration: Duration) -> Self {
+        let stage = innermost_stage(error);
+        Status {
+            status: stage.map_or("failed", Stage::status),
+            exit_code: stage.map_or(1, Stage::exit_code),
+            error: Some(format!("{:#}", error)),
+            duration_secs: duration.as_secs_f64(),
+        }
+    }
+
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::from(self.exit_code)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to write {}", path.display()))?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+/// The stage of the innermost tagged error, following tagged errors into
+/// the errors they wrap
+fn innermost_stage(error: &anyhow::Error) -> Option<Stage> {
+    let mut stage = None;
+    let mut next = Some(error);
+    while let Some(error) = next {
+        next = None;
+        for cause in error.chain() {
+            if let Some(tagged) = cause.downcast_ref::<StageError>() {
+                stage = Some(tagged.stage);
+                next = Some(&tagged.error);
+                break;
+            }
+        }
+    }
+    stage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_untagged_errors_fail_with_exit_code_1() {
+        let status = Status::of(&Err(anyhow!("disk full")), Duration::ZERO);
+        assert_eq!((status.status, status.exit_code), ("failed", 1));
+        assert_eq!(status.error.as_deref(), Some("disk full"));
+
+        let status = Status::of(&Ok(()), Duration::from_millis(1500));
+        assert_eq!((status.status, status.exit_code), ("success", 0));
+        assert_eq!(status.duration_secs, 1.5);
+    }
+
+    #[test]
+    fn test_the_innermost_stage_wins() {
+        let result: Result<()> = Err(anyhow!("NRDP rejected the authentication token"))
+            .stage(Stage::Auth)
+            .context("Failed to download timetable feed")
+            .stage(Stage::Download)
+            .context("Timetable thread failed");
+        let status = Status::of(&result, Duration::ZERO);
+        assert_eq!((status.status, status.exit_code), ("auth_failed", 3));
+        // Tags don't show in the message
+        assert_eq!(
+            status.error.as_deref(),
+            Some(
+                "Timetable thread failed: Failed to download timetable feed: \
+                 NRDP rejected the authentication token"
+            )
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! A minimal STOMP 1.2 client: enough to log in, subscribe to one topic and
+//! read its messages, which is all the Darwin Push Port broker needs.
+
+use anyhow::{Context, Result, bail};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+
+#[derive(Debug)]
+pub struct Frame {
+    pub command: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Frame {
+    /// The first value of a header, which STOMP says wins over repeats
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub struct StompClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl StompClient {
+    /// Connect to `host:port` and log in
+    pub fn connect(addr: &str, username: &str, password: &str) -> Result<Self> {
+        let writer =
+            TcpStream::connect(addr).with_context(|| format!("Failed to connect to {}", addr))?;
+        let reader = BufReader::new(writer.try_clone()?);
+        let mut client = StompClient { reader, writer };
+        let host = addr.split(':').next().unwrap_or(addr);
+        client.send(
+            "CONNECT",
+            &[
+                ("accept-version", "1.2"),
+                ("host", host),
+                ("login", username),
+                ("passcode", password),
+                // The broker's heart-beats would need a second thread to answer
+                ("heart-beat", "0,0"),
+            ],
+        )?;
+        let frame = client.next_frame()?;
+        match frame.command.as_str() {
+            "CONNECTED" => Ok(client),
+            "ERROR" => bail!(
+                "STOMP login refused: {}",
+                frame.header("message").unwrap_or("no reason given")
+            ),
+            other => bail!("Unexpected STOMP {} frame while connecting", other),
+        }
+    }
+
+    pub fn subscribe(&mut self, destination: &str) -> Result<()> {
+        self.send(
+            "SUBSCRIBE",
+            &[("id", "0"), ("destination", destination), ("ack", "auto")],
+        )
+    }
+
+    /// Block until the broker sends the next frame
+    pub fn next_frame(&mut self) -> Result<Frame> {
+        read_frame(&mut self.reader)
+    }
+
+    fn send(&mut self, command: &str, headers: &[(&str, &str)]) -> Result<()> {
+        let mut frame = format!("{}\n", command);
+        for (key, value) in headers {
+            // CONNECT headers are sent as they are
+            if command == "CONNECT" {
+                frame.push_str(&format!("{}:{}\n", key, value));
+            } else {
+                frame.push_str(&format!("{}:{}\n", escape(key), escape(value)));
+            }
+        }
+        frame.push_str("\n\0");
+        self.writer.write_all(frame.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace(':', "\\c")
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('c') => out.push(':'),
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+    out
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let trimmed = line.trim_end_matches(['\n', '\r']).len();
+    line.truncate(trimmed);
+    Ok(Some(line))
+}
+
+fn read_frame<R: BufRead>(reader: &mut R) -> Result<Frame> {
+    // Blank lines between frames are heart-beats
+    let command = loop {
+        match read_line(reader)? {
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break line,
+            None => bail!("STOMP connection closed"),
+        }
+    };
+    let mut headers = Vec::new();
+    while let Some(line) = read_line(reader)? {
+        if line.is_empty() {
+            break;
+        }
+        let (key, value) = line.split_once(':').unwrap_or((&line, ""));
+        headers.push((unescape(key), unescape(value)));
+    }
+    let mut frame = Frame {
+        command,
+        headers,
+        body: Vec::new(),
+    };
+    match frame.header("content-length").map(str::parse::<usize>) {
+        Some(Ok(length)) => {
+            frame.body.resize(length, 0);
+            reader.read_exact(&mut frame.body)?;
+            let mut terminator = [0u8];
+            reader.read_exact(&mut terminator)?;
+        }
+        _ => {
+            reader.read_until(0, &mut frame.body)?;
+            if frame.body.last() == Some(&0) {
+                frame.body.pop();
+            }
+        }
+    }
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_frames() {
+        let mut stream: &[u8] =
+            b"\nMESSAGE\ndestination:/topic/darwin\\cv16\ncontent-length:3\n\n\x1f\0\x8b\0\n\
+CONNECTED\nversion:1.2\n\nhello\0";
+        let message = read_frame(&mut stream).unwrap();
+        assert_eq!(message.command, "MESSAGE");
+        assert_eq!(message.header("destination"), Some("/topic/darwin:v16"));
+        // A body with content-length may contain NULs
+        assert_eq!(message.body, b"\x1f\0\x8b");
+        let connected = read_frame(&mut stream).unwrap();
+        assert_eq!(connected.command, "CONNECTED");
+        assert_eq!(connected.body, b"hello");
+        assert!(read_frame(&mut stream).is_err());
+    }
+}
@@ -0,0 +1,159 @@
+//! Helpers for running the parsers over the small CIF and MSN snippets in
+//! `tests/fixtures`, so conversions can be tested without NRDP credentials.
+
+use crate::bank_holidays::BankHolidays;
+use crate::branding::BrandingTable;
+use crate::line_rules::LineRules;
+use crate::service_groups::ServiceGroups;
+use crate::stats::Stats;
+use crate::{
+    BadTimesPolicy, CifErrorPolicy, ConvertedTrip, CoordinatePolicy, Filters, LocationIndex,
+    OutputOptions, ParsedStation, TimetableContext, TimetableSummary, parse_mca, parse_msn,
+};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Open a file from `tests/fixtures`
+pub fn fixture(name: &str) -> File {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    File::open(&path).unwrap_or_else(|e| panic!("Failed to open {}: {}", path.display(), e))
+}
+
+/// The text of a file from `tests/fixtures`, for tests to edit before
+/// converting it
+pub fn fixture_text(name: &str) -> String {
+    let mut text = String::new();
+    fixture(name).read_to_string(&mut text).unwrap();
+    text
+}
+
+/// An extract of about `lines` lines: the header of `sample.MCA`, then
+/// copies of its associations and schedules under new UIDs. The same as
+/// `benches/cif.rs` reads.
+pub fn synthetic_mca(lines: usize) -> String {
+    let sample = fixture_text("sample.MCA");
+    let header = sample.lines().next().unwrap();
+    let body: Vec<&str> = sample
+        .lines()
+        .filter(|line| !line.starts_with("HD") && !line.starts_with("ZZ"))
+        .collect();
+    let mut extract = format!("{}\n", header);
+    let mut copy = 0;
+    let mut written = 1;
+    while written < lines {
+        let uid = |n: usize| {
+            format!(
+                "{}{:05}",
+                char::from(b'A' + (n / 100_000) as u8),
+                n % 100_000
+            )
+        };
+        let (base, assoc) = (uid(copy * 2), uid(copy * 2 + 1));
+        for line in &body {
+            extract.push_str(&line.replace("C10001", &base).replace("C20001", &assoc));
+            extract.push('\n');
+        }
+        written += body.len();
+        copy += 1;
+    }
+    extract.push_str("ZZ\n");
+    extract
+}
+
+/// Owns everything a [`TimetableContext`] borrows, with no external
+/// location sources so coordinates come from the MSN alone
+pub struct Fixture {
+    pub locations: LocationIndex,
+    pub toc_lookup: HashMap<String, String>,
+    pub branding: BrandingTable,
+    pub service_groups: ServiceGroups,
+    pub line_rules: LineRules,
+    pub filters: Filters,
+    pub output_options: OutputOptions,
+    pub bank_holidays: BankHolidays,
+}
+
+impl Default for Fixture {
+    fn default() -> Self {
+        let toc_lookup = [("GR", "LNER"), ("XC", "CrossCountry")]
+            .into_iter()
+            .map(|(id, name)| (id.to_string(), name.to_string()))
+            .collect();
+        Fixture {
+            locations: LocationIndex::default(),
+            toc_lookup,
+            branding: BrandingTable::builtin(),
+            service_groups: ServiceGroups::builtin(),
+            line_rules: LineRules::default(),
+            filters: Filters::default(),
+            output_options: OutputOptions::default(),
+            bank_holidays: BankHolidays::builtin(),
+        }
+    }
+}
+
+impl Fixture {
+    pub fn context(&self) -> TimetableContext<'_> {
+        TimetableContext {
+            locations: &self.locations,
+            toc_lookup: &self.toc_lookup,
+            branding: &self.branding,
+            service_groups: &self.service_groups,
+            line_rules: &self.line_rules,
+            filters: &self.filters,
+            output_options: &self.output_options,
+            bank_holidays: &self.bank_holidays,
+            on_error: CifErrorPolicy::Abort,
+            on_bad_times: BadTimesPolicy::Keep,
+        }
+    }
+
+    /// Stations from an MSN fixture, by TIPLOC
+    pub fn stations(&self, msn: &str) -> HashMap<String, ParsedStation> {
+        parse_msn(
+            &mut fixture(msn),
+            &self.locations,
+            &CoordinatePolicy::default(),
+            &mut Vec::new(),
+        )
+        .unwrap()
+        .into_iter()
+        .map(|(station, _)| (station.tiploc.clone(), station))
+        .collect()
+    }
+
+    /// Convert an MCA fixture against the stations of an MSN fixture
+    pub fn convert(&self, msn: &str, mca: &str) -> (Vec<ConvertedTrip>, TimetableSummary, Stats) {
+        self.convert_text(msn, mca, &fixture_text(mca))
+    }
+
+    /// Convert MCA text, e.g. a fixture edited by the test, against the
+    /// stations of an MSN fixture
+    pub fn convert_text(
+        &self,
+        msn: &str,
+        name: &str,
+        mca: &str,
+    ) -> (Vec<ConvertedTrip>, TimetableSummary, Stats) {
+        let mut tiploc_map = self.stations(msn);
+        let mut stats = Stats::default();
+        let mut trips = Vec::new();
+        let summary = parse_mca(
+            &mut mca.as_bytes(),
+            name,
+            &mut tiploc_map,
+            &self.context(),
+            &mut stats,
+            |trip| {
+                trips.push(trip);
+                Ok(())
+            },
+        )
+        .unwrap();
+        (trips, summary, stats)
+    }
+}
@@ -0,0 +1,169 @@
+//! Dates and times in Great Britain.
+//!
+//! The CIF gives times as they appear on the station clock. GTFS measures
+//! stop times from noon minus twelve hours on the service day, which is
+//! midnight except on the two days a year the clocks change: it's 23:00 the
+//! evening before when they go forward and 01:00 when they go back. Trips
+//! running across a change need their times adjusted on that date.
+
+use chrono::{DateTime, Datelike, Days, Duration, LocalResult, NaiveDate, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+
+pub const TIMEZONE: Tz = chrono_tz::Europe::London;
+
+const DAY_SECS: u32 = 24 * 3600;
+
+/// Today's date in Great Britain, whatever the machine's own time zone
+pub fn today() -> NaiveDate {
+    Utc::now().with_timezone(&TIMEZONE).date_naive()
+}
+
+fn offset_at_utc_midnight(date: NaiveDate) -> i32 {
+    let utc = date
+        .and_hms_opt(0, 0, 0)
+        .expect("midnight exists")
+        .and_utc();
+    utc.with_timezone(&TIMEZONE)
+        .offset()
+        .fix()
+        .local_minus_utc()
+}
+
+/// The dates in a year on which the clocks change
+pub fn clock_changes(year: i32) -> Vec<NaiveDate> {
+    let Some(first) = NaiveDate::from_ymd_opt(year, 1, 1) else {
+        return Vec::new();
+    };
+    first
+        .iter_days()
+        .take_while(|date| date.year() == year)
+        .filter(|date| {
+            let next = *date + Days::new(1);
+            offset_at_utc_midnight(*date) != offset_at_utc_midnight(next)
+        })
+        .collect()
+}
+
+/// The instant the times of a service day are measured from
+fn service_day_start(date: NaiveDate) -> DateTime<Tz> {
+    let noon = date.and_hms_opt(12, 0, 0).expect("noon exists");
+    TIMEZONE
+        .from_local_datetime(&noon)
+        .earliest()
+        .expect("the clocks never change at noon")
+        - Duration::hours(12)
+}
+
+/// A station clock time on a service date, in seconds past midnight and
+/// beyond for trips running into the next day, as seconds since the start
+/// of the service day. A time skipped by the clocks going forward is read
+/// as the hour after, and one repeated when they go back as the first.
+pub fn service_seconds(date: NaiveDate, clock_secs: u32) -> i64 {
+    let day = date + Days::new(u64::from(clock_secs / DAY_SECS));
+    let local = day.and_hms_opt(0, 0, 0).expect("midnight exists")
+        + Duration::seconds(i64::from(clock_secs % DAY_SECS));
+    let instant = match TIMEZONE.from_local_datetime(&local) {
+        LocalResult::Single(instant) | LocalResult::Ambiguous(instant, _) => instant,
+        LocalResult::None => TIMEZONE
+            .from_local_datetime(&(local + Duration::hours(1)))
+            .earliest()
+            .expect("the clocks only skip an hour"),
+    };
+    (instant - service_day_start(date)).num_seconds()
+}
+
+/// A trip's times on a date, measured from the start of the service day
+#[derive(Debug, PartialEq, Eq)]
+pub enum ShiftedTimes {
+    /// The same as the station clock times
+    Unchanged,
+    /// Moved by the clocks changing
+    Shifted(Vec<u32>),
+    /// Some fall before the service day starts, which GTFS can't express
+    BeforeServiceDay,
+}
+
+/// A trip's times on a date, and whether the clocks changing moves them
+/// from the station clock times
+pub fn shifted_times(date: NaiveDate, clock_secs: &[u32]) -> ShiftedTimes {
+    let Some(shifted) = clock_secs
+        .iter()
+        .map(|&secs| u32::try_from(service_seconds(date, secs)).ok())
+        .collect::<Option<Vec<u32>>>()
+    else {
+        return ShiftedTimes::BeforeServiceDay;
+    };
+    if shifted == clock_secs {
+        ShiftedTimes::Unchanged
+    } else {
+        ShiftedTimes::Shifted(shifted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y%m%d").unwrap()
+    }
+
+    fn hours(h: f64) -> u32 {
+        (h * 3600.0) as u32
+    }
+
+    #[test]
+    fn test_clock_changes_2024() {
+        assert_eq!(clock_changes(2024), [date("20240331"), date("20241027")]);
+    }
+
+    #[test]
+    fn test_times_across_the_spring_change() {
+        // The clocks go forward at 01:00 on 31 March 2024, so the service
+        // day starts at 23:00 the evening before
+        let spring = date("20240331");
+        assert_eq!(service_seconds(spring, hours(0.5)), i64::from(hours(1.5)));
+        assert_eq!(service_seconds(spring, hours(3.0)), i64::from(hours(3.0)));
+        // 01:30 never happens; the train runs at 02:30
+        assert_eq!(service_seconds(spring, hours(1.5)), i64::from(hours(2.5)));
+
+        // A sleeper leaving on Saturday evening arrives an hour sooner
+        let saturday = date("20240330");
+        assert_eq!(
+            shifted_times(saturday, &[hours(23.5), hours(31.0)]),
+            ShiftedTimes::Shifted(vec![hours(23.5), hours(30.0)])
+        );
+        // A daytime train isn't affected
+        assert_eq!(
+            shifted_times(spring, &[hours(9.0), hours(11.0)]),
+            ShiftedTimes::Unchanged
+        );
+        assert_eq!(
+            shifted_times(date("20240301"), &[hours(23.5), hours(31.0)]),
+            ShiftedTimes::Unchanged
+        );
+    }
+
+    #[test]
+    fn test_times_across_the_autumn_change() {
+        // The clocks go back at 02:00 on 27 October 2024, so the service
+        // day starts at 01:00
+        let autumn = date("20241027");
+        assert_eq!(service_seconds(autumn, hours(3.0)), i64::from(hours(3.0)));
+        // 01:30 happens twice; the first, still in summer time, is half an
+        // hour into the service day
+        assert_eq!(service_seconds(autumn, hours(1.5)), i64::from(hours(0.5)));
+        // 00:30 is before the service day starts
+        assert_eq!(
+            shifted_times(autumn, &[hours(0.5), hours(3.0)]),
+            ShiftedTimes::BeforeServiceDay
+        );
+
+        // An overnight train from Saturday takes an hour longer
+        let saturday = date("20241026");
+        assert_eq!(
+            shifted_times(saturday, &[hours(23.0), hours(29.0)]),
+            ShiftedTimes::Shifted(vec![hours(23.0), hours(30.0)])
+        );
+    }
+}
@@ -0,0 +1,105 @@
+//! Welsh station names, written as `translations.txt`.
+//!
+//! Neither the MSN nor the CIF carries Welsh names, so stations with a
+//! distinct Welsh name are listed here by CRS. Stations whose name is the
+//! same in both languages (Llanelli, Bangor, Pontypridd) are left out.
+
+use crate::writer::create_csv;
+use anyhow::Result;
+use serde::Serialize;
+use std::path::Path;
+
+/// GTFS requires feed_info.txt alongside translations.txt
+const FEED_PUBLISHER_NAME: &str = "nationalrail-gtfs";
+const FEED_PUBLISHER_URL: &str = "https://github.com/catenarytransit/nationalrail-gtfs";
+
+#[rustfmt::skip]
+const WELSH_NAMES: &[(&str, &str)] = &[
+    ("AGV", "Y Fenni"),
+    ("BGN", "Pen-y-bont ar Ogwr"),
+    ("BRY", "Y Barri"),
+    ("BYI", "Ynys y Barri"),
+    ("CDF", "Caerdydd Canolog"),
+    ("CDQ", "Caerdydd Heol y Frenhines"),
+    ("CMN", "Caerfyrddin"),
+    ("CPH", "Caerffili"),
+    ("CPW", "Cas-gwent"),
+    ("FLN", "Y Fflint"),
+    ("HHD", "Caergybi"),
+    ("HVF", "Hwlffordd"),
+    ("LLJ", "Cyffordd Llandudno"),
+    ("MER", "Merthyr Tudful"),
+    ("MFH", "Aberdaugleddau"),
+    ("NTH", "Castell-nedd"),
+    ("NWP", "Casnewydd"),
+    ("PMB", "Penfro"),
+    ("RHL", "Y Rhyl"),
+    ("STJ", "Cyffordd Twnnel Hafren"),
+    ("SWA", "Abertawe"),
+    ("TEN", "Dinbych-y-pysgod"),
+    ("WRX", "Wrecsam Cyffredinol"),
+];
+
+pub fn welsh_name(crs: &str) -> Option<&'static str> {
+    WELSH_NAMES
+        .iter()
+        .find(|&&(code, _)| code == crs)
+        .map(|&(_, name)| name)
+}
+
+#[derive(Serialize)]
+struct Translation<'a> {
+    table_name: &'static str,
+    field_name: &'static str,
+    language: &'static str,
+    translation: &'static str,
+    record_id: &'a str,
+}
+
+#[derive(Serialize)]
+struct FeedInfo {
+    feed_publisher_name: &'static str,
+    feed_publisher_url: &'static str,
+    feed_lang: &'static str,
+}
+
+/// Write translations.txt for the given stop ids and Welsh names, and the
+/// feed_info.txt it needs
+pub fn write_translations(
+    output_dir: &Path,
+    buffer_size: usize,
+    stops: &[(String, &'static str)],
+) -> Result<()> {
+    let mut translations = create_csv(output_dir, "translations.txt", buffer_size)?;
+    for (stop_id, name) in stops {
+        translations.serialize(Translation {
+            table_name: "stops",
+            field_name: "stop_name",
+            language: "cy",
+            translation: name,
+            record_id: stop_id,
+        })?;
+    }
+    translations.flush()?;
+
+    let mut feed_info = create_csv(output_dir, "feed_info.txt", buffer_size)?;
+    feed_info.serialize(FeedInfo {
+        feed_publisher_name: FEED_PUBLISHER_NAME,
+        feed_publisher_url: FEED_PUBLISHER_URL,
+        feed_lang: "en",
+    })?;
+    feed_info.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_welsh_names_are_sorted_by_crs() {
+        assert!(WELSH_NAMES.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(welsh_name("CDF"), Some("Caerdydd Canolog"));
+        assert_eq!(welsh_name("KGX"), None);
+    }
+}
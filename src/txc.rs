@@ -0,0 +1,669 @@
+//! TransXChange output, for `--format txc`.
+//!
+//! Local authority and bus open data systems take TransXChange 2.4 rather
+//! than GTFS, so the feed's records are also written as one document per
+//! operator, `txc/{agency_id}.xml`. Each GTFS route becomes a service with
+//! a single line, and each trip a vehicle journey on a journey pattern,
+//! route and route section of its own; stops are referred to by their GTFS
+//! stop ids. A trip's days come from its calendar.txt row and dates as an
+//! operating profile within its service's operating period.
+//!
+//! As with NeTEx, trips are written to a scratch file as they come and
+//! sorted into their operators' documents at the end. Stations,
+//! pathways, levels, shapes and transfers have no counterpart written
+//! here, and frequencies can't be used with TransXChange.
+
+use crate::frequencies::Frequency;
+use crate::netex::{end, start, text, time_and_offset};
+use crate::pathways::{Level, Pathway};
+use crate::shapes::ShapePoint;
+use crate::writer::GtfsWriter;
+use crate::{Agency, Calendar, CalendarDate, Route, Stop, StopTime, Transfer, Trip};
+use anyhow::{Context, Result, bail};
+use chrono::{Duration, NaiveDate, SecondsFormat, Utc};
+use quick_xml::Writer;
+use quick_xml::events::{BytesDecl, BytesStart, Event};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+pub const TXC_DIR: &str = "txc";
+
+/// Open an element with an `id`
+fn start_with_id<W: Write>(w: &mut Writer<W>, name: &str, id: &str) -> io::Result<()> {
+    w.write_event(Event::Start(
+        BytesStart::new(name).with_attributes([("id", id)]),
+    ))
+}
+
+/// A number of seconds as an `xs:duration`
+fn duration(secs: u32) -> String {
+    match secs % 60 {
+        0 => format!("PT{}M", secs / 60),
+        s => format!("PT{}M{}S", secs / 60, s),
+    }
+}
+
+fn parse_date(gtfs: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(gtfs, "%Y%m%d").with_context(|| format!("Bad date {}", gtfs))
+}
+
+fn date_range<W: Write>(w: &mut Writer<W>, from: NaiveDate, to: NaiveDate) -> io::Result<()> {
+    start(w, "DateRange")?;
+    text(w, "StartDate", &from.format("%Y-%m-%d").to_string())?;
+    text(w, "EndDate", &to.format("%Y-%m-%d").to_string())?;
+    end(w, "DateRange")
+}
+
+/// Trips' elements as they're written, to be copied into the documents
+struct Scratch {
+    file: BufWriter<File>,
+    len: u64,
+}
+
+impl Scratch {
+    fn create() -> Result<Self> {
+        Ok(Scratch {
+            file: BufWriter::new(tempfile::tempfile()?),
+            len: 0,
+        })
+    }
+
+    /// Write one element, returning where it is in the file
+    fn append(
+        &mut self,
+        element: impl FnOnce(&mut Writer<Vec<u8>>) -> io::Result<()>,
+    ) -> Result<Range<u64>> {
+        let mut w = Writer::new(Vec::new());
+        element(&mut w)?;
+        let bytes = w.into_inner();
+        self.file.write_all(&bytes)?;
+        let range = self.len..self.len + bytes.len() as u64;
+        self.len = range.end;
+        Ok(range)
+    }
+}
+
+/// Where a trip's elements are in the scratch file
+struct TxcTrip {
+    route_id: String,
+    service_id: String,
+    trip_short_name: String,
+    route_section: Range<u64>,
+    route: Range<u64>,
+    journey_pattern_section: Range<u64>,
+    journey_pattern: Range<u64>,
+    /// The vehicle journey from its code on; its private code and operating
+    /// profile are written on `finish`, once calendars are known
+    vehicle_journey: Range<u64>,
+}
+
+/// What's kept of each GTFS route's trips beyond their elements
+#[derive(Default)]
+struct RouteTrips {
+    trips: Vec<usize>,
+    stops: BTreeSet<String>,
+    /// The first and last stops of the route's first trip
+    ends: Option<(String, String)>,
+}
+
+/// TransXChange documents built from the GTFS records, written on `finish`
+pub struct TxcFeed {
+    dir: PathBuf,
+    agencies: Vec<Agency>,
+    stop_names: HashMap<String, String>,
+    routes: Vec<Route>,
+    calendars: HashMap<String, Calendar>,
+    calendar_dates: HashMap<String, Vec<CalendarDate>>,
+    /// The trip whose stop times are being given
+    current: Option<(Trip, Vec<StopTime>)>,
+    trips: Vec<TxcTrip>,
+    route_trips: HashMap<String, RouteTrips>,
+    scratch: Option<Scratch>,
+}
+
+impl TxcFeed {
+    pub fn create(dir: &Path) -> Result<Self> {
+        Ok(TxcFeed {
+            dir: dir.to_path_buf(),
+            agencies: Vec::new(),
+            stop_names: HashMap::new(),
+            routes: Vec::new(),
+            calendars: HashMap::new(),
+            calendar_dates: HashMap::new(),
+            current: None,
+            trips: Vec::new(),
+            route_trips: HashMap::new(),
+            scratch: Some(Scratch::create()?),
+        })
+    }
+
+    /// Write out the trip whose stop times have all been given
+    fn finish_trip(&mut self) -> Result<()> {
+        let Some((trip, stop_times)) = self.current.take() else {
+            return Ok(());
+        };
+        let Some(scratch) = &mut self.scratch else {
+            bail!("TransXChange feed already finished");
+        };
+        let (Some(first), Some(last)) = (stop_times.first(), stop_times.last()) else {
+            return Ok(());
+        };
+        let id = &trip.trip_id;
+        let links: Vec<(usize, &StopTime, &StopTime)> = stop_times
+            .windows(2)
+            .enumerate()
+            .map(|(i, pair)| (i + 1, &pair[0], &pair[1]))
+            .collect();
+
+        let route_section = scratch.append(|w| {
+            start_with_id(w, "RouteSection", &format!("RS-{}", id))?;
+            for &(i, from, to) in &links {
+                start_with_id(w, "RouteLink", &format!("RL-{}-{}", id, i))?;
+                for (name, stop_time) in [("From", from), ("To", to)] {
+                    start(w, name)?;
+                    text(w, "StopPointRef", &stop_time.stop_id)?;
+                    end(w, name)?;
+                }
+                end(w, "RouteLink")?;
+            }
+            end(w, "RouteSection")
+        })?;
+
+        let route = scratch.append(|w| {
+            start_with_id(w, "Route", &format!("R-{}", id))?;
+            text(w, "Description", &trip.trip_headsign)?;
+            text(w, "RouteSectionRef", &format!("RS-{}", id))?;
+            end(w, "Route")
+        })?;
+
+        let seconds = |time: &str| crate::gtfs_seconds(time).unwrap_or(0);
+        let dwell = |stop_time: &StopTime| {
+            seconds(&stop_time.departure_time).saturating_sub(seconds(&stop_time.arrival_time))
+        };
+        let journey_pattern_section = scratch.append(|w| {
+            start_with_id(w, "JourneyPatternSection", &format!("JPS-{}", id))?;
+            for &(i, from, to) in &links {
+                start_with_id(w, "JourneyPatternTimingLink", &format!("JPTL-{}-{}", id, i))?;
+                for (name, sequence, stop_time, wait) in [
+                    ("From", i, from, if i == 1 { dwell(from) } else { 0 }),
+                    ("To", i + 1, to, dwell(to)),
+                ] {
+                    w.write_event(Event::Start(
+                        BytesStart::new(name)
+                            .with_attributes([("SequenceNumber", sequence.to_string().as_str())]),
+                    ))?;
+                    text(w, "StopPointRef", &stop_time.stop_id)?;
+                    text(w, "TimingStatus", "principalTimingPoint")?;
+                    if wait > 0 {
+                        text(w, "WaitTime", &duration(wait))?;
+                    }
+                    end(w, name)?;
+                }
+                text(w, "RouteLinkRef", &format!("RL-{}-{}", id, i))?;
+                let run = seconds(&to.arrival_time).saturating_sub(seconds(&from.departure_time));
+                text(w, "RunTime", &duration(run))?;
+                end(w, "JourneyPatternTimingLink")?;
+            }
+            end(w, "JourneyPatternSection")
+        })?;
+
+        let journey_pattern = scratch.append(|w| {
+            start_with_id(w, "JourneyPattern", &format!("JP-{}", id))?;
+            text(w, "DestinationDisplay", &trip.trip_headsign)?;
+            // direction_id 0 is towards London
+            let direction = match trip.direction_id {
+                Some(0) => "inbound",
+                Some(_) => "outbound",
+                None => "inboundAndOutbound",
+            };
+            text(w, "Direction", direction)?;
+            text(w, "RouteRef", &format!("R-{}", id))?;
+            text(w, "JourneyPatternSectionRefs", &format!("JPS-{}", id))?;
+            end(w, "JourneyPattern")
+        })?;
+
+        let vehicle_journey = scratch.append(|w| {
+            text(w, "VehicleJourneyCode", id)?;
+            text(w, "ServiceRef", &trip.route_id)?;
+            text(w, "LineRef", &format!("L-{}", trip.route_id))?;
+            text(w, "JourneyPatternRef", &format!("JP-{}", id))?;
+            let (time, shift) = time_and_offset(&first.departure_time);
+            text(w, "DepartureTime", &time)?;
+            if shift > 0 {
+                text(w, "DepartureDayShift", &shift.to_string())?;
+            }
+            end(w, "VehicleJourney")
+        })?;
+
+        let route_trips = self.route_trips.entry(trip.route_id.clone()).or_default();
+        route_trips.trips.push(self.trips.len());
+        route_trips
+            .stops
+            .extend(stop_times.iter().map(|stop_time| stop_time.stop_id.clone()));
+        route_trips
+            .ends
+            .get_or_insert_with(|| (first.stop_id.clone(), last.stop_id.clone()));
+        self.trips.push(TxcTrip {
+            route_id: trip.route_id,
+            service_id: trip.service_id,
+            trip_short_name: trip.trip_short_name,
+            route_section,
+            route,
+            journey_pattern_section,
+            journey_pattern,
+            vehicle_journey,
+        });
+        Ok(())
+    }
+
+    /// The first and last days a service runs
+    fn service_span(&self, service_id: &str) -> Result<Option<(NaiveDate, NaiveDate)>> {
+        let mut span: Option<(NaiveDate, NaiveDate)> = None;
+        let mut extend = |from: NaiveDate, to: NaiveDate| {
+            span = Some(match span {
+                Some((start, end)) => (start.min(from), end.max(to)),
+                None => (from, to),
+            });
+        };
+        if let Some(calendar) = self.calendars.get(service_id) {
+            extend(
+                parse_date(&calendar.start_date)?,
+                parse_date(&calendar.end_date)?,
+            );
+        }
+        for date in self.calendar_dates.get(service_id).into_iter().flatten() {
+            if date.exception_type == 1 {
+                let date = parse_date(&date.date)?;
+                extend(date, date);
+            }
+        }
+        Ok(span)
+    }
+
+    /// The days a trip runs, as its weekdays plus the dates added to them
+    /// and minus those taken away, within the service's operating period
+    fn write_operating_profile<W: Write>(
+        &self,
+        w: &mut Writer<W>,
+        service_id: &str,
+        period: (NaiveDate, NaiveDate),
+    ) -> Result<()> {
+        let calendar = self.calendars.get(service_id);
+        let dates = self
+            .calendar_dates
+            .get(service_id)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        start(w, "OperatingProfile")?;
+        start(w, "RegularDayType")?;
+        let days: Vec<&str> = calendar
+            .map(|calendar| {
+                [
+                    (calendar.monday, "Monday"),
+                    (calendar.tuesday, "Tuesday"),
+                    (calendar.wednesday, "Wednesday"),
+                    (calendar.thursday, "Thursday"),
+                    (calendar.friday, "Friday"),
+                    (calendar.saturday, "Saturday"),
+                    (calendar.sunday, "Sunday"),
+                ]
+                .into_iter()
+                .filter(|&(runs, _)| runs == 1)
+                .map(|(_, day)| day)
+                .collect()
+            })
+            .unwrap_or_default();
+        if days.is_empty() {
+            w.write_event(Event::Empty(BytesStart::new("HolidaysOnly")))?;
+        } else {
+            start(w, "DaysOfWeek")?;
+            for day in days {
+                w.write_event(Event::Empty(BytesStart::new(day)))?;
+            }
+            end(w, "DaysOfWeek")?;
+        }
+        end(w, "RegularDayType")?;
+
+        let added: Vec<NaiveDate> = dates
+            .iter()
+            .filter(|date| date.exception_type == 1)
+            .map(|date| parse_date(&date.date))
+            .collect::<Result<_>>()?;
+        let mut removed: Vec<(NaiveDate, NaiveDate)> = dates
+            .iter()
+            .filter(|date| date.exception_type == 2)
+            .map(|date| parse_date(&date.date).map(|date| (date, date)))
+            .collect::<Result<_>>()?;
+        // The weekdays only hold within the calendar's own dates
+        if let Some(calendar) = calendar {
+            let (from, to) = (
+                parse_date(&calendar.start_date)?,
+                parse_date(&calendar.end_date)?,
+            );
+            if period.0 < from {
+                removed.push((period.0, from - Duration::days(1)));
+            }
+            if to < period.1 {
+                removed.push((to + Duration::days(1), period.1));
+            }
+        }
+        if !added.is_empty() || !removed.is_empty() {
+            start(w, "SpecialDaysOperation")?;
+            if !added.is_empty() {
+                start(w, "DaysOfOperation")?;
+                for date in added {
+                    date_range(w, date, date)?;
+                }
+                end(w, "DaysOfOperation")?;
+            }
+            if !removed.is_empty() {
+                removed.sort();
+                start(w, "DaysOfNonOperation")?;
+                for (from, to) in removed {
+                    date_range(w, from, to)?;
+                }
+                end(w, "DaysOfNonOperation")?;
+            }
+            end(w, "SpecialDaysOperation")?;
+        }
+        end(w, "OperatingProfile")?;
+        Ok(())
+    }
+
+    /// One operator's document, for the given routes of theirs
+    fn write_operator<W: Write>(
+        &self,
+        w: &mut Writer<W>,
+        file: &mut File,
+        agency: &Agency,
+        routes: &[&Route],
+        file_name: &str,
+    ) -> Result<()> {
+        let trips: Vec<&TxcTrip> = routes
+            .iter()
+            .flat_map(|route| &self.route_trips[&route.route_id].trips)
+            .map(|&i| &self.trips[i])
+            .collect();
+        let mut copy = |w: &mut Writer<W>, range: &Range<u64>| -> Result<()> {
+            file.seek(SeekFrom::Start(range.start))?;
+            io::copy(&mut (&mut *file).take(range.end - range.start), w.get_mut())?;
+            Ok(())
+        };
+
+        let now = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+        w.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+        w.write_event(Event::Start(
+            BytesStart::new("TransXChange").with_attributes([
+                ("xmlns", "http://www.transxchange.org.uk/"),
+                ("xml:lang", "en"),
+                ("CreationDateTime", now.as_str()),
+                ("ModificationDateTime", now.as_str()),
+                ("Modification", "new"),
+                ("RevisionNumber", "0"),
+                ("FileName", file_name),
+                ("SchemaVersion", "2.4"),
+            ]),
+        ))?;
+
+        let stops: BTreeSet<&String> = routes
+            .iter()
+            .flat_map(|route| &self.route_trips[&route.route_id].stops)
+            .collect();
+        start(w, "StopPoints")?;
+        for stop_id in stops {
+            start(w, "AnnotatedStopPointRef")?;
+            text(w, "StopPointRef", stop_id)?;
+            let name = self.stop_names.get(stop_id).unwrap_or(stop_id);
+            text(w, "CommonName", name)?;
+            end(w, "AnnotatedStopPointRef")?;
+        }
+        end(w, "StopPoints")?;
+
+        start(w, "RouteSections")?;
+        for trip in &trips {
+            copy(w, &trip.route_section)?;
+        }
+        end(w, "RouteSections")?;
+        start(w, "Routes")?;
+        for trip in &trips {
+            copy(w, &trip.route)?;
+        }
+        end(w, "Routes")?;
+        start(w, "JourneyPatternSections")?;
+        for trip in &trips {
+            copy(w, &trip.journey_pattern_section)?;
+        }
+        end(w, "JourneyPatternSections")?;
+
+        start(w, "Operators")?;
+        start_with_id(w, "Operator", &agency.agency_id)?;
+        text(w, "OperatorCode", &agency.agency_id)?;
+        text(w, "OperatorShortName", &agency.agency_name)?;
+        end(w, "Operator")?;
+        end(w, "Operators")?;
+
+        start(w, "Services")?;
+        let mut periods = HashMap::new();
+        for route in routes {
+            let route_trips = &self.route_trips[&route.route_id];
+            let mut period: Option<(NaiveDate, NaiveDate)> = None;
+            for &i in &route_trips.trips {
+                if let Some((from, to)) = self.service_span(&self.trips[i].service_id)? {
+                    period = Some(match period {
+                        Some((start, end)) => (start.min(from), end.max(to)),
+                        None => (from, to),
+                    });
+                }
+            }
+            let Some(period) = period else {
+                continue;
+            };
+            periods.insert(route.route_id.as_str(), period);
+
+            start(w, "Service")?;
+            text(w, "ServiceCode", &route.route_id)?;
+            start(w, "Lines")?;
+            start_with_id(w, "Line", &format!("L-{}", route.route_id))?;
+            text(w, "LineName", &route.route_short_name)?;
+            end(w, "Line")?;
+            end(w, "Lines")?;
+            start(w, "OperatingPeriod")?;
+            text(w, "StartDate", &period.0.format("%Y-%m-%d").to_string())?;
+            text(w, "EndDate", &period.1.format("%Y-%m-%d").to_string())?;
+            end(w, "OperatingPeriod")?;
+            text(w, "RegisteredOperatorRef", &agency.agency_id)?;
+            text(w, "Mode", crate::netex::transport_mode(route.route_type))?;
+            text(w, "Description", &route.route_long_name)?;
+            start(w, "StandardService")?;
+            let (origin, destination) = route_trips.ends.clone().unwrap_or_default();
+            let name = |stop_id: &String| self.stop_names.get(stop_id).unwrap_or(stop_id).clone();
+            text(w, "Origin", &name(&origin))?;
+            text(w, "Destination", &name(&destination))?;
+            for &i in &route_trips.trips {
+                copy(w, &self.trips[i].journey_pattern)?;
+            }
+            end(w, "StandardService")?;
+            end(w, "Service")?;
+        }
+        end(w, "Services")?;
+
+        start(w, "VehicleJourneys")?;
+        for trip in &trips {
+            let Some(&period) = periods.get(trip.route_id.as_str()) else {
+                continue;
+            };
+            if self.service_span(&trip.service_id)?.is_none() {
+                continue;
+            }
+            start(w, "VehicleJourney")?;
+            text(w, "PrivateCode", &trip.trip_short_name)?;
+            self.write_operating_profile(w, &trip.service_id, period)?;
+            copy(w, &trip.vehicle_journey)?;
+        }
+        end(w, "VehicleJourneys")?;
+        end(w, "TransXChange")?;
+        Ok(())
+    }
+}
+
+impl GtfsWriter for TxcFeed {
+    fn agency(&mut self, agency: &Agency) -> Result<()> {
+        self.agencies.push(agency.clone());
+        Ok(())
+    }
+
+    fn stop(&mut self, stop: &Stop) -> Result<()> {
+        self.stop_names
+            .insert(stop.stop_id.clone(), stop.stop_name.clone());
+        Ok(())
+    }
+
+    fn route(&mut self, route: &Route) -> Result<()> {
+        self.routes.push(route.clone());
+        Ok(())
+    }
+
+    fn trip(&mut self, trip: &Trip) -> Result<()> {
+        self.finish_trip()?;
+        self.current = Some((trip.clone(), Vec::new()));
+        Ok(())
+    }
+
+    fn stop_time(&mut self, stop_time: &StopTime) -> Result<()> {
+        match &mut self.current {
+            Some((trip, stop_times)) if trip.trip_id == stop_time.trip_id => {
+                stop_times.push(stop_time.clone());
+                Ok(())
+            }
+            _ => bail!(
+                "Stop time of trip {} given apart from its trip",
+                stop_time.trip_id
+            ),
+        }
+    }
+
+    fn calendar(&mut self, calendar: &Calendar) -> Result<()> {
+        self.calendars
+            .insert(calendar.service_id.clone(), calendar.clone());
+        Ok(())
+    }
+
+    fn calendar_date(&mut self, calendar_date: &CalendarDate) -> Result<()> {
+        self.calendar_dates
+            .entry(calendar_date.service_id.clone())
+            .or_default()
+            .push(calendar_date.clone());
+        Ok(())
+    }
+
+    fn transfer(&mut self, _transfer: &Transfer) -> Result<()> {
+        Ok(())
+    }
+
+    fn frequency(&mut self, _frequency: &Frequency) -> Result<()> {
+        bail!("Frequencies can't be written to TransXChange")
+    }
+
+    fn pathway(&mut self, _pathway: &Pathway) -> Result<()> {
+        Ok(())
+    }
+
+    fn level(&mut self, _level: &Level) -> Result<()> {
+        Ok(())
+    }
+
+    fn shape(&mut self, _shape: &ShapePoint) -> Result<()> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finish_trip()?;
+        let Some(scratch) = self.scratch.take() else {
+            return Ok(());
+        };
+        let mut file = scratch.file.into_inner().map_err(|e| e.into_error())?;
+        fs::create_dir_all(&self.dir)?;
+        for agency in &self.agencies {
+            let routes: Vec<&Route> = self
+                .routes
+                .iter()
+                .filter(|route| {
+                    route.agency_id == agency.agency_id
+                        && self.route_trips.contains_key(&route.route_id)
+                })
+                .collect();
+            if routes.is_empty() {
+                continue;
+            }
+            let file_name = format!("{}.xml", agency.agency_id);
+            let path = self.dir.join(&file_name);
+            let out = File::create(&path)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+            let mut w = Writer::new(BufWriter::new(out));
+            self.write_operator(&mut w, &mut file, agency, &routes, &file_name)?;
+            w.get_mut().flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::Fixture;
+    use crate::{CalendarMode, write_calendars, write_converted_trip};
+    use quick_xml::Reader;
+
+    #[test]
+    fn test_duration() {
+        assert_eq!(duration(0), "PT0M");
+        assert_eq!(duration(300), "PT5M");
+        assert_eq!(duration(330), "PT5M30S");
+    }
+
+    #[test]
+    fn test_write_fixture_trips_as_txc() {
+        let (trips, summary, _) = Fixture::default().convert("sample.MSN", "sample.MCA");
+        let dir = tempfile::tempdir().unwrap();
+        let mut feed = TxcFeed::create(&dir.path().join(TXC_DIR)).unwrap();
+        for trip in &trips {
+            write_converted_trip(&mut feed, trip).unwrap();
+        }
+        write_calendars(&mut feed, &summary.calendars, CalendarMode::Calendar).unwrap();
+        for agency in summary.agencies.values() {
+            feed.agency(agency).unwrap();
+        }
+        for route in summary.routes.values() {
+            feed.route(route).unwrap();
+        }
+        feed.finish().unwrap();
+
+        // A document per operator, well formed, between them holding every trip
+        let mut journeys = 0;
+        for entry in fs::read_dir(dir.path().join(TXC_DIR)).unwrap() {
+            let xml = fs::read_to_string(entry.unwrap().path()).unwrap();
+            let mut reader = Reader::from_str(&xml);
+            loop {
+                match reader.read_event().unwrap() {
+                    Event::Start(e) if e.name().as_ref() == b"VehicleJourney" => journeys += 1,
+                    Event::Eof => break,
+                    _ => {}
+                }
+            }
+        }
+        assert_eq!(journeys, trips.len());
+
+        let xml = fs::read_to_string(dir.path().join(TXC_DIR).join("GR.xml")).unwrap();
+        let trip = &trips[0].trip;
+        assert!(xml.contains(&format!(
+            "<VehicleJourneyCode>{}</VehicleJourneyCode>",
+            trip.trip_id
+        )));
+        assert!(xml.contains("<Operator id=\"GR\"><OperatorCode>GR</OperatorCode>"));
+        assert!(xml.contains("<RegularDayType><DaysOfWeek><Monday/>"));
+        assert!(xml.contains("<DepartureTime>09:00:00</DepartureTime>"));
+    }
+}
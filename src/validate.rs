@@ -0,0 +1,230 @@
+//! Post-conversion checks on the written feed.
+//!
+//! The feed is read back from disk rather than checked as it's built, so
+//! the report describes exactly what consumers will see.
+
+use crate::gtfs_seconds;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Offending ids kept per category, to point at without bloating the report
+const MAX_EXAMPLES: usize = 10;
+
+#[derive(Debug, Default, Serialize)]
+pub struct ValidationReport {
+    /// Issue counts by category
+    pub errors: BTreeMap<&'static str, usize>,
+    /// The first few ids behind each category
+    pub examples: BTreeMap<&'static str, Vec<String>>,
+}
+
+impl ValidationReport {
+    fn error(&mut self, category: &'static str, id: impl Into<String>) {
+        *self.errors.entry(category).or_default() += 1;
+        let examples = self.examples.entry(category).or_default();
+        if examples.len() < MAX_EXAMPLES {
+            examples.push(id.into());
+        }
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.errors.values().sum()
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to write {}", path.display()))?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct StopRow {
+    stop_id: String,
+}
+
+#[derive(Deserialize)]
+struct RouteRow {
+    route_id: String,
+}
+
+#[derive(Deserialize)]
+struct CalendarRow {
+    service_id: String,
+}
+
+#[derive(Deserialize)]
+struct CalendarDateRow {
+    service_id: String,
+    exception_type: u8,
+}
+
+#[derive(Deserialize)]
+struct TripRow {
+    route_id: String,
+    service_id: String,
+    trip_id: String,
+}
+
+#[derive(Deserialize)]
+struct StopTimeRow {
+    trip_id: String,
+    arrival_time: String,
+    departure_time: String,
+    stop_id: String,
+}
+
+fn ids<R: Read, T: for<'de> Deserialize<'de>>(
+    reader: R,
+    id: impl Fn(T) -> String,
+) -> Result<HashSet<String>> {
+    csv::Reader::from_reader(reader)
+        .deserialize()
+        .map(|row| Ok(id(row?)))
+        .collect()
+}
+
+/// The readers for each file the checks need
+pub struct FeedFiles<R> {
+    pub stops: R,
+    pub routes: R,
+    pub calendar: R,
+    pub calendar_dates: R,
+    pub trips: R,
+    pub stop_times: R,
+}
+
+impl FeedFiles<File> {
+    pub fn open(dir: &Path) -> Result<Self> {
+        let open = |name: &str| {
+            let path = dir.join(name);
+            File::open(&path).with_context(|| format!("Failed to open {}", path.display()))
+        };
+        Ok(FeedFiles {
+            stops: open("stops.txt")?,
+            routes: open("routes.txt")?,
+            calendar: open("calendar.txt")?,
+            calendar_dates: open("calendar_dates.txt")?,
+            trips: open("trips.txt")?,
+            stop_times: open("stop_times.txt")?,
+        })
+    }
+}
+
+/// Check referential integrity, that every trip has at least two stops and
+/// that times never go backwards along a trip
+pub fn validate<R: Read>(files: FeedFiles<R>) -> Result<ValidationReport> {
+    let mut report = ValidationReport::default();
+    let stops = ids(files.stops, |s: StopRow| s.stop_id)?;
+    let routes = ids(files.routes, |r: RouteRow| r.route_id)?;
+    // Services can be defined by added dates alone
+    let mut services = ids(files.calendar, |c: CalendarRow| c.service_id)?;
+    for row in csv::Reader::from_reader(files.calendar_dates).deserialize() {
+        let row: CalendarDateRow = row?;
+        if row.exception_type == 1 {
+            services.insert(row.service_id);
+        }
+    }
+
+    let mut trips: HashMap<String, usize> = HashMap::new();
+    for trip in csv::Reader::from_reader(files.trips).deserialize() {
+        let trip: TripRow = trip?;
+        if !routes.contains(&trip.route_id) {
+            report.error("unknown_route", &trip.trip_id);
+        }
+        if !services.contains(&trip.service_id) {
+            report.error("unknown_service", &trip.trip_id);
+        }
+        trips.insert(trip.trip_id, 0);
+    }
+
+    // Last departure seen per trip
+    let mut last_time: HashMap<String, u32> = HashMap::new();
+    let mut non_monotonic: HashSet<String> = HashSet::new();
+    for stop_time in csv::Reader::from_reader(files.stop_times).deserialize() {
+        let st: StopTimeRow = stop_time?;
+        if !stops.contains(&st.stop_id) {
+            report.error("unknown_stop", format!("{} at {}", st.trip_id, st.stop_id));
+        }
+        match trips.get_mut(&st.trip_id) {
+            Some(count) => *count += 1,
+            None => report.error("unknown_trip", &st.trip_id),
+        }
+        let (Some(arrival), Some(departure)) = (
+            gtfs_seconds(&st.arrival_time),
+            gtfs_seconds(&st.departure_time),
+        ) else {
+            report.error("invalid_time", &st.trip_id);
+            continue;
+        };
+        let previous = last_time.insert(st.trip_id.clone(), departure);
+        if (previous.is_some_and(|p| arrival < p) || departure < arrival)
+            && non_monotonic.insert(st.trip_id.clone())
+        {
+            report.error("non_monotonic_times", &st.trip_id);
+        }
+    }
+
+    let mut short: Vec<&String> = trips
+        .iter()
+        .filter(|&(_, &count)| count < 2)
+        .map(|(trip_id, _)| trip_id)
+        .collect();
+    short.sort();
+    for trip_id in short {
+        report.error("too_few_stops", trip_id);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_flags_broken_references_and_times() {
+        let files = FeedFiles {
+            stops: "stop_id,stop_name\nKNGX,London Kings Cross\nYORK,York\n".as_bytes(),
+            routes: "route_id,route_type\nGR,2\n".as_bytes(),
+            calendar: "service_id,monday\nS1,1\n".as_bytes(),
+            calendar_dates: "service_id,date,exception_type\n\
+                             S3,20240101,1\n\
+                             S2,20240101,2\n"
+                .as_bytes(),
+            trips: "route_id,service_id,trip_id\n\
+                    GR,S1,ok\n\
+                    GR,S1,backwards\n\
+                    GR,S3,dated\n\
+                    XX,S2,lonely\n"
+                .as_bytes(),
+            stop_times: "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+                         ok,10:00:00,10:00:00,KNGX,1\n\
+                         ok,11:50:00,11:52:00,YORK,2\n\
+                         backwards,23:30:00,23:30:00,KNGX,1\n\
+                         backwards,01:20:00,01:20:00,YORK,2\n\
+                         dated,10:00:00,10:00:00,KNGX,1\n\
+                         dated,11:50:00,11:52:00,YORK,2\n\
+                         lonely,10:00:00,10:00:00,LEEDS,1\n\
+                         ghost,10:00:00,10:00:00,KNGX,1\n"
+                .as_bytes(),
+        };
+        let report = validate(files).unwrap();
+        let expected: BTreeMap<&str, usize> = [
+            ("non_monotonic_times", 1),
+            ("too_few_stops", 1),
+            ("unknown_route", 1),
+            ("unknown_service", 1),
+            ("unknown_stop", 1),
+            ("unknown_trip", 1),
+        ]
+        .into();
+        assert_eq!(report.errors, expected);
+        assert_eq!(report.examples["too_few_stops"], vec!["lonely"]);
+        assert_eq!(report.error_count(), 6);
+    }
+}
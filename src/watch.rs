@@ -0,0 +1,261 @@
+//! The `watch` subcommand: rebuild the feed as new timetables come out.
+//!
+//! Rebuilds run on a cron schedule in UK time (`--schedule "0 4 * * *"`),
+//! when a conditional GET shows NRDP has a new timetable extract
+//! (`--poll-interval 900`), or both, and once at startup. Every rebuild is
+//! converted into a staging directory beside the published one and only
+//! swapped into place once it validates without errors, so a bad extract
+//! leaves the last good feed being served. The conversion options are the
+//! top-level ones, given before `watch`.
+//!
+//! The outcome of each rebuild can be sent to webhooks (see [`webhook`]).
+
+use crate::credentials::{Credentials, Service};
+use crate::metrics::{self, Metrics};
+use crate::nrdp::{self, FeedVersion, NrdpClient};
+use crate::stats::Stats;
+use crate::status::{Stage, Status};
+use crate::timezone::TIMEZONE;
+use crate::webhook::{self, Notification, WebhookFormat};
+use crate::writer::{parent_dir, replace_dir};
+use crate::{Args, InputSource, TIMETABLE_URL};
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use clap::ArgGroup;
+use cron::Schedule;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+#[derive(clap::Args)]
+#[command(group(ArgGroup::new("trigger").required(true).multiple(true)))]
+pub struct WatchArgs {
+    /// Cron schedule of rebuilds in UK time: minute hour day month weekday,
+    /// optionally preceded by seconds (e.g. "0 4 * * *")
+    #[arg(long, group = "trigger", value_parser = parse_schedule)]
+    schedule: Option<Schedule>,
+
+    /// Seconds between checks for a new NRDP timetable extract, rebuilding when there is one
+    #[arg(long, group = "trigger", value_name = "SECS")]
+    poll_interval: Option<u64>,
+
+    /// Directory to publish the feed to, replaced only by a rebuild that validates
+    #[arg(long, default_value = "./gtfs_output")]
+    publish_dir: PathBuf,
+
+    /// Also publish the GTFS files zipped at this path
+    #[arg(long, value_name = "PATH")]
+    publish_zip: Option<PathBuf>,
+
+    /// Address to serve Prometheus metrics on (e.g. :9090)
+    #[arg(long, value_name = "ADDR")]
+    serve_metrics: Option<String>,
+
+    /// URL to POST a JSON notification to after every rebuild (repeatable)
+    #[arg(long = "webhook", value_name = "URL")]
+    webhooks: Vec<String>,
+
+    /// Payload to send to the webhooks
+    #[arg(long, value_enum, default_value_t = WebhookFormat::Generic)]
+    webhook_format: WebhookFormat,
+}
+
+/// A cron expression, with the seconds field the `cron` crate requires
+/// added if left out
+fn parse_schedule(s: &str) -> Result<Schedule, String> {
+    let expression = if s.split_whitespace().count() == 5 {
+        format!("0 {}", s)
+    } else {
+        s.to_string()
+    };
+    Schedule::from_str(&expression).map_err(|e| format!("invalid schedule '{}': {}", s, e))
+}
+
+/// Convert into a staging directory and publish the feed if it validates
+fn rebuild(args: &Args, credentials: &Credentials, watch: &WatchArgs) -> Result<Stats> {
+    let parent = parent_dir(&watch.publish_dir);
+    fs::create_dir_all(parent)?;
+    let staging = tempfile::Builder::new()
+        .prefix(".gtfs-staging")
+        .tempdir_in(parent)?;
+    let (stats, report) = crate::convert(args, credentials, staging.path())?;
+    if report.error_count() > 0 {
+        return Err(Stage::Validation.tag(anyhow::anyhow!(
+            "Validation found {} errors; keeping the published feed",
+            report.error_count()
+        )));
+    }
+    if let Some(zip_path) = &watch.publish_zip {
+        let zip = tempfile::NamedTempFile::new_in(parent_dir(zip_path))?;
+        crate::writer::zip_feed(staging.path(), zip.path())?;
+        zip.persist(zip_path)
+            .with_context(|| format!("Failed to publish {}", zip_path.display()))?;
+    }
+    replace_dir(&staging.keep(), &watch.publish_dir)?;
+    println!(
+        "Published {} trips to {}.",
+        stats.trips(),
+        watch.publish_dir.display()
+    );
+    Ok(stats)
+}
+
+/// What sets off a rebuild
+struct Triggers {
+    schedule: Option<Schedule>,
+    poll: Option<(Duration, NrdpClient)>,
+    version: Option<FeedVersion>,
+    runtime: Runtime,
+}
+
+impl Triggers {
+    /// Whether the timetable extract has changed since it was last seen
+    fn extract_changed(&mut self) -> bool {
+        let Some((_, nrdp)) = &self.poll else {
+            return false;
+        };
+        match self
+            .runtime
+            .block_on(nrdp.feed_changed(TIMETABLE_URL, self.version.as_ref()))
+        {
+            Ok(Some(version)) => {
+                let first = self.version.is_none();
+                self.version = Some(version);
+                // The first check only learns the version built at startup
+                !first
+            }
+            Ok(None) => false,
+            Err(e) => {
+                println!("Warning: could not check for a new extract: {:#}", e);
+                false
+            }
+        }
+    }
+
+    /// Sleep until the next scheduled rebuild, or until NRDP has a new
+    /// extract, whichever comes first
+    fn wait(&mut self) {
+        let next_run: Option<DateTime<Utc>> = self.schedule.as_ref().and_then(|schedule| {
+            schedule
+                .upcoming(TIMEZONE)
+                .next()
+                .map(|time| time.with_timezone(&Utc))
+        });
+        if let Some(time) = next_run {
+            println!(
+                "Next scheduled rebuild at {}.",
+                time.with_timezone(&TIMEZONE)
+            );
+        }
+        loop {
+            let until_run = next_run.map(|time| (time - Utc::now()).to_std().unwrap_or_default());
+            if until_run == Some(Duration::ZERO) {
+                return;
+            }
+            let poll = self.poll.as_ref().map(|(interval, _)| *interval);
+            let sleep = match (until_run, poll) {
+                (Some(run), Some(poll)) => run.min(poll),
+                (Some(wait), None) | (None, Some(wait)) => wait,
+                (None, None) => unreachable!("clap requires a trigger"),
+            };
+            std::thread::sleep(sleep);
+            if poll.is_some_and(|poll| sleep == poll) && self.extract_changed() {
+                println!("NRDP has a new timetable extract.");
+                return;
+            }
+        }
+    }
+}
+
+pub fn run(watch: WatchArgs, args: &Args, credentials: &Credentials) -> Result<()> {
+    if args.dry_run {
+        bail!("--dry-run doesn't apply to watch");
+    }
+    let runtime = nrdp::runtime()?;
+    let poll = match watch.poll_interval {
+        Some(_) if args.source != InputSource::Cif => {
+            bail!("--poll-interval only applies to --source cif")
+        }
+        Some(secs) => {
+            let (username, password) = credentials.get(Service::Nrdp)?;
+            let nrdp = runtime.block_on(NrdpClient::connect(
+                args.http_options().client()?,
+                args.retry_policy(),
+                username,
+                password,
+                args.token_cache(),
+            ))?;
+            Some((Duration::from_secs(secs), nrdp))
+        }
+        None => None,
+    };
+    let mut triggers = Triggers {
+        schedule: watch.schedule.clone(),
+        poll,
+        version: None,
+        runtime,
+    };
+    // Learn the version of the extract the first rebuild will use
+    triggers.extract_changed();
+
+    let metrics = Arc::new(Metrics::default());
+    if let Some(listen) = &watch.serve_metrics {
+        metrics::serve(Arc::clone(&metrics), listen)?;
+    }
+    let client = args.http_options().blocking_client()?;
+    loop {
+        let started = Instant::now();
+        let result = rebuild(args, credentials, &watch);
+        let notification = match &result {
+            Ok(stats) => {
+                metrics.record_update(started.elapsed(), stats.records_read, stats.trips());
+                Notification {
+                    status: "success",
+                    error: None,
+                    duration_secs: started.elapsed().as_secs_f64(),
+                    stats: Some(stats),
+                }
+            }
+            Err(e) => {
+                metrics.record_error();
+                println!("Rebuild failed: {:#}", e);
+                let status = Status::failure(e, started.elapsed());
+                Notification {
+                    status: status.status,
+                    error: status.error,
+                    duration_secs: status.duration_secs,
+                    stats: None,
+                }
+            }
+        };
+        for url in &watch.webhooks {
+            if let Err(e) = webhook::send(&client, url, watch.webhook_format, &notification) {
+                println!("Warning: webhook {} failed: {:#}", url, e);
+            }
+        }
+        triggers.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parse_schedule_adds_seconds() {
+        let schedule = parse_schedule("30 4 * * *").unwrap();
+        let after = TIMEZONE
+            .with_ymd_and_hms(2024, 3, 30, 12, 0, 0)
+            .single()
+            .unwrap();
+        // The clocks go forward overnight; 04:30 is still 04:30 UK time
+        let next = schedule.after(&after).next().unwrap();
+        assert_eq!(next.to_rfc3339(), "2024-03-31T04:30:00+01:00");
+        assert!(parse_schedule("0 0 4 * * *").is_ok());
+        assert!(parse_schedule("every day").is_err());
+    }
+}
@@ -0,0 +1,120 @@
+//! Notifications of `watch` rebuilds, POSTed as JSON to webhooks.
+//!
+//! The generic payload carries the outcome with the full stats, for
+//! scripts. Slack incoming webhooks and Matrix hookshot webhooks get a
+//! one-line summary as a message instead.
+
+use crate::stats::Stats;
+use anyhow::{Result, bail};
+use clap::ValueEnum;
+use reqwest::blocking::Client;
+use serde::Serialize;
+use serde_json::json;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WebhookFormat {
+    /// `{"status", "error", "duration_secs", "stats"}`
+    Generic,
+    /// A Slack incoming webhook message
+    Slack,
+    /// A Matrix hookshot generic webhook message
+    Matrix,
+}
+
+/// How a rebuild went
+#[derive(Debug, Serialize)]
+pub struct Notification<'a> {
+    pub status: &'static str,
+    pub error: Option<String>,
+    pub duration_secs: f64,
+    pub stats: Option<&'a Stats>,
+}
+
+impl Notification<'_> {
+    fn summary(&self) -> String {
+        match (self.stats, &self.error) {
+            (_, Some(error)) => format!("GTFS rebuild failed: {}", error),
+            (Some(stats), None) => {
+                let dates = match (stats.first_service_date, stats.last_service_date) {
+                    (Some(first), Some(last)) => format!(", running {} to {}", first, last),
+                    _ => String::new(),
+                };
+                format!(
+                    "GTFS rebuilt in {:.0}s: {} trips on {} calendars{}",
+                    self.duration_secs,
+                    stats.trips(),
+                    stats.calendars,
+                    dates
+                )
+            }
+            (None, None) => format!("GTFS rebuild: {}", self.status),
+        }
+    }
+
+    fn payload(&self, format: WebhookFormat) -> serde_json::Value {
+        match format {
+            WebhookFormat::Generic => json!(self),
+            WebhookFormat::Slack => json!({ "text": self.summary() }),
+            WebhookFormat::Matrix => json!({
+                "text": self.summary(),
+                "username": "nationalrail-gtfs",
+            }),
+        }
+    }
+}
+
+/// POST the notification to a webhook
+pub fn send(
+    client: &Client,
+    url: &str,
+    format: WebhookFormat,
+    notification: &Notification,
+) -> Result<()> {
+    let response = client
+        .post(url)
+        .json(&notification.payload(format))
+        .send()?;
+    let status = response.status();
+    if !status.is_success() {
+        bail!("HTTP {}: {}", status, response.text().unwrap_or_default());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_payloads() {
+        let mut stats = Stats::default();
+        let date = |d: u32| NaiveDate::from_ymd_opt(2024, 5, d).unwrap();
+        stats.trip_written("GR", date(1), date(31));
+        stats.calendars = 1;
+        let success = Notification {
+            status: "success",
+            error: None,
+            duration_secs: 92.4,
+            stats: Some(&stats),
+        };
+        assert_eq!(
+            success.payload(WebhookFormat::Slack),
+            json!({ "text": "GTFS rebuilt in 92s: 1 trips on 1 calendars, running 2024-05-01 to 2024-05-31" })
+        );
+        let generic = success.payload(WebhookFormat::Generic);
+        assert_eq!(generic["status"], "success");
+        assert_eq!(generic["stats"]["trips_per_toc"]["GR"], 1);
+
+        let failure = Notification {
+            status: "download_failed",
+            error: Some("Failed to download timetable feed: HTTP 503".to_string()),
+            duration_secs: 3.0,
+            stats: None,
+        };
+        assert_eq!(
+            failure.payload(WebhookFormat::Matrix)["text"],
+            "GTFS rebuild failed: Failed to download timetable feed: HTTP 503"
+        );
+    }
+}
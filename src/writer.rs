@@ -0,0 +1,676 @@
+//! Destinations for the core GTFS files.
+//!
+//! The conversion writes records through [`GtfsWriter`] rather than to CSV
+//! handles directly, so the same pipeline can fill a feed directory or
+//! collect everything in memory for tests.
+
+use crate::frequencies::Frequency;
+use crate::pathways::{Level, Pathway};
+use crate::shapes::ShapePoint;
+use crate::{Agency, Calendar, CalendarDate, Route, Stop, StopTime, Transfer, Trip};
+use anyhow::{Context, Result};
+use csv::{Writer, WriterBuilder};
+use serde::Serialize;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use zip::write::FileOptions;
+
+/// Receives the records of agency.txt, stops.txt, routes.txt, trips.txt,
+/// stop_times.txt, calendar.txt, calendar_dates.txt, transfers.txt,
+/// frequencies.txt, pathways.txt, levels.txt and shapes.txt, in any
+/// interleaving
+pub trait GtfsWriter {
+    fn agency(&mut self, agency: &Agency) -> Result<()>;
+    fn stop(&mut self, stop: &Stop) -> Result<()>;
+    fn route(&mut self, route: &Route) -> Result<()>;
+    fn trip(&mut self, trip: &Trip) -> Result<()>;
+    fn stop_time(&mut self, stop_time: &StopTime) -> Result<()>;
+    fn calendar(&mut self, calendar: &Calendar) -> Result<()>;
+    fn calendar_date(&mut self, calendar_date: &CalendarDate) -> Result<()>;
+    fn transfer(&mut self, transfer: &Transfer) -> Result<()>;
+    fn frequency(&mut self, frequency: &Frequency) -> Result<()>;
+    fn pathway(&mut self, pathway: &Pathway) -> Result<()>;
+    fn level(&mut self, level: &Level) -> Result<()>;
+    fn shape(&mut self, shape: &ShapePoint) -> Result<()>;
+
+    /// Flush anything buffered. Nothing is guaranteed to be written until
+    /// this has been called.
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// Bytes buffered per file before a write, by default. Much more than the
+/// csv crate's own 8 KiB, as stop_times.txt runs to hundreds of megabytes
+/// and small writes are slow on network storage.
+pub const DEFAULT_BUFFER_SIZE: usize = 1 << 20;
+
+/// A feed written as CSV files into a directory. frequencies.txt,
+/// pathways.txt, levels.txt and shapes.txt are only created if there are
+/// rows to write. trips.txt comes out by trip id and stop_times.txt by trip
+/// id and stop sequence, whatever order they're written in.
+pub struct DirectoryFeed {
+    dir: PathBuf,
+    buffer_size: usize,
+    agency: Writer<File>,
+    stops: Writer<File>,
+    routes: Writer<File>,
+    trips: SortedCsv,
+    stop_times: SortedCsv,
+    calendar: Writer<File>,
+    calendar_dates: Writer<File>,
+    transfers: Writer<File>,
+    frequencies: Option<Writer<File>>,
+    pathways: Option<Writer<File>>,
+    levels: Option<Writer<File>>,
+    shapes: Option<Writer<File>>,
+}
+
+/// Create a CSV file in `dir`, buffering `buffer_size` bytes between writes
+pub fn create_csv(dir: &Path, name: &str, buffer_size: usize) -> Result<Writer<File>> {
+    let path = dir.join(name);
+    WriterBuilder::new()
+        .buffer_capacity(buffer_size)
+        .from_path(&path)
+        .with_context(|| format!("Failed to create {}", path.display()))
+}
+
+impl DirectoryFeed {
+    pub fn create(dir: &Path, buffer_size: usize) -> Result<Self> {
+        let open = |name: &str| create_csv(dir, name, buffer_size);
+        Ok(DirectoryFeed {
+            dir: dir.to_path_buf(),
+            buffer_size,
+            agency: open("agency.txt")?,
+            stops: open("stops.txt")?,
+            routes: open("routes.txt")?,
+            trips: SortedCsv::create(dir, "trips.txt", buffer_size)?,
+            stop_times: SortedCsv::create(dir, "stop_times.txt", buffer_size)?,
+            calendar: open("calendar.txt")?,
+            calendar_dates: open("calendar_dates.txt")?,
+            transfers: open("transfers.txt")?,
+            frequencies: None,
+            pathways: None,
+            levels: None,
+            shapes: None,
+        })
+    }
+}
+
+impl GtfsWriter for DirectoryFeed {
+    fn agency(&mut self, agency: &Agency) -> Result<()> {
+        Ok(self.agency.serialize(agency)?)
+    }
+
+    fn stop(&mut self, stop: &Stop) -> Result<()> {
+        Ok(self.stops.serialize(stop)?)
+    }
+
+    fn route(&mut self, route: &Route) -> Result<()> {
+        Ok(self.routes.serialize(route)?)
+    }
+
+    fn trip(&mut self, trip: &Trip) -> Result<()> {
+        self.trips.serialize(&trip.trip_id, 0, trip)
+    }
+
+    fn stop_time(&mut self, stop_time: &StopTime) -> Result<()> {
+        self.stop_times
+            .serialize(&stop_time.trip_id, stop_time.stop_sequence, stop_time)
+    }
+
+    fn calendar(&mut self, calendar: &Calendar) -> Result<()> {
+        Ok(self.calendar.serialize(calendar)?)
+    }
+
+    fn calendar_date(&mut self, calendar_date: &CalendarDate) -> Result<()> {
+        Ok(self.calendar_dates.serialize(calendar_date)?)
+    }
+
+    fn transfer(&mut self, transfer: &Transfer) -> Result<()> {
+        Ok(self.transfers.serialize(transfer)?)
+    }
+
+    fn frequency(&mut self, frequency: &Frequency) -> Result<()> {
+        let writer = match &mut self.frequencies {
+            Some(writer) => writer,
+            None => {
+                self.frequencies
+                    .insert(create_csv(&self.dir, "frequencies.txt", self.buffer_size)?)
+            }
+        };
+        Ok(writer.serialize(frequency)?)
+    }
+
+    fn pathway(&mut self, pathway: &Pathway) -> Result<()> {
+        let writer = match &mut self.pathways {
+            Some(writer) => writer,
+            None => self
+                .pathways
+                .insert(create_csv(&self.dir, "pathways.txt", self.buffer_size)?),
+        };
+        Ok(writer.serialize(pathway)?)
+    }
+
+    fn level(&mut self, level: &Level) -> Result<()> {
+        let writer = match &mut self.levels {
+            Some(writer) => writer,
+            None => self
+                .levels
+                .insert(create_csv(&self.dir, "levels.txt", self.buffer_size)?),
+        };
+        Ok(writer.serialize(level)?)
+    }
+
+    fn shape(&mut self, shape: &ShapePoint) -> Result<()> {
+        let writer = match &mut self.shapes {
+            Some(writer) => writer,
+            None => self
+                .shapes
+                .insert(create_csv(&self.dir, "shapes.txt", self.buffer_size)?),
+        };
+        Ok(writer.serialize(shape)?)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        for writer in [
+            &mut self.agency,
+            &mut self.stops,
+            &mut self.routes,
+            &mut self.calendar,
+            &mut self.calendar_dates,
+            &mut self.transfers,
+        ]
+        .into_iter()
+        .chain(&mut self.frequencies)
+        .chain(&mut self.pathways)
+        .chain(&mut self.levels)
+        .chain(&mut self.shapes)
+        {
+            writer.flush()?;
+        }
+        self.trips.finish()?;
+        self.stop_times.finish()
+    }
+}
+
+/// Rows kept together under one key, by where they are in the scratch file
+struct Block {
+    key: String,
+    /// Sequence numbers of the first row and the last
+    sequence: u32,
+    last: u32,
+    start: u64,
+    len: u64,
+}
+
+/// A CSV file written in order of a key and sequence number. Rows go to an
+/// unnamed scratch file as they come, each run of rows under the same key
+/// in rising sequence as one block, and the blocks are copied out in order
+/// at the end, so only the index is held in memory.
+struct SortedCsv {
+    path: PathBuf,
+    buffer_size: usize,
+    scratch: Option<Writer<Counted>>,
+    header: Option<Vec<u8>>,
+    blocks: Vec<Block>,
+}
+
+/// The scratch file, counting the bytes written to it. Flushing is left to
+/// the end, so the CSV writer can hand over each row as it's serialised.
+struct Counted {
+    file: BufWriter<File>,
+    written: u64,
+}
+
+impl Write for Counted {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SortedCsv {
+    fn create(dir: &Path, name: &str, buffer_size: usize) -> Result<Self> {
+        let file = tempfile::tempfile_in(dir)
+            .with_context(|| format!("Failed to create scratch file for {}", name))?;
+        let counted = Counted {
+            file: BufWriter::with_capacity(buffer_size, file),
+            written: 0,
+        };
+        Ok(SortedCsv {
+            path: dir.join(name),
+            buffer_size,
+            scratch: Some(WriterBuilder::new().has_headers(false).from_writer(counted)),
+            header: None,
+            blocks: Vec::new(),
+        })
+    }
+
+    fn serialize<T: Serialize>(&mut self, key: &str, sequence: u32, record: &T) -> Result<()> {
+        if self.header.is_none() {
+            let mut first = Writer::from_writer(Vec::new());
+            first.serialize(record)?;
+            let bytes = first.into_inner().map_err(|e| e.into_error())?;
+            let end = bytes.iter().position(|&b| b == b'\n').map_or(0, |i| i + 1);
+            self.header = Some(bytes[..end].to_vec());
+        }
+        let scratch = self
+            .scratch
+            .as_mut()
+            .context("Sorted CSV already finished")?;
+        let start = scratch.get_ref().written;
+        scratch.serialize(record)?;
+        scratch.flush()?;
+        let len = scratch.get_ref().written - start;
+        match self.blocks.last_mut() {
+            Some(block) if block.key == key && block.last <= sequence => {
+                block.last = sequence;
+                block.len += len;
+            }
+            _ => self.blocks.push(Block {
+                key: key.to_string(),
+                sequence,
+                last: sequence,
+                start,
+                len,
+            }),
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let Some(scratch) = self.scratch.take() else {
+            return Ok(());
+        };
+        let counted = scratch.into_inner().map_err(|e| e.into_error())?;
+        let mut scratch = counted.file.into_inner().map_err(|e| e.into_error())?;
+        let mut blocks = std::mem::take(&mut self.blocks);
+        blocks.sort_by(|a, b| (&a.key, a.sequence).cmp(&(&b.key, b.sequence)));
+
+        let file = File::create(&self.path)
+            .with_context(|| format!("Failed to create {}", self.path.display()))?;
+        let mut out = BufWriter::with_capacity(self.buffer_size, file);
+        if let Some(header) = &self.header {
+            out.write_all(header)?;
+        }
+        let mut buffer = Vec::new();
+        for block in blocks {
+            buffer.resize(block.len as usize, 0);
+            scratch.seek(SeekFrom::Start(block.start))?;
+            scratch.read_exact(&mut buffer)?;
+            out.write_all(&buffer)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes to another feed with a prefix on every stop, trip, route, service
+/// and shape id, for `--id-prefix`. Agency, pathway and level ids are left
+/// as they are.
+pub struct PrefixedFeed {
+    inner: Box<dyn GtfsWriter>,
+    prefix: String,
+}
+
+impl PrefixedFeed {
+    pub fn new(inner: Box<dyn GtfsWriter>, prefix: &str) -> Self {
+        PrefixedFeed {
+            inner,
+            prefix: prefix.to_string(),
+        }
+    }
+
+    fn id(&self, id: &str) -> String {
+        format!("{}{}", self.prefix, id)
+    }
+}
+
+impl GtfsWriter for PrefixedFeed {
+    fn agency(&mut self, agency: &Agency) -> Result<()> {
+        self.inner.agency(agency)
+    }
+
+    fn stop(&mut self, stop: &Stop) -> Result<()> {
+        let mut stop = stop.clone();
+        stop.stop_id = self.id(&stop.stop_id);
+        stop.parent_station = stop.parent_station.map(|parent| self.id(&parent));
+        self.inner.stop(&stop)
+    }
+
+    fn route(&mut self, route: &Route) -> Result<()> {
+        let mut route = route.clone();
+        route.route_id = self.id(&route.route_id);
+        self.inner.route(&route)
+    }
+
+    fn trip(&mut self, trip: &Trip) -> Result<()> {
+        let mut trip = trip.clone();
+        trip.route_id = self.id(&trip.route_id);
+        trip.service_id = self.id(&trip.service_id);
+        trip.trip_id = self.id(&trip.trip_id);
+        trip.shape_id = trip.shape_id.map(|shape| self.id(&shape));
+        self.inner.trip(&trip)
+    }
+
+    fn stop_time(&mut self, stop_time: &StopTime) -> Result<()> {
+        let mut stop_time = stop_time.clone();
+        stop_time.trip_id = self.id(&stop_time.trip_id);
+        stop_time.stop_id = self.id(&stop_time.stop_id);
+        self.inner.stop_time(&stop_time)
+    }
+
+    fn calendar(&mut self, calendar: &Calendar) -> Result<()> {
+        let mut calendar = calendar.clone();
+        calendar.service_id = self.id(&calendar.service_id);
+        self.inner.calendar(&calendar)
+    }
+
+    fn calendar_date(&mut self, calendar_date: &CalendarDate) -> Result<()> {
+        let mut calendar_date = calendar_date.clone();
+        calendar_date.service_id = self.id(&calendar_date.service_id);
+        self.inner.calendar_date(&calendar_date)
+    }
+
+    fn transfer(&mut self, transfer: &Transfer) -> Result<()> {
+        let mut transfer = transfer.clone();
+        transfer.from_stop_id = self.id(&transfer.from_stop_id);
+        transfer.to_stop_id = self.id(&transfer.to_stop_id);
+        transfer.from_trip_id = transfer.from_trip_id.map(|trip| self.id(&trip));
+        transfer.to_trip_id = transfer.to_trip_id.map(|trip| self.id(&trip));
+        self.inner.transfer(&transfer)
+    }
+
+    fn frequency(&mut self, frequency: &Frequency) -> Result<()> {
+        let mut frequency = frequency.clone();
+        frequency.trip_id = self.id(&frequency.trip_id);
+        self.inner.frequency(&frequency)
+    }
+
+    fn pathway(&mut self, pathway: &Pathway) -> Result<()> {
+        let mut pathway = pathway.clone();
+        pathway.from_stop_id = self.id(&pathway.from_stop_id);
+        pathway.to_stop_id = self.id(&pathway.to_stop_id);
+        self.inner.pathway(&pathway)
+    }
+
+    fn level(&mut self, level: &Level) -> Result<()> {
+        self.inner.level(level)
+    }
+
+    fn shape(&mut self, shape: &ShapePoint) -> Result<()> {
+        let mut shape = shape.clone();
+        shape.shape_id = self.id(&shape.shape_id);
+        self.inner.shape(&shape)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.inner.finish()
+    }
+}
+
+/// Zip the GTFS files of a feed directory
+pub fn zip_feed(dir: &Path, zip_path: &Path) -> Result<()> {
+    let mut names: Vec<String> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| name.ends_with(".txt"))
+        .collect();
+    names.sort();
+    let mut zip = zip::ZipWriter::new(File::create(zip_path)?);
+    let options = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    for name in names {
+        zip.start_file(name.as_str(), options)?;
+        std::io::copy(&mut File::open(dir.join(&name))?, &mut zip)?;
+    }
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+/// `path` with `suffix` added to its file name
+pub fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map_or_else(OsString::new, OsString::from);
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// The directory `path` is in, as something that can be created in
+pub fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Move `staging` into place as `target`. Directories can't be renamed
+/// over each other, so the old feed is moved aside first and put back if
+/// the new one can't be moved in. This isn't atomic: between the two
+/// renames there's no feed at `target` at all, and a reader that looks then
+/// finds it missing rather than old or new.
+pub fn replace_dir(staging: &Path, target: &Path) -> Result<()> {
+    let previous = sibling(target, ".previous");
+    if previous.exists() {
+        fs::remove_dir_all(&previous)?;
+    }
+    let had_previous = target.exists();
+    if had_previous {
+        fs::rename(target, &previous)
+            .with_context(|| format!("Failed to move {} aside", target.display()))?;
+    }
+    if let Err(e) = fs::rename(staging, target) {
+        if had_previous {
+            let _ = fs::rename(&previous, target);
+        }
+        return Err(e).with_context(|| format!("Failed to publish to {}", target.display()));
+    }
+    if had_previous {
+        fs::remove_dir_all(&previous)?;
+    }
+    Ok(())
+}
+
+/// A feed kept as records, in the order they were written
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemoryFeed {
+    pub agencies: Vec<Agency>,
+    pub stops: Vec<Stop>,
+    pub routes: Vec<Route>,
+    pub trips: Vec<Trip>,
+    pub stop_times: Vec<StopTime>,
+    pub calendars: Vec<Calendar>,
+    pub calendar_dates: Vec<CalendarDate>,
+    pub transfers: Vec<Transfer>,
+    pub frequencies: Vec<Frequency>,
+    pub pathways: Vec<Pathway>,
+    pub levels: Vec<Level>,
+    pub shapes: Vec<ShapePoint>,
+}
+
+#[cfg(test)]
+impl GtfsWriter for MemoryFeed {
+    fn agency(&mut self, agency: &Agency) -> Result<()> {
+        self.agencies.push(agency.clone());
+        Ok(())
+    }
+
+    fn stop(&mut self, stop: &Stop) -> Result<()> {
+        self.stops.push(stop.clone());
+        Ok(())
+    }
+
+    fn route(&mut self, route: &Route) -> Result<()> {
+        self.routes.push(route.clone());
+        Ok(())
+    }
+
+    fn trip(&mut self, trip: &Trip) -> Result<()> {
+        self.trips.push(trip.clone());
+        Ok(())
+    }
+
+    fn stop_time(&mut self, stop_time: &StopTime) -> Result<()> {
+        self.stop_times.push(stop_time.clone());
+        Ok(())
+    }
+
+    fn calendar(&mut self, calendar: &Calendar) -> Result<()> {
+        self.calendars.push(calendar.clone());
+        Ok(())
+    }
+
+    fn calendar_date(&mut self, calendar_date: &CalendarDate) -> Result<()> {
+        self.calendar_dates.push(calendar_date.clone());
+        Ok(())
+    }
+
+    fn transfer(&mut self, transfer: &Transfer) -> Result<()> {
+        self.transfers.push(transfer.clone());
+        Ok(())
+    }
+
+    fn frequency(&mut self, frequency: &Frequency) -> Result<()> {
+        self.frequencies.push(frequency.clone());
+        Ok(())
+    }
+
+    fn pathway(&mut self, pathway: &Pathway) -> Result<()> {
+        self.pathways.push(pathway.clone());
+        Ok(())
+    }
+
+    fn level(&mut self, level: &Level) -> Result<()> {
+        self.levels.push(level.clone());
+        Ok(())
+    }
+
+    fn shape(&mut self, shape: &ShapePoint) -> Result<()> {
+        self.shapes.push(shape.clone());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::Fixture;
+    use std::fs;
+
+    #[test]
+    fn test_directory_feed_with_a_small_buffer() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut feed = DirectoryFeed::create(dir.path(), 16).unwrap();
+        for to_trip in ["T2", "T3"] {
+            feed.transfer(&Transfer {
+                from_stop_id: "PBRO".to_string(),
+                to_stop_id: "PBRO".to_string(),
+                from_trip_id: Some("T1".to_string()),
+                to_trip_id: Some(to_trip.to_string()),
+                transfer_type: 4,
+                min_transfer_time: None,
+            })
+            .unwrap();
+        }
+        feed.finish().unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.path().join("transfers.txt")).unwrap(),
+            "from_stop_id,to_stop_id,from_trip_id,to_trip_id,transfer_type,min_transfer_time\n\
+             PBRO,PBRO,T1,T2,4,\nPBRO,PBRO,T1,T3,4,\n"
+        );
+        assert!(!dir.path().join("frequencies.txt").exists());
+    }
+
+    #[test]
+    fn test_prefixed_feed() {
+        let dir = tempfile::tempdir().unwrap();
+        let inner = DirectoryFeed::create(dir.path(), 16).unwrap();
+        let mut feed = PrefixedFeed::new(Box::new(inner), "gbrail:");
+        feed.transfer(&Transfer {
+            from_stop_id: "PBRO".to_string(),
+            to_stop_id: "PBRO".to_string(),
+            from_trip_id: Some("T1".to_string()),
+            to_trip_id: None,
+            transfer_type: 4,
+            min_transfer_time: None,
+        })
+        .unwrap();
+        feed.finish().unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.path().join("transfers.txt")).unwrap(),
+            "from_stop_id,to_stop_id,from_trip_id,to_trip_id,transfer_type,min_transfer_time\n\
+             gbrail:PBRO,gbrail:PBRO,gbrail:T1,,4,\n"
+        );
+    }
+
+    #[test]
+    fn test_trips_and_stop_times_come_out_in_order() {
+        let (trips, _, _) = Fixture::default().convert("sample.MSN", "sample.MCA");
+        let dir = tempfile::tempdir().unwrap();
+        let mut feed = DirectoryFeed::create(dir.path(), 16).unwrap();
+        for converted in trips.iter().rev() {
+            feed.trip(&converted.trip).unwrap();
+            // The calls of each trip in two runs, the later first
+            let (first, rest) = converted.stop_times.split_at(1);
+            for stop_time in rest.iter().chain(first) {
+                feed.stop_time(stop_time).unwrap();
+            }
+        }
+        feed.finish().unwrap();
+
+        let column = |name: &str, index: usize| -> Vec<String> {
+            csv::Reader::from_path(dir.path().join(name))
+                .unwrap()
+                .records()
+                .map(|row| row.unwrap()[index].to_string())
+                .collect()
+        };
+        assert_eq!(
+            column("trips.txt", 2),
+            ["C10001_240101_P", "C20001_240101_P"]
+        );
+        let calls: Vec<(String, String)> = column("stop_times.txt", 0)
+            .into_iter()
+            .zip(column("stop_times.txt", 4))
+            .collect();
+        let mut sorted = calls.clone();
+        sorted.sort();
+        assert_eq!(calls, sorted);
+        assert_eq!(
+            calls.len(),
+            trips.iter().map(|t| t.stop_times.len()).sum::<usize>()
+        );
+    }
+
+    #[test]
+    fn test_replace_dir_swaps_in_the_new_feed() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("gtfs_output");
+        let staging = dir.path().join(".gtfs-staging");
+        fs::create_dir(&target).unwrap();
+        fs::write(target.join("stops.txt"), "old").unwrap();
+        fs::create_dir(&staging).unwrap();
+        fs::write(staging.join("stops.txt"), "new").unwrap();
+
+        replace_dir(&staging, &target).unwrap();
+        assert_eq!(fs::read_to_string(target.join("stops.txt")).unwrap(), "new");
+        assert!(!staging.exists());
+        assert!(!sibling(&target, ".previous").exists());
+
+        // Publishing for the first time
+        let fresh = dir.path().join("fresh");
+        fs::create_dir(&staging).unwrap();
+        replace_dir(&staging, &fresh).unwrap();
+        assert!(fresh.is_dir());
+    }
+}
@@ -1,3 +1,3 @@
 cd gtfs_output
-zip -r ../nationalrailuk.zip .
+zip -r ../nationalrailuk.zip . -x report.json stats.json
 cd ..